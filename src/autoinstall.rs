@@ -0,0 +1,386 @@
+use serde::Serialize;
+use serde_yaml::Value;
+
+// The autoinstall config of subiquity, the installer of Ubuntu Server. It
+// comes as cloud-init user-data, under an "autoinstall" key. Only the
+// sections we fill are modelled.
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Autoinstall {
+    pub version: u32,
+    pub locale: String,
+    pub keyboard: Keyboard,
+    // An IANA name, e.g. "Europe/Paris".
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    pub identity: Identity,
+    pub ssh: Ssh,
+    pub storage: Storage,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub packages: Vec<String>,
+    // Run once the install is done, with the new system mounted on /target.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub late_commands: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Keyboard {
+    pub layout: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub variant: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Identity {
+    pub hostname: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub realname: String,
+    pub username: String,
+    // Hashed, see crypt::sha512_crypt.
+    pub password: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Ssh {
+    pub install_server: bool,
+    pub authorized_keys: Vec<String>,
+    pub allow_pw: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Storage {
+    pub layout: StorageLayout,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StorageLayout {
+    pub name: LayoutName,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mode: Option<LayoutMode>,
+}
+
+// Both take the whole of the biggest disk, unless told otherwise by the mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LayoutName {
+    Lvm,
+    Direct,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LayoutMode {
+    // Installs in the biggest free space, and leaves the partitions alone.
+    UseGap,
+}
+
+impl Default for Autoinstall {
+    fn default() -> Autoinstall {
+        Autoinstall {
+            version: 1,
+            locale: "en_US.UTF-8".to_string(),
+            keyboard: Keyboard { layout: "us".to_string(), variant: String::new() },
+            timezone: None,
+            identity: Identity {
+                hostname: "ubuntu".to_string(),
+                realname: String::new(),
+                username: "ubuntu".to_string(),
+                password: String::new(),
+            },
+            ssh: Ssh { install_server: false, authorized_keys: Vec::new(), allow_pw: true },
+            storage: Storage { layout: StorageLayout { name: LayoutName::Lvm, mode: None } },
+            packages: Vec::new(),
+            late_commands: Vec::new(),
+        }
+    }
+}
+
+// Names subiquity refuses for the first user, on top of system accounts.
+const RESERVED_USERNAMES: &[&str] = &["root", "daemon", "bin", "sys", "sync", "games", "man", "lp", "mail", "news", "uucp", "proxy", "www-data", "backup", "list", "irc", "gnats", "nobody", "systemd-network", "syslog", "messagebus", "sshd", "ubuntu-admin"];
+
+fn is_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 63
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+        && name.chars().all(|v| v.is_ascii_lowercase() || v.is_ascii_digit() || v == '-')
+}
+
+fn is_username(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_lowercase() || first == '_' => (),
+        _ => return false,
+    }
+    name.len() <= 32 && chars.all(|v| v.is_ascii_lowercase() || v.is_ascii_digit() || v == '_' || v == '-')
+}
+
+// Like "en_US.UTF-8", "sr_RS.UTF-8@latin", or "C.UTF-8".
+fn is_locale(locale: &str) -> bool {
+    let mut parts = locale.splitn(2, '@');
+    let name = parts.next().unwrap_or("").trim_end_matches(".UTF-8");
+    if let Some(modifier) = parts.next() {
+        if modifier.is_empty() || !modifier.chars().all(|v| v.is_ascii_lowercase()) {
+            return false;
+        }
+    }
+    if name == "C" {
+        return true;
+    }
+    let mut parts = name.splitn(2, '_');
+    let language = parts.next().unwrap_or("");
+    let territory = parts.next().unwrap_or("");
+    (2..=3).contains(&language.len())
+        && language.chars().all(|v| v.is_ascii_lowercase())
+        && territory.len() == 2
+        && territory.chars().all(|v| v.is_ascii_uppercase())
+}
+
+// The part of subiquity's autoinstall schema covering the sections we fill,
+// and the others an edited config is likely to have.
+const SCHEMA: &str = include_str!("autoinstall-schema.json");
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(number) if number.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Sequence(_) => "array",
+        Value::Mapping(_) => "object",
+    }
+}
+
+// Checks `value` against `schema`, with the keywords of JSON schema our
+// schema uses: type, enum, minimum, maximum, properties, required,
+// additionalProperties and items.
+fn check_schema(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let keyword = |name: &str| schema.get(name);
+    let value_type = type_name(value);
+    if let Some(types) = keyword("type") {
+        let allowed: Vec<&str> = match types {
+            Value::Sequence(types) => types.iter().filter_map(Value::as_str).collect(),
+            types => types.as_str().into_iter().collect(),
+        };
+        // Integers are numbers too.
+        if !allowed.iter().any(|&v| v == value_type || (v == "number" && value_type == "integer")) {
+            return Err(format!("{} should be of type {}, not {}", path, allowed.join(" or "), value_type));
+        }
+    }
+    if let Some(Value::Sequence(values)) = keyword("enum") {
+        if !values.contains(value) {
+            let names: Vec<&str> = values.iter().filter_map(Value::as_str).collect();
+            return Err(format!("{} should be one of {}", path, names.join(", ")));
+        }
+    }
+    if let Some(number) = value.as_f64() {
+        if let Some(minimum) = keyword("minimum").and_then(Value::as_f64) {
+            if number < minimum {
+                return Err(format!("{} should be at least {}", path, minimum));
+            }
+        }
+        if let Some(maximum) = keyword("maximum").and_then(Value::as_f64) {
+            if number > maximum {
+                return Err(format!("{} should be at most {}", path, maximum));
+            }
+        }
+    }
+
+    if let Value::Mapping(mapping) = value {
+        let properties = keyword("properties");
+        if let Some(Value::Sequence(required)) = keyword("required") {
+            if let Some(name) = required.iter().filter_map(Value::as_str).find(|&name| value.get(name).is_none()) {
+                return Err(format!("{}.{} is missing", path, name));
+            }
+        }
+        for (key, item) in mapping {
+            let key = key.as_str().ok_or_else(|| format!("{} has a key that isn't a string", path))?;
+            let path = format!("{}.{}", path, key);
+            match properties.and_then(|v| v.get(key)) {
+                Some(schema) => check_schema(schema, item, &path)?,
+                None if keyword("additionalProperties") == Some(&Value::Bool(false)) => return Err(format!("{} isn't allowed", path)),
+                None => (),
+            }
+        }
+    }
+    if let (Value::Sequence(items), Some(schema)) = (value, keyword("items")) {
+        for (idx, item) in items.iter().enumerate() {
+            check_schema(schema, item, &format!("{}[{}]", path, idx))?;
+        }
+    }
+    Ok(())
+}
+
+const SSH_KEY_TYPES: &[&str] = &["ssh-rsa", "ssh-dss", "ssh-ed25519", "ecdsa-sha2-nistp256", "ecdsa-sha2-nistp384", "ecdsa-sha2-nistp521", "sk-ssh-ed25519@openssh.com", "sk-ecdsa-sha2-nistp256@openssh.com"];
+
+impl Autoinstall {
+    // Checks what subiquity's autoinstall schema requires, and what its own
+    // screens would refuse, so the install doesn't stop halfway to ask.
+    pub fn validate(&self) -> Result<(), String> {
+        let schema: Value = serde_yaml::from_str(SCHEMA).map_err(|err| format!("The autoinstall schema is invalid: {}", err))?;
+        let config = serde_yaml::to_value(self).map_err(|err| err.to_string())?;
+        check_schema(&schema, &config, "autoinstall")?;
+
+        if self.version != 1 {
+            return Err(format!("Unsupported autoinstall version {}", self.version));
+        }
+        if !is_locale(&self.locale) {
+            return Err(format!("\"{}\" isn't a locale, like en_US.UTF-8", self.locale));
+        }
+        if self.keyboard.layout.is_empty() || self.keyboard.layout.contains(char::is_whitespace) {
+            return Err("The keyboard layout is missing".to_string());
+        }
+        if self.keyboard.variant.contains(char::is_whitespace) {
+            return Err(format!("\"{}\" isn't a keyboard variant", self.keyboard.variant));
+        }
+        if let Some(timezone) = &self.timezone {
+            if timezone.is_empty() || timezone.contains(char::is_whitespace) {
+                return Err(format!("\"{}\" isn't a time zone", timezone));
+            }
+        }
+
+        let identity = &self.identity;
+        if !is_hostname(&identity.hostname) {
+            return Err("The computer name can only contain lower case letters, digits and dashes".to_string());
+        }
+        if !is_username(&identity.username) {
+            return Err("The user name must start with a lower case letter, and can only contain lower case letters, digits, dashes and underscores".to_string());
+        }
+        if RESERVED_USERNAMES.contains(&identity.username.as_str()) {
+            return Err(format!("The user name \"{}\" is reserved", identity.username));
+        }
+        if identity.realname.contains(&[':', ',', '='][..]) {
+            return Err("The name can't contain :, , or =".to_string());
+        }
+        if !identity.password.starts_with("$6$") {
+            return Err("The password is missing".to_string());
+        }
+
+        for key in &self.ssh.authorized_keys {
+            let kind = key.split_whitespace().next().unwrap_or("");
+            if !SSH_KEY_TYPES.contains(&kind) || key.split_whitespace().count() < 2 {
+                return Err(format!("\"{}\" isn't an SSH public key", key));
+            }
+        }
+        if !self.ssh.install_server && !self.ssh.authorized_keys.is_empty() {
+            return Err("SSH keys need the SSH server".to_string());
+        }
+        if let Some(package) = self.packages.iter().find(|v| v.is_empty() || v.contains(char::is_whitespace)) {
+            return Err(format!("\"{}\" isn't a package name", package));
+        }
+        if self.late_commands.iter().any(|v| v.trim().is_empty()) {
+            return Err("A command is empty".to_string());
+        }
+        Ok(())
+    }
+
+    // cloud-init's NoCloud wants a meta-data next to the user-data, with an
+    // instance-id.
+    pub fn to_meta_data(&self) -> String {
+        format!("instance-id: {}\n", self.identity.hostname)
+    }
+
+    // The cloud-init user-data holding the config.
+    pub fn to_user_data(&self) -> Result<String, serde_yaml::Error> {
+        #[derive(Serialize)]
+        struct UserData<'a> {
+            autoinstall: &'a Autoinstall,
+        }
+        let yaml = serde_yaml::to_string(&UserData { autoinstall: self })?;
+        Ok(format!("#cloud-config\n{}\n", yaml.trim_start_matches("---\n").trim_end()))
+    }
+}
+
+// Quotes `value` for the sh that runs late-commands.
+pub fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+// A late-command writing `content` to `path`, readable by root only.
+pub fn write_file_command(path: &str, content: &str) -> String {
+    let dir = path.rsplitn(2, '/').nth(1).unwrap_or("/");
+    format!("mkdir -p {} && umask 077 && printf '%s' {} > {}", shell_quote(dir), shell_quote(content), shell_quote(path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> Autoinstall {
+        let mut config = Autoinstall::default();
+        config.identity.password = crate::crypt::sha512_crypt_with_salt("ubuntu", "saltsalt");
+        config
+    }
+
+    fn schema() -> Value {
+        serde_yaml::from_str(SCHEMA).unwrap()
+    }
+
+    fn check(yaml: &str) -> Result<(), String> {
+        check_schema(&schema(), &serde_yaml::from_str(yaml).unwrap(), "autoinstall")
+    }
+
+    #[test]
+    fn accepts_our_configs() {
+        let mut config = config();
+        assert_eq!(config.validate(), Ok(()));
+        config.timezone = Some("Europe/Paris".to_string());
+        config.storage.layout.mode = Some(LayoutMode::UseGap);
+        config.ssh.install_server = true;
+        config.ssh.authorized_keys.push("ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIA user@host".to_string());
+        config.packages.push("vim".to_string());
+        config.late_commands.push("true".to_string());
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn checks_the_schema() {
+        assert_eq!(check("version: 1\nlocale: en_US.UTF-8\nearly-commands: [[echo, hi], 'true']"), Ok(()));
+        assert_eq!(check("locale: en_US.UTF-8"), Err("autoinstall.version is missing".to_string()));
+        assert_eq!(check("version: 2"), Err("autoinstall.version should be at most 1".to_string()));
+        assert_eq!(check("version: '1'"), Err("autoinstall.version should be of type integer, not string".to_string()));
+        assert_eq!(check("version: 1\nkeyboard: {variant: intl}"), Err("autoinstall.keyboard.layout is missing".to_string()));
+        assert_eq!(check("version: 1\nkeyboard: {layout: us, model: pc105}"), Err("autoinstall.keyboard.model isn't allowed".to_string()));
+        assert_eq!(check("version: 1\nidentity: {hostname: a, username: b}"), Err("autoinstall.identity.password is missing".to_string()));
+        assert_eq!(check("version: 1\nstorage: {layout: {name: btrfs}}"), Err("autoinstall.storage.layout.name should be one of lvm, direct, zfs".to_string()));
+        assert_eq!(check("version: 1\npackages: [vim, 3]"), Err("autoinstall.packages[1] should be of type string, not integer".to_string()));
+        assert_eq!(check("version: 1\nlate-commands: [{run: true}]"), Err("autoinstall.late-commands[0] should be of type string or array, not object".to_string()));
+        // Sections we don't model are left to subiquity.
+        assert_eq!(check("version: 1\nsnaps: [{name: code}]"), Ok(()));
+    }
+
+    #[test]
+    fn refuses_what_subiquity_would() {
+        let mut config = config();
+        config.identity.username = "root".to_string();
+        assert!(config.validate().is_err());
+        let mut config = self::config();
+        config.identity.hostname = "My PC".to_string();
+        assert!(config.validate().is_err());
+        let mut config = self::config();
+        config.locale = "english".to_string();
+        assert!(config.validate().is_err());
+        let mut config = self::config();
+        config.ssh.authorized_keys.push("ssh-rsa AAAA".to_string());
+        assert!(config.validate().is_err());
+        assert!(Autoinstall::default().validate().is_err());
+    }
+
+    #[test]
+    fn writes_user_data() {
+        let user_data = config().to_user_data().unwrap();
+        assert!(user_data.starts_with("#cloud-config\nautoinstall:\n  version: 1\n"));
+        let value: Value = serde_yaml::from_str(&user_data).unwrap();
+        assert_eq!(check_schema(&schema(), &value["autoinstall"], "autoinstall"), Ok(()));
+        assert_eq!(value["autoinstall"]["storage"]["layout"]["name"].as_str(), Some("lvm"));
+    }
+
+    #[test]
+    fn quotes_for_sh() {
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(write_file_command("/target/etc/x", "a b"), "mkdir -p '/target/etc' && umask 077 && printf '%s' 'a b' > '/target/etc/x'");
+    }
+}
@@ -0,0 +1,267 @@
+use crate::iso::{Iso, SECTOR_SIZE};
+use crate::partition::{self, Mbr, EFI_SYSTEM_PARTITION, MBR_EFI_PARTITION};
+
+use std::io::{self, Read, Seek};
+
+// Figures out how an image can boot. El Torito only matters for optical
+// discs: off a USB flash drive, BIOSes run the boot code in the MBR, and UEFI
+// firmwares look for an EFI system partition. isohybrid images have both on
+// top of the ISO9660 filesystem.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Bios,
+    Efi,
+    Other(u8),
+}
+
+impl Platform {
+    fn from_raw(platform: u8) -> Platform {
+        match platform {
+            0 => Platform::Bios,
+            0xEF => Platform::Efi,
+            v => Platform::Other(v),
+        }
+    }
+}
+
+// An El Torito boot catalog entry.
+#[derive(Debug, Clone)]
+pub struct BootEntry {
+    pub platform: Platform,
+    pub bootable: bool,
+    // Offset and size of the boot image, in bytes. A size of 0 means the
+    // rest of the image, for EFI entries made by some tools.
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BootInfo {
+    pub entries: Vec<BootEntry>,
+    // isohybrid MBR boot code.
+    pub mbr_boot_code: bool,
+    // An EFI system partition, in the MBR or the GPT.
+    pub efi_partition: bool,
+    // EFI/BOOT/BOOT*.EFI, which is all a UEFI firmware needs on a FAT
+    // partition.
+    pub efi_files: bool,
+}
+
+impl BootInfo {
+    pub fn inspect<R: Read + Seek>(mut image: R) -> io::Result<BootInfo> {
+        let mut info = BootInfo::default();
+
+        // isohybrid tables always use 512 byte sectors.
+        if let Some(mbr) = Mbr::read(&mut image, 512)? {
+            info.mbr_boot_code = mbr.has_boot_code();
+            info.efi_partition = mbr.partitions.iter().any(|v| v.kind == MBR_EFI_PARTITION);
+        }
+        if let Some(partitions) = partition::read_gpt(&mut image, 512)? {
+            info.efi_partition |= partitions.iter().any(|v| v.kind == EFI_SYSTEM_PARTITION);
+        }
+
+        let mut iso = Iso::open(image)?;
+        if let Some(catalog) = iso.boot_catalog {
+            let mut sector = vec![0; SECTOR_SIZE as usize];
+            iso.read_at(catalog, &mut sector)?;
+            info.entries = parse_catalog(&sector);
+        }
+        if let Some(dir) = iso.find("EFI/BOOT")? {
+            if dir.is_dir {
+                info.efi_files = iso.read_dir(&dir)?.iter().any(|v| {
+                    let name = v.name.to_ascii_uppercase();
+                    !v.is_dir && name.starts_with("BOOT") && name.ends_with(".EFI")
+                });
+            }
+        }
+        Ok(info)
+    }
+
+    // Whether writing the image as is gives a drive a BIOS can boot.
+    pub fn bios(&self) -> bool {
+        self.mbr_boot_code && self.entries.iter().any(|v| v.bootable && v.platform == Platform::Bios)
+    }
+
+    // Whether writing the image as is gives a drive a UEFI firmware can boot.
+    pub fn uefi(&self) -> bool {
+        self.efi_partition
+    }
+
+    pub fn describe(&self) -> &'static str {
+        match (self.bios(), self.uefi()) {
+            (true, true) => "legacy BIOS and UEFI",
+            (true, false) => "legacy BIOS only",
+            (false, true) => "UEFI only",
+            (false, false) if self.efi_files => "UEFI only, after copying its files",
+            (false, false) => "neither legacy BIOS nor UEFI",
+        }
+    }
+
+    // Why the image wouldn't boot from a USB flash drive, if it wouldn't.
+    pub fn usb_warning(&self) -> Option<String> {
+        if self.bios() || self.uefi() {
+            None
+        } else if self.efi_files {
+            Some("This image isn't a hybrid image, so it won't boot once written as is. Copying its files instead should make it boot on UEFI computers.".to_string())
+        } else if !self.entries.is_empty() {
+            Some("This image is only meant to boot from a CD or DVD, and won't boot from a USB flash drive.".to_string())
+        } else {
+            Some("This image isn't bootable.".to_string())
+        }
+    }
+}
+
+fn parse_catalog(catalog: &[u8]) -> Vec<BootEntry> {
+    let mut entries = Vec::new();
+    // The validation entry starts with a header ID of 1, ends with 55 AA,
+    // and all its words add up to 0.
+    let validation = &catalog[..32];
+    let sum = validation.chunks_exact(2).fold(0u16, |sum, v| sum.wrapping_add(u16::from_le_bytes([v[0], v[1]])));
+    if validation[0] != 1 || validation[30..32] != [0x55, 0xAA] || sum != 0 {
+        return entries;
+    }
+
+    let parse_entry = |entry: &[u8], platform: u8| BootEntry {
+        platform: Platform::from_raw(platform),
+        bootable: entry[0] == 0x88,
+        offset: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]) as u64 * SECTOR_SIZE,
+        // Counted in 512 byte "virtual" sectors.
+        size: u16::from_le_bytes([entry[6], entry[7]]) as u64 * 512,
+    };
+    // The default entry is for the platform of the validation entry.
+    entries.push(parse_entry(&catalog[32..64], validation[1]));
+
+    // Then come sections for other platforms, each header followed by its
+    // entries. 0x91 marks the last header.
+    let mut pos = 64;
+    while pos + 32 <= catalog.len() {
+        let header = &catalog[pos..pos + 32];
+        if header[0] != 0x90 && header[0] != 0x91 {
+            break;
+        }
+        let platform = header[1];
+        let mut count = u16::from_le_bytes([header[2], header[3]]);
+        pos += 32;
+        while count > 0 && pos + 32 <= catalog.len() {
+            let entry = &catalog[pos..pos + 32];
+            pos += 32;
+            // Extension entries continue the previous entry's selection
+            // criteria.
+            if entry[0] == 0x44 {
+                continue;
+            }
+            entries.push(parse_entry(entry, platform));
+            count -= 1;
+        }
+        if header[0] == 0x91 {
+            break;
+        }
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    // See testdata/make-small-iso.sh.
+    const IMAGE: &[u8] = include_bytes!("../testdata/small.iso");
+
+    fn entry(indicator: u8, lba: u32, sectors: u16) -> [u8; 32] {
+        let mut entry = [0; 32];
+        entry[0] = indicator;
+        entry[6..8].copy_from_slice(&sectors.to_le_bytes());
+        entry[8..12].copy_from_slice(&lba.to_le_bytes());
+        entry
+    }
+
+    fn header(id: u8, platform: u8, count: u16) -> [u8; 32] {
+        let mut header = [0; 32];
+        header[0] = id;
+        header[1] = platform;
+        header[2..4].copy_from_slice(&count.to_le_bytes());
+        header
+    }
+
+    // A validation entry with a valid checksum, then `records`.
+    fn catalog(platform: u8, records: &[[u8; 32]]) -> Vec<u8> {
+        let mut validation = [0; 32];
+        validation[0] = 1;
+        validation[1] = platform;
+        validation[30..32].copy_from_slice(&[0x55, 0xAA]);
+        let sum = validation.chunks_exact(2).fold(0u16, |sum, v| sum.wrapping_add(u16::from_le_bytes([v[0], v[1]])));
+        validation[28..30].copy_from_slice(&0u16.wrapping_sub(sum).to_le_bytes());
+
+        let mut catalog = validation.to_vec();
+        for record in records {
+            catalog.extend_from_slice(record);
+        }
+        catalog.resize(SECTOR_SIZE as usize, 0);
+        catalog
+    }
+
+    #[test]
+    fn inspects_images_needing_their_files_copied() {
+        let info = BootInfo::inspect(Cursor::new(IMAGE)).unwrap();
+        assert!(info.efi_files);
+        assert!(!info.efi_partition);
+        assert!(!info.mbr_boot_code);
+        assert!(!info.uefi());
+        assert!(!info.bios());
+        assert_eq!(info.describe(), "UEFI only, after copying its files");
+        assert!(info.usb_warning().unwrap().contains("Copying its files"));
+
+        assert_eq!(info.entries.len(), 1);
+        let entry = &info.entries[0];
+        assert_eq!(entry.platform, Platform::Efi);
+        assert!(entry.bootable);
+        let mut iso = Iso::open(Cursor::new(IMAGE)).unwrap();
+        let efi = iso.find("EFI/boot/bootx64.efi").unwrap().unwrap();
+        assert_eq!(entry.offset, efi.offset());
+    }
+
+    #[test]
+    fn checks_the_validation_entry() {
+        let records = [entry(0x88, 20, 4)];
+        assert_eq!(parse_catalog(&catalog(0, &records)).len(), 1);
+
+        let mut bad_sum = catalog(0, &records);
+        bad_sum[28] ^= 1;
+        assert!(parse_catalog(&bad_sum).is_empty());
+        let mut no_key = catalog(0, &records);
+        no_key[31] = 0;
+        assert!(parse_catalog(&no_key).is_empty());
+        let mut bad_id = catalog(0, &records);
+        bad_id[0] = 0x90;
+        assert!(parse_catalog(&bad_id).is_empty());
+    }
+
+    #[test]
+    fn parses_sections() {
+        let mut extension = [0; 32];
+        extension[0] = 0x44;
+        let catalog = catalog(0, &[
+            entry(0x88, 20, 4),
+            header(0x90, 0xEF, 2),
+            entry(0x88, 30, 0),
+            extension,
+            extension,
+            entry(0x00, 40, 8),
+            header(0x91, 0x02, 1),
+            entry(0x88, 50, 1),
+            // Past the last header.
+            entry(0x88, 60, 1),
+        ]);
+        let entries = parse_catalog(&catalog);
+        let summary: Vec<_> = entries.iter().map(|v| (v.platform, v.bootable, v.offset / SECTOR_SIZE, v.size)).collect();
+        assert_eq!(summary, [
+            (Platform::Bios, true, 20, 2048),
+            (Platform::Efi, true, 30, 0),
+            (Platform::Efi, false, 40, 4096),
+            (Platform::Other(2), true, 50, 512),
+        ]);
+    }
+}
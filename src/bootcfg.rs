@@ -0,0 +1,630 @@
+use crate::iso::{Iso, SECTOR_SIZE};
+use crate::partition::{Gpt, Mbr};
+use crate::writer::round_up;
+
+use md5::Md5;
+use sha2::Digest;
+
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+// Adds kernel parameters to the boot menus of an image. GRUB boots the image
+// on UEFI and with loopback.cfg when booting the ISO from another GRUB,
+// isolinux boots it on legacy BIOSes.
+
+const GRUB_CONFIGS: &[&str] = &["boot/grub/grub.cfg", "boot/grub/loopback.cfg"];
+const ISOLINUX_CONFIGS: &[&str] = &["isolinux/txt.cfg"];
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BootOptions {
+    // Sticks to the firmware's framebuffer, for GPUs the kernel drivers
+    // don't handle.
+    pub nomodeset: bool,
+    // Installs without asking for confirmation, when there's an autoinstall
+    // config.
+    pub autoinstall: bool,
+    // Where cloud-init fetches user-data and meta-data from, e.g.
+    // "http://192.168.0.1/ubuntu/".
+    pub seed_url: Option<String>,
+    // Kernel messages and a login prompt on the first serial port, besides
+    // the screen.
+    pub serial_console: bool,
+    // Casper only looks for a persistence partition with this.
+    pub persistent: bool,
+}
+
+impl BootOptions {
+    pub fn is_empty(&self) -> bool {
+        self.kernel_args().is_empty()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(url) = &self.seed_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                return Err("The seed URL must start with http:// or https://".to_string());
+            }
+            if url.chars().any(|v| v.is_whitespace() || v == '"' || v == '\'') {
+                return Err("The seed URL can't contain spaces or quotes".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn kernel_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.persistent {
+            args.push("persistent".to_string());
+        }
+        if self.nomodeset {
+            args.push("nomodeset".to_string());
+        }
+        if self.autoinstall {
+            args.push("autoinstall".to_string());
+        }
+        if let Some(url) = &self.seed_url {
+            args.push(format!("ds=nocloud-net;s={}", url));
+        }
+        if self.serial_console {
+            // The last one becomes /dev/console, which the installer runs
+            // on.
+            args.push("console=tty0".to_string());
+            args.push("console=ttyS0,115200n8".to_string());
+        }
+        args
+    }
+}
+
+// Lines with their line endings, so patching doesn't change those.
+fn lines_with_endings(text: &str) -> Vec<&str> {
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (idx, _) in text.match_indices('\n') {
+        lines.push(&text[start..=idx]);
+        start = idx + 1;
+    }
+    if start < text.len() {
+        lines.push(&text[start..]);
+    }
+    lines
+}
+
+// GRUB splits words on the same characters as a shell.
+fn grub_escape(arg: &str) -> String {
+    let mut escaped = String::with_capacity(arg.len());
+    for c in arg.chars() {
+        if ";&|<>$\\{}()".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+// Adds `args` to every kernel command line of a boot menu. They go before
+// "---", since what comes after it is for the installed system too.
+pub fn patch_config(config: &str, args: &[String], grub: bool) -> String {
+    let mut patched = String::with_capacity(config.len() + 256);
+    for line in lines_with_endings(config) {
+        let content = line.trim_end_matches(&['\r', '\n'][..]);
+        let newline = &line[content.len()..];
+        let keyword = content.split_whitespace().next().unwrap_or("").to_ascii_lowercase();
+        let is_kernel_line = if grub {
+            keyword == "linux" || keyword == "linuxefi"
+        } else {
+            keyword == "append"
+        };
+        if !is_kernel_line {
+            patched.push_str(line);
+            continue;
+        }
+
+        let words: Vec<&str> = content.split_whitespace().collect();
+        let missing: Vec<String> = args
+            .iter()
+            .map(|v| if grub { grub_escape(v) } else { v.clone() })
+            .filter(|v| !words.contains(&v.as_str()))
+            .collect();
+        if missing.is_empty() {
+            patched.push_str(line);
+            continue;
+        }
+        let missing = missing.join(" ");
+        match content.find(" ---") {
+            Some(pos) => {
+                patched.push_str(content[..pos].trim_end());
+                patched.push(' ');
+                patched.push_str(&missing);
+                patched.push_str(&content[pos..]);
+            }
+            None => {
+                patched.push_str(content.trim_end());
+                patched.push(' ');
+                patched.push_str(&missing);
+            }
+        }
+        patched.push_str(newline);
+    }
+    patched
+}
+
+// The boot menus of the image with `options` applied, as (path, contents),
+// for those that changed. md5sum.txt comes along with them, so checking the
+// disc for defects still passes.
+pub fn patched_files<R: Read + Seek>(iso: &mut Iso<R>, options: &BootOptions) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let args = options.kernel_args();
+    let mut files = Vec::new();
+    if args.is_empty() {
+        return Ok(files);
+    }
+    let configs = GRUB_CONFIGS.iter().map(|v| (v, true)).chain(ISOLINUX_CONFIGS.iter().map(|v| (v, false)));
+    for (path, grub) in configs {
+        let entry = match iso.find(path)? {
+            Some(entry) if !entry.is_dir => entry,
+            _ => continue,
+        };
+        let config = String::from_utf8_lossy(&iso.read_file(&entry)?).into_owned();
+        let patched = patch_config(&config, &args, grub);
+        if patched != config {
+            files.push((path.to_string(), patched.into_bytes()));
+        }
+    }
+
+    if let Some(entry) = iso.find("md5sum.txt")? {
+        let list = String::from_utf8_lossy(&iso.read_file(&entry)?).into_owned();
+        let mut updated = String::with_capacity(list.len());
+        for line in lines_with_endings(&list) {
+            // Lines look like "<md5>  ./casper/vmlinuz".
+            let path = line.splitn(2, char::is_whitespace).nth(1).unwrap_or("").trim().trim_start_matches("./");
+            match files.iter().find(|v| v.0.eq_ignore_ascii_case(path)) {
+                // Hashes all have the same length, so the file keeps its
+                // size.
+                Some((_, data)) if line.len() > 32 => {
+                    updated.push_str(&format!("{:x}", Md5::digest(data)));
+                    updated.push_str(&line[32..]);
+                }
+                _ => updated.push_str(line),
+            }
+        }
+        if !files.is_empty() && updated != list {
+            files.push(("md5sum.txt".to_string(), updated.into_bytes()));
+        }
+    }
+    Ok(files)
+}
+
+// Changes to make to an image while writing it, as (offset, data).
+#[derive(Debug, Clone, Default)]
+pub struct ImagePatch {
+    // The length of the patched image, more than the original's when files
+    // had to move to its end.
+    pub len: u64,
+    patches: Vec<(u64, Vec<u8>)>,
+}
+
+fn both_endian(value: u32) -> Vec<u8> {
+    let mut bytes = value.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&value.to_be_bytes());
+    bytes
+}
+
+impl ImagePatch {
+    // Works out how to apply `options` to the image without rebuilding it.
+    // Files that still fit in their last sector get rewritten in place,
+    // others move past the end of the image, and their directory records get
+    // pointed at the new location.
+    pub fn new<R: Read + Seek>(mut image: R, image_len: u64, options: &BootOptions) -> io::Result<ImagePatch> {
+        let mut patch = ImagePatch { len: image_len, patches: Vec::new() };
+        // Not every image is an ISO9660 one, but those can still be written
+        // without options.
+        if options.is_empty() {
+            return Ok(patch);
+        }
+        let mbr = Mbr::read(&mut image, 512)?;
+        let gpt = match &mbr {
+            Some(mbr) if mbr.protects_gpt() => Gpt::read(&mut image, 512)?,
+            _ => None,
+        };
+        let mut iso = Iso::open(image)?;
+        let files = patched_files(&mut iso, options)?;
+        if files.is_empty() {
+            return Ok(patch);
+        }
+
+        let old_end = round_up(std::cmp::max(image_len, iso.primary.volume_size), SECTOR_SIZE);
+        let mut end = old_end;
+        let mut moved = Vec::new();
+        for (path, data) in files {
+            let entries = iso.find_all(&path)?;
+            let entry = entries.first().ok_or_else(|| invalid_input(&format!("{} is missing", path)))?;
+            if !entry.is_contiguous() {
+                return Err(invalid_input(&format!("{} is too big to patch", path)));
+            }
+            let len = data.len() as u64;
+            let offset = if entry.size() > 0 && len <= round_up(entry.size(), SECTOR_SIZE) {
+                entry.offset()
+            } else {
+                let offset = end;
+                end += round_up(len, SECTOR_SIZE);
+                moved.push(path.clone());
+                offset
+            };
+            patch.patches.push((offset, data));
+            for entry in &entries {
+                patch.patches.push((entry.record_offset() + 2, both_endian((offset / SECTOR_SIZE) as u32)));
+                patch.patches.push((entry.record_offset() + 10, both_endian(len as u32)));
+            }
+        }
+        if end == old_end {
+            return Ok(patch);
+        }
+
+        patch.len = end;
+        let sectors = both_endian((end / SECTOR_SIZE) as u32);
+        patch.patches.push((iso.primary.offset + 80, sectors.clone()));
+        if let Some(joliet) = &iso.joliet {
+            patch.patches.push((joliet.offset + 80, sectors));
+        }
+        // The isohybrid partition that holds the filesystem has to grow too,
+        // or reading the moved files through it fails. Partitions appended
+        // after the filesystem stay where they are.
+        if let Some(mut mbr) = mbr {
+            let mut changed = false;
+            for partition in mbr.partitions.iter_mut() {
+                if !partition.is_empty() && partition.start_lba as u64 * 512 <= 16 * SECTOR_SIZE && partition.end_lba() * 512 == old_end {
+                    partition.sectors = (end / 512 - partition.start_lba as u64) as u32;
+                    changed = true;
+                }
+            }
+            // On a GPT, the filesystem's partition can only grow when it's
+            // the last one, over the backup GPT, which then moves to the new
+            // end. Otherwise it would overlap the partitions after it, and the
+            // moved files would be out of its reach.
+            if let Some(mut gpt) = gpt {
+                let end_lba = gpt.end_lba();
+                let filesystem = gpt.entries.iter_mut().find(|v| !v.is_empty() && v.first_lba * 512 <= 16 * SECTOR_SIZE);
+                match filesystem {
+                    Some(partition) if partition.last_lba + 1 == end_lba => {
+                        partition.last_lba = end / 512 - 1;
+                        gpt.last_usable = partition.last_lba;
+                        patch.len = end + 512 + round_up(gpt.entries.len() as u64 * 128, 512);
+                        patch.patches.extend(gpt.blocks(512, patch.len)?);
+                        mbr.grow_protective(512, patch.len);
+                        changed = true;
+                    }
+                    Some(_) => return Err(invalid_input(&format!("{} is too big to patch", moved.join(", ")))),
+                    None => (),
+                }
+            }
+            if changed {
+                let mut sector = Cursor::new(vec![0; 512]);
+                mbr.write(&mut sector)?;
+                patch.patches.push((0, sector.into_inner()));
+            }
+        }
+        Ok(patch)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patches.is_empty()
+    }
+
+    pub fn reader<R: Read>(&self, image: R, image_len: u64) -> PatchedReader<'_, R> {
+        PatchedReader { inner: image, inner_len: image_len, patch: self, pos: 0 }
+    }
+}
+
+// Reads an image with the patches applied, and zeros between its original
+// end and the files moved past it.
+pub struct PatchedReader<'a, R> {
+    inner: R,
+    inner_len: u64,
+    patch: &'a ImagePatch,
+    pos: u64,
+}
+
+impl<'a, R: Read> Read for PatchedReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.patch.len {
+            return Ok(0);
+        }
+        let len = std::cmp::min(buf.len() as u64, self.patch.len - self.pos) as usize;
+        let buf = &mut buf[..len];
+        let read = if self.pos < self.inner_len {
+            let to_read = std::cmp::min(len as u64, self.inner_len - self.pos) as usize;
+            let read = self.inner.read(&mut buf[..to_read])?;
+            if read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The image is truncated"));
+            }
+            read
+        } else {
+            for byte in buf.iter_mut() {
+                *byte = 0;
+            }
+            len
+        };
+
+        let (start, end) = (self.pos, self.pos + read as u64);
+        for (offset, data) in &self.patch.patches {
+            let (patch_start, patch_end) = (*offset, offset + data.len() as u64);
+            if patch_end <= start || patch_start >= end {
+                continue;
+            }
+            let from = std::cmp::max(start, patch_start);
+            let to = std::cmp::min(end, patch_end);
+            buf[(from - start) as usize..(to - start) as usize].copy_from_slice(&data[(from - patch_start) as usize..(to - patch_start) as usize]);
+        }
+        self.pos = end;
+        Ok(read)
+    }
+}
+
+// Lets the partition table checks run against the patched image, as the
+// patches can grow the ISO partition.
+impl<'a, R: Read + Seek> Seek for PatchedReader<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(offset) => self.patch.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }.ok_or_else(|| invalid_input("Seeking before the start of the image"))?;
+        if pos < self.inner_len {
+            self.inner.seek(SeekFrom::Start(pos))?;
+        }
+        self.pos = pos;
+        Ok(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::partition::tests::check_backup;
+    use crate::partition::{GptPartition, Guid, MbrPartition, BASIC_DATA_PARTITION, EFI_SYSTEM_PARTITION};
+    use crate::verify::{self, IsoFiles};
+    use std::io::Write;
+
+    // See testdata/make-small-iso.sh.
+    const IMAGE: &[u8] = include_bytes!("../testdata/small.iso");
+    const GRUB_CFG: &str = "menuentry \"Try or Install Ubuntu\" {\n\tlinux\t/casper/vmlinuz  ---\n}\n";
+    // The GPT entries, behind the backup header.
+    const GPT_TAIL: u64 = 512 + 128 * 128;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|v| v.to_string()).collect()
+    }
+
+    fn options() -> BootOptions {
+        BootOptions { nomodeset: true, autoinstall: true, ..BootOptions::default() }
+    }
+
+    // Enough for grub.cfg to outgrow its sector, and move.
+    fn long_options() -> BootOptions {
+        BootOptions { seed_url: Some(format!("http://192.168.0.1/{}/", "a".repeat(2100))), ..BootOptions::default() }
+    }
+
+    fn apply(image: &[u8], options: &BootOptions) -> io::Result<(ImagePatch, Vec<u8>)> {
+        let patch = ImagePatch::new(Cursor::new(image), image.len() as u64, options)?;
+        let mut patched = Vec::new();
+        patch.reader(Cursor::new(image), image.len() as u64).read_to_end(&mut patched)?;
+        assert_eq!(patched.len() as u64, patch.len);
+        Ok((patch, patched))
+    }
+
+    // Reads the patched image back like GRUB and casper would.
+    fn check_files(image: &[u8], options: &BootOptions, volume_size: u64) {
+        let mut iso = Iso::open(Cursor::new(image.to_vec())).unwrap();
+        assert_eq!(iso.primary.volume_size, volume_size);
+        assert_eq!(iso.joliet.as_ref().unwrap().volume_size, volume_size);
+        let expected = patch_config(GRUB_CFG, &options.kernel_args(), true);
+        let found = iso.find_all("boot/grub/grub.cfg").unwrap();
+        assert_eq!(found.len(), 2);
+        for entry in &found {
+            assert_eq!(String::from_utf8(iso.read_file(entry).unwrap()).unwrap(), expected);
+        }
+        let md5sums = iso.find("md5sum.txt").unwrap().unwrap();
+        let md5sums = String::from_utf8(iso.read_file(&md5sums).unwrap()).unwrap();
+        assert!(md5sums.contains(&format!("{:x}  ./boot/grub/grub.cfg\n", Md5::digest(expected.as_bytes()))));
+        assert!(verify::check_md5sums(&mut IsoFiles::new(iso).unwrap(), |_, _| ()).is_ok());
+    }
+
+    fn with_mbr(mut image: Vec<u8>) -> Vec<u8> {
+        let len = image.len() as u64;
+        let mut mbr = Mbr::protective(512, len);
+        mbr.partitions[0] = MbrPartition { bootable: true, kind: 0x17, start_lba: 0, sectors: (len / 512) as u32 };
+        mbr.write(&mut Cursor::new(&mut image[..])).unwrap();
+        image
+    }
+
+    // The filesystem in the first GPT partition, from the 16th ISO9660
+    // sector like xorriso does, with `after` bytes of ESP after it.
+    fn with_gpt(after: u64) -> Vec<u8> {
+        let len = IMAGE.len() as u64 + after + GPT_TAIL;
+        let mut image = Cursor::new(IMAGE.to_vec());
+        image.get_mut().resize(len as usize, 0);
+        let mut gpt = Gpt::new(512, len);
+        let partition = |kind, first_lba, last_lba, name: &str| GptPartition { kind, guid: Guid::random(), first_lba, last_lba, attributes: 0, name: name.to_string() };
+        let iso_end = IMAGE.len() as u64 / 512;
+        gpt.add(partition(BASIC_DATA_PARTITION, 16 * SECTOR_SIZE / 512, iso_end - 1, "ISO9660")).unwrap();
+        if after > 0 {
+            gpt.add(partition(EFI_SYSTEM_PARTITION, iso_end, iso_end + after / 512 - 1, "Appended2")).unwrap();
+        }
+        gpt.write(&mut image, 512, len).unwrap();
+        Mbr::protective(512, len).write(&mut image).unwrap();
+        image.into_inner()
+    }
+
+    #[test]
+    fn adds_args_before_the_separator() {
+        let config = "menuentry \"Ubuntu\" {\n\tlinux\t/casper/vmlinuz quiet splash ---\n\tinitrd\t/casper/initrd\n}\n";
+        assert_eq!(
+            patch_config(config, &args(&["nomodeset", "autoinstall"]), true),
+            "menuentry \"Ubuntu\" {\n\tlinux\t/casper/vmlinuz quiet splash nomodeset autoinstall ---\n\tinitrd\t/casper/initrd\n}\n"
+        );
+        // Without one, at the end.
+        assert_eq!(patch_config("linuxefi /casper/vmlinuz quiet  \n", &args(&["nomodeset"]), true), "linuxefi /casper/vmlinuz quiet nomodeset\n");
+        // Nothing to do without kernel lines.
+        assert_eq!(patch_config("set timeout=30\n", &args(&["nomodeset"]), true), "set timeout=30\n");
+    }
+
+    #[test]
+    fn skips_args_already_there() {
+        let config = "linux /casper/vmlinuz nomodeset ---\n";
+        assert_eq!(patch_config(config, &args(&["nomodeset"]), true), config);
+        assert_eq!(patch_config(config, &args(&["nomodeset", "autoinstall"]), true), "linux /casper/vmlinuz nomodeset autoinstall ---\n");
+        // Patching twice changes nothing more.
+        let args = BootOptions { seed_url: Some("http://10.0.0.1/".to_string()), ..options() }.kernel_args();
+        let once = patch_config(config, &args, true);
+        assert_eq!(patch_config(&once, &args, true), once);
+    }
+
+    #[test]
+    fn keeps_line_endings() {
+        let config = "menuentry \"Ubuntu\" {\r\n\tlinux /casper/vmlinuz ---\r\n}\r\nlinux /casper/vmlinuz";
+        assert_eq!(
+            patch_config(config, &args(&["nomodeset"]), true),
+            "menuentry \"Ubuntu\" {\r\n\tlinux /casper/vmlinuz nomodeset ---\r\n}\r\nlinux /casper/vmlinuz nomodeset"
+        );
+    }
+
+    #[test]
+    fn patches_isolinux_appends() {
+        let config = "label live\n  kernel /casper/vmlinuz\n  append  file=/cdrom/preseed/ubuntu.seed initrd=/casper/initrd quiet splash ---\nlinux /casper/vmlinuz\n";
+        assert_eq!(
+            patch_config(config, &args(&["nomodeset", "ds=nocloud-net;s=http://10.0.0.1/"]), false),
+            "label live\n  kernel /casper/vmlinuz\n  append  file=/cdrom/preseed/ubuntu.seed initrd=/casper/initrd quiet splash nomodeset ds=nocloud-net;s=http://10.0.0.1/ ---\nlinux /casper/vmlinuz\n"
+        );
+    }
+
+    #[test]
+    fn escapes_grub_words() {
+        assert_eq!(grub_escape("ds=nocloud-net;s=http://10.0.0.1/ubuntu/"), "ds=nocloud-net\\;s=http://10.0.0.1/ubuntu/");
+        assert_eq!(grub_escape("console=ttyS0,115200n8"), "console=ttyS0,115200n8");
+        assert_eq!(grub_escape("a$b{c}"), "a\\$b\\{c\\}");
+        let args = args(&["ds=nocloud-net;s=http://10.0.0.1/"]);
+        let patched = patch_config("linux /casper/vmlinuz ---\n", &args, true);
+        assert_eq!(patched, "linux /casper/vmlinuz ds=nocloud-net\\;s=http://10.0.0.1/ ---\n");
+        assert_eq!(patch_config(&patched, &args, true), patched);
+    }
+
+    #[test]
+    fn updates_md5sums() {
+        let mut iso = Iso::open(Cursor::new(IMAGE)).unwrap();
+        assert!(patched_files(&mut iso, &BootOptions::default()).unwrap().is_empty());
+
+        let files = patched_files(&mut iso, &options()).unwrap();
+        let paths: Vec<&str> = files.iter().map(|v| v.0.as_str()).collect();
+        assert_eq!(paths, ["boot/grub/grub.cfg", "md5sum.txt"]);
+        assert_eq!(files[0].1, patch_config(GRUB_CFG, &options().kernel_args(), true).into_bytes());
+
+        let entry = iso.find("md5sum.txt").unwrap().unwrap();
+        let old = String::from_utf8(iso.read_file(&entry).unwrap()).unwrap();
+        let new = String::from_utf8(files[1].1.clone()).unwrap();
+        assert_eq!(old.len(), new.len());
+        for (old, new) in old.lines().zip(new.lines()) {
+            if new.ends_with("./boot/grub/grub.cfg") {
+                assert_eq!(new, format!("{:x}  ./boot/grub/grub.cfg", Md5::digest(&files[0].1)));
+                assert_ne!(old, new);
+            } else {
+                assert_eq!(old, new);
+            }
+        }
+    }
+
+    #[test]
+    fn patches_files_in_place() {
+        let (patch, patched) = apply(IMAGE, &options()).unwrap();
+        assert!(!patch.is_empty());
+        assert_eq!(patch.len, IMAGE.len() as u64);
+        check_files(&patched, &options(), IMAGE.len() as u64);
+        // Only a few sectors change.
+        let changed = patched.chunks(2048).zip(IMAGE.chunks(2048)).filter(|(a, b)| a != b).count();
+        assert!(changed <= 6, "{}", changed);
+
+        let (patch, patched) = apply(IMAGE, &BootOptions::default()).unwrap();
+        assert!(patch.is_empty());
+        assert_eq!(patched, IMAGE);
+    }
+
+    #[test]
+    fn moves_files_past_the_end() {
+        let (patch, patched) = apply(IMAGE, &long_options()).unwrap();
+        assert!(patch.len > IMAGE.len() as u64);
+        assert_eq!(patch.len % SECTOR_SIZE, 0);
+        assert_eq!(&patched[..16 * SECTOR_SIZE as usize], &IMAGE[..16 * SECTOR_SIZE as usize]);
+        check_files(&patched, &long_options(), patch.len);
+
+        let mut iso = Iso::open(Cursor::new(patched)).unwrap();
+        let grub_cfg = iso.find("boot/grub/grub.cfg").unwrap().unwrap();
+        assert_eq!(grub_cfg.offset(), IMAGE.len() as u64);
+    }
+
+    #[test]
+    fn grows_the_mbr_partition() {
+        let image = with_mbr(IMAGE.to_vec());
+        let (patch, patched) = apply(&image, &long_options()).unwrap();
+        check_files(&patched, &long_options(), patch.len);
+        let mbr = Mbr::read(&mut Cursor::new(&patched), 512).unwrap().unwrap();
+        assert_eq!(mbr.partitions[0].end_lba() * 512, patch.len);
+        assert!(mbr.partitions[0].bootable);
+        assert_eq!(&patched[512..], &apply(IMAGE, &long_options()).unwrap().1[512..]);
+    }
+
+    #[test]
+    fn grows_the_last_gpt_partition() {
+        let image = with_gpt(0);
+        let (patch, patched) = apply(&image, &long_options()).unwrap();
+        let volume_size = patch.len - GPT_TAIL;
+        check_files(&patched, &long_options(), volume_size);
+
+        let mut disk = tempfile::tempfile().unwrap();
+        disk.write_all(&patched).unwrap();
+        let gpt = Gpt::read(&mut disk, 512).unwrap().unwrap();
+        let (_, partition) = gpt.partitions().next().unwrap();
+        assert_eq!(partition.first_lba, 64);
+        assert_eq!((partition.last_lba + 1) * 512, volume_size);
+        assert_eq!(gpt.last_usable, partition.last_lba);
+        check_backup(&mut disk, &gpt, 512, patch.len);
+        let mbr = Mbr::read(&mut disk, 512).unwrap().unwrap();
+        assert!(mbr.protects_gpt());
+        assert_eq!(mbr.partitions[0].end_lba() * 512, patch.len);
+    }
+
+    #[test]
+    fn seeks_through_patched_images() {
+        let image = with_gpt(0);
+        let (patch, patched) = apply(&image, &long_options()).unwrap();
+        let mut reader = patch.reader(Cursor::new(&image), image.len() as u64);
+        // Past the original end, back into the patched GPT, and into the middle
+        // of a patched file.
+        let moved = image.len() as u64 - GPT_TAIL;
+        for pos in [SeekFrom::Start(moved + 100), SeekFrom::Start(500), SeekFrom::Current(-10), SeekFrom::End(-700)] {
+            let at = reader.seek(pos).unwrap() as usize;
+            let mut buf = vec![0; 1000];
+            let read = reader.read(&mut buf).unwrap();
+            assert!(read > 0);
+            assert_eq!(&buf[..read], &patched[at..at + read]);
+        }
+        assert!(reader.seek(SeekFrom::Current(-(patch.len as i64) - 1)).is_err());
+
+        // The partition table checks see the grown partition.
+        let gpt = Gpt::read(&mut reader, 512).unwrap().unwrap();
+        let (_, partition) = gpt.partitions().next().unwrap();
+        assert_eq!((partition.last_lba + 1) * 512, patch.len - GPT_TAIL);
+    }
+
+    #[test]
+    fn refuses_to_move_files_out_of_the_gpt_partition() {
+        let image = with_gpt(1 << 20);
+        let err = apply(&image, &long_options()).unwrap_err();
+        assert_eq!(err.to_string(), "boot/grub/grub.cfg is too big to patch");
+        // Files that stay where they are are fine.
+        let (patch, patched) = apply(&image, &options()).unwrap();
+        assert_eq!(patch.len, image.len() as u64);
+        check_files(&patched, &options(), IMAGE.len() as u64);
+        assert_eq!(&patched[..17408], &image[..17408]);
+    }
+}
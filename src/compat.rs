@@ -0,0 +1,615 @@
+use crate::disk::format_size;
+use crate::release::Edition;
+
+use std::cmp::Reverse;
+
+// Checks this computer against what the image needs, and against hardware
+// Ubuntu is known to have trouble with, before wiping a drive and rebooting
+// on it.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuArch {
+    X86,
+    X64,
+    Arm64,
+    Other,
+}
+
+// The registers of the CPUID leaves that hold the feature flags: 1, 7 and
+// 0x80000001.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Cpuid {
+    pub leaf1_ecx: u32,
+    pub leaf1_edx: u32,
+    pub leaf7_ebx: u32,
+    pub ext1_ecx: u32,
+    pub ext1_edx: u32,
+}
+
+fn has_bits(reg: u32, bits: &[u32]) -> bool {
+    bits.iter().all(|&bit| reg & (1 << bit) != 0)
+}
+
+impl Cpuid {
+    // The x86-64 microarchitecture level, from 1 to 3, or 0 for a CPU
+    // without 64-bit support.
+    pub fn x86_64_level(&self) -> u8 {
+        // Long mode, SYSCALL, then FPU, CX8, CMOV, MMX, FXSR, SSE and SSE2.
+        let v1 = has_bits(self.ext1_edx, &[11, 29]) && has_bits(self.leaf1_edx, &[0, 8, 15, 23, 24, 25, 26]);
+        // SSE3, SSSE3, CX16, SSE4.1, SSE4.2, POPCNT, then LAHF/SAHF.
+        let v2 = has_bits(self.leaf1_ecx, &[0, 9, 13, 19, 20, 23]) && has_bits(self.ext1_ecx, &[0]);
+        // FMA, MOVBE, OSXSAVE, AVX, F16C, then BMI1, AVX2, BMI2, then LZCNT.
+        let v3 = has_bits(self.leaf1_ecx, &[12, 22, 27, 28, 29]) && has_bits(self.leaf7_ebx, &[3, 5, 8]) && has_bits(self.ext1_ecx, &[5]);
+        match (v1, v2, v3) {
+            (false, _, _) => 0,
+            (true, false, _) => 1,
+            (true, true, false) => 2,
+            (true, true, true) => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Device {
+    pub name: String,
+    // e.g. PCI\VEN_8086&DEV_A0F0&SUBSYS_00748086&REV_20\3&11583659&0&A3
+    pub pnp_id: String,
+    // The hardware IDs, the most specific first. Those of PCI devices hold
+    // their class code.
+    pub hardware_ids: Vec<String>,
+    // The service of its driver, e.g. storahci.
+    pub driver: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciIds {
+    pub vendor: u16,
+    pub device: u16,
+    // Base class and subclass, e.g. 0x0104 for RAID controllers.
+    pub class: Option<u16>,
+}
+
+fn hex_field(id: &str, key: &str, len: usize) -> Option<u32> {
+    let pos = id.find(key)? + key.len();
+    let hex = id.get(pos..pos + len)?;
+    u32::from_str_radix(hex, 16).ok()
+}
+
+impl Device {
+    pub fn pci_ids(&self) -> Option<PciIds> {
+        let id = self.pnp_id.to_ascii_uppercase();
+        if !id.starts_with("PCI\\") {
+            return None;
+        }
+        let class = self.hardware_ids.iter()
+            .filter_map(|v| hex_field(&v.to_ascii_uppercase(), "&CC_", 4))
+            .next()
+            .map(|v| v as u16);
+        Some(PciIds {
+            vendor: hex_field(&id, "VEN_", 4)? as u16,
+            device: hex_field(&id, "DEV_", 4)? as u16,
+            class,
+        })
+    }
+
+    // Intel RST in RAID mode, or with VMD, hides the disks behind a
+    // controller Linux only drives with extra setup, if at all.
+    pub fn is_raid_controller(&self) -> bool {
+        self.pci_ids().and_then(|v| v.class) == Some(RAID_CONTROLLER)
+            || RAID_DRIVERS.iter().any(|v| v.eq_ignore_ascii_case(&self.driver))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Hardware {
+    pub cpu_name: String,
+    pub arch: Option<CpuArch>,
+    // None on CPUs that aren't x86 ones.
+    pub cpuid: Option<Cpuid>,
+    pub ram: u64,
+    // The biggest disk that isn't a removable one, which Ubuntu would go on.
+    pub largest_disk: Option<u64>,
+    pub gpus: Vec<Device>,
+    pub wifi: Vec<Device>,
+    pub storage_controllers: Vec<Device>,
+}
+
+// What an image needs to run and install.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Requirements {
+    pub arch: CpuArch,
+    pub ram: u64,
+    pub disk: u64,
+}
+
+impl Requirements {
+    // From the release notes. The live server installer runs from memory,
+    // so it needs more than the installed system.
+    pub fn new(edition: Edition, arch: Option<&str>) -> Requirements {
+        let arch = match arch {
+            Some("arm64") => CpuArch::Arm64,
+            Some("i386") => CpuArch::X86,
+            _ => CpuArch::X64,
+        };
+        match edition {
+            Edition::Desktop => Requirements { arch, ram: 4 << 30, disk: 25 << 30 },
+            Edition::Server => Requirements { arch, ram: 1536 << 20, disk: 5 << 30 },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Ok,
+    Warning,
+    Problem,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Finding {
+    pub status: Status,
+    pub text: String,
+}
+
+fn finding(status: Status, text: String) -> Finding {
+    Finding { status, text }
+}
+
+const NVIDIA: u16 = 0x10DE;
+const BROADCOM: u16 = 0x14E4;
+
+// Wi-Fi chips the kernel of a stable release doesn't drive, or only lately,
+// as (vendor, device, name).
+const UNSUPPORTED_WIFI: &[(u16, u16, &str)] = &[
+    (0x14C3, 0x7902, "MediaTek MT7902"),
+    (0x10EC, 0xB852, "Realtek RTL8852BE"),
+    (0x10EC, 0xB85B, "Realtek RTL8852BE"),
+    (0x10EC, 0xC852, "Realtek RTL8852CE"),
+    (0x10EC, 0x8922, "Realtek RTL8922AE"),
+];
+
+const RAID_CONTROLLER: u16 = 0x0104;
+
+// The RST drivers that only drive RAID and VMD controllers. iaStorAC and
+// iaStorAVC drive AHCI ones too.
+const RAID_DRIVERS: &[&str] = &["iaStorV", "iaStorVD", "iaVROC"];
+
+fn check_cpu(hw: &Hardware, req: &Requirements) -> Finding {
+    let name = if hw.cpu_name.is_empty() { "The processor" } else { &hw.cpu_name };
+    // Check the architecture Windows reports first: x64 programs like us
+    // run emulated on ARM, and see an x64 processor there. 32-bit Windows
+    // often runs on 64-bit processors though, so CPUID tells for those.
+    let runs = match (req.arch, hw.arch) {
+        (CpuArch::Arm64, Some(CpuArch::Arm64)) => Some(true),
+        (CpuArch::Arm64, Some(_)) | (_, Some(CpuArch::Arm64)) => Some(false),
+        _ => None,
+    };
+    if runs == Some(false) {
+        let image = match hw.arch {
+            Some(CpuArch::Arm64) => "arm64",
+            _ => "amd64",
+        };
+        return finding(Status::Problem, format!("{} isn't the kind this image is built for. Pick the {} image instead.", name, image));
+    }
+    match (req.arch, hw.cpuid.map(|v| v.x86_64_level())) {
+        (CpuArch::X64, Some(0)) => finding(Status::Problem, format!("{} doesn't support 64-bit, which this image needs.", name)),
+        (CpuArch::X64, Some(level)) => finding(Status::Ok, format!("{} supports 64-bit (x86-64-v{}).", name, level)),
+        (CpuArch::X86, Some(_)) => finding(Status::Ok, format!("{} runs this image.", name)),
+        _ if runs == Some(true) => finding(Status::Ok, format!("{} runs this image.", name)),
+        _ => finding(Status::Warning, format!("Couldn't tell whether {} runs this image.", name)),
+    }
+}
+
+fn check_ram(hw: &Hardware, req: &Requirements) -> Finding {
+    if hw.ram == 0 {
+        return finding(Status::Warning, format!("Couldn't tell how much memory this computer has. Ubuntu needs {}.", format_size(req.ram)));
+    }
+    if hw.ram < req.ram {
+        return finding(Status::Problem, format!("This computer has {} of memory, and Ubuntu needs {}.", format_size(hw.ram), format_size(req.ram)));
+    }
+    finding(Status::Ok, format!("This computer has {} of memory.", format_size(hw.ram)))
+}
+
+fn check_disk(hw: &Hardware, req: &Requirements) -> Finding {
+    match hw.largest_disk {
+        None => finding(Status::Warning, format!("Couldn't find a disk to install Ubuntu on. It needs {}.", format_size(req.disk))),
+        Some(size) if size < req.disk => finding(Status::Problem, format!("The biggest disk holds {}, and Ubuntu needs {}.", format_size(size), format_size(req.disk))),
+        Some(size) => finding(Status::Ok, format!("The biggest disk holds {}.", format_size(size))),
+    }
+}
+
+fn check_gpus(hw: &Hardware) -> Vec<Finding> {
+    hw.gpus.iter().map(|gpu| match gpu.pci_ids() {
+        Some(ids) if ids.vendor == NVIDIA => finding(Status::Warning, format!("{}: if the screen stays black or garbled when starting Ubuntu, start it with the \"nomodeset\" boot option, then install the NVIDIA driver from \"Additional Drivers\".", gpu.name)),
+        _ => finding(Status::Ok, format!("{} works with the drivers Ubuntu comes with.", gpu.name)),
+    }).collect()
+}
+
+fn check_wifi(hw: &Hardware) -> Vec<Finding> {
+    hw.wifi.iter().filter_map(|adapter| {
+        let ids = adapter.pci_ids()?;
+        if ids.vendor == BROADCOM {
+            return Some(finding(Status::Warning, format!("{} needs a proprietary driver, installed along with third-party software. Keep a cable or phone tethering at hand to get it.", adapter.name)));
+        }
+        UNSUPPORTED_WIFI.iter().find(|v| v.0 == ids.vendor && v.1 == ids.device).map(|v| {
+            finding(Status::Warning, format!("{} ({}) only works with recent kernels, and may not work at all. Keep a cable or phone tethering at hand.", adapter.name, v.2))
+        })
+    }).collect()
+}
+
+fn check_storage(hw: &Hardware) -> Vec<Finding> {
+    hw.storage_controllers.iter()
+        .filter(|v| v.is_raid_controller())
+        .map(|controller| finding(Status::Problem, format!("{} runs the disks in RAID mode (Intel RST or VMD on most laptops). Ubuntu's installer won't see them, so it can't install next to Windows or replace it, until the firmware settings switch them to AHCI. The checklist before rebooting helps with that.", controller.name)))
+        .collect()
+}
+
+// The findings, the worst first.
+pub fn report(hw: &Hardware, req: &Requirements) -> Vec<Finding> {
+    let mut findings = vec![check_cpu(hw, req), check_ram(hw, req), check_disk(hw, req)];
+    findings.extend(check_gpus(hw));
+    findings.extend(check_wifi(hw));
+    findings.extend(check_storage(hw));
+    findings.sort_by_key(|v| Reverse(v.status));
+    findings
+}
+
+#[cfg(windows)]
+pub use self::win::{hardware, largest_disk, raid_controller};
+
+#[cfg(windows)]
+mod win {
+    use super::{CpuArch, Cpuid, Device, Hardware};
+    use crate::disk::format_size;
+
+    use winapi::um::sysinfoapi::GetPhysicallyInstalledSystemMemory;
+    use winreg::RegKey;
+    use winreg::enums::HKEY_LOCAL_MACHINE;
+    use serde::Deserialize;
+
+    // What Windows runs as natively, even when we run emulated.
+    fn arch() -> Option<CpuArch> {
+        let arch: String = RegKey::predef(HKEY_LOCAL_MACHINE)
+            .open_subkey(r"SYSTEM\CurrentControlSet\Control\Session Manager\Environment")
+            .and_then(|key| key.get_value("PROCESSOR_ARCHITECTURE"))
+            .ok()?;
+        Some(match arch.as_str() {
+            "AMD64" => CpuArch::X64,
+            "x86" => CpuArch::X86,
+            "ARM64" => CpuArch::Arm64,
+            _ => CpuArch::Other,
+        })
+    }
+
+    fn cpu_name() -> String {
+        RegKey::predef(HKEY_LOCAL_MACHINE)
+            .open_subkey(r"HARDWARE\DESCRIPTION\System\CentralProcessor\0")
+            .and_then(|key| key.get_value::<String, _>("ProcessorNameString"))
+            .map(|v| v.trim().to_string())
+            .unwrap_or_default()
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn cpuid() -> Option<Cpuid> {
+        #[cfg(target_arch = "x86")]
+        use std::arch::x86::{__cpuid, __cpuid_count};
+        #[cfg(target_arch = "x86_64")]
+        use std::arch::x86_64::{__cpuid, __cpuid_count};
+
+        unsafe {
+            let max = __cpuid(0).eax;
+            let max_ext = __cpuid(0x8000_0000).eax;
+            let leaf1 = __cpuid(1);
+            let leaf7 = if max >= 7 { __cpuid_count(7, 0).ebx } else { 0 };
+            let (ext1_ecx, ext1_edx) = if max_ext >= 0x8000_0001 {
+                let ext1 = __cpuid(0x8000_0001);
+                (ext1.ecx, ext1.edx)
+            } else {
+                (0, 0)
+            };
+            Some(Cpuid { leaf1_ecx: leaf1.ecx, leaf1_edx: leaf1.edx, leaf7_ebx: leaf7, ext1_ecx, ext1_edx })
+        }
+    }
+
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    fn cpuid() -> Option<Cpuid> {
+        None
+    }
+
+    // Installed, rather than what Windows can use, which is a bit less.
+    fn ram() -> u64 {
+        let mut kb = 0;
+        if unsafe { GetPhysicallyInstalledSystemMemory(&mut kb) } == 0 {
+            return 0;
+        }
+        kb * 1024
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename = "Win32_DiskDrive")]
+    #[serde(rename_all = "PascalCase")]
+    struct DiskDrive {
+        model: Option<String>,
+        // WMI hands out 64-bit integers as strings.
+        size: Option<String>,
+        media_type: Option<String>,
+        interface_type: Option<String>,
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename_all = "PascalCase")]
+    struct PnpEntity {
+        name: Option<String>,
+        #[serde(rename = "PNPDeviceID")]
+        pnp_device_id: Option<String>,
+        #[serde(rename = "HardwareID")]
+        hardware_id: Option<Vec<String>>,
+        service: Option<String>,
+    }
+
+    impl From<PnpEntity> for Device {
+        fn from(entity: PnpEntity) -> Device {
+            Device {
+                name: entity.name.unwrap_or_default(),
+                pnp_id: entity.pnp_device_id.unwrap_or_default(),
+                hardware_ids: entity.hardware_id.unwrap_or_default(),
+                driver: entity.service.unwrap_or_default(),
+            }
+        }
+    }
+
+    fn devices(wmi: &wmi::WMIConnection, condition: &str) -> Vec<Device> {
+        let query = format!("SELECT Name, PNPDeviceID, HardwareID, Service FROM Win32_PnPEntity WHERE {}", condition);
+        match wmi.raw_query::<PnpEntity>(&query) {
+            Ok(entities) => entities.into_iter().map(Device::from).collect(),
+            Err(err) => {
+                eprintln!("Failed to list devices: {}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    const STORAGE_CONTROLLERS: &str = "PNPClass = 'SCSIAdapter' OR PNPClass = 'HDC'";
+
+    // The internal disks, with their size, which is what the installer picks
+    // from.
+    fn fixed_disks(wmi: &wmi::WMIConnection) -> Vec<(String, u64)> {
+        match wmi.query::<DiskDrive>() {
+            Ok(disks) => disks.into_iter()
+                .filter(|v| v.media_type.as_deref() == Some("Fixed hard disk media") && v.interface_type.as_deref() != Some("USB"))
+                .filter_map(|v| Some((v.model.unwrap_or_default(), v.size?.parse().ok()?)))
+                .collect(),
+            Err(err) => {
+                eprintln!("Failed to list disks: {}", err);
+                Vec::new()
+            }
+        }
+    }
+
+    fn connect() -> Option<wmi::WMIConnection> {
+        match wmi::COMLibrary::new().and_then(wmi::WMIConnection::new) {
+            Ok(wmi) => Some(wmi),
+            Err(err) => {
+                eprintln!("Failed to connect to WMI: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn hardware() -> Hardware {
+        let mut hw = Hardware {
+            cpu_name: cpu_name(),
+            arch: arch(),
+            cpuid: cpuid(),
+            ram: ram(),
+            ..Hardware::default()
+        };
+        let wmi = match connect() {
+            Some(wmi) => wmi,
+            None => return hw,
+        };
+        hw.largest_disk = fixed_disks(&wmi).into_iter().map(|v| v.1).max();
+        // PnP doesn't tell wireless adapters apart from the other network
+        // ones, but their names do.
+        hw.gpus = devices(&wmi, "PNPClass = 'Display'");
+        hw.wifi = devices(&wmi, "PNPClass = 'Net' AND (Name LIKE '%Wi-Fi%' OR Name LIKE '%Wireless%' OR Name LIKE '%WLAN%' OR Name LIKE '%802.11%')");
+        hw.storage_controllers = devices(&wmi, STORAGE_CONTROLLERS);
+        hw
+    }
+
+    // Names the disk an autoinstall takes by default, e.g. "the 476.9 GB disk
+    // (Samsung SSD 970)".
+    pub fn largest_disk() -> Option<String> {
+        let (model, size) = fixed_disks(&connect()?).into_iter().max_by_key(|v| v.1)?;
+        Some(match model.trim() {
+            "" => format!("the {} disk", format_size(size)),
+            model => format!("the {} disk ({})", format_size(size), model),
+        })
+    }
+
+    // The name of the controller that keeps the disks in RAID mode, if any.
+    pub fn raid_controller() -> Option<String> {
+        devices(&connect()?, STORAGE_CONTROLLERS).into_iter().find(Device::is_raid_controller).map(|v| v.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bits(bits: &[u32]) -> u32 {
+        bits.iter().fold(0, |reg, bit| reg | 1 << bit)
+    }
+
+    // A CPU with every flag up to `level`.
+    fn cpuid(level: u8) -> Cpuid {
+        let mut cpuid = Cpuid::default();
+        if level >= 1 {
+            cpuid.ext1_edx |= bits(&[11, 29]);
+            cpuid.leaf1_edx |= bits(&[0, 8, 15, 23, 24, 25, 26]);
+        }
+        if level >= 2 {
+            cpuid.leaf1_ecx |= bits(&[0, 9, 13, 19, 20, 23]);
+            cpuid.ext1_ecx |= bits(&[0]);
+        }
+        if level >= 3 {
+            cpuid.leaf1_ecx |= bits(&[12, 22, 27, 28, 29]);
+            cpuid.leaf7_ebx |= bits(&[3, 5, 8]);
+            cpuid.ext1_ecx |= bits(&[5]);
+        }
+        cpuid
+    }
+
+    fn x64_pc() -> Hardware {
+        Hardware {
+            cpu_name: "Intel Core i5-8250U".to_string(),
+            arch: Some(CpuArch::X64),
+            cpuid: Some(cpuid(3)),
+            ram: 8 << 30,
+            largest_disk: Some(256 << 30),
+            ..Hardware::default()
+        }
+    }
+
+    fn device(name: &str, pnp_id: &str, hardware_ids: &[&str], driver: &str) -> Device {
+        Device {
+            name: name.to_string(),
+            pnp_id: pnp_id.to_string(),
+            hardware_ids: hardware_ids.iter().map(|v| v.to_string()).collect(),
+            driver: driver.to_string(),
+        }
+    }
+
+    fn controller(class: &str, driver: &str) -> Device {
+        Device {
+            name: "Intel Storage Controller".to_string(),
+            pnp_id: "PCI\\VEN_8086&DEV_A0D3&SUBSYS_00748086&REV_20\\3&11583659&0&B8".to_string(),
+            hardware_ids: vec![
+                "PCI\\VEN_8086&DEV_A0D3&SUBSYS_00748086&REV_20".to_string(),
+                format!("PCI\\VEN_8086&DEV_A0D3&CC_{}", class),
+                format!("PCI\\VEN_8086&CC_{}", class),
+            ],
+            driver: driver.to_string(),
+        }
+    }
+
+    #[test]
+    fn reads_pci_ids() {
+        let gpu = device("NVIDIA GeForce RTX 3050", "PCI\\VEN_10DE&DEV_25A2&SUBSYS_0A771028&REV_A1\\4&2B2A5F4&0&0008", &["PCI\\VEN_10DE&DEV_25A2&CC_030000", "PCI\\VEN_10DE&CC_0300"], "nvlddmkm");
+        assert_eq!(gpu.pci_ids(), Some(PciIds { vendor: NVIDIA, device: 0x25A2, class: Some(0x0300) }));
+        let lower = device("Wi-Fi", "pci\\ven_14e4&dev_43a0\\0", &[], "");
+        assert_eq!(lower.pci_ids(), Some(PciIds { vendor: BROADCOM, device: 0x43A0, class: None }));
+
+        assert_eq!(device("Mouse", "USB\\VID_046D&PID_C077\\5&1A1B", &[], "mouhid").pci_ids(), None);
+        assert_eq!(device("Root", "ACPI\\PNP0A08\\0", &["ACPI\\PNP0A08"], "pci").pci_ids(), None);
+        assert_eq!(device("Broken", "PCI\\VEN_XYZW&DEV_1234\\0", &[], "").pci_ids(), None);
+    }
+
+    #[test]
+    fn finds_raid_controllers() {
+        assert!(controller("0104", "iaStorVD").is_raid_controller());
+        // The driver gives VMD away, whatever the class.
+        assert!(controller("0880", "iaStorVD").is_raid_controller());
+        assert!(controller("0880", "iaVROC").is_raid_controller());
+        assert!(controller("0104", "").is_raid_controller());
+
+        assert!(!controller("0106", "iaStorAC").is_raid_controller());
+        assert!(!controller("0106", "storahci").is_raid_controller());
+        assert!(!controller("0108", "stornvme").is_raid_controller());
+        // Nor PnP devices that aren't PCI ones.
+        assert!(!device("Storage Spaces Controller", "ROOT\\SPACEPORT\\0000", &["Root\\Spaceport"], "spaceport").is_raid_controller());
+
+        let hw = Hardware { storage_controllers: vec![controller("0106", "storahci"), controller("0104", "iaStorVD")], ..Hardware::default() };
+        assert_eq!(check_storage(&hw).len(), 1);
+    }
+
+    #[test]
+    fn computes_x86_64_levels() {
+        assert_eq!(Cpuid::default().x86_64_level(), 0);
+        for level in 0..=3 {
+            assert_eq!(cpuid(level).x86_64_level(), level);
+        }
+        // Newer flags don't make up for missing older ones.
+        let mut no_long_mode = cpuid(3);
+        no_long_mode.ext1_edx &= !bits(&[29]);
+        assert_eq!(no_long_mode.x86_64_level(), 0);
+        let mut no_popcnt = cpuid(3);
+        no_popcnt.leaf1_ecx &= !bits(&[23]);
+        assert_eq!(no_popcnt.x86_64_level(), 1);
+        let mut no_avx2 = cpuid(3);
+        no_avx2.leaf7_ebx &= !bits(&[5]);
+        assert_eq!(no_avx2.x86_64_level(), 2);
+    }
+
+    #[test]
+    fn checks_the_cpu() {
+        let amd64 = Requirements::new(Edition::Desktop, Some("amd64"));
+        let arm64 = Requirements::new(Edition::Desktop, Some("arm64"));
+        assert_eq!(arm64.arch, CpuArch::Arm64);
+        assert_eq!(Requirements::new(Edition::Desktop, None).arch, CpuArch::X64);
+
+        let finding = check_cpu(&x64_pc(), &amd64);
+        assert_eq!(finding.status, Status::Ok);
+        assert!(finding.text.contains("x86-64-v3"), "{}", finding.text);
+
+        // x64 Windows, arm64 image.
+        let finding = check_cpu(&x64_pc(), &arm64);
+        assert_eq!(finding.status, Status::Problem);
+        assert!(finding.text.contains("Pick the amd64 image"), "{}", finding.text);
+
+        // And the reverse, where we run emulated and CPUID says x64.
+        let arm_pc = Hardware { arch: Some(CpuArch::Arm64), ..x64_pc() };
+        let finding = check_cpu(&arm_pc, &amd64);
+        assert_eq!(finding.status, Status::Problem);
+        assert!(finding.text.contains("Pick the arm64 image"), "{}", finding.text);
+        let arm_pc = Hardware { cpuid: None, ..arm_pc };
+        assert_eq!(check_cpu(&arm_pc, &arm64).status, Status::Ok);
+
+        // 32-bit Windows on a 64-bit CPU.
+        let x86_pc = Hardware { arch: Some(CpuArch::X86), cpuid: Some(cpuid(2)), ..x64_pc() };
+        assert_eq!(check_cpu(&x86_pc, &amd64).status, Status::Ok);
+        let old_pc = Hardware { cpuid: Some(cpuid(0)), ..x86_pc };
+        assert_eq!(check_cpu(&old_pc, &amd64).status, Status::Problem);
+        let unknown = Hardware { arch: None, cpuid: None, ..x64_pc() };
+        assert_eq!(check_cpu(&unknown, &amd64).status, Status::Warning);
+    }
+
+    #[test]
+    fn checks_ram_and_disk() {
+        let desktop = Requirements::new(Edition::Desktop, None);
+        let server = Requirements::new(Edition::Server, None);
+        let with = |ram: u64, disk: Option<u64>| Hardware { ram, largest_disk: disk, ..x64_pc() };
+
+        assert_eq!(check_ram(&with(4 << 30, None), &desktop).status, Status::Ok);
+        assert_eq!(check_ram(&with((4 << 30) - 1, None), &desktop).status, Status::Problem);
+        assert_eq!(check_ram(&with(2 << 30, None), &server).status, Status::Ok);
+        assert_eq!(check_ram(&with(0, None), &desktop).status, Status::Warning);
+
+        assert_eq!(check_disk(&with(0, Some(25 << 30)), &desktop).status, Status::Ok);
+        assert_eq!(check_disk(&with(0, Some(16 << 30)), &desktop).status, Status::Problem);
+        assert_eq!(check_disk(&with(0, Some(16 << 30)), &server).status, Status::Ok);
+        assert_eq!(check_disk(&with(0, None), &desktop).status, Status::Warning);
+    }
+
+    #[test]
+    fn reports_the_worst_first() {
+        let req = Requirements::new(Edition::Desktop, None);
+        assert!(report(&x64_pc(), &req).iter().all(|v| v.status == Status::Ok));
+
+        let hw = Hardware {
+            ram: 0,
+            largest_disk: Some(16 << 30),
+            gpus: vec![device("Intel UHD Graphics 620", "PCI\\VEN_8086&DEV_5917\\3&0", &[], "igfx"), device("NVIDIA GeForce MX150", "PCI\\VEN_10DE&DEV_1D10\\4&0", &[], "nvlddmkm")],
+            wifi: vec![device("Broadcom 802.11ac Network Adapter", "PCI\\VEN_14E4&DEV_43A0\\4&0", &[], "BCMWL63A")],
+            storage_controllers: vec![controller("0104", "iaStorVD")],
+            ..x64_pc()
+        };
+        let findings = report(&hw, &req);
+        let statuses: Vec<Status> = findings.iter().map(|v| v.status).collect();
+        assert_eq!(statuses, [Status::Problem, Status::Problem, Status::Warning, Status::Warning, Status::Warning, Status::Ok, Status::Ok]);
+        // In the order they were checked, within a status.
+        assert!(findings[0].text.starts_with("The biggest disk"));
+        assert!(findings[1].text.starts_with("Intel Storage Controller"));
+        assert!(findings[2].text.starts_with("Couldn't tell how much memory"));
+    }
+}
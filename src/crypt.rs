@@ -0,0 +1,118 @@
+use crate::writer::random_bytes;
+
+use sha2::{Digest, Sha512};
+
+// SHA-512 crypt, the "$6$" password hashes of /etc/shadow, following Ulrich
+// Drepper's specification with the default 5000 rounds.
+
+const ROUNDS: usize = 5000;
+const ALPHABET: &[u8] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+// The digest gets encoded three bytes at a time, in this order.
+const ORDER: [(usize, usize, usize); 21] = [
+    (0, 21, 42), (22, 43, 1), (44, 2, 23), (3, 24, 45), (25, 46, 4), (47, 5, 26), (6, 27, 48),
+    (28, 49, 7), (50, 8, 29), (9, 30, 51), (31, 52, 10), (53, 11, 32), (12, 33, 54), (34, 55, 13),
+    (56, 14, 35), (15, 36, 57), (37, 58, 16), (59, 17, 38), (18, 39, 60), (40, 61, 19), (62, 20, 41),
+];
+
+fn encode(out: &mut String, b2: u8, b1: u8, b0: u8, chars: usize) {
+    let mut w = (b2 as u32) << 16 | (b1 as u32) << 8 | b0 as u32;
+    for _ in 0..chars {
+        out.push(ALPHABET[(w & 0x3f) as usize] as char);
+        w >>= 6;
+    }
+}
+
+// `len` bytes of `digest` repeated.
+fn repeat(digest: &[u8], len: usize) -> Vec<u8> {
+    digest.iter().cycle().take(len).cloned().collect()
+}
+
+pub fn sha512_crypt_with_salt(password: &str, salt: &str) -> String {
+    let password = password.as_bytes();
+    let salt = &salt.as_bytes()[..std::cmp::min(salt.len(), 16)];
+
+    let alternate = Sha512::new().chain(password).chain(salt).chain(password).finalize();
+
+    let mut hasher = Sha512::new().chain(password).chain(salt).chain(repeat(&alternate, password.len()));
+    let mut len = password.len();
+    while len > 0 {
+        if len & 1 != 0 {
+            hasher.update(alternate);
+        } else {
+            hasher.update(password);
+        }
+        len >>= 1;
+    }
+    let mut digest = hasher.finalize();
+
+    let mut hasher = Sha512::new();
+    for _ in 0..password.len() {
+        hasher.update(password);
+    }
+    let p_bytes = repeat(&hasher.finalize(), password.len());
+
+    let mut hasher = Sha512::new();
+    for _ in 0..16 + digest[0] as usize {
+        hasher.update(salt);
+    }
+    let s_bytes = repeat(&hasher.finalize(), salt.len());
+
+    for round in 0..ROUNDS {
+        let mut hasher = Sha512::new();
+        if round % 2 == 1 {
+            hasher.update(&p_bytes);
+        } else {
+            hasher.update(digest);
+        }
+        if round % 3 != 0 {
+            hasher.update(&s_bytes);
+        }
+        if round % 7 != 0 {
+            hasher.update(&p_bytes);
+        }
+        if round % 2 == 1 {
+            hasher.update(digest);
+        } else {
+            hasher.update(&p_bytes);
+        }
+        digest = hasher.finalize();
+    }
+
+    let mut out = format!("$6${}$", String::from_utf8_lossy(salt));
+    for &(a, b, c) in ORDER.iter() {
+        encode(&mut out, digest[a], digest[b], digest[c], 4);
+    }
+    encode(&mut out, 0, 0, digest[63], 2);
+    out
+}
+
+// Hashes a password with a random salt.
+pub fn sha512_crypt(password: &str) -> String {
+    let mut bytes = [0; 16];
+    random_bytes(&mut bytes);
+    let salt: String = bytes.iter().map(|v| ALPHABET[(v & 0x3f) as usize] as char).collect();
+    sha512_crypt_with_salt(password, &salt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // From the SHA-crypt specification, and glibc's crypt().
+    #[test]
+    fn known_vectors() {
+        assert_eq!(sha512_crypt_with_salt("Hello world!", "saltstring"), "$6$saltstring$svn8UoSVapNtMuq1ukKS4tPQd8iKwSMHWjl/O817G3uBnIFNjnQJuesI68u4OTLiBFdcbYEdFCoEOfaS35inz1");
+        assert_eq!(sha512_crypt_with_salt("", "saltstring"), "$6$saltstring$kyGrqt6gmjAdtFLPrflEFifSYLCWWq1pyx95SvqinLDy2UHmj0sTF0MSLMwxPFZc3tu5kQckI8fks0zOPda3n1");
+        // Salts stop at 16 characters.
+        assert_eq!(sha512_crypt_with_salt("This is just a test", "toolongsaltstringtoolong"), "$6$toolongsaltstrin$lQ8jolhgVRVhY4b5pZKaysCLi0QBxGoNeKQzQ3glMhwllF7oGDZxUhx1yxdYcz/e1JSbq3y6JMxxl8audkUEm0");
+    }
+
+    #[test]
+    fn random_salts() {
+        let hash = sha512_crypt("password");
+        assert!(hash.starts_with("$6$"));
+        assert_eq!(hash.split('$').count(), 4);
+        assert_ne!(hash, sha512_crypt("password"));
+    }
+}
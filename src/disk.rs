@@ -0,0 +1,368 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusType {
+    Usb,
+    Sata,
+    Ata,
+    Nvme,
+    Scsi,
+    Sas,
+    Sd,
+    Mmc,
+    Raid,
+    Virtual,
+    Unknown,
+}
+
+impl BusType {
+    // Values of the STORAGE_BUS_TYPE enum.
+    pub fn from_raw(val: u32) -> BusType {
+        match val {
+            0x01 => BusType::Scsi,
+            0x02 | 0x03 => BusType::Ata,
+            0x07 => BusType::Usb,
+            0x08 => BusType::Raid,
+            0x0A => BusType::Sas,
+            0x0B => BusType::Sata,
+            0x0C => BusType::Sd,
+            0x0D => BusType::Mmc,
+            0x0E | 0x0F => BusType::Virtual,
+            0x11 => BusType::Nvme,
+            _ => BusType::Unknown,
+        }
+    }
+}
+
+impl fmt::Display for BusType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            BusType::Usb => "USB",
+            BusType::Sata => "SATA",
+            BusType::Ata => "ATA",
+            BusType::Nvme => "NVMe",
+            BusType::Scsi => "SCSI",
+            BusType::Sas => "SAS",
+            BusType::Sd => "SD card",
+            BusType::Mmc => "MMC",
+            BusType::Raid => "RAID",
+            BusType::Virtual => "Virtual",
+            BusType::Unknown => "Unknown bus",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiskInfo {
+    pub id: String,
+    // Drive letter of the volume, e.g. `E:\`.
+    pub path: String,
+    pub label: String,
+    pub filesystem: String,
+    pub device_number: u32,
+    pub size: u64,
+    pub vendor: String,
+    pub model: String,
+    // Often empty on cheap flash drives.
+    pub serial: String,
+    pub bus: BusType,
+    pub removable: bool,
+    // Holds the partition the firmware boots from (the ESP, or "System
+    // Reserved" on BIOS machines).
+    pub boot: bool,
+    // Holds the Windows system volume.
+    pub system: bool,
+    pub pagefile: bool,
+    // None when we can't tell, without admin rights.
+    pub bitlocker: Option<bool>,
+}
+
+impl DiskInfo {
+    pub fn can_hold(&self, image_size: u64) -> bool {
+        self.size >= image_size
+    }
+
+    pub fn physical_path(&self) -> String {
+        format!(r"\\.\PhysicalDrive{}", self.device_number)
+    }
+
+    pub fn display_name(&self) -> String {
+        let mut s = self.path.clone();
+        if !self.label.is_empty() {
+            s += &format!(" {}", self.label);
+        }
+        if !self.filesystem.is_empty() {
+            s += &format!(" ({})", self.filesystem);
+        }
+        s
+    }
+
+    pub fn details(&self) -> String {
+        let model = match (self.vendor.is_empty(), self.model.is_empty()) {
+            (false, false) => format!("{} {}", self.vendor, self.model),
+            (true, false) => self.model.clone(),
+            (false, true) => self.vendor.clone(),
+            (true, true) => "Unknown device".to_string(),
+        };
+        format!("{} - {} - {}", format_size(self.size), model, self.bus)
+    }
+}
+
+// Formats a size the way the Windows explorer does: powers of 1024, with
+// decimal unit names.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["bytes", "KB", "MB", "GB", "TB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(windows)]
+pub use self::win::{bitlocker_drives, volume_disks};
+
+#[cfg(windows)]
+mod win {
+    use super::{BusType, DiskInfo};
+    use crate::win32::{as_bytes, from_wide, ioctl, ioctl_out, open_device};
+
+    use winapi::shared::minwindef::MAX_PATH;
+    use winapi::um::fileapi::{GetVolumeInformationByHandleW, GetVolumeNameForVolumeMountPointW, GetVolumePathNamesForVolumeNameW};
+    use winapi::um::winnt::GENERIC_READ;
+    use winapi::um::winioctl::{GET_LENGTH_INFORMATION, IOCTL_DISK_GET_LENGTH_INFO, IOCTL_STORAGE_GET_DEVICE_NUMBER, IOCTL_STORAGE_QUERY_PROPERTY, PropertyStandardQuery, STORAGE_DEVICE_NUMBER, STORAGE_PROPERTY_QUERY, StorageDeviceProperty};
+
+    use winapi::um::sysinfoapi::GetSystemWindowsDirectoryW;
+    use winapi::um::winioctl::IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS;
+    use winreg::RegKey;
+    use winreg::enums::HKEY_LOCAL_MACHINE;
+    use serde::Deserialize;
+
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr;
+
+    struct DeviceDescriptor {
+        vendor: String,
+        model: String,
+        serial: String,
+        bus: BusType,
+        removable: bool,
+    }
+
+    // STORAGE_DEVICE_DESCRIPTOR isn't in winapi. The strings are stored
+    // after the structure, and referenced by their offset.
+    fn parse_device_descriptor(buf: &[u8]) -> DeviceDescriptor {
+        let u32_at = |off: usize| {
+            let mut val = [0; 4];
+            val.copy_from_slice(&buf[off..off + 4]);
+            u32::from_le_bytes(val) as usize
+        };
+        let str_at = |off: usize| {
+            if off == 0 || off >= buf.len() {
+                return String::new();
+            }
+            let end = buf[off..].iter().position(|v| *v == 0).map(|v| off + v).unwrap_or(buf.len());
+            String::from_utf8_lossy(&buf[off..end]).trim().to_string()
+        };
+        DeviceDescriptor {
+            removable: buf[10] != 0,
+            vendor: str_at(u32_at(12)),
+            model: str_at(u32_at(16)),
+            serial: str_at(u32_at(24)),
+            bus: BusType::from_raw(u32_at(28) as u32),
+        }
+    }
+
+    impl DiskInfo {
+        // Gathers everything we know about the disk backing the volume with
+        // the given device interface ID. Returns None if the volume isn't
+        // mounted on a drive letter.
+        pub fn query(id: &str) -> io::Result<Option<DiskInfo>> {
+            let volume = open_device(id, GENERIC_READ)?;
+
+            let mut label = [0; MAX_PATH + 1];
+            let mut filesystem = [0; MAX_PATH + 1];
+            let ret = unsafe {
+                GetVolumeInformationByHandleW(
+                    volume.as_raw_handle() as _,
+                    label.as_mut_ptr(),
+                    label.len() as u32,
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    ptr::null_mut(),
+                    filesystem.as_mut_ptr(),
+                    filesystem.len() as u32,
+                )
+            };
+            // An unformatted or unreadable volume has no label nor filesystem.
+            if ret == 0 {
+                label[0] = 0;
+                filesystem[0] = 0;
+            }
+
+            let path = match drive_letter(id)? {
+                Some(path) => path,
+                None => return Ok(None),
+            };
+
+            let number: STORAGE_DEVICE_NUMBER = ioctl_out(&volume, IOCTL_STORAGE_GET_DEVICE_NUMBER, &[])?;
+            let disk = open_device(&format!(r"\\.\PhysicalDrive{}", number.DeviceNumber), 0)?;
+
+            let query = STORAGE_PROPERTY_QUERY {
+                PropertyId: StorageDeviceProperty,
+                QueryType: PropertyStandardQuery,
+                AdditionalParameters: [0],
+            };
+            let mut desc = [0u8; 1024];
+            ioctl(&disk, IOCTL_STORAGE_QUERY_PROPERTY, as_bytes(&query), &mut desc)?;
+            let desc = parse_device_descriptor(&desc);
+
+            let length: GET_LENGTH_INFORMATION = ioctl_out(&disk, IOCTL_DISK_GET_LENGTH_INFO, &[])?;
+
+            let sys = SystemDisks::query()?;
+            let number = number.DeviceNumber;
+
+            Ok(Some(DiskInfo {
+                id: id.to_string(),
+                path,
+                label: from_wide(&label),
+                filesystem: from_wide(&filesystem),
+                device_number: number,
+                size: unsafe { *length.Length.QuadPart() } as u64,
+                vendor: desc.vendor,
+                model: desc.model,
+                serial: desc.serial,
+                bus: desc.bus,
+                removable: desc.removable,
+                boot: sys.boot.contains(&number),
+                system: sys.system.contains(&number),
+                pagefile: sys.pagefile.contains(&number),
+                bitlocker: sys.bitlocker.as_ref().map(|v| v.contains(&number)),
+            }))
+        }
+    }
+
+    fn drive_letter(id: &str) -> io::Result<Option<String>> {
+        let mut mount_point: Vec<u16> = id.encode_utf16().collect();
+        mount_point.extend(&['\\' as u16, 0]);
+
+        let mut volume_name = [0; MAX_PATH + 1];
+        let ret = unsafe {
+            GetVolumeNameForVolumeMountPointW(mount_point.as_ptr(), volume_name.as_mut_ptr(), volume_name.len() as u32)
+        };
+        if ret == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut names = [0; MAX_PATH * 4];
+        let mut char_count = 0;
+        let ret = unsafe {
+            GetVolumePathNamesForVolumeNameW(volume_name.as_ptr(), names.as_mut_ptr(), names.len() as u32, &mut char_count)
+        };
+        if ret == 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The names are a list of NUL-terminated strings, ending with an
+        // empty string.
+        Ok(names[..char_count as usize]
+            .split(|v| *v == 0)
+            .map(|name| String::from_utf16_lossy(name))
+            .find(|name| !name.is_empty()))
+    }
+
+    // Disk numbers of the disks Windows can't live without.
+    struct SystemDisks {
+        boot: Vec<u32>,
+        system: Vec<u32>,
+        pagefile: Vec<u32>,
+        bitlocker: Option<Vec<u32>>,
+    }
+
+    impl SystemDisks {
+        fn query() -> io::Result<SystemDisks> {
+            let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+
+            // NT path of the system partition, e.g. \Device\HarddiskVolume1.
+            let system_partition: String = hklm.open_subkey(r"SYSTEM\Setup")?.get_value("SystemPartition")?;
+            let boot = volume_disks(&format!(r"\\?\GLOBALROOT{}", system_partition))?;
+
+            let mut windows_dir = [0; MAX_PATH + 1];
+            let len = unsafe { GetSystemWindowsDirectoryW(windows_dir.as_mut_ptr(), windows_dir.len() as u32) };
+            if len == 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let system = volume_disks(&drive_device(&from_wide(&windows_dir)))?;
+
+            // Paths in the form \??\C:\pagefile.sys, one per line.
+            let page_files: String = hklm
+                .open_subkey(r"SYSTEM\CurrentControlSet\Control\Session Manager\Memory Management")?
+                .get_value("ExistingPageFiles")
+                .unwrap_or_default();
+            let mut pagefile = Vec::new();
+            for page_file in page_files.lines().filter(|v| !v.is_empty()) {
+                pagefile.extend(volume_disks(&drive_device(page_file.trim_start_matches(r"\??\")))?);
+            }
+
+            // This needs admin rights. Without them, we can't tell, and the
+            // policy refuses the disks.
+            let bitlocker = match bitlocker_drives() {
+                Ok(drives) => {
+                    let mut disks = Vec::new();
+                    for drive in drives {
+                        disks.extend(volume_disks(&drive_device(&drive))?);
+                    }
+                    Some(disks)
+                },
+                Err(_) => None,
+            };
+
+            Ok(SystemDisks { boot, system, pagefile, bitlocker })
+        }
+    }
+
+    // Turns a path like C:\Windows into the device path of its volume.
+    fn drive_device(path: &str) -> String {
+        format!(r"\\.\{}", &path[..2])
+    }
+
+    pub fn volume_disks(volume_path: &str) -> io::Result<Vec<u32>> {
+        let volume = open_device(volume_path, 0)?;
+        // VOLUME_DISK_EXTENTS, with room for a volume spanning a handful of
+        // disks. The extents start at offset 8 and are 24 bytes each, with
+        // the disk number first.
+        let mut buf = [0u8; 8 + 24 * 8];
+        ioctl(&volume, IOCTL_VOLUME_GET_VOLUME_DISK_EXTENTS, &[], &mut buf)?;
+        let count = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        Ok(buf[8..].chunks(24)
+            .take(count)
+            .map(|extent| u32::from_le_bytes([extent[0], extent[1], extent[2], extent[3]]))
+            .collect())
+    }
+
+    #[derive(Deserialize)]
+    #[serde(rename = "Win32_EncryptableVolume")]
+    #[serde(rename_all = "PascalCase")]
+    struct EncryptableVolume {
+        drive_letter: Option<String>,
+        protection_status: u32,
+    }
+
+    // Drive letters of the volumes BitLocker protects, e.g. "C:".
+    pub fn bitlocker_drives() -> Result<Vec<String>, wmi::WMIError> {
+        let wmi = wmi::WMIConnection::with_namespace_path(r"ROOT\CIMV2\Security\MicrosoftVolumeEncryption", wmi::COMLibrary::new()?)?;
+        let volumes: Vec<EncryptableVolume> = wmi.query()?;
+        Ok(volumes.into_iter()
+            .filter(|volume| volume.protection_status == 1)
+            .filter_map(|volume| volume.drive_letter)
+            .collect())
+    }
+}
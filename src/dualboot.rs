@@ -0,0 +1,485 @@
+use crate::disk::format_size;
+
+use std::cmp;
+use std::io;
+
+// Making room for Ubuntu next to Windows, by shrinking the partition Windows
+// runs from. The installer then goes in the free space left behind.
+
+// What Ubuntu needs, and what Windows needs to keep free for its updates.
+pub const MIN_UBUNTU_SIZE: u64 = 25 << 30;
+pub const WINDOWS_FREE_SPACE: u64 = 20 << 30;
+// Partitions start on MiB boundaries, like Windows and the installer put them.
+const ALIGNMENT: u64 = 1 << 20;
+
+const PARTITION_STYLE_MBR: u32 = 0;
+const PARTITION_STYLE_GPT: u32 = 1;
+// Size of DRIVE_LAYOUT_INFORMATION_EX up to its first entry, and of a
+// PARTITION_INFORMATION_EX.
+const LAYOUT_HEADER_SIZE: usize = 48;
+const PARTITION_ENTRY_SIZE: usize = 144;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LayoutPartition {
+    pub number: u32,
+    // In bytes.
+    pub offset: u64,
+    pub length: u64,
+}
+
+impl LayoutPartition {
+    pub fn end(&self) -> u64 {
+        self.offset + self.length
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskLayout {
+    pub gpt: bool,
+    // Where the last partition can end, before the backup GPT.
+    pub usable_end: u64,
+    pub partitions: Vec<LayoutPartition>,
+}
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    let mut val = [0; 8];
+    val.copy_from_slice(&buf[off..off + 8]);
+    u64::from_le_bytes(val)
+}
+
+// Parses what IOCTL_DISK_GET_DRIVE_LAYOUT_EX returns. MBR layouts don't say
+// where the disk ends, hence `disk_size`.
+pub fn parse_drive_layout(buf: &[u8], disk_size: u64) -> io::Result<DiskLayout> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg);
+    if buf.len() < LAYOUT_HEADER_SIZE {
+        return Err(invalid("The drive layout is truncated"));
+    }
+    let (gpt, usable_end) = match u32_at(buf, 0) {
+        PARTITION_STYLE_MBR => (false, disk_size),
+        PARTITION_STYLE_GPT => (true, u64_at(buf, 24) + u64_at(buf, 32)),
+        _ => return Err(invalid("The disk has no partition table")),
+    };
+    let count = u32_at(buf, 4) as usize;
+    if buf.len() < LAYOUT_HEADER_SIZE + count * PARTITION_ENTRY_SIZE {
+        return Err(invalid("The drive layout is truncated"));
+    }
+
+    let mut partitions = Vec::new();
+    for entry in buf[LAYOUT_HEADER_SIZE..].chunks(PARTITION_ENTRY_SIZE).take(count) {
+        let partition = LayoutPartition {
+            number: u32_at(entry, 24),
+            offset: u64_at(entry, 8),
+            length: u64_at(entry, 16),
+        };
+        // MBR layouts always come with 4 entries, and list extended
+        // partitions along with the logical ones they hold.
+        let extended = !gpt && [0x05, 0x0F, 0x85].contains(&entry[32]);
+        if partition.length != 0 && !extended {
+            partitions.push(partition);
+        }
+    }
+    partitions.sort_by_key(|v| v.offset);
+    Ok(DiskLayout { gpt, usable_end, partitions })
+}
+
+// Tallies the clusters FSCTL_GET_VOLUME_BITMAP reports in use, one bit per
+// cluster.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BitmapUsage {
+    pub used: u64,
+    pub last_used: Option<u64>,
+}
+
+impl BitmapUsage {
+    pub fn add(&mut self, start_lcn: u64, bitmap: &[u8], clusters: u64) {
+        let clusters = cmp::min(clusters, bitmap.len() as u64 * 8);
+        for (idx, &byte) in bitmap.iter().enumerate() {
+            let first = idx as u64 * 8;
+            if byte == 0 || first >= clusters {
+                continue;
+            }
+            let valid = cmp::min(8, clusters - first);
+            let byte = if valid < 8 { byte & ((1 << valid) - 1) } else { byte };
+            self.used += byte.count_ones() as u64;
+            if byte != 0 {
+                self.last_used = Some(start_lcn + first + 7 - byte.leading_zeros() as u64);
+            }
+        }
+    }
+}
+
+// What keeps Windows' volume from shrinking further, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShrinkLimits {
+    pub used: u64,
+    // The end of the last cluster in use.
+    pub last_used_end: u64,
+    // The smallest size Windows agrees to shrink to, after moving what can
+    // be moved.
+    pub supported_min: Option<u64>,
+}
+
+impl ShrinkLimits {
+    pub fn from_bitmap(usage: &BitmapUsage, cluster_size: u64, supported_min: Option<u64>) -> ShrinkLimits {
+        ShrinkLimits {
+            used: usage.used * cluster_size,
+            last_used_end: usage.last_used.map(|v| (v + 1) * cluster_size).unwrap_or(0),
+            supported_min,
+        }
+    }
+
+    // Without Windows' limit, nothing gets moved and the last cluster in use
+    // decides.
+    pub fn min_size(&self) -> u64 {
+        cmp::max(self.used + WINDOWS_FREE_SPACE, self.supported_min.unwrap_or(self.last_used_end))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ShrinkPlan {
+    pub partition_number: u32,
+    pub old_length: u64,
+    pub new_length: u64,
+    // The free space Ubuntu gets, which may already have been there.
+    pub free: u64,
+}
+
+impl ShrinkPlan {
+    pub fn needs_shrinking(&self) -> bool {
+        self.new_length < self.old_length
+    }
+
+    pub fn describe(&self) -> String {
+        if self.needs_shrinking() {
+            format!("Windows will shrink from {} to {}, leaving {} for Ubuntu.", format_size(self.old_length), format_size(self.new_length), format_size(self.free))
+        } else {
+            format!("Ubuntu will use the {} of free space on the disk.", format_size(self.free))
+        }
+    }
+}
+
+fn align_up(val: u64) -> u64 {
+    (val + ALIGNMENT - 1) / ALIGNMENT * ALIGNMENT
+}
+
+fn align_down(val: u64) -> u64 {
+    val / ALIGNMENT * ALIGNMENT
+}
+
+// The biggest free space between partitions, or after the last one.
+fn largest_gap(layout: &DiskLayout) -> u64 {
+    let mut largest = 0;
+    let mut prev_end = None;
+    for partition in &layout.partitions {
+        if let Some(end) = prev_end {
+            largest = cmp::max(largest, align_down(partition.offset).saturating_sub(align_up(end)));
+        }
+        prev_end = Some(cmp::max(prev_end.unwrap_or(0), partition.end()));
+    }
+    cmp::max(largest, align_down(layout.usable_end).saturating_sub(align_up(prev_end.unwrap_or(0))))
+}
+
+// Works out how far to shrink the partition, to give Ubuntu half of what
+// Windows can spare, and at least MIN_UBUNTU_SIZE. The free space is the
+// gap between the partition and the next one, often a recovery partition.
+// The installer goes in the biggest gap, so when there's already one big
+// enough, Windows stays as it is.
+pub fn plan_shrink(layout: &DiskLayout, partition_number: u32, limits: &ShrinkLimits) -> Result<ShrinkPlan, String> {
+    let partition = layout.partitions.iter().find(|v| v.number == partition_number)
+        .ok_or_else(|| format!("Partition {} isn't on the disk", partition_number))?;
+    if !layout.gpt && layout.partitions.len() >= 4 {
+        return Err("The disk already has 4 partitions, the most an MBR disk can have".to_string());
+    }
+    let largest = largest_gap(layout);
+    if largest >= MIN_UBUNTU_SIZE {
+        return Ok(ShrinkPlan {
+            partition_number,
+            old_length: partition.length,
+            new_length: partition.length,
+            free: largest,
+        });
+    }
+
+    let gap_end = layout.partitions.iter()
+        .map(|v| v.offset)
+        .filter(|&v| v >= partition.end())
+        .min()
+        .unwrap_or(layout.usable_end);
+    let gap_end = align_down(gap_end);
+
+    let min_end = align_up(partition.offset + limits.min_size());
+    let spare = gap_end.saturating_sub(min_end);
+    if spare < MIN_UBUNTU_SIZE {
+        return Err(format!("Windows can only spare {}, and Ubuntu needs {}", format_size(spare), format_size(MIN_UBUNTU_SIZE)));
+    }
+
+    let new_end = align_down(gap_end - cmp::max(MIN_UBUNTU_SIZE, spare / 2));
+    Ok(ShrinkPlan {
+        partition_number,
+        old_length: partition.length,
+        new_length: new_end - partition.offset,
+        free: gap_end - new_end,
+    })
+}
+
+#[cfg(windows)]
+pub use self::win::{shrink_system_drive, system_shrink_plan};
+
+#[cfg(windows)]
+mod win {
+    use super::{parse_drive_layout, plan_shrink, BitmapUsage, ShrinkLimits, ShrinkPlan, LAYOUT_HEADER_SIZE, PARTITION_ENTRY_SIZE};
+    use crate::win32::{ioctl, ioctl_out, open_device, to_wide};
+
+    use winapi::shared::winerror::ERROR_MORE_DATA;
+    use winapi::um::fileapi::GetDiskFreeSpaceW;
+    use winapi::um::winbase::CREATE_NO_WINDOW;
+    use winapi::um::winnt::GENERIC_READ;
+    use winapi::um::winioctl::{FSCTL_GET_VOLUME_BITMAP, GET_LENGTH_INFORMATION, IOCTL_DISK_GET_DRIVE_LAYOUT_EX, IOCTL_DISK_GET_LENGTH_INFO, IOCTL_STORAGE_GET_DEVICE_NUMBER, STORAGE_DEVICE_NUMBER};
+
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::process::CommandExt;
+    use std::process::Command;
+
+    fn other(msg: String) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, msg)
+    }
+
+    fn system_drive() -> String {
+        std::env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string())
+    }
+
+    // Runs a PowerShell script, which prints the error message when it fails.
+    fn powershell(script: &str) -> io::Result<String> {
+        let script = format!("$ErrorActionPreference = 'Stop'; try {{ {} }} catch {{ Write-Output $_.Exception.Message; exit 1 }}", script);
+        let output = Command::new("powershell.exe")
+            .args(&["-NoProfile", "-NonInteractive", "-Command", &script])
+            .creation_flags(CREATE_NO_WINDOW)
+            .output()?;
+        let text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if !output.status.success() {
+            return Err(other(if text.is_empty() { "PowerShell failed".to_string() } else { text }));
+        }
+        Ok(text)
+    }
+
+    fn cluster_size(drive: &str) -> io::Result<u64> {
+        let root = to_wide(&format!(r"{}\", drive));
+        let (mut sectors_per_cluster, mut bytes_per_sector, mut free, mut total) = (0, 0, 0, 0);
+        if unsafe { GetDiskFreeSpaceW(root.as_ptr(), &mut sectors_per_cluster, &mut bytes_per_sector, &mut free, &mut total) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(sectors_per_cluster as u64 * bytes_per_sector as u64)
+    }
+
+    // Goes through the volume bitmap a chunk at a time, each one starting
+    // with its first cluster number and the number of clusters left.
+    fn volume_usage(volume: &File) -> io::Result<BitmapUsage> {
+        let mut usage = BitmapUsage::default();
+        let mut buf = vec![0u8; 16 + (1 << 20)];
+        let mut start_lcn = 0u64;
+        loop {
+            let (len, more) = match ioctl(volume, FSCTL_GET_VOLUME_BITMAP, &start_lcn.to_le_bytes(), &mut buf) {
+                Ok(len) => (len, false),
+                Err(err) if err.raw_os_error() == Some(ERROR_MORE_DATA as i32) => (buf.len(), true),
+                Err(err) => return Err(err),
+            };
+            let chunk_start = u64::from_le_bytes([buf[0], buf[1], buf[2], buf[3], buf[4], buf[5], buf[6], buf[7]]);
+            let clusters = u64::from_le_bytes([buf[8], buf[9], buf[10], buf[11], buf[12], buf[13], buf[14], buf[15]]);
+            let bitmap = &buf[16..len];
+            usage.add(chunk_start, bitmap, clusters);
+            if !more {
+                return Ok(usage);
+            }
+            start_lcn = chunk_start + bitmap.len() as u64 * 8;
+        }
+    }
+
+    // The smallest size Windows' storage management agrees to, which is what
+    // VDS computes from the files it can't move.
+    fn supported_min(drive: &str) -> io::Result<u64> {
+        let text = powershell(&format!("(Get-PartitionSupportedSize -DriveLetter {}).SizeMin", &drive[..1]))?;
+        text.parse().map_err(|_| other(format!("Unexpected minimum size \"{}\"", text)))
+    }
+
+    // How to make room for Ubuntu on the disk Windows runs from.
+    pub fn system_shrink_plan() -> io::Result<ShrinkPlan> {
+        let drive = system_drive();
+        let volume = open_device(&format!(r"\\.\{}", drive), GENERIC_READ)?;
+        let number: STORAGE_DEVICE_NUMBER = ioctl_out(&volume, IOCTL_STORAGE_GET_DEVICE_NUMBER, &[])?;
+
+        let disk = open_device(&format!(r"\\.\PhysicalDrive{}", number.DeviceNumber), GENERIC_READ)?;
+        let length: GET_LENGTH_INFORMATION = ioctl_out(&disk, IOCTL_DISK_GET_LENGTH_INFO, &[])?;
+        let mut buf = vec![0u8; LAYOUT_HEADER_SIZE + 128 * PARTITION_ENTRY_SIZE];
+        let len = ioctl(&disk, IOCTL_DISK_GET_DRIVE_LAYOUT_EX, &[], &mut buf)?;
+        let layout = parse_drive_layout(&buf[..len], unsafe { *length.Length.QuadPart() } as u64)?;
+
+        let usage = volume_usage(&volume)?;
+        let supported_min = supported_min(&drive).map_err(|err| other(format!("Failed to query how far {} can shrink: {}", drive, err)))?;
+        let limits = ShrinkLimits::from_bitmap(&usage, cluster_size(&drive)?, Some(supported_min));
+        plan_shrink(&layout, number.PartitionNumber, &limits).map_err(other)
+    }
+
+    // Windows moves what's in the way, then shrinks the file system and the
+    // partition together. The plan gets worked out again first, as Windows
+    // kept writing to the drive since the last one.
+    pub fn shrink_system_drive() -> io::Result<ShrinkPlan> {
+        let plan = system_shrink_plan()?;
+        if plan.needs_shrinking() {
+            powershell(&format!("Resize-Partition -DriveLetter {} -Size {}", &system_drive()[..1], plan.new_length))?;
+        }
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1 << 30;
+
+    // Lays out what IOCTL_DISK_GET_DRIVE_LAYOUT_EX returns, with entries as
+    // (number, offset, length, MBR type).
+    fn layout_buf(gpt: bool, usable: (u64, u64), entries: &[(u32, u64, u64, u8)]) -> Vec<u8> {
+        let mut buf = vec![0u8; LAYOUT_HEADER_SIZE + entries.len() * PARTITION_ENTRY_SIZE];
+        buf[0..4].copy_from_slice(&(if gpt { PARTITION_STYLE_GPT } else { PARTITION_STYLE_MBR }).to_le_bytes());
+        buf[4..8].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+        if gpt {
+            buf[24..32].copy_from_slice(&usable.0.to_le_bytes());
+            buf[32..40].copy_from_slice(&usable.1.to_le_bytes());
+        }
+        for (idx, &(number, offset, length, kind)) in entries.iter().enumerate() {
+            let entry = &mut buf[LAYOUT_HEADER_SIZE + idx * PARTITION_ENTRY_SIZE..];
+            entry[8..16].copy_from_slice(&offset.to_le_bytes());
+            entry[16..24].copy_from_slice(&length.to_le_bytes());
+            entry[24..28].copy_from_slice(&number.to_le_bytes());
+            entry[32] = kind;
+        }
+        buf
+    }
+
+    // A typical Windows install: ESP, MSR, Windows, then recovery at the end.
+    fn windows_layout(disk_size: u64) -> DiskLayout {
+        let recovery = disk_size - GIB;
+        let buf = layout_buf(true, (17408, disk_size - 17408 - 16896), &[
+            (1, 1 << 20, 100 << 20, 0),
+            (2, 101 << 20, 16 << 20, 0),
+            (4, recovery, 500 << 20, 0),
+            (3, 117 << 20, recovery - (117 << 20), 0),
+        ]);
+        parse_drive_layout(&buf, disk_size).unwrap()
+    }
+
+    fn limits(used: u64) -> ShrinkLimits {
+        ShrinkLimits { used, last_used_end: used, supported_min: Some(used) }
+    }
+
+    #[test]
+    fn parses_gpt_layouts() {
+        let layout = windows_layout(256 * GIB);
+        assert!(layout.gpt);
+        assert_eq!(layout.usable_end, 256 * GIB - 16896);
+        let numbers: Vec<_> = layout.partitions.iter().map(|v| v.number).collect();
+        assert_eq!(numbers, vec![1, 2, 3, 4]);
+        assert_eq!(layout.partitions[2], LayoutPartition { number: 3, offset: 117 << 20, length: 255 * GIB - (117 << 20) });
+    }
+
+    #[test]
+    fn parses_mbr_layouts() {
+        let buf = layout_buf(false, (0, 0), &[
+            (1, 1 << 20, 50 << 20, 0x07),
+            (2, 51 << 20, 20 * GIB, 0x07),
+            (0, 0, 0, 0),
+            (0, 21 * GIB, 10 * GIB, 0x0F),
+            (5, 21 * GIB + (1 << 20), 5 * GIB, 0x83),
+        ]);
+        let layout = parse_drive_layout(&buf, 64 * GIB).unwrap();
+        assert!(!layout.gpt);
+        assert_eq!(layout.usable_end, 64 * GIB);
+        let numbers: Vec<_> = layout.partitions.iter().map(|v| v.number).collect();
+        assert_eq!(numbers, vec![1, 2, 5]);
+    }
+
+    #[test]
+    fn rejects_bad_layouts() {
+        assert!(parse_drive_layout(&[0; 20], GIB).is_err());
+        let mut buf = layout_buf(true, (0, GIB), &[(1, 1 << 20, 1 << 20, 0)]);
+        buf.truncate(LAYOUT_HEADER_SIZE + 10);
+        assert!(parse_drive_layout(&buf, GIB).is_err());
+        let mut buf = layout_buf(true, (0, GIB), &[]);
+        buf[0] = 2;
+        assert!(parse_drive_layout(&buf, GIB).is_err());
+    }
+
+    #[test]
+    fn shrinks_windows_in_half() {
+        let layout = windows_layout(256 * GIB);
+        let plan = plan_shrink(&layout, 3, &limits(60 * GIB)).unwrap();
+        assert!(plan.needs_shrinking());
+        let windows = layout.partitions[2];
+        let new_end = windows.offset + plan.new_length;
+        assert_eq!(new_end % ALIGNMENT, 0);
+        assert_eq!(new_end + plan.free, layout.partitions[3].offset);
+        assert!(plan.new_length >= 80 * GIB);
+        let spare = layout.partitions[3].offset - align_up(windows.offset + 80 * GIB);
+        assert!(plan.free >= spare / 2 - ALIGNMENT && plan.free <= spare / 2 + ALIGNMENT);
+    }
+
+    #[test]
+    fn gives_ubuntu_at_least_its_minimum() {
+        let layout = windows_layout(128 * GIB);
+        let plan = plan_shrink(&layout, 3, &limits(70 * GIB)).unwrap();
+        assert!(plan.free >= MIN_UBUNTU_SIZE);
+        assert!(plan.new_length >= 90 * GIB);
+    }
+
+    #[test]
+    fn refuses_when_windows_cant_spare_enough() {
+        let layout = windows_layout(128 * GIB);
+        assert!(plan_shrink(&layout, 3, &limits(90 * GIB)).is_err());
+        // Windows won't move its files out of the way.
+        let stuck = ShrinkLimits { used: 20 * GIB, last_used_end: 120 * GIB, supported_min: None };
+        assert!(plan_shrink(&layout, 3, &stuck).is_err());
+        assert!(plan_shrink(&layout, 7, &limits(20 * GIB)).is_err());
+    }
+
+    #[test]
+    fn uses_free_space_already_there() {
+        let disk_size = 256 * GIB;
+        let buf = layout_buf(true, (17408, disk_size - 17408 - 16896), &[
+            (1, 1 << 20, 100 << 20, 0),
+            (3, 117 << 20, 100 * GIB, 0),
+        ]);
+        let layout = parse_drive_layout(&buf, disk_size).unwrap();
+        let plan = plan_shrink(&layout, 3, &limits(60 * GIB)).unwrap();
+        assert!(!plan.needs_shrinking());
+        assert!(plan.free > 150 * GIB);
+    }
+
+    #[test]
+    fn refuses_full_mbr_disks() {
+        let buf = layout_buf(false, (0, 0), &[
+            (1, 1 << 20, GIB, 0x07),
+            (2, 2 * GIB, 100 * GIB, 0x07),
+            (3, 110 * GIB, GIB, 0x27),
+            (4, 120 * GIB, GIB, 0x0C),
+        ]);
+        let layout = parse_drive_layout(&buf, 128 * GIB).unwrap();
+        assert!(plan_shrink(&layout, 2, &limits(20 * GIB)).is_err());
+    }
+
+    #[test]
+    fn counts_used_clusters() {
+        let mut usage = BitmapUsage::default();
+        usage.add(0, &[0b0000_0101, 0, 0b1000_0000], 24);
+        assert_eq!(usage, BitmapUsage { used: 3, last_used: Some(23) });
+        // Bits past the end of the volume don't count.
+        let mut usage = BitmapUsage::default();
+        usage.add(100, &[0xFF], 3);
+        assert_eq!(usage, BitmapUsage { used: 3, last_used: Some(102) });
+    }
+}
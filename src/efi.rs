@@ -0,0 +1,400 @@
+use crate::partition::{Gpt, Guid, Mbr, EFI_SYSTEM_PARTITION, MBR_EFI_PARTITION};
+
+use std::io::{self, Read, Seek};
+
+// UEFI load options, as stored in the Boot#### variables, and the device
+// paths they boot.
+
+// Where firmwares look for a bootloader on removable drives.
+pub const REMOVABLE_MEDIA_PATH: &str = r"\EFI\BOOT\BOOTX64.EFI";
+// The vendor GUID of Boot####, BootOrder and BootNext.
+pub const EFI_GLOBAL_VARIABLE: &str = "{8BE4DF61-93CA-11D2-AA0D-00E098032B8C}";
+
+pub const LOAD_OPTION_ACTIVE: u32 = 0x1;
+// What our options are called in the firmware's boot menu.
+pub const DESCRIPTION: &str = "Ubuntu USB flash drive";
+
+const MEDIA_DEVICE_PATH: u8 = 0x04;
+const MEDIA_HARDDRIVE_DP: u8 = 0x01;
+const MEDIA_FILEPATH_DP: u8 = 0x04;
+const END_DEVICE_PATH_TYPE: u8 = 0x7F;
+const END_ENTIRE_DEVICE_PATH_SUBTYPE: u8 = 0xFF;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiskSignature {
+    Mbr(u32),
+    Gpt(Guid),
+}
+
+// A partition, the way firmwares find it again: by the signature of its disk
+// (or its own GUID on GPT disks), its number and where it lies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HardDrive {
+    // Starting at 1.
+    pub partition_number: u32,
+    pub start_lba: u64,
+    pub sectors: u64,
+    pub signature: DiskSignature,
+}
+
+fn push_node(path: &mut Vec<u8>, kind: u8, subtype: u8, data: &[u8]) {
+    path.push(kind);
+    path.push(subtype);
+    path.extend_from_slice(&(4 + data.len() as u16).to_le_bytes());
+    path.extend_from_slice(data);
+}
+
+fn utf16z(s: &str) -> Vec<u8> {
+    s.encode_utf16().chain(Some(0)).flat_map(|v| v.to_le_bytes().to_vec()).collect()
+}
+
+// A device path to a file on a partition, e.g.
+// HD(1,GPT,<guid>,0x800,0x82000)/\EFI\BOOT\BOOTX64.EFI.
+pub fn file_device_path(drive: &HardDrive, file: &str) -> Vec<u8> {
+    let mut data = Vec::with_capacity(38);
+    data.extend_from_slice(&drive.partition_number.to_le_bytes());
+    data.extend_from_slice(&drive.start_lba.to_le_bytes());
+    data.extend_from_slice(&drive.sectors.to_le_bytes());
+    let (signature, format, kind) = match drive.signature {
+        DiskSignature::Mbr(signature) => {
+            let mut bytes = [0; 16];
+            bytes[..4].copy_from_slice(&signature.to_le_bytes());
+            (bytes, 1, 1)
+        }
+        DiskSignature::Gpt(guid) => (guid.0, 2, 2),
+    };
+    data.extend_from_slice(&signature);
+    data.push(format);
+    data.push(kind);
+
+    let mut path = Vec::new();
+    push_node(&mut path, MEDIA_DEVICE_PATH, MEDIA_HARDDRIVE_DP, &data);
+    push_node(&mut path, MEDIA_DEVICE_PATH, MEDIA_FILEPATH_DP, &utf16z(file));
+    push_node(&mut path, END_DEVICE_PATH_TYPE, END_ENTIRE_DEVICE_PATH_SUBTYPE, &[]);
+    path
+}
+
+// The contents of a Boot#### variable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LoadOption {
+    pub attributes: u32,
+    // Shown in the firmware's boot menu.
+    pub description: String,
+    pub file_path_list: Vec<u8>,
+    pub optional_data: Vec<u8>,
+}
+
+impl LoadOption {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&self.attributes.to_le_bytes());
+        data.extend_from_slice(&(self.file_path_list.len() as u16).to_le_bytes());
+        data.extend_from_slice(&utf16z(&self.description));
+        data.extend_from_slice(&self.file_path_list);
+        data.extend_from_slice(&self.optional_data);
+        data
+    }
+
+    pub fn parse(data: &[u8]) -> io::Result<LoadOption> {
+        if data.len() < 6 {
+            return Err(invalid_data("The load option is truncated"));
+        }
+        let attributes = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+        let path_len = u16::from_le_bytes([data[4], data[5]]) as usize;
+
+        let mut description = Vec::new();
+        let mut pos = 6;
+        loop {
+            if pos + 2 > data.len() {
+                return Err(invalid_data("The load option's description isn't terminated"));
+            }
+            let c = u16::from_le_bytes([data[pos], data[pos + 1]]);
+            pos += 2;
+            if c == 0 {
+                break;
+            }
+            description.push(c);
+        }
+        if pos + path_len > data.len() {
+            return Err(invalid_data("The load option's device path is truncated"));
+        }
+        Ok(LoadOption {
+            attributes,
+            description: String::from_utf16_lossy(&description),
+            file_path_list: data[pos..pos + path_len].to_vec(),
+            optional_data: data[pos + path_len..].to_vec(),
+        })
+    }
+}
+
+pub fn boot_variable_name(number: u16) -> String {
+    format!("Boot{:04X}", number)
+}
+
+// BootOrder is an array of option numbers.
+pub fn parse_boot_order(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2).map(|v| u16::from_le_bytes([v[0], v[1]])).collect()
+}
+
+// A late-command removing our options from the firmware once Ubuntu is
+// installed, as the drive has done its job. efibootmgr lists them like
+// "Boot0004* Ubuntu USB flash drive", followed by their device path on newer
+// versions. BIOS installs have no options to remove.
+pub fn remove_options_command() -> String {
+    format!("for n in $(efibootmgr 2>/dev/null | sed -n 's/^Boot\\([0-9A-Fa-f]\\{{4\\}}\\)[* ] {}\\(\\t.*\\)\\{{0,1\\}}$/\\1/p'); do efibootmgr -q -b \"$n\" -B; done || true", DESCRIPTION)
+}
+
+// Finds the EFI system partition of a drive. Like firmwares, only trusts the
+// GPT when the MBR protects it: isohybrid images come with both, and the
+// MBR is what counts when it isn't a protective one.
+pub fn find_esp<D: Read + Seek>(dev: &mut D, sector_size: u64) -> io::Result<Option<HardDrive>> {
+    let mbr = match Mbr::read(dev, sector_size)? {
+        Some(mbr) => mbr,
+        None => return Ok(None),
+    };
+    if mbr.protects_gpt() {
+        let gpt = match Gpt::read(dev, sector_size)? {
+            Some(gpt) => gpt,
+            None => return Ok(None),
+        };
+        return Ok(gpt.partitions().find(|(_, v)| v.kind == EFI_SYSTEM_PARTITION).map(|(number, v)| HardDrive {
+            partition_number: number,
+            start_lba: v.first_lba,
+            sectors: v.last_lba + 1 - v.first_lba,
+            signature: DiskSignature::Gpt(v.guid),
+        }));
+    }
+    Ok(mbr.partitions.iter().enumerate().find(|(_, v)| v.kind == MBR_EFI_PARTITION).map(|(idx, v)| HardDrive {
+        partition_number: idx as u32 + 1,
+        start_lba: v.start_lba as u64,
+        sectors: v.sectors as u64,
+        signature: DiskSignature::Mbr(mbr.disk_signature()),
+    }))
+}
+
+#[cfg(windows)]
+pub use self::win::{boot_next, can_boot_next, cancel_boot_next, remove_stale_options, restart};
+
+#[cfg(windows)]
+mod win {
+    use super::{boot_variable_name, file_device_path, parse_boot_order, HardDrive, LoadOption, DESCRIPTION, EFI_GLOBAL_VARIABLE, LOAD_OPTION_ACTIVE, REMOVABLE_MEDIA_PATH};
+    use crate::win32::{enable_privilege, to_wide};
+
+    use winapi::shared::winerror::ERROR_ENVVAR_NOT_FOUND;
+    use winapi::um::reason::{SHTDN_REASON_FLAG_PLANNED, SHTDN_REASON_MAJOR_OPERATINGSYSTEM, SHTDN_REASON_MINOR_RECONFIG};
+    use winapi::um::winbase::{GetFirmwareEnvironmentVariableW, SetFirmwareEnvironmentVariableW};
+    use winapi::um::winnt::{SE_SHUTDOWN_NAME, SE_SYSTEM_ENVIRONMENT_NAME};
+    use winapi::um::winuser::{ExitWindowsEx, EWX_REBOOT};
+
+    use std::io;
+
+    // None if the variable doesn't exist.
+    fn get_variable(name: &str) -> io::Result<Option<Vec<u8>>> {
+        let name_w = to_wide(name);
+        let guid_w = to_wide(EFI_GLOBAL_VARIABLE);
+        let mut buf = vec![0u8; 4096];
+        let len = unsafe { GetFirmwareEnvironmentVariableW(name_w.as_ptr(), guid_w.as_ptr(), buf.as_mut_ptr() as *mut _, buf.len() as u32) };
+        if len == 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() == Some(ERROR_ENVVAR_NOT_FOUND as i32) {
+                return Ok(None);
+            }
+            return Err(err);
+        }
+        buf.truncate(len as usize);
+        Ok(Some(buf))
+    }
+
+    // No data deletes the variable.
+    fn set_variable(name: &str, data: &[u8]) -> io::Result<()> {
+        let name_w = to_wide(name);
+        let guid_w = to_wide(EFI_GLOBAL_VARIABLE);
+        if unsafe { SetFirmwareEnvironmentVariableW(name_w.as_ptr(), guid_w.as_ptr(), data.as_ptr() as *mut _, data.len() as u32) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    // Whether the firmware variables are there, and we're allowed to touch
+    // them.
+    pub fn can_boot_next() -> bool {
+        enable_privilege(SE_SYSTEM_ENVIRONMENT_NAME).is_ok() && get_variable("BootOrder").is_ok()
+    }
+
+    // Our options from previous runs, and the first free number.
+    fn scan_options() -> io::Result<(Vec<u16>, Option<u16>)> {
+        let order = get_variable("BootOrder")?.map(|v| parse_boot_order(&v)).unwrap_or_default();
+        let mut ours = Vec::new();
+        let mut free = None;
+        for number in 0..=0xFFFF {
+            match get_variable(&boot_variable_name(number))? {
+                Some(data) => {
+                    let matches = LoadOption::parse(&data).map(|v| v.description == DESCRIPTION).unwrap_or(false);
+                    if matches && !order.contains(&number) {
+                        ours.push(number);
+                    }
+                }
+                None if free.is_none() => free = Some(number),
+                None => (),
+            }
+            // Firmwares number options from 0 without gaps, mostly. Past the
+            // last one in BootOrder, there's no need to look further.
+            if let Some(free) = free {
+                if order.iter().all(|&v| v < free) {
+                    break;
+                }
+            }
+        }
+        Ok((ours, free))
+    }
+
+    // Our option from a previous run, or else the first free number.
+    fn option_number() -> io::Result<u16> {
+        let (ours, free) = scan_options()?;
+        ours.first().copied().or(free).ok_or_else(|| io::Error::new(io::ErrorKind::Other, "There's no room left for another boot option."))
+    }
+
+    // Removes the options previous runs left behind, except for one BootNext
+    // still points to, which hasn't been booted yet. Installs remove theirs
+    // themselves, see remove_options_command.
+    pub fn remove_stale_options() -> io::Result<()> {
+        enable_privilege(SE_SYSTEM_ENVIRONMENT_NAME)?;
+        let next = get_variable("BootNext")?.map(|v| parse_boot_order(&v)).unwrap_or_default();
+        for number in scan_options()?.0 {
+            if !next.contains(&number) {
+                set_variable(&boot_variable_name(number), &[])?;
+            }
+        }
+        Ok(())
+    }
+
+    // Makes the firmware boot the drive's ESP on the next boot only. The
+    // option stays out of BootOrder, so later boots go back to Windows.
+    pub fn boot_next(esp: &HardDrive) -> io::Result<()> {
+        enable_privilege(SE_SYSTEM_ENVIRONMENT_NAME)?;
+        let number = option_number()?;
+        let option = LoadOption {
+            attributes: LOAD_OPTION_ACTIVE,
+            description: DESCRIPTION.to_string(),
+            file_path_list: file_device_path(esp, REMOVABLE_MEDIA_PATH),
+            optional_data: Vec::new(),
+        };
+        set_variable(&boot_variable_name(number), &option.serialize())?;
+        set_variable("BootNext", &number.to_le_bytes())
+    }
+
+    // For when the restart doesn't happen after all.
+    pub fn cancel_boot_next() -> io::Result<()> {
+        set_variable("BootNext", &[])
+    }
+
+    pub fn restart() -> io::Result<()> {
+        enable_privilege(SE_SHUTDOWN_NAME)?;
+        if unsafe { ExitWindowsEx(EWX_REBOOT, SHTDN_REASON_MAJOR_OPERATINGSYSTEM | SHTDN_REASON_MINOR_RECONFIG | SHTDN_REASON_FLAG_PLANNED) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpt_esp() -> HardDrive {
+        HardDrive {
+            partition_number: 2,
+            start_lba: 0x800,
+            sectors: 0x2000,
+            signature: DiskSignature::Gpt(Guid([0x11; 16])),
+        }
+    }
+
+    #[test]
+    fn builds_file_device_paths() {
+        let path = file_device_path(&gpt_esp(), r"\A.EFI");
+        let mut expected = vec![0x04, 0x01, 42, 0];
+        expected.extend_from_slice(&2u32.to_le_bytes());
+        expected.extend_from_slice(&0x800u64.to_le_bytes());
+        expected.extend_from_slice(&0x2000u64.to_le_bytes());
+        expected.extend_from_slice(&[0x11; 16]);
+        expected.extend_from_slice(&[2, 2]);
+        expected.extend_from_slice(&[0x04, 0x04, 18, 0]);
+        expected.extend_from_slice(&[b'\\', 0, b'A', 0, b'.', 0, b'E', 0, b'F', 0, b'I', 0, 0, 0]);
+        expected.extend_from_slice(&[0x7F, 0xFF, 4, 0]);
+        assert_eq!(path, expected);
+    }
+
+    #[test]
+    fn mbr_signatures() {
+        let esp = HardDrive { signature: DiskSignature::Mbr(0x1234_5678), ..gpt_esp() };
+        let path = file_device_path(&esp, REMOVABLE_MEDIA_PATH);
+        assert_eq!(&path[24..40], &[0x78, 0x56, 0x34, 0x12, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(&path[40..42], &[1, 1]);
+        // Each node says how long it is, up to the end node.
+        let mut pos = 0;
+        while path[pos] != END_DEVICE_PATH_TYPE {
+            pos += u16::from_le_bytes([path[pos + 2], path[pos + 3]]) as usize;
+        }
+        assert_eq!(pos + 4, path.len());
+    }
+
+    #[test]
+    fn load_options_round_trip() {
+        let option = LoadOption {
+            attributes: LOAD_OPTION_ACTIVE,
+            description: DESCRIPTION.to_string(),
+            file_path_list: file_device_path(&gpt_esp(), REMOVABLE_MEDIA_PATH),
+            optional_data: vec![1, 2, 3],
+        };
+        let data = option.serialize();
+        assert_eq!(&data[..4], &[1, 0, 0, 0]);
+        assert_eq!(u16::from_le_bytes([data[4], data[5]]) as usize, option.file_path_list.len());
+        assert_eq!(data.len(), 6 + (DESCRIPTION.len() + 1) * 2 + option.file_path_list.len() + 3);
+        assert_eq!(LoadOption::parse(&data).unwrap(), option);
+    }
+
+    #[test]
+    fn rejects_broken_load_options() {
+        assert!(LoadOption::parse(&[1, 0, 0]).is_err());
+        // No terminating NUL.
+        assert!(LoadOption::parse(&[1, 0, 0, 0, 4, 0, b'A', 0]).is_err());
+        // A device path longer than the variable.
+        assert!(LoadOption::parse(&[1, 0, 0, 0, 4, 0, b'A', 0, 0, 0, 0x7F, 0xFF]).is_err());
+        let option = LoadOption::parse(&[0, 0, 0, 0, 4, 0, 0, 0, 0x7F, 0xFF, 4, 0]).unwrap();
+        assert_eq!(option.description, "");
+        assert_eq!(option.file_path_list, vec![0x7F, 0xFF, 4, 0]);
+        assert!(option.optional_data.is_empty());
+    }
+
+    #[test]
+    fn boot_variables() {
+        assert_eq!(boot_variable_name(0x1A), "Boot001A");
+        assert_eq!(parse_boot_order(&[1, 0, 0x0A, 0, 3]), vec![1, 10]);
+    }
+
+    // Runs the late-command against a fake efibootmgr.
+    #[cfg(unix)]
+    #[test]
+    fn removes_options_from_the_installed_system() {
+        use std::os::unix::fs::PermissionsExt;
+        use std::process::Command;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let log = dir.join("log");
+        let script = format!("#!/bin/sh\nif [ $# -eq 0 ]; then\nprintf 'BootCurrent: 0004\\nBootOrder: 0001,0000\\nBoot0000* Windows Boot Manager\\tHD(1,GPT)\\nBoot0004* {0}\\tHD(2,MBR)\\nBoot000A  {0}\\nBoot000B* {0} too\\n'\nelse\necho \"$@\" >> {1}\nfi\n", DESCRIPTION, log.display());
+        let fake = dir.join("efibootmgr");
+        std::fs::write(&fake, script).unwrap();
+        std::fs::set_permissions(&fake, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let path = format!("{}:{}", dir.display(), std::env::var("PATH").unwrap_or_default());
+        let status = Command::new("sh").arg("-c").arg(remove_options_command()).env("PATH", path).status().unwrap();
+        assert!(status.success());
+        assert_eq!(std::fs::read_to_string(&log).unwrap(), "-q -b 0004 -B\n-q -b 000A -B\n");
+    }
+}
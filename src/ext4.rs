@@ -0,0 +1,360 @@
+use crate::writer::{random_bytes, round_up};
+
+use std::io::{self, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A minimal mkfs.ext4: no journal, no flex_bg, no checksums. Just enough for
+// casper to mount it as its persistence overlay, and for the kernel to take
+// it from there.
+
+const BLOCK_SIZE: u64 = 4096;
+const BLOCKS_PER_GROUP: u64 = BLOCK_SIZE * 8;
+const INODE_SIZE: u64 = 256;
+// One inode per 64KiB of space. Inode tables have to be zeroed, and writing
+// them out is what takes the most time on a slow flash drive.
+const INODE_RATIO: u64 = 65536;
+const DESC_SIZE: u64 = 32;
+
+const ROOT_INO: u32 = 2;
+const LOST_FOUND_INO: u32 = 11;
+const FIRST_INO: u32 = 11;
+
+const COMPAT_EXT_ATTR: u32 = 0x8;
+const COMPAT_DIR_INDEX: u32 = 0x20;
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x1;
+const RO_COMPAT_LARGE_FILE: u32 = 0x2;
+const RO_COMPAT_HUGE_FILE: u32 = 0x8;
+const RO_COMPAT_DIR_NLINK: u32 = 0x20;
+const RO_COMPAT_EXTRA_ISIZE: u32 = 0x40;
+
+const EXTENTS_FL: u32 = 0x80000;
+const FT_DIR: u8 = 2;
+
+struct Layout {
+    blocks: u64,
+    groups: u64,
+    inodes_per_group: u64,
+    itable_blocks: u64,
+    gdt_blocks: u64,
+}
+
+impl Layout {
+    fn new(size: u64) -> io::Result<Layout> {
+        let mut blocks = size / BLOCK_SIZE;
+        let inodes_per_group = round_up(std::cmp::min(blocks, BLOCKS_PER_GROUP) * BLOCK_SIZE / INODE_RATIO, BLOCK_SIZE / INODE_SIZE);
+        let itable_blocks = inodes_per_group * INODE_SIZE / BLOCK_SIZE;
+        loop {
+            let groups = round_up(blocks, BLOCKS_PER_GROUP) / BLOCKS_PER_GROUP;
+            let layout = Layout {
+                blocks,
+                groups,
+                inodes_per_group,
+                itable_blocks,
+                gdt_blocks: round_up(groups * DESC_SIZE, BLOCK_SIZE) / BLOCK_SIZE,
+            };
+            // Like mke2fs, drop a last group too small to be useful.
+            let last = groups - 1;
+            if layout.group_blocks(last) < layout.overhead(last) + 50 {
+                if groups == 1 {
+                    return Err(io::Error::new(io::ErrorKind::Other, "The partition is too small for an ext4 filesystem"));
+                }
+                blocks = last * BLOCKS_PER_GROUP;
+                continue;
+            }
+            return Ok(layout);
+        }
+    }
+
+    fn group_blocks(&self, group: u64) -> u64 {
+        std::cmp::min(BLOCKS_PER_GROUP, self.blocks - group * BLOCKS_PER_GROUP)
+    }
+
+    fn super_blocks(&self, group: u64) -> u64 {
+        if has_super(group) { 1 + self.gdt_blocks } else { 0 }
+    }
+
+    fn overhead(&self, group: u64) -> u64 {
+        self.super_blocks(group) + 2 + self.itable_blocks
+    }
+
+    fn block_bitmap(&self, group: u64) -> u64 {
+        group * BLOCKS_PER_GROUP + self.super_blocks(group)
+    }
+
+    fn inode_bitmap(&self, group: u64) -> u64 {
+        self.block_bitmap(group) + 1
+    }
+
+    fn inode_table(&self, group: u64) -> u64 {
+        self.block_bitmap(group) + 2
+    }
+
+    fn first_data_block(&self, group: u64) -> u64 {
+        self.inode_table(group) + self.itable_blocks
+    }
+}
+
+// With sparse_super, only groups 0, 1 and powers of 3, 5 and 7 hold a backup
+// of the superblock.
+fn has_super(group: u64) -> bool {
+    if group <= 1 {
+        return true;
+    }
+    [3, 5, 7].iter().any(|base| {
+        let mut n = *base;
+        while n < group {
+            n *= base;
+        }
+        n == group
+    })
+}
+
+fn put_u16(buf: &mut [u8], off: usize, val: u16) {
+    buf[off..off + 2].copy_from_slice(&val.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], off: usize, val: u32) {
+    buf[off..off + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+// Formats the `size` bytes starting at `offset` in `dev`. Only whole 4KiB
+// blocks get written, so `offset` must be aligned on the device's sectors.
+pub fn format<D: Write + Seek>(dev: &mut D, offset: u64, size: u64, label: &str) -> io::Result<()> {
+    let layout = Layout::new(size)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0) as u32;
+
+    let root_block = layout.first_data_block(0);
+    let lost_found_block = root_block + 1;
+
+    let mut write_block = |block: u64, data: &[u8]| -> io::Result<()> {
+        dev.seek(SeekFrom::Start(offset + block * BLOCK_SIZE))?;
+        dev.write_all(data)
+    };
+
+    // Group descriptors and bitmaps.
+    let mut gdt = vec![0; (layout.gdt_blocks * BLOCK_SIZE) as usize];
+    let mut free_blocks = 0;
+    let mut free_inodes = 0;
+    for group in 0..layout.groups {
+        let group_blocks = layout.group_blocks(group);
+        let mut used_blocks = layout.overhead(group);
+        let mut used_inodes = 0;
+        let mut used_dirs = 0;
+        if group == 0 {
+            used_blocks += 2;
+            used_inodes = FIRST_INO as u64;
+            used_dirs = 2;
+        }
+
+        let mut block_bitmap = vec![0; BLOCK_SIZE as usize];
+        for bit in (0..used_blocks).chain(group_blocks..BLOCKS_PER_GROUP) {
+            block_bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+        let mut inode_bitmap = vec![0; BLOCK_SIZE as usize];
+        for bit in (0..used_inodes).chain(layout.inodes_per_group..BLOCKS_PER_GROUP) {
+            inode_bitmap[(bit / 8) as usize] |= 1 << (bit % 8);
+        }
+        write_block(layout.block_bitmap(group), &block_bitmap)?;
+        write_block(layout.inode_bitmap(group), &inode_bitmap)?;
+
+        let desc = &mut gdt[(group * DESC_SIZE) as usize..((group + 1) * DESC_SIZE) as usize];
+        put_u32(desc, 0, layout.block_bitmap(group) as u32);
+        put_u32(desc, 4, layout.inode_bitmap(group) as u32);
+        put_u32(desc, 8, layout.inode_table(group) as u32);
+        put_u16(desc, 12, (group_blocks - used_blocks) as u16);
+        put_u16(desc, 14, (layout.inodes_per_group - used_inodes) as u16);
+        put_u16(desc, 16, used_dirs);
+
+        free_blocks += group_blocks - used_blocks;
+        free_inodes += layout.inodes_per_group - used_inodes;
+    }
+
+    // Inode tables. Everything is zero, except for the root and lost+found
+    // directories.
+    let zeroes = vec![0; (layout.itable_blocks * BLOCK_SIZE) as usize];
+    for group in 0..layout.groups {
+        write_block(layout.inode_table(group), &zeroes)?;
+    }
+    let mut inodes = vec![0; BLOCK_SIZE as usize];
+    for &(ino, mode, links, block) in &[(ROOT_INO, 0o40755, 3, root_block), (LOST_FOUND_INO, 0o40700, 2, lost_found_block)] {
+        let inode = &mut inodes[((ino - 1) as u64 * INODE_SIZE) as usize..(ino as u64 * INODE_SIZE) as usize];
+        put_u16(inode, 0, mode);
+        put_u32(inode, 4, BLOCK_SIZE as u32);
+        put_u32(inode, 8, now);
+        put_u32(inode, 12, now);
+        put_u32(inode, 16, now);
+        put_u16(inode, 26, links);
+        put_u32(inode, 28, (BLOCK_SIZE / 512) as u32);
+        put_u32(inode, 32, EXTENTS_FL);
+        // Extent header, followed by a single extent.
+        put_u16(inode, 40, 0xF30A);
+        put_u16(inode, 42, 1);
+        put_u16(inode, 44, 4);
+        put_u32(inode, 52, 0);
+        put_u16(inode, 56, 1);
+        put_u32(inode, 60, block as u32);
+        put_u16(inode, 128, 32);
+        put_u32(inode, 144, now);
+    }
+    // Inodes 1 to 16 all fit in the first block of the table.
+    write_block(layout.inode_table(0), &inodes)?;
+
+    let mut root = vec![0; BLOCK_SIZE as usize];
+    let mut pos = 0;
+    for &(ino, name) in &[(ROOT_INO, "."), (ROOT_INO, ".."), (LOST_FOUND_INO, "lost+found")] {
+        let rec_len = if name == "lost+found" { BLOCK_SIZE as usize - pos } else { 12 };
+        write_dirent(&mut root[pos..], ino, rec_len, name);
+        pos += rec_len;
+    }
+    write_block(root_block, &root)?;
+
+    let mut lost_found = vec![0; BLOCK_SIZE as usize];
+    write_dirent(&mut lost_found[..], LOST_FOUND_INO, 12, ".");
+    write_dirent(&mut lost_found[12..], ROOT_INO, BLOCK_SIZE as usize - 12, "..");
+    write_block(lost_found_block, &lost_found)?;
+
+    // Superblock, and its backups.
+    let mut sb = vec![0; 1024];
+    put_u32(&mut sb, 0, (layout.groups * layout.inodes_per_group) as u32);
+    put_u32(&mut sb, 4, layout.blocks as u32);
+    put_u32(&mut sb, 12, free_blocks as u32);
+    put_u32(&mut sb, 16, free_inodes as u32);
+    put_u32(&mut sb, 20, 0);
+    put_u32(&mut sb, 24, 2);
+    put_u32(&mut sb, 28, 2);
+    put_u32(&mut sb, 32, BLOCKS_PER_GROUP as u32);
+    put_u32(&mut sb, 36, BLOCKS_PER_GROUP as u32);
+    put_u32(&mut sb, 40, layout.inodes_per_group as u32);
+    put_u32(&mut sb, 48, now);
+    put_u16(&mut sb, 54, 0xFFFF);
+    put_u16(&mut sb, 56, 0xEF53);
+    put_u16(&mut sb, 58, 1);
+    put_u16(&mut sb, 60, 1);
+    put_u32(&mut sb, 64, now);
+    put_u32(&mut sb, 76, 1);
+    put_u32(&mut sb, 84, FIRST_INO);
+    put_u16(&mut sb, 88, INODE_SIZE as u16);
+    put_u32(&mut sb, 92, COMPAT_EXT_ATTR | COMPAT_DIR_INDEX);
+    put_u32(&mut sb, 96, INCOMPAT_FILETYPE | INCOMPAT_EXTENTS);
+    put_u32(&mut sb, 100, RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_HUGE_FILE | RO_COMPAT_DIR_NLINK | RO_COMPAT_EXTRA_ISIZE);
+    random_bytes(&mut sb[104..120]);
+    let label = label.as_bytes();
+    sb[120..120 + std::cmp::min(label.len(), 16)].copy_from_slice(&label[..std::cmp::min(label.len(), 16)]);
+    random_bytes(&mut sb[236..252]);
+    // Half MD4 directory hashes, signed.
+    sb[252] = 1;
+    put_u32(&mut sb, 264, now);
+    put_u16(&mut sb, 348, 32);
+    put_u16(&mut sb, 350, 32);
+    put_u32(&mut sb, 352, 1);
+
+    for group in (0..layout.groups).filter(|group| has_super(*group)) {
+        put_u16(&mut sb, 90, group as u16);
+        let mut block = vec![0; BLOCK_SIZE as usize];
+        // The primary superblock sits 1KiB into the filesystem, after the
+        // boot sector. Backups start their group.
+        let sb_off = if group == 0 { 1024 } else { 0 };
+        block[sb_off..sb_off + 1024].copy_from_slice(&sb);
+        write_block(group * BLOCKS_PER_GROUP, &block)?;
+        write_block(group * BLOCKS_PER_GROUP + 1, &gdt)?;
+    }
+
+    dev.flush()
+}
+
+fn write_dirent(buf: &mut [u8], ino: u32, rec_len: usize, name: &str) {
+    put_u32(buf, 0, ino);
+    put_u16(buf, 4, rec_len as u16);
+    buf[6] = name.len() as u8;
+    buf[7] = FT_DIR;
+    buf[8..8 + name.len()].copy_from_slice(name.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::Path;
+    use std::process::Command;
+    use tempfile::NamedTempFile;
+
+    fn superblock(file: &mut File, offset: u64) -> Vec<u8> {
+        let mut sb = vec![0; 1024];
+        file.seek(SeekFrom::Start(offset + 1024)).unwrap();
+        file.read_exact(&mut sb).unwrap();
+        sb
+    }
+
+    fn u32_at(buf: &[u8], off: usize) -> u32 {
+        u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+    }
+
+    // Has e2fsck go through the filesystem, where it's installed.
+    fn fsck(path: &Path) {
+        match Command::new("e2fsck").arg("-fn").arg(path).output() {
+            Ok(output) => assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout)),
+            Err(err) => assert_eq!(err.kind(), io::ErrorKind::NotFound),
+        }
+    }
+
+    fn format_image(size: u64) -> NamedTempFile {
+        let mut image = NamedTempFile::new().unwrap();
+        image.as_file().set_len(size).unwrap();
+        format(image.as_file_mut(), 0, size, "writable").unwrap();
+        image
+    }
+
+    #[test]
+    fn formats_a_single_group() {
+        let mut image = format_image(64 << 20);
+        let sb = superblock(image.as_file_mut(), 0);
+        assert_eq!(&sb[56..58], &[0x53, 0xEF]);
+        assert_eq!(u32_at(&sb, 4), (64 << 20) / BLOCK_SIZE as u32);
+        assert_eq!(&sb[120..136], b"writable\0\0\0\0\0\0\0\0");
+        fsck(image.path());
+    }
+
+    #[test]
+    fn formats_many_groups() {
+        // The last group is a partial one.
+        let size = (1 << 30) + (100 << 20);
+        let mut image = format_image(size);
+        let sb = superblock(image.as_file_mut(), 0);
+        assert_eq!(u32_at(&sb, 4) as u64, size / BLOCK_SIZE);
+        assert_eq!(u32_at(&sb, 0), 9 * u32_at(&sb, 40));
+        fsck(image.path());
+    }
+
+    #[test]
+    fn drops_a_tiny_last_group() {
+        let size = (128 << 20) + (64 << 10);
+        let mut image = format_image(size);
+        let sb = superblock(image.as_file_mut(), 0);
+        assert_eq!(u32_at(&sb, 4) as u64, BLOCKS_PER_GROUP);
+        fsck(image.path());
+    }
+
+    #[test]
+    fn formats_at_an_offset() {
+        let mut image = NamedTempFile::new().unwrap();
+        image.as_file().set_len(80 << 20).unwrap();
+        format(image.as_file_mut(), 16 << 20, 64 << 20, "casper-rw").unwrap();
+        let sb = superblock(image.as_file_mut(), 16 << 20);
+        assert_eq!(&sb[56..58], &[0x53, 0xEF]);
+        assert_eq!(&sb[120..129], b"casper-rw");
+        // Nothing gets written before the filesystem.
+        let mut before = vec![0; 16 << 20];
+        image.as_file_mut().seek(SeekFrom::Start(0)).unwrap();
+        image.as_file_mut().read_exact(&mut before).unwrap();
+        assert!(before.iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn refuses_tiny_partitions() {
+        assert!(Layout::new(100 << 10).is_err());
+    }
+}
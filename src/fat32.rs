@@ -0,0 +1,808 @@
+use crate::writer::{random_bytes, read_full, round_up};
+
+use std::collections::HashSet;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A minimal mkfs.fat that fills the filesystem as it goes. File contents are
+// written straight to their final place, one after the other, while the FAT
+// and directories are kept in memory and written out by finish().
+
+const RESERVED_SECTORS: u64 = 32;
+const FSINFO_SECTOR: u64 = 1;
+const BACKUP_BOOT_SECTOR: u64 = 6;
+const FAT_COUNT: u64 = 2;
+// Less than this many clusters, and it's FAT16 as far as anybody is
+// concerned.
+const MIN_CLUSTERS: u64 = 65525;
+const MAX_CLUSTERS: u64 = 0x0FFF_FFF5;
+const END_OF_CHAIN: u32 = 0x0FFF_FFFF;
+const MEDIA_FIXED: u8 = 0xF8;
+const DATA_ALIGNMENT: u64 = 1024 * 1024;
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+pub const MAX_FILE_SIZE: u64 = 0xFFFF_FFFF;
+
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_LONG_NAME: u8 = 0x0F;
+// Windows NT's flags for short names that are all lower case.
+const LOWER_BASE: u8 = 0x08;
+const LOWER_EXT: u8 = 0x10;
+
+const DIR_ENTRY_SIZE: usize = 32;
+const LFN_CHARS: usize = 13;
+
+fn other_err(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+struct Layout {
+    sector_size: u64,
+    sectors: u64,
+    sectors_per_cluster: u64,
+    reserved_sectors: u64,
+    fat_sectors: u64,
+    clusters: u64,
+}
+
+impl Layout {
+    fn new(size: u64, sector_size: u64) -> io::Result<Layout> {
+        let sectors = size / sector_size;
+        // The cluster sizes Windows picks.
+        let cluster_size = match size {
+            v if v <= 260 * 1024 * 1024 => 512,
+            v if v <= 8 << 30 => 4096,
+            v if v <= 16 << 30 => 8192,
+            v if v <= 32 << 30 => 16384,
+            _ => 32768,
+        };
+        let sectors_per_cluster = std::cmp::max(cluster_size / sector_size, 1);
+
+        let mut reserved_sectors = RESERVED_SECTORS;
+        let mut fat_sectors = 1;
+        // Growing the FAT leaves less room for clusters, so iterate until it
+        // settles.
+        loop {
+            // Align the data area so clusters don't straddle flash pages.
+            let data_start = round_up((reserved_sectors + FAT_COUNT * fat_sectors) * sector_size, DATA_ALIGNMENT) / sector_size;
+            if data_start >= sectors {
+                return Err(other_err("The partition is too small for a FAT32 filesystem"));
+            }
+            let clusters = (sectors - data_start) / sectors_per_cluster;
+            let needed = round_up((clusters + 2) * 4, sector_size) / sector_size;
+            if needed > fat_sectors {
+                fat_sectors = needed;
+                continue;
+            }
+            reserved_sectors = data_start - FAT_COUNT * fat_sectors;
+
+            if clusters < MIN_CLUSTERS {
+                return Err(other_err("The partition is too small for a FAT32 filesystem"));
+            }
+            if clusters > MAX_CLUSTERS {
+                return Err(other_err("The partition is too big for a FAT32 filesystem"));
+            }
+            return Ok(Layout { sector_size, sectors, sectors_per_cluster, reserved_sectors, fat_sectors, clusters });
+        }
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster * self.sector_size
+    }
+
+    fn cluster_offset(&self, cluster: u32) -> u64 {
+        (self.reserved_sectors + FAT_COUNT * self.fat_sectors + (cluster as u64 - 2) * self.sectors_per_cluster) * self.sector_size
+    }
+}
+
+// The cluster size, and how many bytes of clusters there are, in a
+// filesystem over `size` bytes. Directories take clusters too.
+pub fn capacity(size: u64, sector_size: u64) -> io::Result<(u64, u64)> {
+    let layout = Layout::new(size, sector_size)?;
+    Ok((layout.cluster_size(), layout.clusters * layout.cluster_size()))
+}
+
+// The bytes of directory entries `name` takes, its long name included.
+pub fn entry_len(name: &str) -> u64 {
+    match plain_short_name(name) {
+        Some(_) => DIR_ENTRY_SIZE as u64,
+        None => (1 + round_up(name.encode_utf16().count() as u64, LFN_CHARS as u64) / LFN_CHARS as u64) * DIR_ENTRY_SIZE as u64,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DirId(usize);
+
+struct Directory {
+    entries: Vec<u8>,
+    short_names: HashSet<[u8; 11]>,
+    parent: usize,
+    // Offsets of entries pointing at subdirectories, which only get their
+    // clusters in finish().
+    subdirs: Vec<(usize, usize)>,
+    first_cluster: u32,
+}
+
+impl Directory {
+    fn new(parent: usize) -> Directory {
+        Directory { entries: Vec::new(), short_names: HashSet::new(), parent, subdirs: Vec::new(), first_cluster: 0 }
+    }
+}
+
+pub struct Fat32Writer<'a, D> {
+    dev: &'a mut D,
+    offset: u64,
+    layout: Layout,
+    label: [u8; 11],
+    fat: Vec<u32>,
+    next_cluster: u32,
+    dirs: Vec<Directory>,
+    date: u16,
+    time: u16,
+}
+
+impl<'a, D: Write + Seek> Fat32Writer<'a, D> {
+    // Creates a filesystem over the `size` bytes starting at `offset` in
+    // `dev`, with sectors of `sector_size` bytes. Nothing gets written before
+    // finish().
+    pub fn new(dev: &'a mut D, offset: u64, size: u64, sector_size: u64, label: &str) -> io::Result<Fat32Writer<'a, D>> {
+        let layout = Layout::new(size, sector_size)?;
+        let mut fat = vec![0; layout.clusters as usize + 2];
+        fat[0] = 0x0FFF_FF00 | MEDIA_FIXED as u32;
+        fat[1] = END_OF_CHAIN;
+
+        let (date, time) = dos_time(SystemTime::now());
+        let mut writer = Fat32Writer {
+            dev,
+            offset,
+            layout,
+            label: short_label(label),
+            fat,
+            next_cluster: 2,
+            dirs: vec![Directory::new(0)],
+            date,
+            time,
+        };
+        let mut volume_entry = [0; DIR_ENTRY_SIZE];
+        volume_entry[..11].copy_from_slice(&writer.label);
+        volume_entry[11] = ATTR_VOLUME_ID;
+        writer.set_times(&mut volume_entry);
+        writer.dirs[0].entries.extend_from_slice(&volume_entry);
+        Ok(writer)
+    }
+
+    pub fn root(&self) -> DirId {
+        DirId(0)
+    }
+
+    pub fn create_dir(&mut self, parent: DirId, name: &str) -> io::Result<DirId> {
+        let id = self.dirs.len();
+        let mut dir = Directory::new(parent.0);
+        // "." and "..", their clusters get filled in by finish().
+        for dot_name in &[*b".          ", *b"..         "] {
+            let mut entry = [0; DIR_ENTRY_SIZE];
+            entry[..11].copy_from_slice(dot_name);
+            entry[11] = ATTR_DIRECTORY;
+            self.set_times(&mut entry);
+            dir.entries.extend_from_slice(&entry);
+        }
+        self.dirs.push(dir);
+
+        let offset = self.add_entry(parent.0, name, ATTR_DIRECTORY, 0, 0)?;
+        self.dirs[parent.0].subdirs.push((offset, id));
+        Ok(DirId(id))
+    }
+
+    // Copies `len` bytes from `data` into a new file. Files are always stored
+    // in one piece, and this returns where it starts in `dev`.
+    pub fn create_file<R: Read>(&mut self, parent: DirId, name: &str, len: u64, data: &mut R) -> io::Result<u64> {
+        if len > MAX_FILE_SIZE {
+            return Err(other_err(&format!("{} is too big for FAT32", name)));
+        }
+        let first_cluster = self.allocate(len)?;
+        self.add_entry(parent.0, name, ATTR_ARCHIVE, first_cluster, len as u32)?;
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let start = self.offset + self.layout.cluster_offset(first_cluster);
+        self.dev.seek(SeekFrom::Start(start))?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        let mut written = 0;
+        while written < len {
+            let to_read = std::cmp::min(CHUNK_SIZE as u64, len - written) as usize;
+            let read = read_full(data, &mut buf[..to_read])?;
+            if read != to_read {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The file is shorter than expected"));
+            }
+            // Only whole sectors can be written.
+            let to_write = round_up(read as u64, self.layout.sector_size) as usize;
+            for byte in &mut buf[read..to_write] {
+                *byte = 0;
+            }
+            self.dev.write_all(&buf[..to_write])?;
+            written += read as u64;
+        }
+        Ok(start)
+    }
+
+    // Bytes left for file contents.
+    pub fn free_space(&self) -> u64 {
+        (self.layout.clusters + 2 - self.next_cluster as u64) * self.layout.cluster_size()
+    }
+
+    // Writes the directories, the FATs, and the boot sectors.
+    pub fn finish(mut self) -> io::Result<()> {
+        let cluster_size = self.layout.cluster_size();
+        for idx in 0..self.dirs.len() {
+            let len = std::cmp::max(self.dirs[idx].entries.len() as u64, 1);
+            let first_cluster = self.allocate(len)?;
+            self.dirs[idx].first_cluster = first_cluster;
+        }
+
+        for idx in 0..self.dirs.len() {
+            let subdirs = self.dirs[idx].subdirs.clone();
+            for (offset, subdir) in subdirs {
+                let cluster = self.dirs[subdir].first_cluster;
+                set_cluster(&mut self.dirs[idx].entries[offset..offset + DIR_ENTRY_SIZE], cluster);
+            }
+            if idx != 0 {
+                let cluster = self.dirs[idx].first_cluster;
+                // ".." points at cluster 0 when the parent is the root.
+                let parent = self.dirs[idx].parent;
+                let parent_cluster = if parent == 0 { 0 } else { self.dirs[parent].first_cluster };
+                let entries = &mut self.dirs[idx].entries;
+                set_cluster(&mut entries[..DIR_ENTRY_SIZE], cluster);
+                set_cluster(&mut entries[DIR_ENTRY_SIZE..DIR_ENTRY_SIZE * 2], parent_cluster);
+            }
+
+            let mut data = std::mem::take(&mut self.dirs[idx].entries);
+            data.resize(round_up(std::cmp::max(data.len() as u64, 1), cluster_size) as usize, 0);
+            let offset = self.offset + self.layout.cluster_offset(self.dirs[idx].first_cluster);
+            self.dev.seek(SeekFrom::Start(offset))?;
+            self.dev.write_all(&data)?;
+        }
+
+        let sector_size = self.layout.sector_size as usize;
+        let mut fat = vec![0; self.layout.fat_sectors as usize * sector_size];
+        for (entry, value) in fat.chunks_exact_mut(4).zip(&self.fat) {
+            entry.copy_from_slice(&value.to_le_bytes());
+        }
+        let fat_start = self.offset + self.layout.reserved_sectors * self.layout.sector_size;
+        self.dev.seek(SeekFrom::Start(fat_start))?;
+        for _ in 0..FAT_COUNT {
+            self.dev.write_all(&fat)?;
+        }
+
+        // The boot sector goes last, so a half written filesystem doesn't
+        // look valid.
+        let mut reserved = vec![0; self.layout.reserved_sectors as usize * sector_size];
+        let boot_sector = self.boot_sector();
+        let fsinfo = self.fsinfo();
+        for &base in &[0, BACKUP_BOOT_SECTOR as usize] {
+            reserved[base * sector_size..base * sector_size + 512].copy_from_slice(&boot_sector);
+            let fsinfo_start = (base + FSINFO_SECTOR as usize) * sector_size;
+            reserved[fsinfo_start..fsinfo_start + 512].copy_from_slice(&fsinfo);
+            // Sectors bigger than 512 bytes have the signature at their end
+            // too.
+            for sector in base..base + 3 {
+                reserved[(sector + 1) * sector_size - 2..(sector + 1) * sector_size].copy_from_slice(&[0x55, 0xAA]);
+            }
+        }
+        self.dev.seek(SeekFrom::Start(self.offset))?;
+        self.dev.write_all(&reserved)?;
+        self.dev.flush()
+    }
+
+    // Allocates enough contiguous clusters for `len` bytes, and returns the
+    // first one. Empty files get cluster 0.
+    fn allocate(&mut self, len: u64) -> io::Result<u32> {
+        let count = round_up(len, self.layout.cluster_size()) / self.layout.cluster_size();
+        if count == 0 {
+            return Ok(0);
+        }
+        if self.next_cluster as u64 + count > self.layout.clusters + 2 {
+            return Err(other_err("There isn't enough space left on the drive"));
+        }
+        let first = self.next_cluster;
+        let last = first + count as u32 - 1;
+        for cluster in first..last {
+            self.fat[cluster as usize] = cluster + 1;
+        }
+        self.fat[last as usize] = END_OF_CHAIN;
+        self.next_cluster = last + 1;
+        Ok(first)
+    }
+
+    // Appends the entries for `name` to a directory, and returns the offset
+    // of its short name entry.
+    fn add_entry(&mut self, dir: usize, name: &str, attr: u8, cluster: u32, size: u32) -> io::Result<usize> {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        if units.is_empty() || units.len() > 255 {
+            return Err(other_err(&format!("Invalid file name: {}", name)));
+        }
+
+        let mut entry = [0; DIR_ENTRY_SIZE];
+        let (short_name, case_flags) = match plain_short_name(name) {
+            Some(plain) if !self.dirs[dir].short_names.contains(&plain.0) => plain,
+            _ => {
+                let short_name = self.generate_short_name(dir, name)?;
+                let checksum = short_name_checksum(&short_name);
+                let chunks: Vec<&[u16]> = units.chunks(LFN_CHARS).collect();
+                // Long name entries come last chunk first.
+                for (idx, chunk) in chunks.iter().enumerate().rev() {
+                    let mut lfn = [0; DIR_ENTRY_SIZE];
+                    let mut order = idx as u8 + 1;
+                    if idx == chunks.len() - 1 {
+                        order |= 0x40;
+                    }
+                    lfn[0] = order;
+                    lfn[11] = ATTR_LONG_NAME;
+                    lfn[13] = checksum;
+                    // Padded with a NUL and then 0xFFFF.
+                    let mut chars = [0xFFFF; LFN_CHARS];
+                    chars[..chunk.len()].copy_from_slice(chunk);
+                    if chunk.len() < LFN_CHARS {
+                        chars[chunk.len()] = 0;
+                    }
+                    let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+                    for (&off, &unit) in offsets.iter().zip(&chars) {
+                        lfn[off..off + 2].copy_from_slice(&unit.to_le_bytes());
+                    }
+                    self.dirs[dir].entries.extend_from_slice(&lfn);
+                }
+                (short_name, 0)
+            }
+        };
+        self.dirs[dir].short_names.insert(short_name);
+
+        entry[..11].copy_from_slice(&short_name);
+        entry[11] = attr;
+        entry[12] = case_flags;
+        self.set_times(&mut entry);
+        set_cluster(&mut entry, cluster);
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+
+        let offset = self.dirs[dir].entries.len();
+        self.dirs[dir].entries.extend_from_slice(&entry);
+        Ok(offset)
+    }
+
+    // Windows style BASENA~1.EXT names for files that need a long name.
+    fn generate_short_name(&self, dir: usize, name: &str) -> io::Result<[u8; 11]> {
+        let (base, ext) = match name.rfind('.') {
+            Some(pos) if pos > 0 => (&name[..pos], &name[pos + 1..]),
+            _ => (name, ""),
+        };
+        let base: Vec<u8> = base.chars().filter(|&c| c != '.' && c != ' ').map(short_char).collect();
+        let ext: Vec<u8> = ext.chars().filter(|&c| c != ' ').map(short_char).take(3).collect();
+
+        for n in 1..1_000_000u32 {
+            let tail = format!("~{}", n);
+            let keep = std::cmp::min(base.len(), 8 - tail.len());
+            let mut short_name = [b' '; 11];
+            short_name[..keep].copy_from_slice(&base[..keep]);
+            short_name[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+            short_name[8..8 + ext.len()].copy_from_slice(&ext);
+            if !self.dirs[dir].short_names.contains(&short_name) {
+                return Ok(short_name);
+            }
+        }
+        Err(other_err(&format!("Too many files similar to {}", name)))
+    }
+
+    fn set_times(&self, entry: &mut [u8]) {
+        for &off in &[14, 22] {
+            entry[off..off + 2].copy_from_slice(&self.time.to_le_bytes());
+        }
+        for &off in &[16, 18, 24] {
+            entry[off..off + 2].copy_from_slice(&self.date.to_le_bytes());
+        }
+    }
+
+    fn boot_sector(&self) -> [u8; 512] {
+        let layout = &self.layout;
+        let mut sector = [0; 512];
+        // A jump over the BPB, to boot code that isn't there. This drive only
+        // boots through UEFI.
+        sector[0..3].copy_from_slice(&[0xEB, 0x58, 0x90]);
+        sector[3..11].copy_from_slice(b"MSWIN4.1");
+        sector[11..13].copy_from_slice(&(layout.sector_size as u16).to_le_bytes());
+        sector[13] = layout.sectors_per_cluster as u8;
+        sector[14..16].copy_from_slice(&(layout.reserved_sectors as u16).to_le_bytes());
+        sector[16] = FAT_COUNT as u8;
+        sector[21] = MEDIA_FIXED;
+        sector[24..26].copy_from_slice(&63u16.to_le_bytes());
+        sector[26..28].copy_from_slice(&255u16.to_le_bytes());
+        sector[28..32].copy_from_slice(&((self.offset / layout.sector_size) as u32).to_le_bytes());
+        sector[32..36].copy_from_slice(&(layout.sectors as u32).to_le_bytes());
+        sector[36..40].copy_from_slice(&(layout.fat_sectors as u32).to_le_bytes());
+        sector[44..48].copy_from_slice(&self.dirs[0].first_cluster.to_le_bytes());
+        sector[48..50].copy_from_slice(&(FSINFO_SECTOR as u16).to_le_bytes());
+        sector[50..52].copy_from_slice(&(BACKUP_BOOT_SECTOR as u16).to_le_bytes());
+        sector[64] = 0x80;
+        sector[66] = 0x29;
+        random_bytes(&mut sector[67..71]);
+        sector[71..82].copy_from_slice(&self.label);
+        sector[82..90].copy_from_slice(b"FAT32   ");
+        sector[510..512].copy_from_slice(&[0x55, 0xAA]);
+        sector
+    }
+
+    fn fsinfo(&self) -> [u8; 512] {
+        let free = self.layout.clusters + 2 - self.next_cluster as u64;
+        let mut sector = [0; 512];
+        sector[0..4].copy_from_slice(b"RRaA");
+        sector[484..488].copy_from_slice(b"rrAa");
+        sector[488..492].copy_from_slice(&(free as u32).to_le_bytes());
+        sector[492..496].copy_from_slice(&self.next_cluster.to_le_bytes());
+        sector[508..512].copy_from_slice(&[0, 0, 0x55, 0xAA]);
+        sector
+    }
+}
+
+fn set_cluster(entry: &mut [u8], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+fn is_short_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "$%'-_@~`!(){}^#&".contains(c)
+}
+
+fn short_char(c: char) -> u8 {
+    if is_short_char(c) {
+        c.to_ascii_uppercase() as u8
+    } else {
+        b'_'
+    }
+}
+
+// Names that fit in 8.3 don't need a long name entry, as long as each part is
+// either all upper or all lower case.
+fn plain_short_name(name: &str) -> Option<([u8; 11], u8)> {
+    let (base, ext) = match name.find('.') {
+        Some(pos) => (&name[..pos], &name[pos + 1..]),
+        None => (name, ""),
+    };
+    if base.is_empty() || base.len() > 8 || ext.len() > 3 || name.ends_with('.') {
+        return None;
+    }
+    if !base.chars().chain(ext.chars()).all(is_short_char) {
+        return None;
+    }
+
+    let mut flags = 0;
+    for &(part, flag) in &[(base, LOWER_BASE), (ext, LOWER_EXT)] {
+        let has_upper = part.chars().any(|c| c.is_ascii_uppercase());
+        let has_lower = part.chars().any(|c| c.is_ascii_lowercase());
+        match (has_upper, has_lower) {
+            (true, true) => return None,
+            (false, true) => flags |= flag,
+            _ => (),
+        }
+    }
+
+    let mut short_name = [b' '; 11];
+    short_name[..base.len()].copy_from_slice(base.to_ascii_uppercase().as_bytes());
+    short_name[8..8 + ext.len()].copy_from_slice(ext.to_ascii_uppercase().as_bytes());
+    Some((short_name, flags))
+}
+
+fn short_label(label: &str) -> [u8; 11] {
+    let mut short_label = [b' '; 11];
+    for (dst, c) in short_label.iter_mut().zip(label.chars()) {
+        *dst = if c == ' ' { b' ' } else { short_char(c) };
+    }
+    short_label
+}
+
+fn short_name_checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &c| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(c))
+}
+
+// FAT timestamps are local time, but UTC is close enough for files nobody
+// will ever edit.
+fn dos_time(time: SystemTime) -> (u16, u16) {
+    let secs = time.duration_since(UNIX_EPOCH).map(|v| v.as_secs()).unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let secs_of_day = secs % 86400;
+
+    // Days since 1970-01-01 to a civil date, from Howard Hinnant's
+    // date algorithms.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let year = std::cmp::max(year, 1980) - 1980;
+    let date = ((year as u16) << 9) | ((month as u16) << 5) | day as u16;
+    let time = ((secs_of_day / 3600) as u16) << 11 | ((secs_of_day / 60 % 60) as u16) << 5 | (secs_of_day % 60 / 2) as u16;
+    (date, time)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    use std::io::Cursor;
+
+    fn u16_at(buf: &[u8], off: usize) -> u64 {
+        u16::from_le_bytes([buf[off], buf[off + 1]]) as u64
+    }
+
+    fn u32_at(buf: &[u8], off: usize) -> u64 {
+        u32::from_le_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]]) as u64
+    }
+
+    fn read_at<D: Read + Seek>(dev: &mut D, offset: u64, len: u64) -> Vec<u8> {
+        let mut buf = vec![0; len as usize];
+        dev.seek(SeekFrom::Start(offset)).unwrap();
+        dev.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    struct Reader<'a, D> {
+        dev: &'a mut D,
+        data_start: u64,
+        cluster_size: u64,
+        fat: Vec<u64>,
+        used: HashSet<u64>,
+        files: BTreeMap<String, Vec<u8>>,
+    }
+
+    impl<'a, D: Read + Seek> Reader<'a, D> {
+        // The clusters of a chain, each used only once on the whole
+        // filesystem.
+        fn chain(&mut self, mut cluster: u64) -> Vec<u64> {
+            let mut clusters = Vec::new();
+            while cluster != 0 && cluster < 0x0FFF_FFF8 {
+                assert!(cluster >= 2 && (cluster as usize) < self.fat.len(), "cluster {} out of the FAT", cluster);
+                assert!(self.used.insert(cluster), "cluster {} is cross-linked", cluster);
+                clusters.push(cluster);
+                cluster = self.fat[cluster as usize];
+            }
+            clusters
+        }
+
+        fn read_chain(&mut self, cluster: u64) -> Vec<u8> {
+            let mut data = Vec::new();
+            for cluster in self.chain(cluster) {
+                let offset = self.data_start + (cluster - 2) * self.cluster_size;
+                data.extend(read_at(self.dev, offset, self.cluster_size));
+            }
+            data
+        }
+
+        fn read_dir(&mut self, path: &str, cluster: u64, parent: u64) {
+            let data = self.read_chain(cluster);
+            let mut long_name: Vec<u16> = Vec::new();
+            let mut checksum = None;
+            for (idx, entry) in data.chunks_exact(DIR_ENTRY_SIZE).enumerate() {
+                match entry[0] {
+                    0 => break,
+                    0xE5 => continue,
+                    _ => (),
+                }
+                let attr = entry[11];
+                if attr == ATTR_LONG_NAME {
+                    let offsets = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+                    let chunk: Vec<u16> = offsets.iter().map(|&off| u16_at(entry, off) as u16).take_while(|&v| v != 0 && v != 0xFFFF).collect();
+                    long_name.splice(0..0, chunk);
+                    checksum = Some(entry[13]);
+                    continue;
+                }
+                let first_cluster = u16_at(entry, 20) << 16 | u16_at(entry, 26);
+                if attr & ATTR_VOLUME_ID != 0 {
+                    assert!(path.is_empty() && idx == 0, "volume label outside the root");
+                    continue;
+                }
+                let mut short_name = [0; 11];
+                short_name.copy_from_slice(&entry[..11]);
+                if &short_name == b".          " {
+                    assert_eq!(first_cluster, cluster);
+                    continue;
+                }
+                if &short_name == b"..         " {
+                    assert_eq!(first_cluster, parent);
+                    continue;
+                }
+                let name = if long_name.is_empty() {
+                    let mut base = String::from_utf8(entry[..8].to_vec()).unwrap().trim_end().to_string();
+                    let mut ext = String::from_utf8(entry[8..11].to_vec()).unwrap().trim_end().to_string();
+                    if entry[12] & LOWER_BASE != 0 {
+                        base = base.to_lowercase();
+                    }
+                    if entry[12] & LOWER_EXT != 0 {
+                        ext = ext.to_lowercase();
+                    }
+                    if ext.is_empty() { base } else { format!("{}.{}", base, ext) }
+                } else {
+                    assert_eq!(checksum, Some(short_name_checksum(&short_name)));
+                    String::from_utf16(&long_name).unwrap()
+                };
+                long_name.clear();
+                let child = if path.is_empty() { name } else { format!("{}/{}", path, name) };
+                if attr & ATTR_DIRECTORY != 0 {
+                    self.files.insert(format!("{}/", child), Vec::new());
+                    self.read_dir(&child, first_cluster, cluster_or_root(cluster, path));
+                } else {
+                    let size = u32_at(entry, 28);
+                    let mut data = self.read_chain(first_cluster);
+                    assert_eq!(data.len() as u64, round_up(size, self.cluster_size), "{} has the wrong number of clusters", child);
+                    data.truncate(size as usize);
+                    self.files.insert(child, data);
+                }
+            }
+        }
+    }
+
+    // ".." points at 0 for the root's children.
+    fn cluster_or_root(cluster: u64, path: &str) -> u64 {
+        if path.is_empty() { 0 } else { cluster }
+    }
+
+    // Reads back the filesystem at `offset`, as paths to contents, with
+    // directories ending in a slash. Checks what fsck would on the way.
+    pub fn read_files<D: Read + Seek>(dev: &mut D, offset: u64) -> BTreeMap<String, Vec<u8>> {
+        let boot = read_at(dev, offset, 512);
+        assert_eq!(&boot[510..512], &[0x55, 0xAA]);
+        assert_eq!(&boot[82..90], b"FAT32   ");
+        let sector_size = u16_at(&boot, 11);
+        let cluster_size = boot[13] as u64 * sector_size;
+        let reserved = u16_at(&boot, 14);
+        let fat_count = boot[16] as u64;
+        let sectors = u32_at(&boot, 32);
+        let fat_sectors = u32_at(&boot, 36);
+        let root = u32_at(&boot, 44);
+        assert_eq!(read_at(dev, offset + BACKUP_BOOT_SECTOR * sector_size, 512), boot);
+        let fsinfo = read_at(dev, offset + u16_at(&boot, 48) * sector_size, 512);
+        assert_eq!((&fsinfo[0..4], &fsinfo[484..488]), (&b"RRaA"[..], &b"rrAa"[..]));
+
+        let fat_len = fat_sectors * sector_size;
+        let fat = read_at(dev, offset + reserved * sector_size, fat_len);
+        for idx in 1..fat_count {
+            assert_eq!(read_at(dev, offset + (reserved + idx * fat_sectors) * sector_size, fat_len), fat);
+        }
+        let data_start = (reserved + fat_count * fat_sectors) * sector_size;
+        let clusters = (sectors * sector_size - data_start) / cluster_size;
+        assert!(clusters >= MIN_CLUSTERS && clusters + 2 <= fat_len / 4);
+        let fat: Vec<u64> = fat.chunks_exact(4).take(clusters as usize + 2).map(|v| u32_at(v, 0) & 0x0FFF_FFFF).collect();
+        let free = fat.iter().skip(2).filter(|&&v| v == 0).count() as u64;
+        assert_eq!(u32_at(&fsinfo, 488), free);
+
+        let mut reader = Reader { dev, data_start: offset + data_start, cluster_size, fat, used: HashSet::new(), files: BTreeMap::new() };
+        reader.read_dir("", root, 0);
+        // Nothing allocated that nothing points at.
+        assert_eq!(reader.used.len() as u64, clusters - free);
+        reader.files
+    }
+
+    fn disk(len: u64) -> Cursor<Vec<u8>> {
+        Cursor::new(vec![0; len as usize])
+    }
+
+    #[test]
+    fn writes_what_it_reads_back() {
+        const OFFSET: u64 = 1 << 20;
+        let mut dev = disk(OFFSET + (40 << 20));
+        let big: Vec<u8> = (0..10_000u32).map(|v| v as u8).collect();
+        let mut fat = Fat32Writer::new(&mut dev, OFFSET, 40 << 20, 512, "Ubuntu 22.04.3 LTS amd64").unwrap();
+        let root = fat.root();
+        let boot = fat.create_dir(root, "boot").unwrap();
+        let grub = fat.create_dir(boot, "grub").unwrap();
+        let long = fat.create_dir(root, "A Folder With A Long Name").unwrap();
+        let files: Vec<(DirId, &str, Vec<u8>)> = vec![
+            (root, "README.TXT", b"upper".to_vec()),
+            (root, "md5sum.txt", b"lower".to_vec()),
+            (root, "Mixed.Txt", b"mixed".to_vec()),
+            (root, "empty", Vec::new()),
+            (root, "one cluster", vec![7; 512]),
+            (grub, "grub.cfg", b"menuentry".to_vec()),
+            (long, "a file with a long name 1.txt", b"1".to_vec()),
+            (long, "a file with a long name 2.txt", b"2".to_vec()),
+            (long, "caf\u{e9} \u{1F600}.txt", b"unicode".to_vec()),
+            (long, "big.bin", big.clone()),
+        ];
+        let mut offsets = Vec::new();
+        for (dir, name, data) in &files {
+            offsets.push(fat.create_file(*dir, name, data.len() as u64, &mut Cursor::new(data)).unwrap());
+        }
+        fat.finish().unwrap();
+
+        // Files are in one piece, where create_file said.
+        for ((_, _, data), offset) in files.iter().zip(&offsets) {
+            if !data.is_empty() {
+                assert_eq!(&read_at(&mut dev, *offset, data.len() as u64), data);
+            }
+        }
+        let read = read_files(&mut dev, OFFSET);
+        let expected: BTreeMap<String, Vec<u8>> = vec![
+            ("README.TXT", &b"upper"[..]),
+            ("md5sum.txt", b"lower"),
+            ("Mixed.Txt", b"mixed"),
+            ("empty", b""),
+            ("one cluster", &[7; 512]),
+            ("boot/", b""),
+            ("boot/grub/", b""),
+            ("boot/grub/grub.cfg", b"menuentry"),
+            ("A Folder With A Long Name/", b""),
+            ("A Folder With A Long Name/a file with a long name 1.txt", b"1"),
+            ("A Folder With A Long Name/a file with a long name 2.txt", b"2"),
+            ("A Folder With A Long Name/caf\u{e9} \u{1F600}.txt", b"unicode"),
+            ("A Folder With A Long Name/big.bin", &big),
+        ].into_iter().map(|(k, v)| (k.to_string(), v.to_vec())).collect();
+        assert_eq!(read, expected);
+        // Labels keep what fits, in short name characters.
+        assert_eq!(&read_at(&mut dev, OFFSET + 71, 11), b"UBUNTU 22_0");
+    }
+
+    #[test]
+    fn makes_windows_short_names() {
+        let mut dev = disk(40 << 20);
+        let mut fat = Fat32Writer::new(&mut dev, 0, 40 << 20, 512, "TEST").unwrap();
+        let root = fat.root();
+        for name in &["a file with a long name 1.txt", "a file with a long name 2.txt", "x.tar.gz", "README.TXT", "readme.txt"] {
+            fat.create_file(root, name, 0, &mut io::empty()).unwrap();
+        }
+        let short_names: HashSet<[u8; 11]> = fat.dirs[0].short_names.clone();
+        for expected in &[b"AFILEW~1TXT", b"AFILEW~2TXT", b"XTAR~1  GZ ", b"README  TXT", b"README~1TXT"] {
+            assert!(short_names.contains(*expected), "{}", String::from_utf8_lossy(*expected));
+        }
+    }
+
+    #[test]
+    fn counts_entries() {
+        assert_eq!(entry_len("README.TXT"), 32);
+        assert_eq!(entry_len("grub.cfg"), 32);
+        assert_eq!(entry_len("Mixed.Txt"), 64);
+        assert_eq!(entry_len("filesystem.squashfs"), 32 * 3);
+        assert_eq!(entry_len("a file with a long name 1.txt"), 32 * 4);
+    }
+
+    #[test]
+    fn picks_the_layout() {
+        assert_eq!(capacity(64 << 20, 512).unwrap().0, 512);
+        assert_eq!(capacity(1 << 30, 512).unwrap().0, 4096);
+        assert_eq!(capacity(16 << 30, 512).unwrap().0, 8192);
+        assert_eq!(capacity(64 << 30, 4096).unwrap().0, 32768);
+        // Never less than a sector.
+        assert_eq!(capacity(260 << 20, 4096).unwrap().0, 4096);
+        let (cluster_size, bytes) = capacity(1 << 30, 512).unwrap();
+        assert!(bytes % cluster_size == 0 && bytes < 1 << 30 && bytes > (1 << 30) - (20 << 20));
+        // Too few clusters to be FAT32.
+        assert!(capacity(16 << 20, 512).is_err());
+    }
+
+    #[test]
+    fn stops_when_full() {
+        let mut dev = disk(40 << 20);
+        let mut fat = Fat32Writer::new(&mut dev, 0, 40 << 20, 512, "TEST").unwrap();
+        let root = fat.root();
+        let free = fat.free_space();
+        assert_eq!(capacity(40 << 20, 512).unwrap().1, free);
+        fat.create_file(root, "a", 1, &mut Cursor::new(b"a")).unwrap();
+        assert_eq!(fat.free_space(), free - 512);
+        assert!(fat.create_file(root, "b", free, &mut io::repeat(0)).is_err());
+        assert!(fat.create_file(root, "c", MAX_FILE_SIZE + 1, &mut io::repeat(0)).is_err());
+        let err = fat.create_file(root, "d", 100, &mut Cursor::new(b"short")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn dates_files() {
+        // 2020-02-29 12:34:56 UTC.
+        let time = UNIX_EPOCH + std::time::Duration::from_secs(1_582_979_696);
+        assert_eq!(dos_time(time), ((40 << 9) | (2 << 5) | 29, (12 << 11) | (34 << 5) | 28));
+        // FAT starts in 1980.
+        assert_eq!(dos_time(UNIX_EPOCH), ((1 << 5) | 1, 0));
+    }
+}
@@ -0,0 +1,423 @@
+use crate::fat32::{self, DirId, Fat32Writer};
+use crate::iso::{DirEntry, Iso};
+use crate::partition::{self, GptPartition, Guid, EFI_SYSTEM_PARTITION, LINUX_FILESYSTEM_PARTITION};
+use crate::writer::{round_up, AlignedBuffer, Target};
+
+use std::collections::HashMap;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+
+// The other way to make a bootable drive: a GPT with a single FAT32 EFI
+// system partition, with the files of the image copied over. Only UEFI
+// firmwares boot from it, but it doesn't depend on the image being an
+// isohybrid, and Windows can still read the drive afterwards. Images with
+// files over 4GiB get a second partition, holding the image as is.
+
+const ALIGNMENT: u64 = 1024 * 1024;
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+fn other_err(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+struct Node {
+    // Index of the parent directory in the list, None for the root's
+    // children.
+    parent: Option<usize>,
+    // From the root, e.g. "casper/vmlinuz".
+    path: String,
+    entry: DirEntry,
+}
+
+// Lists the whole tree, parents before their children.
+fn read_tree<R: Read + Seek>(iso: &mut Iso<R>, dir: &DirEntry, parent: Option<usize>, nodes: &mut Vec<Node>) -> io::Result<()> {
+    for entry in iso.read_dir(dir)? {
+        // FAT has no symlinks. The ones on Ubuntu images are only there for
+        // the sake of compatibility, and some loop back to their parent.
+        if entry.symlink.is_some() {
+            continue;
+        }
+        let path = match parent {
+            Some(idx) => format!("{}/{}", nodes[idx].path, entry.name),
+            None => entry.name.clone(),
+        };
+        let idx = nodes.len();
+        nodes.push(Node { parent, path, entry: entry.clone() });
+        if entry.is_dir {
+            read_tree(iso, &entry, Some(idx), nodes)?;
+        }
+    }
+    Ok(())
+}
+
+fn extension(name: &str) -> Option<&str> {
+    name.rfind('.').map(|pos| &name[pos..])
+}
+
+// FAT32 can't hold files over 4GiB, so those stay in the image, which goes
+// on a partition of its own. Casper looks for its squashfs files on every
+// partition, and would settle for an incomplete set, so the files next to
+// them with the same extension stay there too.
+fn left_in_image(nodes: &[Node], max_file_size: u64) -> Vec<bool> {
+    let too_big: Vec<&Node> = nodes.iter().filter(|v| !v.entry.is_dir && v.entry.size() > max_file_size).collect();
+    nodes.iter().map(|node| {
+        !node.entry.is_dir && too_big.iter().any(|big| {
+            big.path == node.path || (big.parent == node.parent && extension(&big.entry.name).is_some() && extension(&big.entry.name) == extension(&node.entry.name))
+        })
+    }).collect()
+}
+
+// The bytes of clusters the files of `lens`, and the directories, take.
+// Files and directories each start on a cluster of their own.
+fn space_needed(nodes: &[Node], lens: &[Option<u64>], cluster_size: u64) -> u64 {
+    // The root comes last, with its volume label entry. Other directories
+    // start with "." and "..".
+    let mut dir_lens: Vec<u64> = nodes.iter().map(|_| 64).collect();
+    dir_lens.push(32);
+    let mut needed = 0;
+    for (node, len) in nodes.iter().zip(lens) {
+        if !node.entry.is_dir && len.is_none() {
+            continue;
+        }
+        dir_lens[node.parent.unwrap_or(nodes.len())] += fat32::entry_len(&node.entry.name);
+        if let Some(len) = len {
+            needed += round_up(*len, cluster_size);
+        }
+    }
+    let dirs = nodes.iter().map(|v| v.entry.is_dir).chain(std::iter::once(true));
+    needed + dir_lens.iter().zip(dirs).filter(|v| v.1).map(|(len, _)| round_up(*len, cluster_size)).sum::<u64>()
+}
+
+// Reports how many bytes went through it.
+struct ProgressReader<'a, R, F> {
+    inner: R,
+    done: &'a mut u64,
+    total: u64,
+    progress: &'a mut F,
+}
+
+impl<'a, R: Read, F: FnMut(u64, u64)> Read for ProgressReader<'a, R, F> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        *self.done += read as u64;
+        (self.progress)(*self.done, self.total);
+        Ok(read)
+    }
+}
+
+// Where each file ended up on the drive, as (offset, length), by path
+// relative to the root, e.g. "casper/vmlinuz".
+pub type FileMap = HashMap<String, (u64, u64)>;
+
+// Repartitions `target` and copies the files of the ISO9660 `image` to it.
+// Files in `replaced`, as (path, contents), get copied with those contents
+// instead. `reserved` bytes are left free at the end of the drive, for more
+// partitions.
+pub fn write_files<R, T, F>(image: R, target: &mut T, replaced: &[(String, Vec<u8>)], reserved: u64, progress: F) -> io::Result<FileMap>
+where
+    R: Read + Seek,
+    T: Target,
+    F: FnMut(u64, u64),
+{
+    copy_files(image, target, replaced, reserved, fat32::MAX_FILE_SIZE, progress)
+}
+
+fn copy_files<R, T, F>(mut image: R, target: &mut T, replaced: &[(String, Vec<u8>)], reserved: u64, max_file_size: u64, mut progress: F) -> io::Result<FileMap>
+where
+    R: Read + Seek,
+    T: Target,
+    F: FnMut(u64, u64),
+{
+    let image_len = image.seek(SeekFrom::End(0))?;
+    let mut iso = Iso::open(image)?;
+    // Go through the whole tree, and make sure it fits, before touching the
+    // drive.
+    let mut nodes = Vec::new();
+    let root = iso.root().clone();
+    read_tree(&mut iso, &root, None, &mut nodes)?;
+    let in_image = left_in_image(&nodes, max_file_size);
+    // What gets copied, and how long it is.
+    let lens: Vec<Option<u64>> = nodes.iter().zip(&in_image).map(|(node, &in_image)| {
+        if node.entry.is_dir || in_image {
+            return None;
+        }
+        match replaced.iter().find(|v| v.0.eq_ignore_ascii_case(&node.path)) {
+            Some((_, data)) => Some(data.len() as u64),
+            None => Some(node.entry.size()),
+        }
+    }).collect();
+    let image_part_len = if in_image.contains(&true) { round_up(image_len, ALIGNMENT) } else { 0 };
+
+    let sector_size = target.sector_size();
+    let disk_size = target.size()?;
+    let (first_lba, last_lba) = partition::gpt_usable_lbas(sector_size, disk_size);
+    let start = round_up(first_lba * sector_size, ALIGNMENT);
+    let end = ((last_lba + 1) * sector_size).saturating_sub(reserved) / ALIGNMENT * ALIGNMENT;
+    let image_start = end.saturating_sub(image_part_len);
+    if image_start <= start {
+        return Err(other_err("The drive is too small"));
+    }
+    let (cluster_size, capacity) = fat32::capacity(image_start - start, sector_size)?;
+    if space_needed(&nodes, &lens, cluster_size) > capacity {
+        return Err(other_err("The files of the image don't fit on the drive"));
+    }
+    let total = lens.iter().flatten().sum::<u64>() + if image_part_len > 0 { image_len } else { 0 };
+
+    target.clear_partition_table()?;
+
+    let mut fat = Fat32Writer::new(target, start, image_start - start, sector_size, iso.volume_id())?;
+    let mut dirs: Vec<Option<DirId>> = Vec::with_capacity(nodes.len());
+    let mut files = FileMap::new();
+    let mut done = 0;
+    for (node, len) in nodes.iter().zip(&lens) {
+        let parent = match node.parent {
+            Some(idx) => dirs[idx].unwrap(),
+            None => fat.root(),
+        };
+        if node.entry.is_dir {
+            dirs.push(Some(fat.create_dir(parent, &node.entry.name)?));
+            continue;
+        }
+        dirs.push(None);
+        let len = match len {
+            Some(len) => *len,
+            // Read back from the image partition.
+            None => {
+                if node.entry.is_contiguous() {
+                    files.insert(node.path.clone(), (image_start + node.entry.offset(), node.entry.size()));
+                }
+                continue;
+            }
+        };
+        let replacement = replaced.iter().find(|v| v.0.eq_ignore_ascii_case(&node.path));
+        let offset = match replacement {
+            Some((_, data)) => {
+                let offset = fat.create_file(parent, &node.entry.name, len, &mut Cursor::new(data))?;
+                done += len;
+                progress(done, total);
+                offset
+            }
+            None => {
+                let mut reader = ProgressReader {
+                    inner: iso.open_file(&node.entry),
+                    done: &mut done,
+                    total,
+                    progress: &mut progress,
+                };
+                fat.create_file(parent, &node.entry.name, len, &mut reader)?
+            }
+        };
+        files.insert(node.path.clone(), (offset, len));
+    }
+    fat.finish()?;
+
+    let mut partitions = vec![GptPartition {
+        kind: EFI_SYSTEM_PARTITION,
+        guid: Guid::random(),
+        first_lba: start / sector_size,
+        last_lba: image_start / sector_size - 1,
+        attributes: 0,
+        name: "EFI system partition".to_string(),
+    }];
+    if image_part_len > 0 {
+        copy_image(&mut iso, image_len, target, image_start, |read| {
+            done += read;
+            progress(done, total);
+        })?;
+        // Not a basic data partition, or Windows would offer to format it.
+        partitions.push(GptPartition {
+            kind: LINUX_FILESYSTEM_PARTITION,
+            guid: Guid::random(),
+            first_lba: image_start / sector_size,
+            last_lba: end / sector_size - 1,
+            attributes: 0,
+            name: "Image".to_string(),
+        });
+    }
+    partition::write_gpt(target, sector_size, disk_size, &partitions)?;
+    target.rescan()?;
+    Ok(files)
+}
+
+// Writes the whole image at `offset`, padded to whole sectors.
+fn copy_image<R, T, F>(iso: &mut Iso<R>, image_len: u64, target: &mut T, offset: u64, mut progress: F) -> io::Result<()>
+where
+    R: Read + Seek,
+    T: Target,
+    F: FnMut(u64),
+{
+    let sector_size = target.sector_size();
+    let mut buf = AlignedBuffer::new(CHUNK_SIZE, sector_size as usize);
+    target.seek(SeekFrom::Start(offset))?;
+    let mut copied = 0;
+    while copied < image_len {
+        let len = std::cmp::min(CHUNK_SIZE as u64, image_len - copied) as usize;
+        iso.read_at(copied, &mut buf[..len])?;
+        let to_write = round_up(len as u64, sector_size) as usize;
+        for byte in &mut buf[len..to_write] {
+            *byte = 0;
+        }
+        target.write_all(&buf[..to_write])?;
+        copied += len as u64;
+        progress(len as u64);
+    }
+    target.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fat32::tests::read_files;
+    use crate::partition::{Gpt, Mbr};
+    use crate::verify::{self, DeviceFiles, VerifyError};
+    use std::fs::File;
+    use std::io::Write;
+
+    const IMAGE: &[u8] = include_bytes!("../testdata/small.iso");
+    const DISK_SIZE: u64 = 48 << 20;
+
+    fn disk(len: u64) -> File {
+        let mut disk = tempfile::tempfile().unwrap();
+        disk.set_len(len).unwrap();
+        // Something to tell whether it got touched.
+        disk.write_all(b"untouched").unwrap();
+        disk
+    }
+
+    fn untouched(disk: &mut File) -> bool {
+        let mut buf = [0; 9];
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        &buf == b"untouched"
+    }
+
+    fn image_files() -> Vec<(String, Vec<u8>)> {
+        let mut iso = Iso::open(Cursor::new(IMAGE)).unwrap();
+        let mut nodes = Vec::new();
+        let root = iso.root().clone();
+        read_tree(&mut iso, &root, None, &mut nodes).unwrap();
+        nodes.iter().filter(|v| !v.entry.is_dir).map(|v| (v.path.clone(), iso.read_file(&v.entry).unwrap())).collect()
+    }
+
+    fn partitions(disk: &mut File) -> Vec<GptPartition> {
+        let mbr = Mbr::read(disk, 512).unwrap().unwrap();
+        assert!(mbr.protects_gpt());
+        Gpt::read(disk, 512).unwrap().unwrap().partitions().map(|(_, v)| v.clone()).collect()
+    }
+
+    #[test]
+    fn copies_the_files() {
+        let mut disk = disk(DISK_SIZE);
+        let grub_cfg = b"set timeout=5\n".to_vec();
+        let replaced = vec![("BOOT/GRUB/GRUB.CFG".to_string(), grub_cfg.clone())];
+        let mut last = (0, 0);
+        let files = write_files(Cursor::new(IMAGE), &mut disk, &replaced, 0, |done, total| last = (done, total)).unwrap();
+
+        let partitions = partitions(&mut disk);
+        assert_eq!(partitions.len(), 1);
+        assert_eq!(partitions[0].kind, EFI_SYSTEM_PARTITION);
+        assert_eq!(partitions[0].first_lba, ALIGNMENT / 512);
+        let copied = read_files(&mut disk, partitions[0].first_lba * 512);
+        for (path, data) in image_files() {
+            let expected = if path == "boot/grub/grub.cfg" { &grub_cfg } else { &data };
+            assert_eq!(copied.get(&path), Some(expected), "{}", path);
+            let (offset, len) = files[&path];
+            let mut buf = vec![0; len as usize];
+            disk.seek(SeekFrom::Start(offset)).unwrap();
+            disk.read_exact(&mut buf).unwrap();
+            assert_eq!(&buf, expected);
+        }
+        assert!(copied.contains_key("pool/main/a b/deep/er/than/eight/levels/of/dirs/file"));
+        // Not the symlink looping back to the root.
+        assert!(!copied.keys().any(|v| v.starts_with("ubuntu")));
+        assert_eq!(last.0, last.1);
+
+        let mut device = DeviceFiles { target: &mut disk, files, patched: Vec::new() };
+        match verify::check_md5sums(&mut device, |_, _| ()) {
+            Err(VerifyError::CorruptedFiles(files)) => assert_eq!(files, ["boot/grub/grub.cfg"]),
+            other => panic!("{:?}", other.err()),
+        }
+        device.patched = vec!["BOOT/GRUB/GRUB.CFG".to_string()];
+        assert!(verify::check_md5sums(&mut device, |_, _| ()).is_ok());
+    }
+
+    #[test]
+    fn leaves_room_for_more_partitions() {
+        let mut disk = disk(DISK_SIZE);
+        write_files(Cursor::new(IMAGE), &mut disk, &[], 8 << 20, |_, _| ()).unwrap();
+        let partitions = partitions(&mut disk);
+        assert!((partitions[0].last_lba + 1) * 512 <= DISK_SIZE - (8 << 20));
+    }
+
+    #[test]
+    fn checks_the_space_before_touching_the_drive() {
+        // Too small for FAT32.
+        let mut small = disk(16 << 20);
+        assert!(write_files(Cursor::new(IMAGE), &mut small, &[], 0, |_, _| ()).is_err());
+        assert!(untouched(&mut small));
+        // All of it reserved.
+        let mut reserved = disk(DISK_SIZE);
+        assert!(write_files(Cursor::new(IMAGE), &mut reserved, &[], DISK_SIZE, |_, _| ()).is_err());
+        assert!(untouched(&mut reserved));
+
+        // Files that need more clusters than there are.
+        let mut disk = disk(DISK_SIZE);
+        let (cluster_size, capacity) = fat32::capacity(DISK_SIZE - 2 * ALIGNMENT, 512).unwrap();
+        let replaced = vec![("casper/vmlinuz".to_string(), vec![0; (capacity - 20 * cluster_size) as usize])];
+        let err = write_files(Cursor::new(IMAGE), &mut disk, &replaced, 0, |_, _| ()).unwrap_err();
+        assert_eq!(err.to_string(), "The files of the image don't fit on the drive");
+        assert!(untouched(&mut disk));
+    }
+
+    #[test]
+    fn counts_the_clusters_it_takes() {
+        let mut iso = Iso::open(Cursor::new(IMAGE)).unwrap();
+        let mut nodes = Vec::new();
+        let root = iso.root().clone();
+        read_tree(&mut iso, &root, None, &mut nodes).unwrap();
+        let lens: Vec<Option<u64>> = nodes.iter().map(|v| if v.entry.is_dir { None } else { Some(v.entry.size()) }).collect();
+
+        let mut disk = disk(DISK_SIZE);
+        write_files(Cursor::new(IMAGE), &mut disk, &[], 0, |_, _| ()).unwrap();
+        let start = partitions(&mut disk)[0].first_lba * 512;
+        let (cluster_size, capacity) = fat32::capacity(DISK_SIZE - 2 * ALIGNMENT, 512).unwrap();
+        // What the FSInfo sector says is free.
+        let mut fsinfo = [0; 512];
+        disk.seek(SeekFrom::Start(start + 512)).unwrap();
+        disk.read_exact(&mut fsinfo).unwrap();
+        let free = u32::from_le_bytes([fsinfo[488], fsinfo[489], fsinfo[490], fsinfo[491]]) as u64;
+        assert_eq!(space_needed(&nodes, &lens, cluster_size), capacity - free * cluster_size);
+    }
+
+    // Stands in for files over 4GiB.
+    #[test]
+    fn keeps_big_files_in_the_image() {
+        let mut disk = disk(DISK_SIZE);
+        let files = copy_files(Cursor::new(IMAGE), &mut disk, &[], 4 << 20, 4000, |_, _| ()).unwrap();
+
+        let partitions = partitions(&mut disk);
+        assert_eq!(partitions.len(), 2);
+        assert_eq!(partitions[1].kind, LINUX_FILESYSTEM_PARTITION);
+        assert_eq!(partitions[0].last_lba + 1, partitions[1].first_lba);
+        let image_start = partitions[1].first_lba * 512;
+        assert_eq!((partitions[1].last_lba + 1) * 512 - image_start, round_up(IMAGE.len() as u64, ALIGNMENT));
+        assert!((partitions[1].last_lba + 1) * 512 <= DISK_SIZE - (4 << 20));
+        let mut image = vec![0; IMAGE.len()];
+        disk.seek(SeekFrom::Start(image_start)).unwrap();
+        disk.read_exact(&mut image).unwrap();
+        assert!(image == IMAGE);
+
+        // The squashfs files all stay in the image, so casper doesn't find
+        // a partial set on the FAT32 partition.
+        let copied = read_files(&mut disk, partitions[0].first_lba * 512);
+        assert!(!copied.contains_key("casper/vmlinuz"));
+        assert!(!copied.contains_key("casper/filesystem.squashfs"));
+        assert!(!copied.contains_key("casper/minimal.squashfs"));
+        assert!(copied.contains_key("casper/filesystem.manifest"));
+        assert!(copied.contains_key("EFI/boot/bootx64.efi"));
+        assert!(files["casper/filesystem.squashfs"].0 > image_start);
+
+        let mut device = DeviceFiles { target: &mut disk, files, patched: Vec::new() };
+        assert!(verify::check_md5sums(&mut device, |_, _| ()).is_ok());
+    }
+}
@@ -0,0 +1,671 @@
+use std::io::{self, Read, Seek, SeekFrom};
+
+// Reads the file tree of an ISO9660 image. Names come from Rock Ridge when
+// the image has it, then from Joliet, since plain ISO9660 names are upper
+// case and truncated.
+
+pub const SECTOR_SIZE: u64 = 2048;
+
+const FLAG_DIRECTORY: u8 = 0x02;
+const FLAG_MULTI_EXTENT: u8 = 0x80;
+
+// Rock Ridge file types, from the PX entry.
+const S_IFMT: u32 = 0o170_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFLNK: u32 = 0o120_000;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn le_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Names {
+    Plain,
+    Joliet,
+    RockRidge,
+}
+
+#[derive(Debug, Clone)]
+pub struct VolumeDescriptor {
+    pub system_id: String,
+    pub volume_id: String,
+    // In bytes.
+    pub volume_size: u64,
+    // Where the descriptor is in the image.
+    pub offset: u64,
+    root: Vec<u8>,
+}
+
+impl VolumeDescriptor {
+    fn parse(sector: &[u8], offset: u64, joliet: bool) -> VolumeDescriptor {
+        let text = |buf: &[u8]| {
+            if joliet {
+                let units: Vec<u16> = buf.chunks_exact(2).map(|v| u16::from_be_bytes([v[0], v[1]])).collect();
+                String::from_utf16_lossy(&units).trim_end().to_string()
+            } else {
+                String::from_utf8_lossy(buf).trim_end().to_string()
+            }
+        };
+        VolumeDescriptor {
+            system_id: text(&sector[8..40]),
+            volume_id: text(&sector[40..72]),
+            volume_size: le_u32(&sector[80..84]) as u64 * SECTOR_SIZE,
+            offset,
+            root: sector[156..190].to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+    // Rock Ridge only.
+    pub mode: Option<u32>,
+    pub symlink: Option<String>,
+    // Files over 4GiB are split in several extents, as (offset, length) in
+    // bytes.
+    extents: Vec<(u64, u64)>,
+    // Where the (first) directory record of the file is in the image.
+    record: u64,
+}
+
+impl DirEntry {
+    pub fn size(&self) -> u64 {
+        self.extents.iter().map(|v| v.1).sum()
+    }
+
+    // Where the file starts in the image.
+    pub fn offset(&self) -> u64 {
+        self.extents.first().map(|v| v.0).unwrap_or(0)
+    }
+
+    // Whether the contents are stored in one piece.
+    pub fn is_contiguous(&self) -> bool {
+        self.extents.windows(2).all(|v| v[0].0 + v[0].1 == v[1].0)
+    }
+
+    pub fn record_offset(&self) -> u64 {
+        self.record
+    }
+
+    // Where a position in the file is in the image.
+    fn image_offset(&self, pos: u64) -> u64 {
+        let mut extent_start = 0;
+        for &(start, len) in &self.extents {
+            if pos < extent_start + len {
+                return start + pos - extent_start;
+            }
+            extent_start += len;
+        }
+        self.offset() + pos
+    }
+}
+
+// What the System Use area of a directory record says, with Rock Ridge.
+#[derive(Default)]
+struct SystemUse {
+    name: Option<String>,
+    mode: Option<u32>,
+    symlink: Option<String>,
+    symlink_continues: bool,
+    // Deep directories get moved elsewhere, and leave a link behind.
+    child_link: Option<u64>,
+    relocated: bool,
+}
+
+pub struct Iso<R> {
+    inner: R,
+    pub primary: VolumeDescriptor,
+    pub joliet: Option<VolumeDescriptor>,
+    // Offset of the El Torito boot catalog, on bootable images.
+    pub boot_catalog: Option<u64>,
+    pub names: Names,
+    root: DirEntry,
+    // Bytes to skip at the start of each System Use area.
+    susp_skip: usize,
+}
+
+impl<R: Read + Seek> Iso<R> {
+    pub fn open(mut inner: R) -> io::Result<Iso<R>> {
+        let mut primary = None;
+        let mut joliet = None;
+        let mut boot_catalog = None;
+        let mut sector = vec![0; SECTOR_SIZE as usize];
+        // Volume descriptors start at sector 16, and end with a terminator.
+        for idx in 16.. {
+            inner.seek(SeekFrom::Start(idx * SECTOR_SIZE))?;
+            inner.read_exact(&mut sector)?;
+            if &sector[1..6] != b"CD001" {
+                return Err(invalid_data("Not an ISO9660 image"));
+            }
+            match sector[0] {
+                0 if &sector[7..30] == b"EL TORITO SPECIFICATION" => boot_catalog = Some(le_u32(&sector[71..75]) as u64 * SECTOR_SIZE),
+                1 if primary.is_none() => primary = Some(VolumeDescriptor::parse(&sector, idx * SECTOR_SIZE, false)),
+                // Joliet is a supplementary descriptor with a UCS-2 escape
+                // sequence.
+                2 if joliet.is_none() && sector[88] == b'%' && sector[89] == b'/' && b"@CE".contains(&sector[90]) => {
+                    joliet = Some(VolumeDescriptor::parse(&sector, idx * SECTOR_SIZE, true))
+                }
+                255 => break,
+                _ => (),
+            }
+        }
+        let primary = primary.ok_or_else(|| invalid_data("The image has no primary volume descriptor"))?;
+
+        let mut iso = Iso {
+            inner,
+            root: parse_record(&primary.root, primary.offset + 156, Names::Plain).ok_or_else(|| invalid_data("Invalid root directory record"))?,
+            primary,
+            joliet,
+            boot_catalog,
+            names: Names::Plain,
+            susp_skip: 0,
+        };
+        iso.root.name = String::new();
+
+        // Rock Ridge announces itself with an SP entry in the root's "."
+        // record.
+        let mut first = vec![0; SECTOR_SIZE as usize];
+        iso.inner.seek(SeekFrom::Start(iso.root.offset()))?;
+        iso.inner.read_exact(&mut first)?;
+        let dot = &first[..first[0] as usize];
+        if dot.len() >= 34 + 7 && &dot[34..36] == b"SP" && dot[38..40] == [0xBE, 0xEF] {
+            iso.names = Names::RockRidge;
+            iso.susp_skip = dot[40] as usize;
+        } else if let Some(joliet) = &iso.joliet {
+            iso.root = parse_record(&joliet.root, joliet.offset + 156, Names::Joliet).ok_or_else(|| invalid_data("Invalid root directory record"))?;
+            iso.root.name = String::new();
+            iso.names = Names::Joliet;
+        }
+        Ok(iso)
+    }
+
+    pub fn root(&self) -> &DirEntry {
+        &self.root
+    }
+
+    pub fn volume_id(&self) -> &str {
+        &self.primary.volume_id
+    }
+
+    pub fn read_dir(&mut self, dir: &DirEntry) -> io::Result<Vec<DirEntry>> {
+        let mut data = Vec::new();
+        self.open_file(dir).read_to_end(&mut data)?;
+
+        let mut entries: Vec<DirEntry> = Vec::new();
+        // Set when the previous record continues in the next one.
+        let mut continued = false;
+        let mut pos = 0;
+        while pos < data.len() {
+            let len = data[pos] as usize;
+            if len == 0 {
+                // Records don't cross sectors, the rest of this one is
+                // padding.
+                pos = (pos / SECTOR_SIZE as usize + 1) * SECTOR_SIZE as usize;
+                continue;
+            }
+            let record = &data[pos..std::cmp::min(pos + len, data.len())];
+            let record_offset = dir.image_offset(pos as u64);
+            pos += len;
+
+            // Skip "." and "..".
+            if record.len() > 33 && record[32] == 1 && record[33] <= 1 {
+                continue;
+            }
+            let mut entry = match parse_record(record, record_offset, self.names) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let was_continued = continued;
+            continued = record[25] & FLAG_MULTI_EXTENT != 0;
+            if was_continued {
+                if let Some(last) = entries.last_mut() {
+                    last.extents.extend(entry.extents);
+                    continue;
+                }
+            }
+
+            if self.names == Names::RockRidge {
+                let system_use = self.system_use(record)?;
+                if system_use.relocated {
+                    continue;
+                }
+                if let Some(name) = system_use.name {
+                    entry.name = name;
+                }
+                entry.mode = system_use.mode;
+                entry.symlink = system_use.symlink;
+                if let Some(mode) = entry.mode {
+                    entry.is_dir = mode & S_IFMT == S_IFDIR;
+                    if mode & S_IFMT == S_IFLNK && entry.symlink.is_none() {
+                        entry.symlink = Some(String::new());
+                    }
+                }
+                if let Some(location) = system_use.child_link {
+                    entry.is_dir = true;
+                    entry.extents = vec![(location, self.dir_size(location)?)];
+                }
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    // Looks up a path like "boot/grub/grub.cfg". Plain ISO9660 names only
+    // come in upper case, so fall back to comparing without case.
+    pub fn find(&mut self, path: &str) -> io::Result<Option<DirEntry>> {
+        let mut entry = self.root.clone();
+        for component in path.split('/').filter(|v| !v.is_empty()) {
+            if !entry.is_dir {
+                return Ok(None);
+            }
+            let children = self.read_dir(&entry)?;
+            let found = children.iter().find(|v| v.name == component).or_else(|| children.iter().find(|v| v.name.eq_ignore_ascii_case(component)));
+            entry = match found {
+                Some(found) => found.clone(),
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(entry))
+    }
+
+    // Looks up a path in every directory tree of the image: the primary one,
+    // then the Joliet one if there's one. Their records point to the same
+    // data, and both need updating when it moves.
+    pub fn find_all(&mut self, path: &str) -> io::Result<Vec<DirEntry>> {
+        let primary_names = if self.names == Names::RockRidge { Names::RockRidge } else { Names::Plain };
+        let mut trees = vec![(self.primary.root.clone(), self.primary.offset, primary_names)];
+        if let Some(joliet) = &self.joliet {
+            trees.push((joliet.root.clone(), joliet.offset, Names::Joliet));
+        }
+
+        let (root, names) = (self.root.clone(), self.names);
+        let mut found = Vec::new();
+        for (record, offset, tree_names) in trees {
+            let mut tree_root = match parse_record(&record, offset + 156, tree_names) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            tree_root.name = String::new();
+            self.root = tree_root;
+            self.names = tree_names;
+            let entry = self.find(path);
+            self.root = root.clone();
+            self.names = names;
+            found.extend(entry?);
+        }
+        Ok(found)
+    }
+
+    pub fn open_file<'a>(&'a mut self, file: &DirEntry) -> FileReader<'a, R> {
+        FileReader { iso: self, extents: file.extents.clone(), pos: 0 }
+    }
+
+    pub fn read_file(&mut self, file: &DirEntry) -> io::Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(file.size() as usize);
+        self.open_file(file).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    // Reads raw bytes from the image, e.g. from outside the file tree.
+    pub fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        self.inner.seek(SeekFrom::Start(offset))?;
+        self.inner.read_exact(buf)
+    }
+
+    // The size of a directory is only recorded in its own "." entry.
+    fn dir_size(&mut self, location: u64) -> io::Result<u64> {
+        let mut record = [0; 34];
+        self.inner.seek(SeekFrom::Start(location))?;
+        self.inner.read_exact(&mut record)?;
+        Ok(le_u32(&record[10..14]) as u64)
+    }
+
+    fn system_use(&mut self, record: &[u8]) -> io::Result<SystemUse> {
+        let name_len = record[32] as usize;
+        // The name is padded to an even length.
+        let start = 33 + name_len + (1 - name_len % 2) + self.susp_skip;
+        let mut system_use = SystemUse::default();
+        let mut area = record.get(start..).unwrap_or(&[]).to_vec();
+        // A CE entry continues the area elsewhere. Don't follow them forever
+        // on a broken image.
+        for _ in 0..16 {
+            let continuation = parse_system_use(&area, &mut system_use);
+            match continuation {
+                Some((offset, len)) => {
+                    area = vec![0; len as usize];
+                    self.inner.seek(SeekFrom::Start(offset))?;
+                    self.inner.read_exact(&mut area)?;
+                }
+                None => break,
+            }
+        }
+        Ok(system_use)
+    }
+}
+
+// Parses one directory record. Joliet names are big endian UCS-2.
+fn parse_record(record: &[u8], offset: u64, names: Names) -> Option<DirEntry> {
+    if record.len() < 34 || record.len() < 33 + record[32] as usize {
+        return None;
+    }
+    let lba = le_u32(&record[2..6]) as u64;
+    let len = le_u32(&record[10..14]) as u64;
+    let raw_name = &record[33..33 + record[32] as usize];
+
+    let name = if names == Names::Joliet {
+        let units: Vec<u16> = raw_name.chunks_exact(2).map(|v| u16::from_be_bytes([v[0], v[1]])).collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        raw_name.iter().map(|&v| v as char).collect()
+    };
+    // Drop the ";1" version suffix, and the dot of names without an
+    // extension.
+    let name = match name.rfind(';') {
+        Some(pos) => &name[..pos],
+        None => &name[..],
+    };
+    let name = name.trim_end_matches('.').to_string();
+
+    Some(DirEntry {
+        name,
+        is_dir: record[25] & FLAG_DIRECTORY != 0,
+        mode: None,
+        symlink: None,
+        extents: vec![(lba * SECTOR_SIZE, len)],
+        record: offset,
+    })
+}
+
+// Adds what a System Use area holds to `system_use`. Returns the offset and
+// length of the continuation area, if any.
+fn parse_system_use(area: &[u8], system_use: &mut SystemUse) -> Option<(u64, u64)> {
+    let mut continuation = None;
+    let mut pos = 0;
+    while pos + 4 <= area.len() {
+        let len = area[pos + 2] as usize;
+        if len < 4 || pos + len > area.len() {
+            break;
+        }
+        let entry = &area[pos..pos + len];
+        pos += len;
+
+        match &entry[..2] {
+            b"CE" if len >= 28 => {
+                continuation = Some((le_u32(&entry[4..8]) as u64 * SECTOR_SIZE + le_u32(&entry[12..16]) as u64, le_u32(&entry[20..24]) as u64));
+            }
+            // Names can be split over several NM entries.
+            b"NM" if len >= 5 => {
+                let flags = entry[4];
+                // "." and "..", which we skip anyway.
+                if flags & 0x06 != 0 {
+                    continue;
+                }
+                system_use.name.get_or_insert_with(String::new).push_str(&String::from_utf8_lossy(&entry[5..]));
+            }
+            b"PX" if len >= 12 => system_use.mode = Some(le_u32(&entry[4..8])),
+            b"SL" if len >= 5 => {
+                let target = system_use.symlink.get_or_insert_with(String::new);
+                let mut comp_pos = 5;
+                while comp_pos + 2 <= entry.len() {
+                    let flags = entry[comp_pos];
+                    let comp_len = entry[comp_pos + 1] as usize;
+                    let content = entry.get(comp_pos + 2..comp_pos + 2 + comp_len).unwrap_or(&[]);
+                    comp_pos += 2 + comp_len;
+
+                    // Components are separated by slashes, unless the last
+                    // one continues in this one.
+                    if !system_use.symlink_continues && !target.is_empty() && !target.ends_with('/') {
+                        target.push('/');
+                    }
+                    match flags & 0x0E {
+                        0x02 => target.push('.'),
+                        0x04 => target.push_str(".."),
+                        0x08 => target.push('/'),
+                        _ => target.push_str(&String::from_utf8_lossy(content)),
+                    }
+                    system_use.symlink_continues = flags & 0x01 != 0;
+                }
+            }
+            b"CL" if len >= 12 => system_use.child_link = Some(le_u32(&entry[4..8]) as u64 * SECTOR_SIZE),
+            b"RE" => system_use.relocated = true,
+            b"ST" => break,
+            _ => (),
+        }
+    }
+    continuation
+}
+
+// Streams the contents of a file, extent after extent.
+pub struct FileReader<'a, R> {
+    iso: &'a mut Iso<R>,
+    extents: Vec<(u64, u64)>,
+    // Position in the file.
+    pos: u64,
+}
+
+impl<'a, R> FileReader<'a, R> {
+    pub fn len(&self) -> u64 {
+        self.extents.iter().map(|v| v.1).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'a, R: Read + Seek> Read for FileReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut extent_start = 0;
+        for &(start, len) in &self.extents {
+            if self.pos < extent_start + len {
+                let pos_in_extent = self.pos - extent_start;
+                let to_read = std::cmp::min(buf.len() as u64, len - pos_in_extent) as usize;
+                self.iso.inner.seek(SeekFrom::Start(start + pos_in_extent))?;
+                let read = self.iso.inner.read(&mut buf[..to_read])?;
+                if read == 0 && to_read > 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The image is truncated"));
+                }
+                self.pos += read as u64;
+                return Ok(read);
+            }
+            extent_start += len;
+        }
+        Ok(0)
+    }
+}
+
+impl<'a, R: Read + Seek> Seek for FileReader<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::Current(delta) => self.pos as i64 + delta,
+            SeekFrom::End(delta) => self.len() as i64 + delta,
+        };
+        if new_pos < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Seek before the start of the file"));
+        }
+        self.pos = new_pos as u64;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Cursor;
+
+    // See testdata/make-small-iso.sh.
+    const IMAGE: &[u8] = include_bytes!("../testdata/small.iso");
+
+    const FILES: &[&str] = &[
+        ".disk/info",
+        "EFI/boot/bootx64.efi",
+        "boot/grub/grub.cfg",
+        "casper/filesystem.manifest",
+        "casper/filesystem.squashfs",
+        "casper/minimal.squashfs",
+        "casper/vmlinuz",
+        "md5sum.txt",
+        "pool/main/a b/A File With A Long Name.txt",
+        "pool/main/a b/deep/er/than/eight/levels/of/dirs/file",
+    ];
+
+    fn open(image: &[u8]) -> Iso<Cursor<Vec<u8>>> {
+        Iso::open(Cursor::new(image.to_vec())).unwrap()
+    }
+
+    // Every file and symlink under `dir`, as paths to entries.
+    fn walk(iso: &mut Iso<Cursor<Vec<u8>>>, dir: &DirEntry, prefix: &str, found: &mut Vec<(String, DirEntry)>) {
+        for entry in iso.read_dir(dir).unwrap() {
+            let path = format!("{}{}", prefix, entry.name);
+            if entry.is_dir && entry.symlink.is_none() {
+                walk(iso, &entry, &format!("{}/", path), found);
+            } else {
+                found.push((path, entry));
+            }
+        }
+    }
+
+    fn files(iso: &mut Iso<Cursor<Vec<u8>>>) -> Vec<(String, DirEntry)> {
+        let root = iso.root().clone();
+        let mut found = Vec::new();
+        walk(iso, &root, "", &mut found);
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+        found
+    }
+
+    #[test]
+    fn reads_the_volume() {
+        let iso = open(IMAGE);
+        assert_eq!(iso.names, Names::RockRidge);
+        assert_eq!(iso.volume_id(), "UBUNTU_22_04_3_LTS_AMD64");
+        assert_eq!(iso.primary.volume_size, IMAGE.len() as u64);
+        assert!(iso.joliet.is_some());
+
+        // The validation entry starts the El Torito catalog.
+        let catalog = iso.boot_catalog.unwrap() as usize;
+        assert_eq!(IMAGE[catalog], 1);
+        assert_eq!(IMAGE[catalog + 30..catalog + 32], [0x55, 0xAA]);
+
+        assert!(Iso::open(Cursor::new(vec![0; 40 * SECTOR_SIZE as usize])).is_err());
+        assert!(Iso::open(Cursor::new(IMAGE[..16 * SECTOR_SIZE as usize].to_vec())).is_err());
+    }
+
+    #[test]
+    fn reads_the_tree() {
+        let mut iso = open(IMAGE);
+        let found = files(&mut iso);
+        let paths: Vec<&str> = found.iter().map(|v| v.0.as_str()).filter(|v| *v != "boot.catalog" && *v != "ubuntu").collect();
+        assert_eq!(paths, FILES);
+
+        let (_, link) = found.iter().find(|v| v.0 == "ubuntu").unwrap();
+        assert_eq!(link.symlink.as_deref(), Some("."));
+        assert_eq!(link.mode.map(|v| v & S_IFMT), Some(S_IFLNK));
+
+        let (_, vmlinuz) = found.iter().find(|v| v.0 == "casper/vmlinuz").unwrap();
+        assert_eq!(vmlinuz.size(), 9000);
+        assert!(vmlinuz.is_contiguous());
+        let data = iso.read_file(vmlinuz).unwrap();
+        assert_eq!(&data[..], &b"vmlinuz\n".repeat(1200)[..9000]);
+        assert_eq!(&IMAGE[vmlinuz.offset() as usize..][..9000], &data[..]);
+    }
+
+    #[test]
+    fn finds_files() {
+        let mut iso = open(IMAGE);
+        let grub_cfg = iso.find("boot/grub/grub.cfg").unwrap().unwrap();
+        assert!(String::from_utf8(iso.read_file(&grub_cfg).unwrap()).unwrap().contains("/casper/vmlinuz"));
+        assert_eq!(iso.find("/BOOT/GRUB/GRUB.CFG").unwrap().unwrap().offset(), grub_cfg.offset());
+        assert!(iso.find("boot/grub/loopback.cfg").unwrap().is_none());
+        assert!(iso.find("boot/grub/grub.cfg/menu").unwrap().is_none());
+        assert!(iso.find("").unwrap().unwrap().is_dir);
+
+        // Rock Ridge moved this one to rr_moved, where it's hidden.
+        let deep = iso.find("pool/main/a b/deep/er/than/eight/levels/of/dirs/file").unwrap().unwrap();
+        assert_eq!(iso.read_file(&deep).unwrap(), b"deep\n");
+        let rr_moved = iso.find("rr_moved").unwrap().unwrap();
+        assert!(iso.read_dir(&rr_moved).unwrap().is_empty());
+    }
+
+    #[test]
+    fn finds_files_in_every_tree() {
+        let mut iso = open(IMAGE);
+        let found = iso.find_all("casper/vmlinuz").unwrap();
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].offset(), found[1].offset());
+        assert_ne!(found[0].record_offset(), found[1].record_offset());
+        for entry in &found {
+            assert_eq!(le_u32(&IMAGE[entry.record_offset() as usize + 2..]) as u64 * SECTOR_SIZE, entry.offset());
+        }
+        assert!(iso.find_all("casper/initrd").unwrap().is_empty());
+        // The tree it picked is left as it was.
+        assert_eq!(iso.names, Names::RockRidge);
+        assert_eq!(iso.root().offset(), iso.find("").unwrap().unwrap().offset());
+    }
+
+    #[test]
+    fn falls_back_to_joliet_then_plain_names() {
+        // Without the SP entry, Rock Ridge isn't there.
+        let mut image = IMAGE.to_vec();
+        let root = open(IMAGE).root().offset() as usize;
+        image[root + 34..root + 36].copy_from_slice(b"XX");
+        let mut iso = open(&image);
+        assert_eq!(iso.names, Names::Joliet);
+        let found = files(&mut iso);
+        assert!(found.iter().any(|v| v.0 == "pool/main/a b/A File With A Long Name.txt"));
+        assert!(found.iter().any(|v| v.0 == "casper/filesystem.squashfs"));
+
+        // Nor is Joliet, without its escape sequence.
+        let joliet = iso.joliet.as_ref().unwrap().offset as usize;
+        image[joliet + 88] = 0;
+        let mut iso = open(&image);
+        assert_eq!(iso.names, Names::Plain);
+        assert!(iso.joliet.is_none());
+        let vmlinuz = iso.find("casper/vmlinuz").unwrap().unwrap();
+        assert_eq!(vmlinuz.name, "VMLINUZ");
+        assert_eq!(vmlinuz.size(), 9000);
+    }
+
+    #[test]
+    fn seeks_in_files() {
+        let mut iso = open(IMAGE);
+        let squashfs = iso.find("casper/filesystem.squashfs").unwrap().unwrap();
+        let mut file = iso.open_file(&squashfs);
+        assert_eq!(file.len(), 5000);
+        let mut buf = Vec::new();
+        assert_eq!(file.seek(SeekFrom::End(-8)).unwrap(), 4992);
+        file.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, &b"squashfs\n".repeat(556)[4992..5000]);
+        assert_eq!(file.seek(SeekFrom::Current(-9)).unwrap(), 4991);
+        assert!(file.seek(SeekFrom::Current(-5000)).is_err());
+    }
+
+    #[test]
+    fn reads_rock_ridge_entries() {
+        let mut system_use = SystemUse::default();
+        let mut area = Vec::new();
+        // A name split over two NM entries.
+        area.extend_from_slice(&[b'N', b'M', 9, 1, 1, b'l', b'o', b'n', b'g']);
+        area.extend_from_slice(&[b'N', b'M', 9, 1, 0, b'n', b'a', b'm', b'e']);
+        // "../lib/libc.so", with "lib" continued in the next component.
+        area.extend_from_slice(&[b'S', b'L', 20, 1, 0, 0x04, 0, 0x01, 2, b'l', b'i', 0, 1, b'b', 0x01, 4, b'l', b'i', b'b', b'c']);
+        area.extend_from_slice(&[b'S', b'L', 10, 1, 0, 0, 3, b'.', b's', b'o']);
+        area.extend_from_slice(&[b'P', b'X', 36, 1, 0xFF, 0xA1, 0, 0]);
+        area.extend_from_slice(&[0; 28]);
+        area.extend_from_slice(&[b'C', b'E', 28, 1, 30, 0, 0, 0, 0, 0, 0, 30, 100, 0, 0, 0, 0, 0, 0, 100, 50, 0, 0, 0, 0, 0, 0, 50]);
+        area.extend_from_slice(&[b'S', b'T', 4, 1, b'N', b'M', 6, 1, 0, b'x']);
+
+        assert_eq!(parse_system_use(&area, &mut system_use), Some((30 * SECTOR_SIZE + 100, 50)));
+        assert_eq!(system_use.name.as_deref(), Some("longname"));
+        assert_eq!(system_use.symlink.as_deref(), Some("../lib/libc.so"));
+        assert_eq!(system_use.mode, Some(0o120_777));
+        assert!(!system_use.relocated);
+    }
+}
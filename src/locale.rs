@@ -0,0 +1,682 @@
+use crate::autoinstall::Autoinstall;
+
+// Maps the language, keyboard layout and time zone of Windows to their
+// Ubuntu equivalents, so the installed system comes up the same way.
+
+// Keyboard layout IDs (KLIDs), as in HKLM\SYSTEM\CurrentControlSet\Control\
+// Keyboard Layouts, to XKB layouts and variants.
+const KEYBOARD_LAYOUTS: &[(&str, &str, &str)] = &[
+    ("00000401", "ara", ""),
+    ("00000402", "bg", ""),
+    ("00000404", "tw", ""),
+    ("00000405", "cz", ""),
+    ("00010405", "cz", "qwerty"),
+    ("00000406", "dk", ""),
+    ("00000407", "de", ""),
+    ("00000408", "gr", ""),
+    ("00000409", "us", ""),
+    ("00010409", "us", "dvorak"),
+    ("00020409", "us", "intl"),
+    ("0000040A", "es", ""),
+    ("0000040B", "fi", ""),
+    ("0000040C", "fr", ""),
+    ("0000040D", "il", ""),
+    ("0000040E", "hu", ""),
+    ("0000040F", "is", ""),
+    ("00000410", "it", ""),
+    ("00000411", "jp", ""),
+    ("00000412", "kr", ""),
+    ("00000413", "nl", ""),
+    ("00000414", "no", ""),
+    ("00000415", "pl", ""),
+    ("00010415", "pl", "qwertz"),
+    ("00000416", "br", ""),
+    ("00010416", "br", ""),
+    ("00000418", "ro", ""),
+    ("00010418", "ro", "std"),
+    ("00000419", "ru", ""),
+    ("0000041A", "hr", ""),
+    ("0000041B", "sk", ""),
+    ("0000041D", "se", ""),
+    ("0000041E", "th", ""),
+    ("0000041F", "tr", ""),
+    ("0001041F", "tr", "f"),
+    ("00000422", "ua", ""),
+    ("00000423", "by", ""),
+    ("00000424", "si", ""),
+    ("00000425", "ee", ""),
+    ("00000426", "lv", ""),
+    ("00000427", "lt", "ibm"),
+    ("00010427", "lt", ""),
+    ("00000429", "ir", ""),
+    ("0000042A", "vn", ""),
+    ("00000439", "in", ""),
+    ("0000043F", "kz", ""),
+    ("00000804", "cn", ""),
+    ("00000807", "ch", ""),
+    ("00000809", "gb", ""),
+    ("0000080A", "latam", ""),
+    ("0000080C", "be", ""),
+    ("00000813", "be", ""),
+    ("00000816", "pt", ""),
+    ("0000081A", "rs", "latin"),
+    ("00000C0C", "ca", "fr-legacy"),
+    ("00000C1A", "rs", ""),
+    ("00001009", "ca", ""),
+    ("00011009", "ca", "multix"),
+    ("0000100C", "ch", "fr"),
+    ("00001809", "ie", ""),
+];
+
+// CLDR's windowsZones, with the current IANA names rather than the older
+// aliases CLDR keeps. Territory "001" is the default for a Windows zone, the
+// others pick the zone of a country when it has its own.
+const TIME_ZONES: &[(&str, &str, &str)] = &[
+    ("Dateline Standard Time", "001", "Etc/GMT+12"),
+    ("UTC-11", "001", "Etc/GMT+11"),
+    ("Aleutian Standard Time", "001", "America/Adak"),
+    ("Hawaiian Standard Time", "001", "Pacific/Honolulu"),
+    ("Marquesas Standard Time", "001", "Pacific/Marquesas"),
+    ("Alaskan Standard Time", "001", "America/Anchorage"),
+    ("UTC-09", "001", "Etc/GMT+9"),
+    ("Pacific Standard Time (Mexico)", "001", "America/Tijuana"),
+    ("UTC-08", "001", "Etc/GMT+8"),
+    ("Pacific Standard Time", "001", "America/Los_Angeles"),
+    ("Pacific Standard Time", "CA", "America/Vancouver"),
+    ("US Mountain Standard Time", "001", "America/Phoenix"),
+    ("Mountain Standard Time (Mexico)", "001", "America/Chihuahua"),
+    ("Mountain Standard Time", "001", "America/Denver"),
+    ("Mountain Standard Time", "CA", "America/Edmonton"),
+    ("Yukon Standard Time", "001", "America/Whitehorse"),
+    ("Central America Standard Time", "001", "America/Guatemala"),
+    ("Central America Standard Time", "CR", "America/Costa_Rica"),
+    ("Central America Standard Time", "HN", "America/Tegucigalpa"),
+    ("Central America Standard Time", "NI", "America/Managua"),
+    ("Central America Standard Time", "SV", "America/El_Salvador"),
+    ("Central Standard Time", "001", "America/Chicago"),
+    ("Central Standard Time", "CA", "America/Winnipeg"),
+    ("Easter Island Standard Time", "001", "Pacific/Easter"),
+    ("Central Standard Time (Mexico)", "001", "America/Mexico_City"),
+    ("Canada Central Standard Time", "001", "America/Regina"),
+    ("SA Pacific Standard Time", "001", "America/Bogota"),
+    ("SA Pacific Standard Time", "EC", "America/Guayaquil"),
+    ("SA Pacific Standard Time", "JM", "America/Jamaica"),
+    ("SA Pacific Standard Time", "PA", "America/Panama"),
+    ("SA Pacific Standard Time", "PE", "America/Lima"),
+    ("Eastern Standard Time (Mexico)", "001", "America/Cancun"),
+    ("Eastern Standard Time", "001", "America/New_York"),
+    ("Eastern Standard Time", "BS", "America/Nassau"),
+    ("Eastern Standard Time", "CA", "America/Toronto"),
+    ("Haiti Standard Time", "001", "America/Port-au-Prince"),
+    ("Cuba Standard Time", "001", "America/Havana"),
+    ("US Eastern Standard Time", "001", "America/Indiana/Indianapolis"),
+    ("Turks And Caicos Standard Time", "001", "America/Grand_Turk"),
+    ("Paraguay Standard Time", "001", "America/Asuncion"),
+    ("Atlantic Standard Time", "001", "America/Halifax"),
+    ("Atlantic Standard Time", "BM", "Atlantic/Bermuda"),
+    ("Atlantic Standard Time", "GL", "America/Thule"),
+    ("Venezuela Standard Time", "001", "America/Caracas"),
+    ("Central Brazilian Standard Time", "001", "America/Cuiaba"),
+    ("SA Western Standard Time", "001", "America/La_Paz"),
+    ("SA Western Standard Time", "DO", "America/Santo_Domingo"),
+    ("SA Western Standard Time", "PR", "America/Puerto_Rico"),
+    ("SA Western Standard Time", "TT", "America/Port_of_Spain"),
+    ("Pacific SA Standard Time", "001", "America/Santiago"),
+    ("Newfoundland Standard Time", "001", "America/St_Johns"),
+    ("Tocantins Standard Time", "001", "America/Araguaina"),
+    ("E. South America Standard Time", "001", "America/Sao_Paulo"),
+    ("SA Eastern Standard Time", "001", "America/Cayenne"),
+    ("SA Eastern Standard Time", "BR", "America/Fortaleza"),
+    ("SA Eastern Standard Time", "SR", "America/Paramaribo"),
+    ("Argentina Standard Time", "001", "America/Argentina/Buenos_Aires"),
+    ("Greenland Standard Time", "001", "America/Nuuk"),
+    ("Montevideo Standard Time", "001", "America/Montevideo"),
+    ("Magallanes Standard Time", "001", "America/Punta_Arenas"),
+    ("Saint Pierre Standard Time", "001", "America/Miquelon"),
+    ("Bahia Standard Time", "001", "America/Bahia"),
+    ("UTC-02", "001", "Etc/GMT+2"),
+    ("Azores Standard Time", "001", "Atlantic/Azores"),
+    ("Cape Verde Standard Time", "001", "Atlantic/Cape_Verde"),
+    ("UTC", "001", "Etc/UTC"),
+    ("GMT Standard Time", "001", "Europe/London"),
+    ("GMT Standard Time", "ES", "Atlantic/Canary"),
+    ("GMT Standard Time", "FO", "Atlantic/Faroe"),
+    ("GMT Standard Time", "GG", "Europe/Guernsey"),
+    ("GMT Standard Time", "IE", "Europe/Dublin"),
+    ("GMT Standard Time", "IM", "Europe/Isle_of_Man"),
+    ("GMT Standard Time", "JE", "Europe/Jersey"),
+    ("GMT Standard Time", "PT", "Europe/Lisbon"),
+    ("Greenwich Standard Time", "001", "Atlantic/Reykjavik"),
+    ("Greenwich Standard Time", "CI", "Africa/Abidjan"),
+    ("Greenwich Standard Time", "GH", "Africa/Accra"),
+    ("Greenwich Standard Time", "SN", "Africa/Dakar"),
+    ("Sao Tome Standard Time", "001", "Africa/Sao_Tome"),
+    ("Morocco Standard Time", "001", "Africa/Casablanca"),
+    ("W. Europe Standard Time", "001", "Europe/Berlin"),
+    ("W. Europe Standard Time", "AD", "Europe/Andorra"),
+    ("W. Europe Standard Time", "AT", "Europe/Vienna"),
+    ("W. Europe Standard Time", "CH", "Europe/Zurich"),
+    ("W. Europe Standard Time", "GI", "Europe/Gibraltar"),
+    ("W. Europe Standard Time", "IT", "Europe/Rome"),
+    ("W. Europe Standard Time", "LI", "Europe/Vaduz"),
+    ("W. Europe Standard Time", "LU", "Europe/Luxembourg"),
+    ("W. Europe Standard Time", "MC", "Europe/Monaco"),
+    ("W. Europe Standard Time", "MT", "Europe/Malta"),
+    ("W. Europe Standard Time", "NL", "Europe/Amsterdam"),
+    ("W. Europe Standard Time", "NO", "Europe/Oslo"),
+    ("W. Europe Standard Time", "SE", "Europe/Stockholm"),
+    ("W. Europe Standard Time", "SM", "Europe/San_Marino"),
+    ("W. Europe Standard Time", "VA", "Europe/Vatican"),
+    ("Central Europe Standard Time", "001", "Europe/Budapest"),
+    ("Central Europe Standard Time", "AL", "Europe/Tirane"),
+    ("Central Europe Standard Time", "CZ", "Europe/Prague"),
+    ("Central Europe Standard Time", "ME", "Europe/Podgorica"),
+    ("Central Europe Standard Time", "RS", "Europe/Belgrade"),
+    ("Central Europe Standard Time", "SI", "Europe/Ljubljana"),
+    ("Central Europe Standard Time", "SK", "Europe/Bratislava"),
+    ("Romance Standard Time", "001", "Europe/Paris"),
+    ("Romance Standard Time", "BE", "Europe/Brussels"),
+    ("Romance Standard Time", "DK", "Europe/Copenhagen"),
+    ("Romance Standard Time", "ES", "Europe/Madrid"),
+    ("Central European Standard Time", "001", "Europe/Warsaw"),
+    ("Central European Standard Time", "BA", "Europe/Sarajevo"),
+    ("Central European Standard Time", "HR", "Europe/Zagreb"),
+    ("Central European Standard Time", "MK", "Europe/Skopje"),
+    ("W. Central Africa Standard Time", "001", "Africa/Lagos"),
+    ("W. Central Africa Standard Time", "AO", "Africa/Luanda"),
+    ("W. Central Africa Standard Time", "CD", "Africa/Kinshasa"),
+    ("W. Central Africa Standard Time", "CM", "Africa/Douala"),
+    ("W. Central Africa Standard Time", "DZ", "Africa/Algiers"),
+    ("W. Central Africa Standard Time", "TN", "Africa/Tunis"),
+    ("Jordan Standard Time", "001", "Asia/Amman"),
+    ("GTB Standard Time", "001", "Europe/Bucharest"),
+    ("GTB Standard Time", "CY", "Asia/Nicosia"),
+    ("GTB Standard Time", "GR", "Europe/Athens"),
+    ("Middle East Standard Time", "001", "Asia/Beirut"),
+    ("Egypt Standard Time", "001", "Africa/Cairo"),
+    ("E. Europe Standard Time", "001", "Europe/Chisinau"),
+    ("Syria Standard Time", "001", "Asia/Damascus"),
+    ("West Bank Standard Time", "001", "Asia/Hebron"),
+    ("South Africa Standard Time", "001", "Africa/Johannesburg"),
+    ("South Africa Standard Time", "BW", "Africa/Gaborone"),
+    ("South Africa Standard Time", "MW", "Africa/Blantyre"),
+    ("South Africa Standard Time", "MZ", "Africa/Maputo"),
+    ("South Africa Standard Time", "RW", "Africa/Kigali"),
+    ("South Africa Standard Time", "ZM", "Africa/Lusaka"),
+    ("South Africa Standard Time", "ZW", "Africa/Harare"),
+    ("FLE Standard Time", "001", "Europe/Kiev"),
+    ("FLE Standard Time", "AX", "Europe/Mariehamn"),
+    ("FLE Standard Time", "BG", "Europe/Sofia"),
+    ("FLE Standard Time", "EE", "Europe/Tallinn"),
+    ("FLE Standard Time", "FI", "Europe/Helsinki"),
+    ("FLE Standard Time", "LT", "Europe/Vilnius"),
+    ("FLE Standard Time", "LV", "Europe/Riga"),
+    ("Israel Standard Time", "001", "Asia/Jerusalem"),
+    ("South Sudan Standard Time", "001", "Africa/Juba"),
+    ("Kaliningrad Standard Time", "001", "Europe/Kaliningrad"),
+    ("Sudan Standard Time", "001", "Africa/Khartoum"),
+    ("Libya Standard Time", "001", "Africa/Tripoli"),
+    ("Namibia Standard Time", "001", "Africa/Windhoek"),
+    ("Arabic Standard Time", "001", "Asia/Baghdad"),
+    ("Turkey Standard Time", "001", "Europe/Istanbul"),
+    ("Arab Standard Time", "001", "Asia/Riyadh"),
+    ("Arab Standard Time", "BH", "Asia/Bahrain"),
+    ("Arab Standard Time", "KW", "Asia/Kuwait"),
+    ("Arab Standard Time", "QA", "Asia/Qatar"),
+    ("Arab Standard Time", "YE", "Asia/Aden"),
+    ("Belarus Standard Time", "001", "Europe/Minsk"),
+    ("Russian Standard Time", "001", "Europe/Moscow"),
+    ("Russian Standard Time", "UA", "Europe/Simferopol"),
+    ("E. Africa Standard Time", "001", "Africa/Nairobi"),
+    ("E. Africa Standard Time", "ET", "Africa/Addis_Ababa"),
+    ("E. Africa Standard Time", "SO", "Africa/Mogadishu"),
+    ("E. Africa Standard Time", "TZ", "Africa/Dar_es_Salaam"),
+    ("E. Africa Standard Time", "UG", "Africa/Kampala"),
+    ("Volgograd Standard Time", "001", "Europe/Volgograd"),
+    ("Iran Standard Time", "001", "Asia/Tehran"),
+    ("Arabian Standard Time", "001", "Asia/Dubai"),
+    ("Arabian Standard Time", "OM", "Asia/Muscat"),
+    ("Astrakhan Standard Time", "001", "Europe/Astrakhan"),
+    ("Azerbaijan Standard Time", "001", "Asia/Baku"),
+    ("Russia Time Zone 3", "001", "Europe/Samara"),
+    ("Mauritius Standard Time", "001", "Indian/Mauritius"),
+    ("Saratov Standard Time", "001", "Europe/Saratov"),
+    ("Georgian Standard Time", "001", "Asia/Tbilisi"),
+    ("Caucasus Standard Time", "001", "Asia/Yerevan"),
+    ("Afghanistan Standard Time", "001", "Asia/Kabul"),
+    ("West Asia Standard Time", "001", "Asia/Tashkent"),
+    ("Ekaterinburg Standard Time", "001", "Asia/Yekaterinburg"),
+    ("Pakistan Standard Time", "001", "Asia/Karachi"),
+    ("Qyzylorda Standard Time", "001", "Asia/Qyzylorda"),
+    ("India Standard Time", "001", "Asia/Kolkata"),
+    ("Sri Lanka Standard Time", "001", "Asia/Colombo"),
+    ("Nepal Standard Time", "001", "Asia/Kathmandu"),
+    ("Central Asia Standard Time", "001", "Asia/Almaty"),
+    ("Bangladesh Standard Time", "001", "Asia/Dhaka"),
+    ("Omsk Standard Time", "001", "Asia/Omsk"),
+    ("Myanmar Standard Time", "001", "Asia/Yangon"),
+    ("SE Asia Standard Time", "001", "Asia/Bangkok"),
+    ("SE Asia Standard Time", "ID", "Asia/Jakarta"),
+    ("SE Asia Standard Time", "KH", "Asia/Phnom_Penh"),
+    ("SE Asia Standard Time", "LA", "Asia/Vientiane"),
+    ("SE Asia Standard Time", "VN", "Asia/Ho_Chi_Minh"),
+    ("Altai Standard Time", "001", "Asia/Barnaul"),
+    ("W. Mongolia Standard Time", "001", "Asia/Hovd"),
+    ("North Asia Standard Time", "001", "Asia/Krasnoyarsk"),
+    ("N. Central Asia Standard Time", "001", "Asia/Novosibirsk"),
+    ("Tomsk Standard Time", "001", "Asia/Tomsk"),
+    ("China Standard Time", "001", "Asia/Shanghai"),
+    ("China Standard Time", "HK", "Asia/Hong_Kong"),
+    ("China Standard Time", "MO", "Asia/Macau"),
+    ("North Asia East Standard Time", "001", "Asia/Irkutsk"),
+    ("Singapore Standard Time", "001", "Asia/Singapore"),
+    ("Singapore Standard Time", "BN", "Asia/Brunei"),
+    ("Singapore Standard Time", "ID", "Asia/Makassar"),
+    ("Singapore Standard Time", "MY", "Asia/Kuala_Lumpur"),
+    ("Singapore Standard Time", "PH", "Asia/Manila"),
+    ("W. Australia Standard Time", "001", "Australia/Perth"),
+    ("Taipei Standard Time", "001", "Asia/Taipei"),
+    ("Ulaanbaatar Standard Time", "001", "Asia/Ulaanbaatar"),
+    ("Aus Central W. Standard Time", "001", "Australia/Eucla"),
+    ("Transbaikal Standard Time", "001", "Asia/Chita"),
+    ("Tokyo Standard Time", "001", "Asia/Tokyo"),
+    ("Tokyo Standard Time", "ID", "Asia/Jayapura"),
+    ("Tokyo Standard Time", "PW", "Pacific/Palau"),
+    ("Tokyo Standard Time", "TL", "Asia/Dili"),
+    ("North Korea Standard Time", "001", "Asia/Pyongyang"),
+    ("Korea Standard Time", "001", "Asia/Seoul"),
+    ("Yakutsk Standard Time", "001", "Asia/Yakutsk"),
+    ("Cen. Australia Standard Time", "001", "Australia/Adelaide"),
+    ("AUS Central Standard Time", "001", "Australia/Darwin"),
+    ("E. Australia Standard Time", "001", "Australia/Brisbane"),
+    ("AUS Eastern Standard Time", "001", "Australia/Sydney"),
+    ("West Pacific Standard Time", "001", "Pacific/Port_Moresby"),
+    ("West Pacific Standard Time", "GU", "Pacific/Guam"),
+    ("Tasmania Standard Time", "001", "Australia/Hobart"),
+    ("Vladivostok Standard Time", "001", "Asia/Vladivostok"),
+    ("Lord Howe Standard Time", "001", "Australia/Lord_Howe"),
+    ("Bougainville Standard Time", "001", "Pacific/Bougainville"),
+    ("Russia Time Zone 10", "001", "Asia/Srednekolymsk"),
+    ("Magadan Standard Time", "001", "Asia/Magadan"),
+    ("Norfolk Standard Time", "001", "Pacific/Norfolk"),
+    ("Sakhalin Standard Time", "001", "Asia/Sakhalin"),
+    ("Central Pacific Standard Time", "001", "Pacific/Guadalcanal"),
+    ("Russia Time Zone 11", "001", "Asia/Kamchatka"),
+    ("New Zealand Standard Time", "001", "Pacific/Auckland"),
+    ("UTC+12", "001", "Etc/GMT-12"),
+    ("Fiji Standard Time", "001", "Pacific/Fiji"),
+    ("Chatham Islands Standard Time", "001", "Pacific/Chatham"),
+    ("UTC+13", "001", "Etc/GMT-13"),
+    ("Tonga Standard Time", "001", "Pacific/Tongatapu"),
+    ("Samoa Standard Time", "001", "Pacific/Apia"),
+    ("Line Islands Standard Time", "001", "Pacific/Kiritimati"),
+];
+
+// glibc's SUPPORTED UTF-8 locales, as "language_TERRITORY[@modifier]".
+// Others don't get generated, and the installed system would fall back to
+// C.
+const SUPPORTED: &[&str] = &[
+    "aa_DJ", "aa_ER", "aa_ER@saaho", "aa_ET", "af_ZA", "agr_PE", "ak_GH", "am_ET", "an_ES",
+    "anp_IN", "ar_AE", "ar_BH", "ar_DZ", "ar_EG", "ar_IN", "ar_IQ", "ar_JO", "ar_KW", "ar_LB",
+    "ar_LY", "ar_MA", "ar_OM", "ar_QA", "ar_SA", "ar_SD", "ar_SS", "ar_SY", "ar_TN", "ar_YE",
+    "as_IN", "ast_ES", "ayc_PE", "az_AZ", "az_IR", "be_BY", "be_BY@latin", "bem_ZM", "ber_DZ",
+    "ber_MA", "bg_BG", "bhb_IN", "bho_IN", "bho_NP", "bi_VU", "bn_BD", "bn_IN", "bo_CN", "bo_IN",
+    "br_FR", "brx_IN", "bs_BA", "byn_ER", "ca_AD", "ca_ES", "ca_ES@valencia", "ca_FR", "ca_IT",
+    "ce_RU", "chr_US", "ckb_IQ", "cmn_TW", "crh_UA", "cs_CZ", "csb_PL", "cv_RU", "cy_GB", "da_DK",
+    "de_AT", "de_BE", "de_CH", "de_DE", "de_IT", "de_LI", "de_LU", "doi_IN", "dsb_DE", "dv_MV",
+    "dz_BT", "el_CY", "el_GR", "en_AG", "en_AU", "en_BW", "en_CA", "en_DK", "en_GB", "en_HK",
+    "en_IE", "en_IL", "en_IN", "en_NG", "en_NZ", "en_PH", "en_SC", "en_SG", "en_US", "en_ZA",
+    "en_ZM", "en_ZW", "es_AR", "es_BO", "es_CL", "es_CO", "es_CR", "es_CU", "es_DO", "es_EC",
+    "es_ES", "es_GT", "es_HN", "es_MX", "es_NI", "es_PA", "es_PE", "es_PR", "es_PY", "es_SV",
+    "es_US", "es_UY", "es_VE", "et_EE", "eu_ES", "eu_FR", "fa_IR", "ff_SN", "fi_FI", "fil_PH",
+    "fo_FO", "fr_BE", "fr_CA", "fr_CH", "fr_FR", "fr_LU", "fur_IT", "fy_DE", "fy_NL", "ga_IE",
+    "gd_GB", "gez_ER", "gez_ER@abegede", "gez_ET", "gez_ET@abegede", "gl_ES", "gu_IN", "gv_GB",
+    "ha_NG", "hak_TW", "he_IL", "hi_IN", "hif_FJ", "hne_IN", "hr_HR", "hsb_DE", "ht_HT", "hu_HU",
+    "hy_AM", "ia_FR", "id_ID", "ig_NG", "ik_CA", "is_IS", "it_CH", "it_IT", "iu_CA", "ja_JP",
+    "ka_GE", "kab_DZ", "kk_KZ", "kl_GL", "km_KH", "kn_IN", "ko_KR", "kok_IN", "ks_IN",
+    "ks_IN@devanagari", "ku_TR", "kw_GB", "ky_KG", "lb_LU", "lg_UG", "li_BE", "li_NL", "lij_IT",
+    "ln_CD", "lo_LA", "lt_LT", "lv_LV", "lzh_TW", "mag_IN", "mai_IN", "mai_NP", "mfe_MU", "mg_MG",
+    "mhr_RU", "mi_NZ", "miq_NI", "mjw_IN", "mk_MK", "ml_IN", "mn_MN", "mni_IN", "mnw_MM", "mr_IN",
+    "ms_MY", "mt_MT", "my_MM", "nan_TW", "nan_TW@latin", "nb_NO", "nds_DE", "nds_NL", "ne_NP",
+    "nhn_MX", "niu_NU", "niu_NZ", "nl_AW", "nl_BE", "nl_NL", "nn_NO", "nr_ZA", "nso_ZA", "oc_FR",
+    "om_ET", "om_KE", "or_IN", "os_RU", "pa_IN", "pa_PK", "pap_AW", "pap_CW", "pl_PL", "ps_AF",
+    "pt_BR", "pt_PT", "quz_PE", "raj_IN", "rif_MA", "ro_RO", "ru_RU", "ru_UA", "rw_RW", "sa_IN",
+    "sah_RU", "sat_IN", "sc_IT", "sd_IN", "sd_IN@devanagari", "se_NO", "sgs_LT", "shn_MM", "shs_CA",
+    "si_LK", "sid_ET", "sk_SK", "sl_SI", "sm_WS", "so_DJ", "so_ET", "so_KE", "so_SO", "sq_AL",
+    "sq_MK", "sr_ME", "sr_RS", "sr_RS@latin", "ss_ZA", "st_ZA", "sv_FI", "sv_SE", "sw_KE", "sw_TZ",
+    "szl_PL", "ta_IN", "ta_LK", "tcy_IN", "te_IN", "tg_TJ", "th_TH", "the_NP", "ti_ER", "ti_ET",
+    "tig_ER", "tk_TM", "tl_PH", "tn_ZA", "to_TO", "tpi_PG", "tr_CY", "tr_TR", "ts_ZA", "tt_RU",
+    "tt_RU@iqtelif", "ug_CN", "uk_UA", "unm_US", "ur_IN", "ur_PK", "uz_UZ", "uz_UZ@cyrillic",
+    "ve_ZA", "vi_VN", "wa_BE", "wae_CH", "wal_ET", "wo_SN", "xh_ZA", "yi_US", "yo_NG", "yue_HK",
+    "yuw_PG", "zh_CN", "zh_HK", "zh_SG", "zh_TW", "zu_ZA",
+];
+
+// The territory of a language spoken in several, from CLDR's likely
+// subtags. Languages spoken in one take that one.
+const DEFAULT_TERRITORIES: &[(&str, &str)] = &[
+    ("aa", "ET"),
+    ("ar", "EG"),
+    ("az", "AZ"),
+    ("ber", "MA"),
+    ("bho", "IN"),
+    ("bn", "BD"),
+    ("bo", "CN"),
+    ("ca", "ES"),
+    ("de", "DE"),
+    ("el", "GR"),
+    ("en", "US"),
+    ("es", "ES"),
+    ("eu", "ES"),
+    ("fr", "FR"),
+    ("fy", "NL"),
+    ("gez", "ET"),
+    ("it", "IT"),
+    ("li", "NL"),
+    ("mai", "IN"),
+    ("nds", "DE"),
+    ("niu", "NU"),
+    ("nl", "NL"),
+    ("om", "ET"),
+    ("pa", "IN"),
+    ("pap", "CW"),
+    ("pt", "BR"),
+    ("ru", "RU"),
+    ("so", "SO"),
+    ("sq", "AL"),
+    ("sr", "RS"),
+    ("sv", "SE"),
+    ("sw", "TZ"),
+    ("ta", "IN"),
+    ("ti", "ET"),
+    ("tr", "TR"),
+    ("ur", "PK"),
+    ("zh", "CN"),
+];
+
+// A Windows locale name, like "fr-FR" or "sr-Latn-RS", to a glibc one, like
+// "fr_FR.UTF-8" or "sr_RS.UTF-8@latin". Names without a country have no
+// equivalent, and countries glibc has no locale for get the language's
+// usual one, e.g. "en-DE" gets "en_US.UTF-8".
+pub fn ubuntu_locale(name: &str) -> Option<String> {
+    let mut parts = name.split(&['-', '_'][..]);
+    let language = parts.next()?.to_ascii_lowercase();
+    if !(2..=3).contains(&language.len()) || !language.chars().all(|v| v.is_ascii_lowercase()) {
+        return None;
+    }
+
+    let mut script = None;
+    let mut territory = None;
+    let mut variant = None;
+    for part in parts {
+        match part.len() {
+            4 if territory.is_none() => script = Some(part.to_ascii_lowercase()),
+            2 if territory.is_none() && part.chars().all(|v| v.is_ascii_alphabetic()) => territory = Some(part.to_ascii_uppercase()),
+            _ if territory.is_some() => variant = Some(part.to_ascii_lowercase()),
+            _ => return None,
+        }
+    }
+    let territory = territory?;
+
+    // Serbian defaults to Cyrillic and Uzbek to Latin, the other script is
+    // a modifier.
+    let modifier = match (language.as_str(), script.as_deref(), variant.as_deref()) {
+        ("sr", Some("latn"), _) => Some("latin"),
+        ("uz", Some("cyrl"), _) => Some("cyrillic"),
+        ("ca", _, Some("valencia")) => Some("valencia"),
+        _ => None,
+    };
+    let supported = |territory: &str| {
+        let name = match modifier {
+            Some(modifier) => format!("{}_{}@{}", language, territory, modifier),
+            None => format!("{}_{}", language, territory),
+        };
+        SUPPORTED.iter().find(|&&v| v == name)
+    };
+    let default = DEFAULT_TERRITORIES.iter().find(|v| v.0 == language).map(|v| v.1).or_else(|| {
+        let prefix = format!("{}_", language);
+        SUPPORTED.iter().find(|v| v.starts_with(&prefix)).map(|v| &v[prefix.len()..prefix.len() + 2])
+    });
+    let name = supported(&territory).or_else(|| supported(default?))?;
+    Some(match name.find('@') {
+        Some(at) => format!("{}.UTF-8{}", &name[..at], &name[at..]),
+        None => format!("{}.UTF-8", name),
+    })
+}
+
+// The country in a Windows locale name, e.g. "CA" in "fr-CA".
+fn territory(name: &str) -> Option<String> {
+    name.split(&['-', '_'][..])
+        .skip(1)
+        .find(|v| v.len() == 2 && v.chars().all(|v| v.is_ascii_alphabetic()))
+        .map(|v| v.to_ascii_uppercase())
+}
+
+// A KLID, like "0000040C", to an XKB layout and variant. Layouts of IMEs and
+// unknown variants fall back to the language's main layout.
+pub fn xkb_layout(klid: &str) -> Option<(&'static str, &'static str)> {
+    let klid = klid.to_ascii_uppercase();
+    let find = |klid: &str| KEYBOARD_LAYOUTS.iter().find(|v| v.0 == klid).map(|v| (v.1, v.2));
+    if klid.len() != 8 {
+        return None;
+    }
+    find(&klid).or_else(|| find(&format!("0000{}", &klid[4..])))
+}
+
+// A Windows time zone, like "Romance Standard Time", to an IANA one, using
+// the zone of `territory` (an ISO 3166 code) when it has its own.
+pub fn iana_timezone(windows: &str, territory: Option<&str>) -> Option<&'static str> {
+    let zones = || TIME_ZONES.iter().filter(|v| v.0.eq_ignore_ascii_case(windows));
+    territory
+        .and_then(|territory| zones().find(|v| v.1.eq_ignore_ascii_case(territory)))
+        .or_else(|| zones().find(|v| v.1 == "001"))
+        .map(|v| v.2)
+}
+
+// What was found out from Windows, in Windows terms.
+#[derive(Debug, Clone, Default)]
+pub struct WindowsSettings {
+    // The display language, then the regional format, e.g. "fr-FR".
+    pub ui_language: Option<String>,
+    pub user_locale: Option<String>,
+    // The KLID of the input language.
+    pub keyboard_layout: Option<String>,
+    // The key name, e.g. "Romance Standard Time".
+    pub timezone: Option<String>,
+    // Where the user says they live, as an ISO 3166 code.
+    pub territory: Option<String>,
+}
+
+impl WindowsSettings {
+    // Fills what maps to something in Ubuntu, and leaves the rest alone.
+    pub fn apply(&self, config: &mut Autoinstall) {
+        let locale = self.ui_language.iter().chain(self.user_locale.iter()).filter_map(|v| ubuntu_locale(v)).next();
+        if let Some(locale) = locale {
+            config.locale = locale;
+        }
+        if let Some((layout, variant)) = self.keyboard_layout.as_deref().and_then(xkb_layout) {
+            config.keyboard.layout = layout.to_string();
+            config.keyboard.variant = variant.to_string();
+        }
+        // The regional format tells the country when the location isn't
+        // set.
+        let territory = self.territory.clone().or_else(|| territory(self.user_locale.as_deref()?));
+        if let Some(timezone) = self.timezone.as_deref().and_then(|v| iana_timezone(v, territory.as_deref())) {
+            config.timezone = Some(timezone.to_string());
+        }
+    }
+}
+
+#[cfg(windows)]
+pub use self::win::windows_settings;
+
+#[cfg(windows)]
+mod win {
+    use super::WindowsSettings;
+    use crate::win32::from_wide;
+
+    use winapi::um::timezoneapi::{GetDynamicTimeZoneInformation, TIME_ZONE_ID_INVALID};
+    use winapi::um::winnls::{GetGeoInfoW, GetUserDefaultLocaleName, GetUserDefaultUILanguage, GetUserGeoID, LCIDToLocaleName, GEOCLASS_NATION, GEO_ISO2};
+    use winapi::um::winnt::{LOCALE_NAME_MAX_LENGTH, MAKELCID, SORT_DEFAULT};
+    use winapi::um::winuser::{GetKeyboardLayoutNameW, KL_NAMELENGTH};
+
+    use std::mem;
+
+    fn non_empty(value: String) -> Option<String> {
+        if value.is_empty() {
+            None
+        } else {
+            Some(value)
+        }
+    }
+
+    fn ui_language() -> Option<String> {
+        let mut name = [0; LOCALE_NAME_MAX_LENGTH];
+        let lcid = MAKELCID(unsafe { GetUserDefaultUILanguage() }, SORT_DEFAULT);
+        let len = unsafe { LCIDToLocaleName(lcid, name.as_mut_ptr(), name.len() as i32, 0) };
+        if len == 0 {
+            return None;
+        }
+        non_empty(from_wide(&name))
+    }
+
+    fn user_locale() -> Option<String> {
+        let mut name = [0; LOCALE_NAME_MAX_LENGTH];
+        if unsafe { GetUserDefaultLocaleName(name.as_mut_ptr(), name.len() as i32) } == 0 {
+            return None;
+        }
+        non_empty(from_wide(&name))
+    }
+
+    fn keyboard_layout() -> Option<String> {
+        let mut name = [0; KL_NAMELENGTH as usize];
+        if unsafe { GetKeyboardLayoutNameW(name.as_mut_ptr()) } == 0 {
+            return None;
+        }
+        non_empty(from_wide(&name))
+    }
+
+    fn timezone() -> Option<String> {
+        let mut info = unsafe { mem::zeroed() };
+        if unsafe { GetDynamicTimeZoneInformation(&mut info) } == TIME_ZONE_ID_INVALID {
+            return None;
+        }
+        non_empty(from_wide(&info.TimeZoneKeyName))
+    }
+
+    fn territory() -> Option<String> {
+        let mut name = [0; 8];
+        let geo = unsafe { GetUserGeoID(GEOCLASS_NATION) };
+        if unsafe { GetGeoInfoW(geo, GEO_ISO2, name.as_mut_ptr(), name.len() as i32, 0) } == 0 {
+            return None;
+        }
+        non_empty(from_wide(&name))
+    }
+
+    // Whatever can't be read is left out, there's a default for all of it.
+    pub fn windows_settings() -> WindowsSettings {
+        WindowsSettings {
+            ui_language: ui_language(),
+            user_locale: user_locale(),
+            keyboard_layout: keyboard_layout(),
+            timezone: timezone(),
+            territory: territory(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_locales() {
+        assert_eq!(ubuntu_locale("fr-FR").as_deref(), Some("fr_FR.UTF-8"));
+        assert_eq!(ubuntu_locale("pt_br").as_deref(), Some("pt_BR.UTF-8"));
+        assert_eq!(ubuntu_locale("sr-Latn-RS").as_deref(), Some("sr_RS.UTF-8@latin"));
+        assert_eq!(ubuntu_locale("sr-Cyrl-RS").as_deref(), Some("sr_RS.UTF-8"));
+        assert_eq!(ubuntu_locale("uz-Cyrl-UZ").as_deref(), Some("uz_UZ.UTF-8@cyrillic"));
+        assert_eq!(ubuntu_locale("ca-ES-valencia").as_deref(), Some("ca_ES.UTF-8@valencia"));
+        assert_eq!(ubuntu_locale("fr"), None);
+        assert_eq!(ubuntu_locale("x-IV"), None);
+        assert_eq!(ubuntu_locale(""), None);
+    }
+
+    #[test]
+    fn falls_back_to_supported_locales() {
+        assert_eq!(ubuntu_locale("en-DE").as_deref(), Some("en_US.UTF-8"));
+        assert_eq!(ubuntu_locale("es-419").as_deref(), None);
+        assert_eq!(ubuntu_locale("es-GQ").as_deref(), Some("es_ES.UTF-8"));
+        assert_eq!(ubuntu_locale("pt-AO").as_deref(), Some("pt_BR.UTF-8"));
+        assert_eq!(ubuntu_locale("sr-Latn-BA").as_deref(), Some("sr_RS.UTF-8@latin"));
+        assert_eq!(ubuntu_locale("ja-US").as_deref(), Some("ja_JP.UTF-8"));
+        // Languages glibc doesn't know at all.
+        assert_eq!(ubuntu_locale("haw-US"), None);
+
+        for (language, territory) in DEFAULT_TERRITORIES {
+            assert!(SUPPORTED.contains(&format!("{}_{}", language, territory).as_str()), "{}", language);
+        }
+        let mut sorted = SUPPORTED.to_vec();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted, SUPPORTED);
+    }
+
+    #[test]
+    fn maps_keyboard_layouts() {
+        assert_eq!(xkb_layout("0000040C"), Some(("fr", "")));
+        assert_eq!(xkb_layout("0000040c"), Some(("fr", "")));
+        assert_eq!(xkb_layout("00010409"), Some(("us", "dvorak")));
+        // An IME, and a variant we don't know, on top of known languages.
+        assert_eq!(xkb_layout("E0010411"), Some(("jp", "")));
+        assert_eq!(xkb_layout("00050407"), Some(("de", "")));
+        assert_eq!(xkb_layout("0000FFFF"), None);
+        assert_eq!(xkb_layout("409"), None);
+
+        for (idx, (klid, layout, variant)) in KEYBOARD_LAYOUTS.iter().enumerate() {
+            assert!(klid.len() == 8 && klid.chars().all(|v| v.is_ascii_digit() || v.is_ascii_uppercase()), "{}", klid);
+            assert!(!layout.is_empty() && !layout.contains(char::is_whitespace), "{}", klid);
+            assert!(!variant.contains(char::is_whitespace), "{}", klid);
+            assert!(KEYBOARD_LAYOUTS[..idx].iter().all(|v| v.0 != *klid), "{}", klid);
+        }
+    }
+
+    #[test]
+    fn maps_time_zones() {
+        assert_eq!(iana_timezone("Romance Standard Time", None), Some("Europe/Paris"));
+        assert_eq!(iana_timezone("Eastern Standard Time", Some("CA")), Some("America/Toronto"));
+        assert_eq!(iana_timezone("eastern standard time", Some("ca")), Some("America/Toronto"));
+        // Countries without a zone of their own get the default one.
+        assert_eq!(iana_timezone("Eastern Standard Time", Some("FR")), Some("America/New_York"));
+        assert_eq!(iana_timezone("Martian Standard Time", None), None);
+
+        for (idx, (windows, territory, iana)) in TIME_ZONES.iter().enumerate() {
+            assert!(TIME_ZONES.iter().any(|v| v.0 == *windows && v.1 == "001"), "{}", windows);
+            assert!(territory.len() == 2 || *territory == "001", "{}", windows);
+            assert!(iana.contains('/') && !iana.contains(char::is_whitespace), "{}", iana);
+            assert!(TIME_ZONES[..idx].iter().all(|v| v.0 != *windows || v.1 != *territory), "{} {}", windows, territory);
+        }
+    }
+
+    #[test]
+    fn applies_windows_settings() {
+        let settings = WindowsSettings {
+            ui_language: Some("en-DE".to_string()),
+            user_locale: Some("de-CH".to_string()),
+            keyboard_layout: Some("00000807".to_string()),
+            timezone: Some("W. Europe Standard Time".to_string()),
+            territory: None,
+        };
+        let mut config = Autoinstall::default();
+        settings.apply(&mut config);
+        assert_eq!(config.locale, "en_US.UTF-8");
+        assert_eq!((config.keyboard.layout.as_str(), config.keyboard.variant.as_str()), ("ch", ""));
+        // The territory comes from the regional format, not from the
+        // locale it fell back to.
+        assert_eq!(config.timezone.as_deref(), Some("Europe/Zurich"));
+
+        let mut config = Autoinstall::default();
+        WindowsSettings::default().apply(&mut config);
+        assert_eq!(config.locale, Autoinstall::default().locale);
+        assert_eq!(config.timezone, None);
+    }
+}
@@ -2,11 +2,36 @@ mod interop;
 use interop::{ro_initialize, RoInitType};
 
 mod wizard;
-use wizard::{WizardUI, WizardEvent};
+use wizard::{Image, WizardUI, WizardEvent, WriteMode};
 
 mod desktopwindowxamlsource;
 use desktopwindowxamlsource::IDesktopWindowXamlSourceNative;
 
+mod autoinstall;
+mod bootable;
+mod bootcfg;
+mod compat;
+mod crypt;
+mod disk;
+mod dualboot;
+mod efi;
+mod ext4;
+mod fat32;
+mod filecopy;
+mod iso;
+mod locale;
+mod migration;
+mod partition;
+mod persistence;
+mod release;
+mod safety;
+mod seed;
+mod system;
+mod verify;
+mod wifi;
+mod win32;
+mod writer;
+
 use winit::event::{Event, WindowEvent};
 use winit::event_loop::{ControlFlow, EventLoop};
 use winit::window::WindowBuilder;
@@ -58,14 +83,89 @@ fn main() -> winrt::Result<()> {
             } if window_id == win32_window_id => {
                 unsafe { SetWindowPos(hwnd_xaml_island, ptr::null_mut(), 0, 0, size.width as i32, size.height as i32, /*SWP_SHOWWINDOW*/ 0x40); }
             }
+            Event::UserEvent(WizardEvent::CheckCompatibility) => {
+                if let Err(err) = wizard.go_to_compatibility() {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
             Event::UserEvent(WizardEvent::GoToStep2) => {
                 if let Err(err) = wizard.go_to_step2() {
                     eprintln!("{:?}", err);
                     *control_flow = ControlFlow::Exit
                 }
             }
-            Event::UserEvent(WizardEvent::UsbDeviceFound(name)) => {
-                if let Err(err) = wizard.add_usb_device(&name) {
+            Event::UserEvent(WizardEvent::PickImage) => {
+                if let Err(err) = wizard.pick_image() {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::UsbDeviceFound(device)) => {
+                if let Err(err) = wizard.add_usb_device(device) {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::ShowAllDisks(show_all)) => {
+                if let Err(err) = wizard.set_show_all_disks(show_all) {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::VerifyAfterWriting(verify)) => {
+                wizard.set_verify(verify);
+            }
+            Event::UserEvent(WizardEvent::Persistence(persistence)) => {
+                wizard.set_persistence(persistence);
+            }
+            Event::UserEvent(WizardEvent::CopyFiles(copy_files)) => {
+                wizard.set_write_mode(if copy_files { WriteMode::Files } else { WriteMode::Image });
+            }
+            Event::UserEvent(WizardEvent::CustomizeBoot(customize)) => {
+                wizard.set_customize_boot(customize);
+            }
+            Event::UserEvent(WizardEvent::Nomodeset(nomodeset)) => {
+                wizard.set_nomodeset(nomodeset);
+            }
+            Event::UserEvent(WizardEvent::Autoinstall(autoinstall)) => {
+                wizard.set_autoinstall(autoinstall);
+            }
+            Event::UserEvent(WizardEvent::SerialConsole(serial_console)) => {
+                wizard.set_serial_console(serial_console);
+            }
+            Event::UserEvent(WizardEvent::SeedUrl(url)) => {
+                wizard.set_seed_url(url);
+            }
+            Event::UserEvent(WizardEvent::CreateAutoinstall(create)) => {
+                wizard.set_create_autoinstall(create);
+            }
+            Event::UserEvent(WizardEvent::AutoinstallField(field, value)) => {
+                wizard.set_autoinstall_field(field, value);
+            }
+            Event::UserEvent(WizardEvent::InstallSshServer(install)) => {
+                wizard.set_install_ssh_server(install);
+            }
+            Event::UserEvent(WizardEvent::UseLvm(lvm)) => {
+                wizard.set_use_lvm(lvm);
+            }
+            Event::UserEvent(WizardEvent::InstallAlongsideWindows(alongside)) => {
+                wizard.set_install_alongside_windows(alongside);
+            }
+            Event::UserEvent(WizardEvent::MigrateWifi(migrate)) => {
+                wizard.set_migrate_wifi(migrate);
+            }
+            Event::UserEvent(WizardEvent::MigrateFiles(migrate)) => {
+                wizard.set_migrate_files(migrate);
+            }
+            Event::UserEvent(WizardEvent::UserFilesMeasured(user_files)) => {
+                if let Err(err) = wizard.show_user_files(user_files) {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::SaveUserData) => {
+                if let Err(err) = wizard.save_user_data() {
                     eprintln!("{:?}", err);
                     *control_flow = ControlFlow::Exit
                 }
@@ -82,6 +182,69 @@ fn main() -> winrt::Result<()> {
                     *control_flow = ControlFlow::Exit
                 }
             }
+            Event::UserEvent(WizardEvent::GoToStep4(image)) => {
+                if let Err(err) = wizard.go_to_step4(Image::Downloaded(image)) {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::CheckProgress(cur, total)) => {
+                if let Err(err) = wizard.set_checking_image(cur, total) {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::WriteProgress(cur, total)) => {
+                if let Err(err) = wizard.set_writing(cur, total) {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::VerifyProgress(cur, total)) => {
+                if let Err(err) = wizard.set_verifying(cur, total) {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::CreatingSeed) => {
+                if let Err(err) = wizard.set_creating_seed() {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::CreatingPersistence) => {
+                if let Err(err) = wizard.set_creating_persistence() {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::WriteFinished(res)) => {
+                if let Err(err) = wizard.set_write_result(res) {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::RebootChecklist) => {
+                if let Err(err) = wizard.go_to_reboot_checklist() {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
+            Event::UserEvent(WizardEvent::SuspendBitLocker(suspend)) => {
+                wizard.set_suspend_bitlocker(suspend);
+            }
+            Event::UserEvent(WizardEvent::DisableFastStartup(disable)) => {
+                wizard.set_disable_fast_startup(disable);
+            }
+            Event::UserEvent(WizardEvent::PrepareAhciSwitch(prepare)) => {
+                wizard.set_prepare_ahci(prepare);
+            }
+            Event::UserEvent(WizardEvent::RebootToDrive) => {
+                if let Err(err) = wizard.reboot_to_drive() {
+                    eprintln!("{:?}", err);
+                    *control_flow = ControlFlow::Exit
+                }
+            }
             _ => (),
         }
     });
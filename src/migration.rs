@@ -0,0 +1,447 @@
+use crate::autoinstall::write_file_command;
+use crate::disk::format_size;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Copies the user's files from Windows into their new home folder. Subiquity
+// only creates the user on the first start of the installed system, so the
+// late-commands leave a manifest and a script there, which a service runs
+// once the user exists. Only makes sense when Windows stays.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ItemKind {
+    Documents,
+    Pictures,
+    Desktop,
+    Downloads,
+    ChromeBookmarks,
+    EdgeBookmarks,
+    FirefoxBookmarks,
+}
+
+impl ItemKind {
+    pub fn label(self) -> &'static str {
+        match self {
+            ItemKind::Documents => "Documents",
+            ItemKind::Pictures => "Pictures",
+            ItemKind::Desktop => "Desktop",
+            ItemKind::Downloads => "Downloads",
+            ItemKind::ChromeBookmarks => "Chrome bookmarks",
+            ItemKind::EdgeBookmarks => "Edge bookmarks",
+            ItemKind::FirefoxBookmarks => "Firefox bookmarks",
+        }
+    }
+
+    // Where it goes, from the home folder. Browsers can import the
+    // bookmarks from there.
+    fn destination(self) -> &'static str {
+        match self {
+            ItemKind::Documents => "Documents",
+            ItemKind::Pictures => "Pictures",
+            ItemKind::Desktop => "Desktop",
+            ItemKind::Downloads => "Downloads",
+            ItemKind::ChromeBookmarks => "Windows bookmarks/Chrome.json",
+            ItemKind::EdgeBookmarks => "Windows bookmarks/Edge.json",
+            ItemKind::FirefoxBookmarks => "Windows bookmarks/Firefox places.sqlite",
+        }
+    }
+
+    fn id(self) -> &'static str {
+        match self {
+            ItemKind::Documents => "documents",
+            ItemKind::Pictures => "pictures",
+            ItemKind::Desktop => "desktop",
+            ItemKind::Downloads => "downloads",
+            ItemKind::ChromeBookmarks => "chrome-bookmarks",
+            ItemKind::EdgeBookmarks => "edge-bookmarks",
+            ItemKind::FirefoxBookmarks => "firefox-bookmarks",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Item {
+    pub kind: ItemKind,
+    // From the root of the Windows partition, with forward slashes.
+    pub source: String,
+    pub size: u64,
+    pub files: u64,
+}
+
+// The size and number of files under `path`. Links and junctions don't get
+// followed, and what can't be read doesn't count.
+pub fn measure(path: &Path) -> (u64, u64) {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return (0, 0),
+    };
+    if metadata.file_type().is_symlink() {
+        return (0, 0);
+    }
+    if !metadata.is_dir() {
+        return (metadata.len(), 1);
+    }
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return (0, 0),
+    };
+    entries.filter_map(Result::ok).fold((0, 0), |(size, files), entry| {
+        let (entry_size, entry_files) = measure(&entry.path());
+        (size + entry_size, files + entry_files)
+    })
+}
+
+// `path` from the root of the partition mounted at `root`, if it's on it.
+fn relative_source(root: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(root).ok()?;
+    let parts: Option<Vec<&str>> = relative.components().map(|v| v.as_os_str().to_str()).collect();
+    let parts = parts?;
+    if parts.is_empty() {
+        return None;
+    }
+    Some(parts.join("/"))
+}
+
+impl Item {
+    // Measures `path`, on the partition mounted at `root`. None when there's
+    // nothing to copy, or it's on another partition.
+    pub fn new(kind: ItemKind, root: &Path, path: &Path) -> Option<Item> {
+        let source = relative_source(root, path)?;
+        let (size, files) = measure(path);
+        if files == 0 {
+            return None;
+        }
+        Some(Item { kind, source, size, files })
+    }
+
+    pub fn describe(&self) -> String {
+        match self.files {
+            1 => format!("{} ({})", self.kind.label(), format_size(self.size)),
+            files => format!("{} ({} files, {})", self.kind.label(), files, format_size(self.size)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Manifest {
+    // The UUID Linux gives the Windows partition: the NTFS serial number, in
+    // hex.
+    pub volume: String,
+    pub items: Vec<Item>,
+}
+
+const DIR: &str = "/var/lib/windows-migration";
+const SERVICE: &str = "windows-migration.service";
+
+// Copies the files of the manifest given as first argument. The second and
+// third ones stand in for the Windows partition and the home folder.
+const SCRIPT: &str = r#"#!/bin/sh
+set -u
+manifest=$1
+home=${3:-$(getent passwd 1000 | cut -d: -f6)}
+if [ -z "$home" ] || [ ! -d "$home" ]; then
+    echo "The first user has no home folder yet" >&2
+    exit 1
+fi
+if [ $# -ge 2 ]; then
+    source=$2
+else
+    volume=$(sed -n 's/^volume=//p' "$manifest")
+    source=$(mktemp -d)
+    if ! mount -o ro "UUID=$volume" "$source"; then
+        rmdir "$source"
+        exit 1
+    fi
+fi
+owner=$(stat -c %u:%g "$home")
+status=0
+tab=$(printf '\t')
+while IFS="$tab" read -r kind id size path dest; do
+    [ "$kind" = copy ] || continue
+    from="$source/$path"
+    to="$home/$dest"
+    echo "Copying $id"
+    if [ -d "$from" ]; then
+        mkdir -p "$to" && cp -R --preserve=timestamps "$from/." "$to" || status=1
+    elif [ -e "$from" ]; then
+        mkdir -p "$(dirname "$to")" && cp --preserve=timestamps "$from" "$to" || status=1
+    fi
+    [ -e "$home/${dest%%/*}" ] && chown -R "$owner" "$home/${dest%%/*}"
+done < "$manifest"
+if [ $# -lt 2 ]; then
+    umount "$source" && rmdir "$source"
+fi
+# Whatever failed would fail again.
+mv "$manifest" "$manifest.done"
+exit $status
+"#;
+
+impl Manifest {
+    pub fn size(&self) -> u64 {
+        self.items.iter().map(|v| v.size).sum()
+    }
+
+    // A line per item, tab separated, which no Windows file name holds.
+    pub fn render(&self) -> String {
+        let mut out = format!("# Files to copy from Windows\nvolume={}\n", self.volume);
+        for item in &self.items {
+            out.push_str(&format!("copy\t{}\t{}\t{}\t{}\n", item.kind.id(), item.size, item.source, item.kind.destination()));
+        }
+        out
+    }
+
+    // The late-commands installing the manifest, the script, and the service
+    // running it once the user exists.
+    pub fn late_commands(&self) -> Vec<String> {
+        if self.items.is_empty() {
+            return Vec::new();
+        }
+        let unit = format!(
+            "[Unit]\nDescription=Copy the files from Windows\nAfter=cloud-init.service local-fs.target\nConditionPathExists={dir}/manifest\n\n[Service]\nType=oneshot\nExecStart=/bin/sh {dir}/migrate.sh {dir}/manifest\n\n[Install]\nWantedBy=multi-user.target\n",
+            dir = DIR,
+        );
+        vec![
+            write_file_command(&format!("/target{}/manifest", DIR), &self.render()),
+            write_file_command(&format!("/target{}/migrate.sh", DIR), SCRIPT),
+            format!("{} && chmod 644 /target/etc/systemd/system/{}", write_file_command(&format!("/target/etc/systemd/system/{}", SERVICE), &unit), SERVICE),
+            format!("mkdir -p /target/etc/systemd/system/multi-user.target.wants && ln -sf /etc/systemd/system/{0} /target/etc/systemd/system/multi-user.target.wants/{0}", SERVICE),
+        ]
+    }
+}
+
+// The folders Windows keeps them in, and where the bookmarks of each browser
+// are. Firefox keeps them in the most recently used profile.
+pub fn locations(folders: &[(ItemKind, PathBuf)], local_app_data: &Path, app_data: &Path) -> Vec<(ItemKind, PathBuf)> {
+    let mut locations = folders.to_vec();
+    locations.push((ItemKind::ChromeBookmarks, local_app_data.join("Google").join("Chrome").join("User Data").join("Default").join("Bookmarks")));
+    locations.push((ItemKind::EdgeBookmarks, local_app_data.join("Microsoft").join("Edge").join("User Data").join("Default").join("Bookmarks")));
+    let firefox = fs::read_dir(app_data.join("Mozilla").join("Firefox").join("Profiles")).into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|v| v.path().join("places.sqlite"))
+        .filter_map(|v| Some((fs::metadata(&v).ok()?.modified().ok()?, v)))
+        .max();
+    if let Some((_, path)) = firefox {
+        locations.push((ItemKind::FirefoxBookmarks, path));
+    }
+    locations
+}
+
+#[cfg(windows)]
+pub use self::win::user_files;
+
+#[cfg(windows)]
+mod win {
+    use super::{locations, Item, ItemKind, Manifest};
+    use crate::disk::bitlocker_drives;
+    use crate::win32::{ioctl, open_device};
+
+    use winapi::um::winioctl::FSCTL_GET_NTFS_VOLUME_DATA;
+    use winapi::um::winnt::GENERIC_READ;
+    use winreg::RegKey;
+    use winreg::enums::HKEY_CURRENT_USER;
+
+    use std::io;
+    use std::path::PathBuf;
+
+    fn other(msg: String) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, msg)
+    }
+
+    // The first field of NTFS_VOLUME_DATA_BUFFER.
+    fn ntfs_serial(drive: &str) -> io::Result<u64> {
+        let volume = open_device(&format!(r"\\.\{}", drive), GENERIC_READ)?;
+        let mut buf = [0u8; 128];
+        ioctl(&volume, FSCTL_GET_NTFS_VOLUME_DATA, &[], &mut buf)?;
+        let mut serial = [0; 8];
+        serial.copy_from_slice(&buf[..8]);
+        Ok(u64::from_le_bytes(serial))
+    }
+
+    // The folders as Explorer has them, wherever they got moved to.
+    fn shell_folders() -> Vec<(ItemKind, PathBuf)> {
+        let key = match RegKey::predef(HKEY_CURRENT_USER).open_subkey(r"Software\Microsoft\Windows\CurrentVersion\Explorer\Shell Folders") {
+            Ok(key) => key,
+            Err(_) => return Vec::new(),
+        };
+        [
+            (ItemKind::Documents, "Personal"),
+            (ItemKind::Pictures, "My Pictures"),
+            (ItemKind::Desktop, "Desktop"),
+            (ItemKind::Downloads, "{374DE290-123F-4565-9164-39C4925E467B}"),
+        ]
+        .iter()
+        .filter_map(|&(kind, name)| Some((kind, PathBuf::from(key.get_value::<String, _>(name).ok()?))))
+        .collect()
+    }
+
+    // What can be copied from the Windows partition, and what can't.
+    pub fn user_files() -> io::Result<(Manifest, Vec<String>)> {
+        let drive = std::env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string());
+        let protected = bitlocker_drives().map_err(|err| other(err.to_string()))?;
+        if protected.iter().any(|v| v.eq_ignore_ascii_case(&drive)) {
+            return Err(other(format!("BitLocker encrypts {}, which Ubuntu can't read", drive)));
+        }
+        let volume = format!("{:016X}", ntfs_serial(&drive)?);
+        let root = PathBuf::from(format!(r"{}\", drive));
+        let local_app_data = PathBuf::from(std::env::var_os("LOCALAPPDATA").unwrap_or_default());
+        let app_data = PathBuf::from(std::env::var_os("APPDATA").unwrap_or_default());
+        // OneDrive can keep files in the cloud only, which Ubuntu wouldn't
+        // get.
+        let onedrive = std::env::var_os("OneDrive").map(PathBuf::from);
+
+        let mut items = Vec::new();
+        let mut skipped = Vec::new();
+        for (kind, path) in locations(&shell_folders(), &local_app_data, &app_data) {
+            if onedrive.as_ref().map_or(false, |v| path.starts_with(v)) {
+                skipped.push(format!("{}: in OneDrive, which may only keep them in the cloud.", kind.label()));
+                continue;
+            }
+            if !path.starts_with(&root) {
+                if path.exists() {
+                    skipped.push(format!("{}: not on {}.", kind.label(), drive));
+                }
+                continue;
+            }
+            items.extend(Item::new(kind, &root, &path));
+        }
+        Ok((Manifest { volume, items }, skipped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(kind: ItemKind, source: &str, size: u64, files: u64) -> Item {
+        Item { kind, source: source.to_string(), size, files }
+    }
+
+    fn write(path: &Path, len: usize) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, vec![b'x'; len]).unwrap();
+    }
+
+    #[test]
+    fn measures_folders() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        write(&root.join("Users/Ann/Documents/a.txt"), 10);
+        write(&root.join("Users/Ann/Documents/Taxes/2020.pdf"), 1000);
+        fs::create_dir_all(root.join("Users/Ann/Documents/Empty")).unwrap();
+        assert_eq!(measure(&root.join("Users/Ann/Documents")), (1010, 2));
+        assert_eq!(measure(&root.join("Users/Ann/Documents/a.txt")), (10, 1));
+        assert_eq!(measure(&root.join("Users/Ann/Missing")), (0, 0));
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root.join("Users/Ann/Documents"), root.join("Users/Ann/Link")).unwrap();
+            assert_eq!(measure(&root.join("Users/Ann/Link")), (0, 0));
+        }
+
+        let documents = Item::new(ItemKind::Documents, root, &root.join("Users/Ann/Documents")).unwrap();
+        assert_eq!(documents, item(ItemKind::Documents, "Users/Ann/Documents", 1010, 2));
+        assert_eq!(documents.describe(), format!("Documents (2 files, {})", format_size(1010)));
+        assert!(Item::new(ItemKind::Pictures, root, &root.join("Users/Ann/Documents/Empty")).is_none());
+        assert!(Item::new(ItemKind::Documents, &root.join("Users/Bob"), &root.join("Users/Ann/Documents")).is_none());
+        assert!(Item::new(ItemKind::Documents, root, root).is_none());
+    }
+
+    #[test]
+    fn finds_bookmarks() {
+        let dir = tempfile::tempdir().unwrap();
+        let local = dir.path().join("Local");
+        let roaming = dir.path().join("Roaming");
+        let folders = [(ItemKind::Documents, dir.path().join("Documents"))];
+        let profiles = roaming.join("Mozilla/Firefox/Profiles");
+        write(&profiles.join("old.default/places.sqlite"), 1);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        write(&profiles.join("new.default-release/places.sqlite"), 1);
+        fs::create_dir_all(profiles.join("empty")).unwrap();
+
+        assert_eq!(locations(&folders, &local, &roaming), vec![
+            (ItemKind::Documents, dir.path().join("Documents")),
+            (ItemKind::ChromeBookmarks, local.join("Google/Chrome/User Data/Default/Bookmarks")),
+            (ItemKind::EdgeBookmarks, local.join("Microsoft/Edge/User Data/Default/Bookmarks")),
+            (ItemKind::FirefoxBookmarks, profiles.join("new.default-release/places.sqlite")),
+        ]);
+        assert_eq!(locations(&[], &local, &dir.path().join("Nowhere")).len(), 2);
+    }
+
+    #[test]
+    fn renders_manifests() {
+        let manifest = Manifest {
+            volume: "0123456789ABCDEF".to_string(),
+            items: vec![
+                item(ItemKind::Documents, "Users/Ann Lee/Documents", 1010, 2),
+                item(ItemKind::ChromeBookmarks, "Users/Ann Lee/AppData/Local/Google/Chrome/User Data/Default/Bookmarks", 5, 1),
+            ],
+        };
+        assert_eq!(manifest.size(), 1015);
+        assert_eq!(manifest.render(), "# Files to copy from Windows\nvolume=0123456789ABCDEF\n\
+                                      copy\tdocuments\t1010\tUsers/Ann Lee/Documents\tDocuments\n\
+                                      copy\tchrome-bookmarks\t5\tUsers/Ann Lee/AppData/Local/Google/Chrome/User Data/Default/Bookmarks\tWindows bookmarks/Chrome.json\n");
+    }
+
+    #[test]
+    fn writes_late_commands() {
+        assert!(Manifest::default().late_commands().is_empty());
+        let manifest = Manifest { volume: "ABCD".to_string(), items: vec![item(ItemKind::Pictures, "Users/Ann/Pictures", 1, 1)] };
+        let commands = manifest.late_commands();
+        assert_eq!(commands.len(), 4);
+        assert!(commands[0].contains("/target/var/lib/windows-migration/manifest"));
+        assert!(commands[1].contains("/target/var/lib/windows-migration/migrate.sh"));
+        assert!(commands[2].contains("/target/etc/systemd/system/windows-migration.service"));
+        assert!(commands[2].contains("ExecStart=/bin/sh /var/lib/windows-migration/migrate.sh /var/lib/windows-migration/manifest"));
+        assert!(commands[3].ends_with("/target/etc/systemd/system/multi-user.target.wants/windows-migration.service"));
+    }
+
+    // Runs the late-commands into a fake /target, then the script they
+    // install on a fake Windows partition and home folder.
+    #[cfg(unix)]
+    #[test]
+    fn copies_the_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let windows = dir.path().join("windows");
+        let home = dir.path().join("home");
+        let target = dir.path().join("target");
+        fs::create_dir_all(&home).unwrap();
+        write(&windows.join("Users/Ann Lee/Documents/it's a file.txt"), 3);
+        write(&windows.join("Users/Ann Lee/Documents/Sub folder/b.txt"), 4);
+        write(&windows.join("Users/Ann Lee/AppData/Local/Google/Chrome/User Data/Default/Bookmarks"), 5);
+        write(&home.join("Documents/kept.txt"), 6);
+
+        let manifest = Manifest {
+            volume: "ABCD".to_string(),
+            items: vec![
+                item(ItemKind::Documents, "Users/Ann Lee/Documents", 7, 2),
+                item(ItemKind::ChromeBookmarks, "Users/Ann Lee/AppData/Local/Google/Chrome/User Data/Default/Bookmarks", 5, 1),
+                // Gone since it was measured.
+                item(ItemKind::Pictures, "Users/Ann Lee/Pictures", 1, 1),
+            ],
+        };
+        for command in manifest.late_commands() {
+            let command = command.replace("/target", target.to_str().unwrap());
+            assert!(std::process::Command::new("sh").arg("-c").arg(&command).status().unwrap().success(), "{}", command);
+        }
+        assert!(target.join("etc/systemd/system/multi-user.target.wants/windows-migration.service").symlink_metadata().is_ok());
+
+        let manifest_path = target.join("var/lib/windows-migration/manifest");
+        let status = std::process::Command::new("sh")
+            .arg(target.join("var/lib/windows-migration/migrate.sh"))
+            .arg(&manifest_path)
+            .arg(&windows)
+            .arg(&home)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(fs::read(home.join("Documents/it's a file.txt")).unwrap().len(), 3);
+        assert_eq!(fs::read(home.join("Documents/Sub folder/b.txt")).unwrap().len(), 4);
+        assert_eq!(fs::read(home.join("Documents/kept.txt")).unwrap().len(), 6);
+        assert_eq!(fs::read(home.join("Windows bookmarks/Chrome.json")).unwrap().len(), 5);
+        assert!(!home.join("Pictures").exists());
+        // It only runs once.
+        assert!(!manifest_path.exists());
+        assert!(target.join("var/lib/windows-migration/manifest.done").exists());
+    }
+}
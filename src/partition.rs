@@ -0,0 +1,668 @@
+use crate::writer::random_bytes;
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+// Only whole sectors can be read and written on a physical disk, so the MBR
+// gets read and written along with the rest of its sector.
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MbrPartition {
+    pub bootable: bool,
+    pub kind: u8,
+    pub start_lba: u32,
+    pub sectors: u32,
+}
+
+impl MbrPartition {
+    pub fn is_empty(&self) -> bool {
+        self.kind == 0 && self.sectors == 0
+    }
+
+    pub fn end_lba(&self) -> u64 {
+        self.start_lba as u64 + self.sectors as u64
+    }
+
+    fn parse(entry: &[u8]) -> MbrPartition {
+        MbrPartition {
+            bootable: entry[0] == 0x80,
+            kind: entry[4],
+            start_lba: u32::from_le_bytes([entry[8], entry[9], entry[10], entry[11]]),
+            sectors: u32::from_le_bytes([entry[12], entry[13], entry[14], entry[15]]),
+        }
+    }
+
+    fn serialize(&self, entry: &mut [u8]) {
+        if self.is_empty() {
+            for byte in entry.iter_mut() {
+                *byte = 0;
+            }
+            return;
+        }
+        entry[0] = if self.bootable { 0x80 } else { 0 };
+        // Nobody uses CHS addresses anymore. Fill them with the "use LBA"
+        // marker.
+        entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        entry[4] = self.kind;
+        entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        entry[8..12].copy_from_slice(&self.start_lba.to_le_bytes());
+        entry[12..16].copy_from_slice(&self.sectors.to_le_bytes());
+    }
+}
+
+pub struct Mbr {
+    sector: Vec<u8>,
+    pub partitions: [MbrPartition; 4],
+}
+
+const PARTITION_TABLE_OFFSET: usize = 446;
+
+impl Mbr {
+    // Returns None if the disk doesn't have an MBR.
+    pub fn read<D: Read + Seek>(dev: &mut D, sector_size: u64) -> io::Result<Option<Mbr>> {
+        let mut sector = vec![0; sector_size as usize];
+        dev.seek(SeekFrom::Start(0))?;
+        dev.read_exact(&mut sector)?;
+        if sector[510..512] != [0x55, 0xAA] {
+            return Ok(None);
+        }
+        let mut partitions = [MbrPartition::default(); 4];
+        for (idx, partition) in partitions.iter_mut().enumerate() {
+            let off = PARTITION_TABLE_OFFSET + idx * 16;
+            *partition = MbrPartition::parse(&sector[off..off + 16]);
+        }
+        Ok(Some(Mbr { sector, partitions }))
+    }
+
+    pub fn write<D: Write + Seek>(&mut self, dev: &mut D) -> io::Result<()> {
+        for (idx, partition) in self.partitions.iter().enumerate() {
+            let off = PARTITION_TABLE_OFFSET + idx * 16;
+            partition.serialize(&mut self.sector[off..off + 16]);
+        }
+        self.sector[510..512].copy_from_slice(&[0x55, 0xAA]);
+        dev.seek(SeekFrom::Start(0))?;
+        dev.write_all(&self.sector)?;
+        dev.flush()
+    }
+
+    // Isohybrid images come with boot code for BIOSes, plain partition tables
+    // don't.
+    pub fn has_boot_code(&self) -> bool {
+        self.sector[..440].iter().any(|&v| v != 0)
+    }
+
+    // What tells disks apart, along with their partitions' offsets, before
+    // GPT.
+    pub fn disk_signature(&self) -> u32 {
+        u32::from_le_bytes([self.sector[440], self.sector[441], self.sector[442], self.sector[443]])
+    }
+
+    pub fn free_slot(&self) -> Option<usize> {
+        self.partitions.iter().position(|v| v.is_empty())
+    }
+
+    // First LBA after every partition.
+    pub fn end_lba(&self) -> u64 {
+        self.partitions.iter().map(|v| v.end_lba()).max().unwrap_or(0)
+    }
+
+    // A single partition covering the GPT disk, or as much of it as an MBR
+    // can.
+    pub fn protective(sector_size: u64, disk_size: u64) -> Mbr {
+        let mut mbr = Mbr { sector: vec![0; sector_size as usize], partitions: [MbrPartition::default(); 4] };
+        mbr.partitions[0] = MbrPartition {
+            bootable: false,
+            kind: PROTECTIVE_MBR,
+            start_lba: 1,
+            sectors: std::cmp::min(disk_size / sector_size - 1, u32::max_value() as u64) as u32,
+        };
+        mbr
+    }
+
+    // Whether there's a GPT firmwares should read instead, for the whole
+    // disk or along with a hybrid MBR.
+    pub fn protects_gpt(&self) -> bool {
+        self.partitions.iter().any(|v| v.kind == PROTECTIVE_MBR)
+    }
+
+    // Stretches a protective MBR to the end of the disk, after the GPT got
+    // moved there. Hybrid MBRs only protect the GPT itself, and stay as they
+    // are.
+    pub fn grow_protective(&mut self, sector_size: u64, disk_size: u64) {
+        let protective = self.partitions.iter().filter(|v| !v.is_empty()).count() == 1
+            && self.partitions[0].kind == PROTECTIVE_MBR
+            && self.partitions[0].start_lba == 1;
+        if protective {
+            self.partitions[0] = Mbr::protective(sector_size, disk_size).partitions[0];
+        }
+    }
+}
+
+// GUIDs are stored with their first three fields little endian.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn random() -> Guid {
+        let mut bytes = [0; 16];
+        random_bytes(&mut bytes);
+        // Version 4, variant 1.
+        bytes[7] = (bytes[7] & 0x0F) | 0x40;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+        Guid(bytes)
+    }
+}
+
+// C12A7328-F81F-11D2-BA4B-00A0C93EC93B
+pub const EFI_SYSTEM_PARTITION: Guid = Guid([0x28, 0x73, 0x2A, 0xC1, 0x1F, 0xF8, 0xD2, 0x11, 0xBA, 0x4B, 0x00, 0xA0, 0xC9, 0x3E, 0xC9, 0x3B]);
+// The same, in an MBR.
+pub const MBR_EFI_PARTITION: u8 = 0xEF;
+// EBD0A0A2-B9E5-4433-87C0-68B6B72699C7
+pub const BASIC_DATA_PARTITION: Guid = Guid([0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7]);
+// 0FC63DAF-8483-4772-8E79-3D69D8477DE4
+pub const LINUX_FILESYSTEM_PARTITION: Guid = Guid([0xAF, 0x3D, 0xC6, 0x0F, 0x83, 0x84, 0x72, 0x47, 0x8E, 0x79, 0x3D, 0x69, 0xD8, 0x47, 0x7D, 0xE4]);
+
+#[derive(Debug, Clone, Default)]
+pub struct GptPartition {
+    pub kind: Guid,
+    pub guid: Guid,
+    pub first_lba: u64,
+    // Inclusive.
+    pub last_lba: u64,
+    pub attributes: u64,
+    pub name: String,
+}
+
+impl GptPartition {
+    pub fn is_empty(&self) -> bool {
+        self.kind == Guid::default()
+    }
+
+    fn parse(entry: &[u8]) -> GptPartition {
+        let mut kind = Guid::default();
+        kind.0.copy_from_slice(&entry[0..16]);
+        let mut guid = Guid::default();
+        guid.0.copy_from_slice(&entry[16..32]);
+        let name: Vec<u16> = entry[56..128].chunks_exact(2).map(|v| u16::from_le_bytes([v[0], v[1]])).take_while(|&v| v != 0).collect();
+        GptPartition {
+            kind,
+            guid,
+            first_lba: le_u64(&entry[32..40]),
+            last_lba: le_u64(&entry[40..48]),
+            attributes: le_u64(&entry[48..56]),
+            name: String::from_utf16_lossy(&name),
+        }
+    }
+
+    fn serialize(&self, entry: &mut [u8]) {
+        for byte in entry.iter_mut() {
+            *byte = 0;
+        }
+        if self.is_empty() {
+            return;
+        }
+        entry[0..16].copy_from_slice(&self.kind.0);
+        entry[16..32].copy_from_slice(&self.guid.0);
+        entry[32..40].copy_from_slice(&self.first_lba.to_le_bytes());
+        entry[40..48].copy_from_slice(&self.last_lba.to_le_bytes());
+        entry[48..56].copy_from_slice(&self.attributes.to_le_bytes());
+        for (idx, unit) in self.name.encode_utf16().take(36).enumerate() {
+            entry[56 + idx * 2..58 + idx * 2].copy_from_slice(&unit.to_le_bytes());
+        }
+    }
+}
+
+const GPT_ENTRIES: u64 = 128;
+const GPT_ENTRY_SIZE: u64 = 128;
+const GPT_HEADER_SIZE: usize = 92;
+pub const PROTECTIVE_MBR: u8 = 0xEE;
+
+fn le_u32(buf: &[u8]) -> u32 {
+    u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn le_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[..8]);
+    u64::from_le_bytes(bytes)
+}
+
+// Sectors taken by `count` partition entries, on each end of the disk.
+fn gpt_entry_sectors(sector_size: u64, count: u64) -> u64 {
+    (count * GPT_ENTRY_SIZE + sector_size - 1) / sector_size
+}
+
+// First and last LBAs partitions can use on a GPT disk of `disk_size` bytes.
+pub fn gpt_usable_lbas(sector_size: u64, disk_size: u64) -> (u64, u64) {
+    let entry_sectors = gpt_entry_sectors(sector_size, GPT_ENTRIES);
+    (2 + entry_sectors, disk_size / sector_size - 2 - entry_sectors)
+}
+
+// A whole GPT. Unused entries are kept, so partitions keep their numbers.
+#[derive(Debug, Clone)]
+pub struct Gpt {
+    pub disk_guid: Guid,
+    pub first_usable: u64,
+    pub last_usable: u64,
+    // Where the primary entries start, usually LBA 2.
+    pub entries_lba: u64,
+    pub entries: Vec<GptPartition>,
+}
+
+impl Gpt {
+    pub fn new(sector_size: u64, disk_size: u64) -> Gpt {
+        let (first_usable, last_usable) = gpt_usable_lbas(sector_size, disk_size);
+        Gpt {
+            disk_guid: Guid::random(),
+            first_usable,
+            last_usable,
+            entries_lba: 2,
+            entries: vec![GptPartition::default(); GPT_ENTRIES as usize],
+        }
+    }
+
+    // Reads the primary GPT. Returns None if the disk doesn't have a valid
+    // one.
+    pub fn read<D: Read + Seek>(dev: &mut D, sector_size: u64) -> io::Result<Option<Gpt>> {
+        let mut header = vec![0; sector_size as usize];
+        dev.seek(SeekFrom::Start(sector_size))?;
+        dev.read_exact(&mut header)?;
+        let header_size = le_u32(&header[12..16]) as usize;
+        if &header[..8] != b"EFI PART" || header_size < GPT_HEADER_SIZE || header_size > header.len() {
+            return Ok(None);
+        }
+        let crc = le_u32(&header[16..20]);
+        let mut copy = header[..header_size].to_vec();
+        copy[16..20].copy_from_slice(&[0; 4]);
+        if crc32(&copy) != crc {
+            return Ok(None);
+        }
+
+        let entries_lba = le_u64(&header[72..80]);
+        let count = le_u32(&header[80..84]) as usize;
+        let entry_size = le_u32(&header[84..88]) as usize;
+        if entry_size < GPT_ENTRY_SIZE as usize || count > 1024 {
+            return Ok(None);
+        }
+        let mut entries = vec![0; count * entry_size];
+        dev.seek(SeekFrom::Start(entries_lba * sector_size))?;
+        dev.read_exact(&mut entries)?;
+        if crc32(&entries) != le_u32(&header[88..92]) {
+            return Ok(None);
+        }
+
+        let mut disk_guid = Guid::default();
+        disk_guid.0.copy_from_slice(&header[56..72]);
+        Ok(Some(Gpt {
+            disk_guid,
+            first_usable: le_u64(&header[40..48]),
+            last_usable: le_u64(&header[48..56]),
+            entries_lba,
+            entries: entries.chunks_exact(entry_size).map(GptPartition::parse).collect(),
+        }))
+    }
+
+    // The used entries, with their partition numbers, starting at 1.
+    pub fn partitions(&self) -> impl Iterator<Item = (u32, &GptPartition)> {
+        self.entries.iter().enumerate().filter(|(_, v)| !v.is_empty()).map(|(idx, v)| (idx as u32 + 1, v))
+    }
+
+    // Puts the partition in the first unused entry, and returns its number.
+    pub fn add(&mut self, partition: GptPartition) -> io::Result<u32> {
+        if partition.first_lba < self.first_usable || partition.last_lba > self.last_usable || partition.first_lba > partition.last_lba {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The partition is out of the disk's usable space"));
+        }
+        if self.partitions().any(|(_, v)| v.first_lba <= partition.last_lba && partition.first_lba <= v.last_lba) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The partition overlaps another one"));
+        }
+        let idx = self.entries.iter().position(GptPartition::is_empty)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "The partition table is full"))?;
+        self.entries[idx] = partition;
+        Ok(idx as u32 + 1)
+    }
+
+    // First LBA after every partition.
+    pub fn end_lba(&self) -> u64 {
+        self.partitions().map(|(_, v)| v.last_lba + 1).max().unwrap_or(0)
+    }
+
+    fn entry_sectors(&self, sector_size: u64) -> u64 {
+        gpt_entry_sectors(sector_size, self.entries.len() as u64)
+    }
+
+    // Lets partitions use the disk up to the backup GPT at its end. Images
+    // come with their backup GPT at the end of the image instead.
+    pub fn resize(&mut self, sector_size: u64, disk_size: u64) {
+        self.last_usable = disk_size / sector_size - 2 - self.entry_sectors(sector_size);
+    }
+
+    // The primary and backup headers and entries, as (offset, data), with
+    // the backup at the end of the disk.
+    pub fn blocks(&self, sector_size: u64, disk_size: u64) -> io::Result<Vec<(u64, Vec<u8>)>> {
+        let last_lba = disk_size / sector_size - 1;
+        let entry_sectors = self.entry_sectors(sector_size);
+        let backup_entries_lba = last_lba - entry_sectors;
+        if self.last_usable >= backup_entries_lba || self.entries_lba + entry_sectors > self.first_usable {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The GPT doesn't fit on the disk"));
+        }
+
+        let mut entries = vec![0; (entry_sectors * sector_size) as usize];
+        for (partition, entry) in self.entries.iter().zip(entries.chunks_mut(GPT_ENTRY_SIZE as usize)) {
+            partition.serialize(entry);
+        }
+        let entries_crc = crc32(&entries[..self.entries.len() * GPT_ENTRY_SIZE as usize]);
+
+        let header = |current: u64, backup: u64, entries_lba: u64| {
+            let mut sector = vec![0; sector_size as usize];
+            sector[0..8].copy_from_slice(b"EFI PART");
+            sector[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+            sector[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+            sector[24..32].copy_from_slice(&current.to_le_bytes());
+            sector[32..40].copy_from_slice(&backup.to_le_bytes());
+            sector[40..48].copy_from_slice(&self.first_usable.to_le_bytes());
+            sector[48..56].copy_from_slice(&self.last_usable.to_le_bytes());
+            sector[56..72].copy_from_slice(&self.disk_guid.0);
+            sector[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+            sector[80..84].copy_from_slice(&(self.entries.len() as u32).to_le_bytes());
+            sector[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+            sector[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+            let crc = crc32(&sector[..GPT_HEADER_SIZE]);
+            sector[16..20].copy_from_slice(&crc.to_le_bytes());
+            sector
+        };
+
+        let mut backup = entries.clone();
+        backup.extend(header(last_lba, 1, backup_entries_lba));
+        Ok(vec![
+            (sector_size, header(1, last_lba, self.entries_lba)),
+            (self.entries_lba * sector_size, entries),
+            (backup_entries_lba * sector_size, backup),
+        ])
+    }
+
+    // Writes both GPTs, but leaves the MBR alone.
+    pub fn write<D: Write + Seek>(&self, dev: &mut D, sector_size: u64, disk_size: u64) -> io::Result<()> {
+        for (offset, data) in self.blocks(sector_size, disk_size)? {
+            dev.seek(SeekFrom::Start(offset))?;
+            dev.write_all(&data)?;
+        }
+        dev.flush()
+    }
+}
+
+// Writes a protective MBR, and both GPTs with the given partitions.
+pub fn write_gpt<D: Write + Seek>(dev: &mut D, sector_size: u64, disk_size: u64, partitions: &[GptPartition]) -> io::Result<()> {
+    if partitions.len() as u64 > GPT_ENTRIES {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Too many partitions"));
+    }
+    let mut gpt = Gpt::new(sector_size, disk_size);
+    gpt.entries[..partitions.len()].clone_from_slice(partitions);
+    Mbr::protective(sector_size, disk_size).write(dev)?;
+    gpt.write(dev, sector_size, disk_size)
+}
+
+// Reads the partitions of the primary GPT, without the unused entries.
+// Returns None if the disk doesn't have a valid one.
+pub fn read_gpt<D: Read + Seek>(dev: &mut D, sector_size: u64) -> io::Result<Option<Vec<GptPartition>>> {
+    Ok(Gpt::read(dev, sector_size)?.map(|gpt| gpt.partitions().map(|(_, v)| v.clone()).collect()))
+}
+
+// The CRC32 used by GPT, zlib and friends.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    use std::fs::File;
+
+    // Xorshift, so failures can be replayed.
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        // In [low, high].
+        fn range(&mut self, low: u64, high: u64) -> u64 {
+            low + self.next() % (high - low + 1)
+        }
+    }
+
+    fn image(disk_size: u64) -> File {
+        let disk = tempfile::tempfile().unwrap();
+        disk.set_len(disk_size).unwrap();
+        disk
+    }
+
+    fn read_at(disk: &mut File, offset: u64, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        disk.seek(SeekFrom::Start(offset)).unwrap();
+        disk.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    fn fields(partition: &GptPartition) -> (Guid, Guid, u64, u64, u64, String) {
+        (partition.kind, partition.guid, partition.first_lba, partition.last_lba, partition.attributes, partition.name.clone())
+    }
+
+    fn random_partition(rng: &mut Rng, first_lba: u64, last_lba: u64) -> GptPartition {
+        let name_len = rng.range(0, 40);
+        GptPartition {
+            kind: if rng.next() % 2 == 0 { EFI_SYSTEM_PARTITION } else { LINUX_FILESYSTEM_PARTITION },
+            guid: Guid::random(),
+            first_lba,
+            last_lba,
+            attributes: rng.next(),
+            name: (0..name_len).map(|_| (b'a' + rng.range(0, 25) as u8) as char).collect(),
+        }
+    }
+
+    // Checks the backup header at the end of the disk against the primary
+    // GPT.
+    pub fn check_backup(disk: &mut File, gpt: &Gpt, sector_size: u64, disk_size: u64) {
+        let last_lba = disk_size / sector_size - 1;
+        let header = read_at(disk, last_lba * sector_size, sector_size as usize);
+        assert_eq!(&header[..8], b"EFI PART");
+        let mut copy = header[..GPT_HEADER_SIZE].to_vec();
+        copy[16..20].copy_from_slice(&[0; 4]);
+        assert_eq!(crc32(&copy), le_u32(&header[16..20]));
+        assert_eq!(le_u64(&header[24..32]), last_lba);
+        assert_eq!(le_u64(&header[32..40]), 1);
+        assert_eq!(le_u64(&header[40..48]), gpt.first_usable);
+        assert_eq!(le_u64(&header[48..56]), gpt.last_usable);
+        assert_eq!(&header[56..72], &gpt.disk_guid.0);
+        let entries_lba = le_u64(&header[72..80]);
+        assert!(entries_lba > gpt.last_usable);
+        let entries = read_at(disk, entries_lba * sector_size, gpt.entries.len() * GPT_ENTRY_SIZE as usize);
+        assert_eq!(crc32(&entries), le_u32(&header[88..92]));
+        assert_eq!(entries, read_at(disk, gpt.entries_lba * sector_size, entries.len()));
+    }
+
+    #[test]
+    fn computes_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn reads_and_writes_mbrs() {
+        let mut disk = image(1 << 20);
+        assert!(Mbr::read(&mut disk, 512).unwrap().is_none());
+
+        disk.seek(SeekFrom::Start(0)).unwrap();
+        disk.write_all(&[0xEB; 440]).unwrap();
+        disk.write_all(&0x1234_5678u32.to_le_bytes()).unwrap();
+        let mut mbr = Mbr::protective(512, 1 << 20);
+        mbr.partitions[0] = MbrPartition { bootable: true, kind: 0x0C, start_lba: 2048, sectors: 4096 };
+        mbr.partitions[2] = MbrPartition { bootable: false, kind: 0x83, start_lba: 8192, sectors: 1 };
+        mbr.sector = read_at(&mut disk, 0, 512);
+        mbr.write(&mut disk).unwrap();
+
+        let mbr = Mbr::read(&mut disk, 512).unwrap().unwrap();
+        assert!(mbr.has_boot_code());
+        assert_eq!(mbr.disk_signature(), 0x1234_5678);
+        assert_eq!(mbr.partitions[0], MbrPartition { bootable: true, kind: 0x0C, start_lba: 2048, sectors: 4096 });
+        assert!(mbr.partitions[1].is_empty());
+        assert_eq!(mbr.partitions[2].end_lba(), 8193);
+        assert_eq!(mbr.free_slot(), Some(1));
+        assert_eq!(mbr.end_lba(), 8193);
+        assert!(!mbr.protects_gpt());
+        assert_eq!(&read_at(&mut disk, 446 + 16 * 2, 16)[1..4], &[0xFE, 0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn protects_gpts() {
+        let mbr = Mbr::protective(512, 1 << 30);
+        assert!(mbr.protects_gpt());
+        assert!(!mbr.has_boot_code());
+        assert_eq!(mbr.partitions[0].start_lba, 1);
+        assert_eq!(mbr.partitions[0].end_lba(), (1 << 30) / 512);
+        // Past 2TiB, it covers what it can.
+        assert_eq!(Mbr::protective(512, 4 << 40).partitions[0].sectors, u32::max_value());
+
+        let mut mbr = Mbr::protective(512, 1 << 20);
+        mbr.grow_protective(512, 1 << 30);
+        assert_eq!(mbr.partitions[0].end_lba(), (1 << 30) / 512);
+
+        let mut hybrid = Mbr::protective(512, 1 << 20);
+        hybrid.partitions[0].sectors = 63;
+        hybrid.partitions[1] = MbrPartition { bootable: true, kind: MBR_EFI_PARTITION, start_lba: 64, sectors: 1000 };
+        hybrid.grow_protective(512, 1 << 30);
+        assert_eq!(hybrid.partitions[0].sectors, 63);
+        assert_eq!(hybrid.partitions[1].sectors, 1000);
+    }
+
+    #[test]
+    fn checks_new_partitions() {
+        let mut gpt = Gpt::new(512, 1 << 20);
+        assert_eq!((gpt.first_usable, gpt.last_usable), (34, 2047 - 33));
+        assert_eq!(gpt_usable_lbas(4096, 1 << 20), (6, 255 - 5));
+
+        let mut rng = Rng(1);
+        assert!(gpt.add(random_partition(&mut rng, 33, 100)).is_err());
+        assert!(gpt.add(random_partition(&mut rng, 1000, 2015)).is_err());
+        assert!(gpt.add(random_partition(&mut rng, 200, 100)).is_err());
+        assert_eq!(gpt.add(random_partition(&mut rng, 34, 100)).unwrap(), 1);
+        assert!(gpt.add(random_partition(&mut rng, 100, 200)).is_err());
+        assert_eq!(gpt.add(random_partition(&mut rng, 101, 2014)).unwrap(), 2);
+        assert_eq!(gpt.end_lba(), 2015);
+
+        // Numbers stay put, and freed entries get reused.
+        gpt.entries[0] = GptPartition::default();
+        assert_eq!(gpt.partitions().map(|(number, _)| number).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(gpt.add(random_partition(&mut rng, 50, 60)).unwrap(), 1);
+
+        let mut gpt = Gpt::new(512, 1 << 30);
+        for idx in 0..GPT_ENTRIES {
+            gpt.add(random_partition(&mut rng, 2048 + idx, 2048 + idx)).unwrap();
+        }
+        assert!(gpt.add(random_partition(&mut rng, 4096, 4096)).is_err());
+    }
+
+    #[test]
+    fn refuses_damaged_gpts() {
+        let mut disk = image(1 << 20);
+        assert!(Gpt::read(&mut disk, 512).unwrap().is_none());
+
+        let mut gpt = Gpt::new(512, 1 << 20);
+        gpt.add(random_partition(&mut Rng(2), 34, 100)).unwrap();
+        gpt.write(&mut disk, 512, 1 << 20).unwrap();
+        assert!(Gpt::read(&mut disk, 512).unwrap().is_some());
+        // The MBR isn't ours to write.
+        assert_eq!(read_at(&mut disk, 0, 512), vec![0; 512]);
+
+        disk.seek(SeekFrom::Start(2 * 512 + 60)).unwrap();
+        disk.write_all(b"x").unwrap();
+        assert!(Gpt::read(&mut disk, 512).unwrap().is_none());
+        gpt.write(&mut disk, 512, 1 << 20).unwrap();
+        disk.seek(SeekFrom::Start(512 + 48)).unwrap();
+        disk.write_all(b"x").unwrap();
+        assert!(Gpt::read(&mut disk, 512).unwrap().is_none());
+
+        // Nor does a GPT too big for the disk get written.
+        assert!(Gpt::new(512, 1 << 30).write(&mut disk, 512, 1 << 20).is_err());
+    }
+
+    #[test]
+    fn writes_whole_gpts() {
+        let mut disk = image(64 << 20);
+        let partitions = [random_partition(&mut Rng(3), 2048, 4095), random_partition(&mut Rng(4), 4096, 100_000)];
+        write_gpt(&mut disk, 512, 64 << 20, &partitions).unwrap();
+        let mbr = Mbr::read(&mut disk, 512).unwrap().unwrap();
+        assert!(mbr.protects_gpt());
+        assert_eq!(mbr.partitions[0].end_lba(), (64 << 20) / 512);
+        let read = read_gpt(&mut disk, 512).unwrap().unwrap();
+        assert_eq!(read.iter().map(fields).collect::<Vec<_>>(), partitions.iter().map(fields).collect::<Vec<_>>());
+    }
+
+    // Random GPTs survive being written, read back, grown and written again.
+    #[test]
+    fn round_trips_random_gpts() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..100 {
+            let sector_size = if rng.next() % 2 == 0 { 512 } else { 4096 };
+            let disk_size = rng.range(64, 4096) * sector_size;
+            let mut disk = image(disk_size);
+            let mut gpt = Gpt::new(sector_size, disk_size);
+
+            let mut expected: Vec<(u32, GptPartition)> = Vec::new();
+            for _ in 0..rng.range(0, 10) {
+                let first_lba = rng.range(gpt.first_usable - 2, gpt.last_usable + 2);
+                let last_lba = rng.range(first_lba.saturating_sub(1), first_lba + (gpt.last_usable - gpt.first_usable) / 4);
+                let partition = random_partition(&mut rng, first_lba, last_lba);
+                let fits = first_lba >= gpt.first_usable && last_lba <= gpt.last_usable && first_lba <= last_lba
+                    && expected.iter().all(|(_, v)| v.last_lba < first_lba || last_lba < v.first_lba);
+                match gpt.add(partition.clone()) {
+                    Ok(number) => {
+                        assert!(fits);
+                        expected.push((number, partition));
+                    }
+                    Err(_) => assert!(!fits),
+                }
+            }
+            gpt.write(&mut disk, sector_size, disk_size).unwrap();
+
+            let read = Gpt::read(&mut disk, sector_size).unwrap().unwrap();
+            assert_eq!(read.disk_guid, gpt.disk_guid);
+            assert_eq!((read.first_usable, read.last_usable), gpt_usable_lbas(sector_size, disk_size));
+            let check = |gpt: &Gpt| {
+                let partitions: Vec<_> = gpt.partitions().map(|(number, v)| {
+                    let mut v = v.clone();
+                    v.name.truncate(36);
+                    (number, fields(&v))
+                }).collect();
+                let mut names = expected.clone();
+                for (_, v) in &mut names {
+                    v.name.truncate(36);
+                }
+                assert_eq!(partitions, names.iter().map(|(number, v)| (*number, fields(v))).collect::<Vec<_>>());
+            };
+            check(&read);
+            check_backup(&mut disk, &read, sector_size, disk_size);
+
+            // Like an image written to a bigger drive.
+            let mut grown = read.clone();
+            let new_size = disk_size + rng.range(1, 1024) * sector_size;
+            disk.set_len(new_size).unwrap();
+            grown.resize(sector_size, new_size);
+            grown.write(&mut disk, sector_size, new_size).unwrap();
+            let read = Gpt::read(&mut disk, sector_size).unwrap().unwrap();
+            assert_eq!((read.first_usable, read.last_usable), gpt_usable_lbas(sector_size, new_size));
+            check(&read);
+            check_backup(&mut disk, &read, sector_size, new_size);
+        }
+    }
+}
@@ -0,0 +1,216 @@
+use crate::ext4;
+use crate::partition::{Gpt, GptPartition, Guid, Mbr, MbrPartition, LINUX_FILESYSTEM_PARTITION};
+use crate::release::parse_version;
+use crate::writer::{round_up, Target};
+
+use std::io::{self, Read, Seek};
+
+// When booted with `persistent`, casper looks for a filesystem with this
+// label to hold its overlay. It was casper-rw until 19.10.
+pub fn label(version: Option<&str>) -> &'static str {
+    match version.and_then(parse_version) {
+        Some(version) if version < (19, 10) => "casper-rw",
+        _ => "writable",
+    }
+}
+
+const LINUX_PARTITION: u8 = 0x83;
+const ALIGNMENT: u64 = 1024 * 1024;
+const MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+fn other_err(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+// Where the partition goes, given the image's partition table, read either
+// from the image itself or from the drive it was written to.
+fn place<D: Read + Seek>(dev: &mut D, image_len: u64, sector_size: u64, disk_size: u64) -> io::Result<Place> {
+    let mbr = Mbr::read(dev, sector_size)?.ok_or_else(|| other_err("The image has no partition table."))?;
+    let no_space = || other_err("There isn't enough space left on the drive for persistence.");
+
+    // The image's backup GPT sits at its end, so it moves to the end of the
+    // drive to make room.
+    if mbr.protects_gpt() {
+        let mut gpt = Gpt::read(dev, sector_size)?.ok_or_else(|| other_err("The image's GPT is damaged."))?;
+        if !gpt.entries.iter().any(|v| v.is_empty()) {
+            return Err(other_err("The image's partition table is full."));
+        }
+        gpt.resize(sector_size, disk_size);
+        let start = round_up(std::cmp::max(image_len, gpt.end_lba() * sector_size), ALIGNMENT);
+        let end = (gpt.last_usable + 1) * sector_size / ALIGNMENT * ALIGNMENT;
+        if end < start + MIN_SIZE {
+            return Err(no_space());
+        }
+        return Ok(Place::Gpt { mbr, gpt, start, end });
+    }
+
+    let slot = mbr.free_slot().ok_or_else(|| other_err("The image's partition table is full."))?;
+    let start = round_up(std::cmp::max(image_len, mbr.end_lba() * sector_size), ALIGNMENT);
+    // MBR partitions can't go past 2TiB.
+    let end = std::cmp::min(disk_size / sector_size, u32::max_value() as u64) * sector_size;
+    if end < start + MIN_SIZE {
+        return Err(no_space());
+    }
+    Ok(Place::Mbr { mbr, slot, start, end })
+}
+
+enum Place {
+    Mbr { mbr: Mbr, slot: usize, start: u64, end: u64 },
+    Gpt { mbr: Mbr, gpt: Gpt, start: u64, end: u64 },
+}
+
+// Turns down images the partition can't be added to, before they get
+// written. `image` is the image as it will be written.
+pub fn check_image<R: Read + Seek>(image: &mut R, image_len: u64, sector_size: u64, disk_size: u64) -> io::Result<()> {
+    place(image, image_len, sector_size, disk_size).map(|_| ())
+}
+
+// Creates an ext4 partition in the space left after the image, and adds it to
+// the image's partition table.
+pub fn add_partition<T: Target>(target: &mut T, image_len: u64, label: &str) -> io::Result<()> {
+    let sector_size = target.sector_size();
+    let disk_size = target.size()?;
+
+    match place(target, image_len, sector_size, disk_size)? {
+        Place::Mbr { mut mbr, slot, start, end } => {
+            ext4::format(target, start, end - start, label)?;
+            mbr.partitions[slot] = MbrPartition {
+                bootable: false,
+                kind: LINUX_PARTITION,
+                start_lba: (start / sector_size) as u32,
+                sectors: ((end - start) / sector_size) as u32,
+            };
+            mbr.write(target)?;
+        }
+        Place::Gpt { mut mbr, mut gpt, start, end } => {
+            ext4::format(target, start, end - start, label)?;
+            gpt.add(GptPartition {
+                kind: LINUX_FILESYSTEM_PARTITION,
+                guid: Guid::random(),
+                first_lba: start / sector_size,
+                last_lba: end / sector_size - 1,
+                attributes: 0,
+                name: label.to_string(),
+            })?;
+            gpt.write(target, sector_size, disk_size)?;
+            mbr.grow_protective(sector_size, disk_size);
+            mbr.write(target)?;
+        }
+    }
+    target.rescan()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::partition::{gpt_usable_lbas, EFI_SYSTEM_PARTITION, MBR_EFI_PARTITION};
+    use std::fs::File;
+    use std::io::{Read, Seek, SeekFrom};
+
+    const IMAGE_LEN: u64 = 8 << 20;
+    const DISK_SIZE: u64 = 256 << 20;
+
+    fn label_at(disk: &mut File, offset: u64) -> Vec<u8> {
+        let mut label = vec![0; 16];
+        disk.seek(SeekFrom::Start(offset + 1024 + 120)).unwrap();
+        disk.read_exact(&mut label).unwrap();
+        label.into_iter().take_while(|&v| v != 0).collect()
+    }
+
+    #[test]
+    fn picks_the_label_casper_wants() {
+        assert_eq!(label(Some("18.04.5")), "casper-rw");
+        assert_eq!(label(Some("19.10")), "writable");
+        assert_eq!(label(Some("20.04")), "writable");
+        assert_eq!(label(None), "writable");
+    }
+
+    #[test]
+    fn adds_an_mbr_partition() {
+        let mut disk = tempfile::tempfile().unwrap();
+        disk.set_len(DISK_SIZE).unwrap();
+        let mut mbr = Mbr::protective(512, DISK_SIZE);
+        mbr.partitions[0] = MbrPartition { bootable: true, kind: 0x00, start_lba: 0, sectors: (IMAGE_LEN / 512) as u32 };
+        mbr.partitions[1] = MbrPartition { bootable: false, kind: MBR_EFI_PARTITION, start_lba: 100, sectors: 2000 };
+        mbr.write(&mut disk).unwrap();
+
+        add_partition(&mut disk, IMAGE_LEN, "writable").unwrap();
+        let mbr = Mbr::read(&mut disk, 512).unwrap().unwrap();
+        let partition = mbr.partitions[2];
+        assert_eq!(partition.kind, LINUX_PARTITION);
+        assert_eq!(partition.start_lba as u64 * 512, IMAGE_LEN);
+        assert_eq!(partition.end_lba() * 512, DISK_SIZE);
+        assert_eq!(label_at(&mut disk, IMAGE_LEN), b"writable");
+    }
+
+    #[test]
+    fn adds_a_gpt_partition() {
+        let mut disk = tempfile::tempfile().unwrap();
+        disk.set_len(IMAGE_LEN).unwrap();
+        let (first_usable, _) = gpt_usable_lbas(512, IMAGE_LEN);
+        let mut gpt = Gpt::new(512, IMAGE_LEN);
+        gpt.add(GptPartition {
+            kind: EFI_SYSTEM_PARTITION,
+            guid: Guid::random(),
+            first_lba: first_usable,
+            last_lba: 4095,
+            attributes: 0,
+            name: "ESP".to_string(),
+        }).unwrap();
+        gpt.write(&mut disk, 512, IMAGE_LEN).unwrap();
+        Mbr::protective(512, IMAGE_LEN).write(&mut disk).unwrap();
+        // Then the image gets written to a bigger drive.
+        disk.set_len(DISK_SIZE).unwrap();
+
+        add_partition(&mut disk, IMAGE_LEN, "writable").unwrap();
+        let gpt = Gpt::read(&mut disk, 512).unwrap().unwrap();
+        let (number, partition) = gpt.partitions().find(|(_, v)| v.kind == LINUX_FILESYSTEM_PARTITION).unwrap();
+        assert_eq!(number, 2);
+        assert_eq!(partition.name, "writable");
+        assert_eq!(partition.first_lba * 512, IMAGE_LEN);
+        assert_eq!(gpt.last_usable, gpt_usable_lbas(512, DISK_SIZE).1);
+        assert_eq!(label_at(&mut disk, IMAGE_LEN), b"writable");
+        let mbr = Mbr::read(&mut disk, 512).unwrap().unwrap();
+        assert_eq!(mbr.partitions[0].end_lba() * 512, DISK_SIZE);
+    }
+
+    #[test]
+    fn needs_room_after_the_image() {
+        let mut disk = tempfile::tempfile().unwrap();
+        disk.set_len(IMAGE_LEN + (16 << 20)).unwrap();
+        let mut mbr = Mbr::protective(512, IMAGE_LEN);
+        mbr.partitions[0] = MbrPartition { bootable: true, kind: 0x00, start_lba: 0, sectors: (IMAGE_LEN / 512) as u32 };
+        mbr.write(&mut disk).unwrap();
+        assert!(check_image(&mut disk, IMAGE_LEN, 512, IMAGE_LEN + (16 << 20)).is_err());
+        assert!(add_partition(&mut disk, IMAGE_LEN, "writable").is_err());
+    }
+
+    #[test]
+    fn checks_images_before_they_are_written() {
+        let mut image = tempfile::tempfile().unwrap();
+        image.set_len(IMAGE_LEN).unwrap();
+        assert!(check_image(&mut image, IMAGE_LEN, 512, DISK_SIZE).is_err());
+
+        let mut mbr = Mbr::protective(512, IMAGE_LEN);
+        mbr.partitions[0] = MbrPartition { bootable: true, kind: 0x00, start_lba: 0, sectors: (IMAGE_LEN / 512) as u32 };
+        mbr.write(&mut image).unwrap();
+        check_image(&mut image, IMAGE_LEN, 512, DISK_SIZE).unwrap();
+        assert!(check_image(&mut image, IMAGE_LEN, 512, IMAGE_LEN + MIN_SIZE - 512).is_err());
+        for slot in 1..4 {
+            mbr.partitions[slot] = MbrPartition { bootable: false, kind: MBR_EFI_PARTITION, start_lba: 100 * slot as u32, sectors: 100 };
+        }
+        mbr.write(&mut image).unwrap();
+        assert!(check_image(&mut image, IMAGE_LEN, 512, DISK_SIZE).is_err());
+
+        // The image's GPT only covers the image, the check goes by the drive.
+        let mut image = tempfile::tempfile().unwrap();
+        image.set_len(IMAGE_LEN).unwrap();
+        Gpt::new(512, IMAGE_LEN).write(&mut image, 512, IMAGE_LEN).unwrap();
+        Mbr::protective(512, IMAGE_LEN).write(&mut image).unwrap();
+        check_image(&mut image, IMAGE_LEN, 512, DISK_SIZE).unwrap();
+        assert!(check_image(&mut image, IMAGE_LEN, 512, IMAGE_LEN + MIN_SIZE).is_err());
+        // Nothing was written to it.
+        assert_eq!(image.metadata().unwrap().len(), IMAGE_LEN);
+    }
+}
@@ -0,0 +1,198 @@
+use crate::iso::Iso;
+
+use std::io::{self, Read, Seek};
+
+pub struct Release {
+    pub name: &'static str,
+    pub flavor: &'static str,
+    pub version: &'static str,
+    pub arch: &'static str,
+    pub url: &'static str,
+    pub size: u64,
+    pub sha256: &'static str,
+}
+
+// TODO: Find an URL through the RSS feed https://launchpad.net/ubuntu/+cdmirrors-rss
+pub const RELEASES: &[Release] = &[
+    Release {
+        name: "Ubuntu 20.04 LTS (Focal Fossa)",
+        flavor: "Ubuntu",
+        version: "20.04",
+        arch: "amd64",
+        url: "https://mirrors.melbourne.co.uk/ubuntu-releases/20.04/ubuntu-20.04-desktop-amd64.iso",
+        size: 2_715_254_784,
+        sha256: "e5b72e9cfe20988991c9cd87bde43c0b691e3b67b01f76d23f8150615883ce11",
+    },
+];
+
+pub fn default_release() -> &'static Release {
+    &RELEASES[0]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edition {
+    Desktop,
+    Server,
+}
+
+// Turns "20.04" or "22.04.3" into (20, 4), to compare releases.
+pub fn parse_version(version: &str) -> Option<(u32, u32)> {
+    let mut parts = version.split('.');
+    let year = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    Some((year, month))
+}
+
+// The installer only fills the free space next to Windows with the use_gap
+// layout mode of 23.04. Older ones don't know the mode, ignore it and wipe
+// the whole disk.
+pub fn supports_use_gap(version: &str) -> bool {
+    parse_version(version).map_or(false, |v| v >= (23, 4))
+}
+
+// Autoinstall is a feature of subiquity, the installer of the servers since
+// 20.04, and of the desktops since 23.04. The older desktop installer ignores
+// the autoinstall config.
+pub fn has_subiquity(edition: Edition, version: &str) -> bool {
+    let first = match edition {
+        Edition::Server => (20, 4),
+        Edition::Desktop => (23, 4),
+    };
+    parse_version(version).map_or(false, |v| v >= first)
+}
+
+// What an image says about itself.
+#[derive(Debug, Clone)]
+pub struct ReleaseInfo {
+    // e.g. "Ubuntu 20.04 LTS (Focal Fossa)".
+    pub name: String,
+    // "Ubuntu", "Kubuntu", "Ubuntu-Server"...
+    pub flavor: String,
+    pub version: String,
+    pub codename: Option<String>,
+    pub arch: Option<String>,
+    // As YYYY-MM-DD.
+    pub build_date: Option<String>,
+    pub edition: Edition,
+    // Packages installed in the live system.
+    pub packages: Option<usize>,
+}
+
+impl ReleaseInfo {
+    // Looks for the image in our catalog. The size has to match too: daily
+    // builds and point releases share the version of their release.
+    pub fn find_release(&self, image_size: u64) -> Option<&'static Release> {
+        RELEASES.iter().find(|v| {
+            v.flavor.eq_ignore_ascii_case(&self.flavor)
+                && v.version == self.version
+                && Some(v.arch) == self.arch.as_deref()
+                && v.size == image_size
+        })
+    }
+}
+
+// Reads .disk/info, casper/filesystem.manifest and boot/grub/grub.cfg.
+// Returns None for images that don't have a .disk/info.
+pub fn identify<R: Read + Seek>(iso: &mut Iso<R>) -> io::Result<Option<ReleaseInfo>> {
+    let disk_info = match iso.find(".disk/info")? {
+        Some(entry) => String::from_utf8_lossy(&iso.read_file(&entry)?).into_owned(),
+        None => return Ok(None),
+    };
+    let mut info = match parse_disk_info(&disk_info) {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+
+    if let Some(entry) = iso.find("casper/filesystem.manifest")? {
+        let manifest = iso.read_file(&entry)?;
+        info.packages = Some(manifest.split(|&v| v == b'\n').filter(|v| !v.is_empty()).count());
+    }
+    // Server images boot into subiquity, and say so in their menu.
+    if let Some(entry) = iso.find("boot/grub/grub.cfg")? {
+        let grub_cfg = String::from_utf8_lossy(&iso.read_file(&entry)?).into_owned();
+        let server = grub_cfg.lines().any(|line| {
+            let line = line.trim();
+            line.starts_with("menuentry") && line.contains("Server")
+        });
+        if server {
+            info.edition = Edition::Server;
+        }
+    }
+    Ok(Some(info))
+}
+
+// Parses something like:
+// Ubuntu 20.04 LTS "Focal Fossa" - Release amd64 (20200423)
+pub fn parse_disk_info(disk_info: &str) -> Option<ReleaseInfo> {
+    let line = disk_info.lines().next()?.trim();
+    let mut words = line.split_whitespace();
+    let flavor = words.next()?.to_string();
+    let version = words.next()?.to_string();
+    if !version.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+    let lts = words.next() == Some("LTS");
+
+    let codename = line.find('"').and_then(|start| {
+        let end = line[start + 1..].find('"')?;
+        Some(line[start + 1..start + 1 + end].to_string())
+    });
+    // "Release amd64 (20200423)" or "Daily amd64 (20200410)".
+    let build = line.rfind(" - ").map(|pos| &line[pos + 3..]).unwrap_or("");
+    let arch = build.split_whitespace().nth(1).filter(|v| !v.starts_with('(')).map(|v| v.to_string());
+    let build_date = build.rfind('(').and_then(|start| {
+        let date = build[start + 1..].trim_end_matches(')');
+        if date.len() >= 8 && date[..8].bytes().all(|v| v.is_ascii_digit()) {
+            Some(format!("{}-{}-{}", &date[..4], &date[4..6], &date[6..8]))
+        } else {
+            None
+        }
+    });
+
+    let mut name = format!("{} {}", flavor.replace('-', " "), version);
+    if lts {
+        name.push_str(" LTS");
+    }
+    if let Some(codename) = &codename {
+        name.push_str(&format!(" ({})", codename));
+    }
+    let edition = if flavor.contains("Server") { Edition::Server } else { Edition::Desktop };
+
+    Some(ReleaseInfo { name, flavor, version, codename, arch, build_date, edition, packages: None })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_disk_info() {
+        let info = parse_disk_info("Ubuntu-Server 22.04.3 LTS \"Jammy Jellyfish\" - Release amd64 (20230807.2)\n").unwrap();
+        assert_eq!(info.name, "Ubuntu Server 22.04.3 LTS (Jammy Jellyfish)");
+        assert_eq!(info.version, "22.04.3");
+        assert_eq!(info.arch.as_deref(), Some("amd64"));
+        assert_eq!(info.build_date.as_deref(), Some("2023-08-07"));
+        assert_eq!(info.edition, Edition::Server);
+        assert!(parse_disk_info("Some other disk").is_none());
+    }
+
+    #[test]
+    fn compares_versions() {
+        assert_eq!(parse_version("22.04.3"), Some((22, 4)));
+        assert_eq!(parse_version("daily"), None);
+        assert!(!supports_use_gap("20.04"));
+        assert!(!supports_use_gap("22.04.3"));
+        assert!(supports_use_gap("23.04"));
+        assert!(supports_use_gap("24.04.1"));
+    }
+
+    #[test]
+    fn knows_where_subiquity_runs() {
+        assert!(has_subiquity(Edition::Server, "20.04"));
+        assert!(!has_subiquity(Edition::Desktop, "20.04"));
+        assert!(!has_subiquity(Edition::Desktop, "22.10"));
+        assert!(has_subiquity(Edition::Desktop, "23.04"));
+        assert!(!has_subiquity(Edition::Server, "18.04.5"));
+        assert!(!has_subiquity(Edition::Server, "unknown"));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::disk::{BusType, DiskInfo};
+
+use std::fmt;
+
+// The wizard wipes whatever disk the user picks, so this decides which disks
+// they're allowed to pick in the first place.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refusal {
+    BootDisk,
+    SystemDisk,
+    PagefileDisk,
+    BitLocker,
+    // Telling needs admin rights.
+    BitLockerUnknown,
+    NotRemovable,
+    // Drives get renumbered when others come and go.
+    Changed,
+}
+
+impl Refusal {
+    // Refusals that the "show all disks" toggle can't override.
+    pub fn is_hard(&self) -> bool {
+        !matches!(self, Refusal::NotRemovable | Refusal::BitLockerUnknown)
+    }
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Refusal::BootDisk => "This disk holds the partition your computer boots from.",
+            Refusal::SystemDisk => "This disk holds Windows.",
+            Refusal::PagefileDisk => "This disk holds the Windows page file.",
+            Refusal::BitLocker => "This disk is protected by BitLocker.",
+            Refusal::BitLockerUnknown => "Windows didn't tell whether BitLocker protects this disk.",
+            Refusal::NotRemovable => "This disk is not removable.",
+            Refusal::Changed => "This disk isn't the one that was picked anymore.",
+        };
+        f.write_str(s)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SafetyPolicy {
+    pub show_all_disks: bool,
+}
+
+// Every reason not to wipe the disk, the hard ones first.
+fn refusals(disk: &DiskInfo) -> Vec<Refusal> {
+    let mut refusals = Vec::new();
+    if disk.boot {
+        refusals.push(Refusal::BootDisk);
+    }
+    if disk.system {
+        refusals.push(Refusal::SystemDisk);
+    }
+    if disk.pagefile {
+        refusals.push(Refusal::PagefileDisk);
+    }
+    match disk.bitlocker {
+        Some(true) => refusals.push(Refusal::BitLocker),
+        Some(false) => (),
+        None => refusals.push(Refusal::BitLockerUnknown),
+    }
+    if !is_removable(disk) {
+        refusals.push(Refusal::NotRemovable);
+    }
+    refusals
+}
+
+impl SafetyPolicy {
+    pub fn check(&self, disk: &DiskInfo) -> Result<(), Refusal> {
+        match refusals(disk).into_iter().find(|v| v.is_hard() || !self.show_all_disks) {
+            Some(refusal) => Err(refusal),
+            None => Ok(()),
+        }
+    }
+
+    // The refusals "show all disks" lets the user override, which they get
+    // to confirm.
+    pub fn overridden(&self, disk: &DiskInfo) -> Vec<Refusal> {
+        if !self.show_all_disks {
+            return Vec::new();
+        }
+        refusals(disk).into_iter().filter(|v| !v.is_hard()).collect()
+    }
+
+    // Checks the disk again right before wiping it, as `current`, in case it
+    // got swapped for another or got mounted since it was picked as
+    // `picked`.
+    pub fn recheck(&self, picked: &DiskInfo, current: &DiskInfo) -> Result<(), Refusal> {
+        if current.device_number != picked.device_number || current.serial != picked.serial || current.size != picked.size {
+            return Err(Refusal::Changed);
+        }
+        self.check(current)?;
+        // Nor did the user confirm new ones.
+        let confirmed = self.overridden(picked);
+        match self.overridden(current).into_iter().find(|v| !confirmed.contains(v)) {
+            Some(refusal) => Err(refusal),
+            None => Ok(()),
+        }
+    }
+}
+
+// Lots of USB flash drives report themselves as fixed disks, so anything
+// hanging off a USB or card reader bus counts as removable too.
+fn is_removable(disk: &DiskInfo) -> bool {
+    disk.removable || matches!(disk.bus, BusType::Usb | BusType::Sd | BusType::Mmc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usb_drive() -> DiskInfo {
+        DiskInfo {
+            id: r"\\?\STORAGE#Volume#_??_USBSTOR#Disk&Ven_SanDisk".to_string(),
+            path: r"E:\".to_string(),
+            label: "UBUNTU".to_string(),
+            filesystem: "FAT32".to_string(),
+            device_number: 2,
+            size: 16 << 30,
+            vendor: "SanDisk".to_string(),
+            model: "Cruzer Blade".to_string(),
+            serial: "4C530001".to_string(),
+            bus: BusType::Usb,
+            removable: true,
+            boot: false,
+            system: false,
+            pagefile: false,
+            bitlocker: Some(false),
+        }
+    }
+
+    #[test]
+    fn rechecks_the_picked_drive() {
+        let policy = SafetyPolicy::default();
+        let picked = usb_drive();
+        assert_eq!(policy.recheck(&picked, &picked.clone()), Ok(()));
+
+        let mut other = usb_drive();
+        other.serial = "4C530002".to_string();
+        assert_eq!(policy.recheck(&picked, &other), Err(Refusal::Changed));
+        let mut other = usb_drive();
+        other.size = 32 << 30;
+        assert_eq!(policy.recheck(&picked, &other), Err(Refusal::Changed));
+        let mut other = usb_drive();
+        other.device_number = 3;
+        assert_eq!(policy.recheck(&picked, &other), Err(Refusal::Changed));
+
+        // Windows put its page file there since.
+        let mut current = usb_drive();
+        current.pagefile = true;
+        assert_eq!(policy.recheck(&picked, &current), Err(Refusal::PagefileDisk));
+    }
+
+    #[test]
+    fn takes_usb_drives() {
+        let policy = SafetyPolicy::default();
+        assert_eq!(policy.check(&usb_drive()), Ok(()));
+        assert!(policy.overridden(&usb_drive()).is_empty());
+        // Flash drives that say they're fixed, on a USB or card reader bus.
+        for &bus in &[BusType::Usb, BusType::Sd, BusType::Mmc] {
+            let drive = DiskInfo { removable: false, bus, ..usb_drive() };
+            assert_eq!(policy.check(&drive), Ok(()));
+        }
+    }
+
+    #[test]
+    fn refuses_windows_disks() {
+        let show_all = SafetyPolicy { show_all_disks: true };
+        let cases = [
+            (DiskInfo { boot: true, ..usb_drive() }, Refusal::BootDisk),
+            (DiskInfo { system: true, ..usb_drive() }, Refusal::SystemDisk),
+            (DiskInfo { pagefile: true, ..usb_drive() }, Refusal::PagefileDisk),
+            (DiskInfo { bitlocker: Some(true), ..usb_drive() }, Refusal::BitLocker),
+        ];
+        for (drive, refusal) in &cases {
+            assert!(refusal.is_hard());
+            assert_eq!(SafetyPolicy::default().check(drive), Err(*refusal));
+            assert_eq!(show_all.check(drive), Err(*refusal));
+        }
+        // The internal disk, with everything on it.
+        let internal = DiskInfo { boot: true, system: true, pagefile: true, bitlocker: Some(true), removable: false, bus: BusType::Nvme, ..usb_drive() };
+        assert_eq!(show_all.check(&internal), Err(Refusal::BootDisk));
+    }
+
+    #[test]
+    fn lets_fixed_disks_through_when_showing_all() {
+        let drive = DiskInfo { removable: false, bus: BusType::Sata, ..usb_drive() };
+        assert_eq!(SafetyPolicy::default().check(&drive), Err(Refusal::NotRemovable));
+        let show_all = SafetyPolicy { show_all_disks: true };
+        assert_eq!(show_all.check(&drive), Ok(()));
+        assert_eq!(show_all.overridden(&drive), vec![Refusal::NotRemovable]);
+        assert!(SafetyPolicy::default().overridden(&drive).is_empty());
+    }
+
+    #[test]
+    fn fails_closed_without_bitlocker_status() {
+        let drive = DiskInfo { bitlocker: None, ..usb_drive() };
+        assert!(!Refusal::BitLockerUnknown.is_hard());
+        assert_eq!(SafetyPolicy::default().check(&drive), Err(Refusal::BitLockerUnknown));
+        let show_all = SafetyPolicy { show_all_disks: true };
+        assert_eq!(show_all.check(&drive), Ok(()));
+        assert_eq!(show_all.overridden(&drive), vec![Refusal::BitLockerUnknown]);
+
+        let fixed = DiskInfo { removable: false, bus: BusType::Sata, ..drive.clone() };
+        assert_eq!(SafetyPolicy::default().check(&fixed), Err(Refusal::BitLockerUnknown));
+        assert_eq!(show_all.overridden(&fixed), vec![Refusal::BitLockerUnknown, Refusal::NotRemovable]);
+
+        // What got confirmed for the picked drive has to cover it when
+        // it's opened.
+        assert_eq!(show_all.recheck(&drive, &drive.clone()), Ok(()));
+        assert_eq!(show_all.recheck(&usb_drive(), &drive), Err(Refusal::BitLockerUnknown));
+        assert_eq!(show_all.recheck(&drive, &usb_drive()), Ok(()));
+    }
+}
@@ -0,0 +1,238 @@
+use crate::autoinstall::Autoinstall;
+use crate::fat32::Fat32Writer;
+use crate::partition::{Gpt, GptPartition, Guid, Mbr, MbrPartition, BASIC_DATA_PARTITION};
+use crate::writer::{round_up, Target};
+
+use std::io::{self, Cursor, Read, Seek, Write};
+
+// A NoCloud seed: cloud-init, and so subiquity, read their config from any
+// filesystem labeled CIDATA, with user-data and meta-data at its root.
+
+pub const LABEL: &str = "CIDATA";
+
+const FAT32_LBA_PARTITION: u8 = 0x0C;
+const ALIGNMENT: u64 = 1024 * 1024;
+
+fn other_err(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg)
+}
+
+pub struct Seed {
+    user_data: Vec<u8>,
+    meta_data: Vec<u8>,
+}
+
+impl Seed {
+    pub fn new(config: &Autoinstall) -> Result<Seed, String> {
+        config.validate()?;
+        let user_data = config.to_user_data().map_err(|err| err.to_string())?;
+        Ok(Seed { user_data: user_data.into_bytes(), meta_data: config.to_meta_data().into_bytes() })
+    }
+
+    // FAT32 needs 65525 clusters, which are a sector each at this size.
+    pub fn partition_size(sector_size: u64) -> u64 {
+        round_up(40 * 1024 * 1024 / 512 * sector_size, ALIGNMENT)
+    }
+
+    fn format<D: Read + Write + Seek>(&self, dev: &mut D, offset: u64, size: u64, sector_size: u64) -> io::Result<()> {
+        let mut fat = Fat32Writer::new(dev, offset, size, sector_size, LABEL)?;
+        let root = fat.root();
+        fat.create_file(root, "user-data", self.user_data.len() as u64, &mut Cursor::new(&self.user_data))?;
+        fat.create_file(root, "meta-data", self.meta_data.len() as u64, &mut Cursor::new(&self.meta_data))?;
+        fat.finish()
+    }
+
+    // Where the partition goes, given the image's partition table, which
+    // is either read from the image itself or from the drive it was written
+    // to.
+    fn place<D: Read + Seek>(dev: &mut D, image_len: u64, sector_size: u64, disk_size: u64) -> io::Result<Place> {
+        let mbr = Mbr::read(dev, sector_size)?.ok_or_else(|| other_err("The image has no partition table."))?;
+        let size = Seed::partition_size(sector_size);
+        let no_space = || other_err("There isn't enough space left on the drive for the autoinstall config.");
+
+        // Images keep their backup GPT at their end, it moves to the end of
+        // the drive.
+        if mbr.protects_gpt() {
+            let mut gpt = Gpt::read(dev, sector_size)?.ok_or_else(|| other_err("The image's GPT is damaged."))?;
+            if !gpt.entries.iter().any(|v| v.is_empty()) {
+                return Err(other_err("The image's partition table is full."));
+            }
+            gpt.resize(sector_size, disk_size);
+            let start = round_up(std::cmp::max(image_len, gpt.end_lba() * sector_size), ALIGNMENT);
+            if start + size > (gpt.last_usable + 1) * sector_size {
+                return Err(no_space());
+            }
+            return Ok(Place::Gpt { mbr, gpt, start, size });
+        }
+
+        let slot = mbr.free_slot().ok_or_else(|| other_err("The image's partition table is full."))?;
+        let start = round_up(std::cmp::max(image_len, mbr.end_lba() * sector_size), ALIGNMENT);
+        if start + size > disk_size {
+            return Err(no_space());
+        }
+        Ok(Place::Mbr { mbr, slot, start, size })
+    }
+
+    // Turns down images the partition can't be added to, before they get
+    // written. `image` is the image as it will be written.
+    pub fn check_image<R: Read + Seek>(image: &mut R, image_len: u64, sector_size: u64, disk_size: u64) -> io::Result<()> {
+        Seed::place(image, image_len, sector_size, disk_size).map(|_| ())
+    }
+
+    // Adds the seed partition after the image, to the image's partition
+    // table. Drives made by filecopy::write_files have nothing but their
+    // GPT, and an `image_len` of 0.
+    pub fn add_partition<T: Target>(&self, target: &mut T, image_len: u64) -> io::Result<()> {
+        let sector_size = target.sector_size();
+        let disk_size = target.size()?;
+
+        match Seed::place(target, image_len, sector_size, disk_size)? {
+            Place::Mbr { mut mbr, slot, start, size } => {
+                self.format(target, start, size, sector_size)?;
+                mbr.partitions[slot] = MbrPartition {
+                    bootable: false,
+                    kind: FAT32_LBA_PARTITION,
+                    start_lba: (start / sector_size) as u32,
+                    sectors: (size / sector_size) as u32,
+                };
+                mbr.write(target)?;
+            }
+            Place::Gpt { mut mbr, mut gpt, start, size } => {
+                self.format(target, start, size, sector_size)?;
+                gpt.add(GptPartition {
+                    kind: BASIC_DATA_PARTITION,
+                    guid: Guid::random(),
+                    first_lba: start / sector_size,
+                    last_lba: (start + size) / sector_size - 1,
+                    attributes: 0,
+                    name: LABEL.to_string(),
+                })?;
+                gpt.write(target, sector_size, disk_size)?;
+                mbr.grow_protective(sector_size, disk_size);
+                mbr.write(target)?;
+            }
+        }
+        target.rescan()
+    }
+}
+
+enum Place {
+    Mbr { mbr: Mbr, slot: usize, start: u64, size: u64 },
+    Gpt { mbr: Mbr, gpt: Gpt, start: u64, size: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::fat32::tests::read_files;
+    use crate::partition::{gpt_usable_lbas, EFI_SYSTEM_PARTITION, MBR_EFI_PARTITION};
+    use std::fs::File;
+    use std::io::SeekFrom;
+
+    const IMAGE_LEN: u64 = 8 << 20;
+    const DISK_SIZE: u64 = 256 << 20;
+
+    fn seed() -> Seed {
+        let mut config = Autoinstall::default();
+        config.identity.password = crate::crypt::sha512_crypt_with_salt("ubuntu", "saltsalt");
+        Seed::new(&config).unwrap()
+    }
+
+    fn mbr_image(disk_size: u64) -> File {
+        let mut disk = tempfile::tempfile().unwrap();
+        disk.set_len(disk_size).unwrap();
+        let mut mbr = Mbr::protective(512, DISK_SIZE);
+        mbr.partitions[0] = MbrPartition { bootable: true, kind: 0x00, start_lba: 0, sectors: (IMAGE_LEN / 512) as u32 };
+        mbr.partitions[1] = MbrPartition { bootable: false, kind: MBR_EFI_PARTITION, start_lba: 100, sectors: 2000 };
+        mbr.write(&mut disk).unwrap();
+        disk
+    }
+
+    // An image with a GPT sized for itself, written to a bigger drive.
+    fn gpt_image(disk_size: u64) -> File {
+        let mut disk = tempfile::tempfile().unwrap();
+        disk.set_len(IMAGE_LEN).unwrap();
+        let (first_usable, _) = gpt_usable_lbas(512, IMAGE_LEN);
+        let mut gpt = Gpt::new(512, IMAGE_LEN);
+        gpt.add(GptPartition {
+            kind: EFI_SYSTEM_PARTITION,
+            guid: Guid::random(),
+            first_lba: first_usable,
+            last_lba: 4095,
+            attributes: 0,
+            name: "ESP".to_string(),
+        }).unwrap();
+        gpt.write(&mut disk, 512, IMAGE_LEN).unwrap();
+        Mbr::protective(512, IMAGE_LEN).write(&mut disk).unwrap();
+        disk.set_len(disk_size).unwrap();
+        disk
+    }
+
+    fn check_seed(disk: &mut File, offset: u64, seed: &Seed) {
+        let files = read_files(disk, offset);
+        assert_eq!(files.len(), 2);
+        assert_eq!(files["user-data"], seed.user_data);
+        assert_eq!(files["meta-data"], seed.meta_data);
+        assert!(String::from_utf8_lossy(&seed.user_data).starts_with("#cloud-config\n"));
+
+        // blkid goes by the boot sector's label.
+        let mut label = [0; 11];
+        disk.seek(SeekFrom::Start(offset + 71)).unwrap();
+        disk.read_exact(&mut label).unwrap();
+        assert_eq!(&label, b"CIDATA     ");
+    }
+
+    #[test]
+    fn adds_an_mbr_partition() {
+        let seed = seed();
+        let mut disk = mbr_image(DISK_SIZE);
+        Seed::check_image(&mut disk, IMAGE_LEN, 512, DISK_SIZE).unwrap();
+        seed.add_partition(&mut disk, IMAGE_LEN).unwrap();
+
+        let mbr = Mbr::read(&mut disk, 512).unwrap().unwrap();
+        let partition = mbr.partitions[2];
+        assert_eq!(partition.kind, FAT32_LBA_PARTITION);
+        assert_eq!(partition.start_lba as u64 * 512, IMAGE_LEN);
+        assert_eq!(partition.sectors as u64 * 512, Seed::partition_size(512));
+        check_seed(&mut disk, IMAGE_LEN, &seed);
+    }
+
+    #[test]
+    fn adds_a_gpt_partition() {
+        let seed = seed();
+        let mut disk = gpt_image(DISK_SIZE);
+        Seed::check_image(&mut disk, IMAGE_LEN, 512, DISK_SIZE).unwrap();
+        seed.add_partition(&mut disk, IMAGE_LEN).unwrap();
+
+        let gpt = Gpt::read(&mut disk, 512).unwrap().unwrap();
+        let (number, partition) = gpt.partitions().find(|(_, v)| v.kind == BASIC_DATA_PARTITION).unwrap();
+        assert_eq!(number, 2);
+        assert_eq!(partition.name, LABEL);
+        assert_eq!(partition.first_lba * 512, IMAGE_LEN);
+        assert_eq!((partition.last_lba + 1 - partition.first_lba) * 512, Seed::partition_size(512));
+        assert_eq!(gpt.last_usable, gpt_usable_lbas(512, DISK_SIZE).1);
+        let mbr = Mbr::read(&mut disk, 512).unwrap().unwrap();
+        assert_eq!(mbr.partitions[0].end_lba() * 512, DISK_SIZE);
+        check_seed(&mut disk, IMAGE_LEN, &seed);
+    }
+
+    #[test]
+    fn turns_down_images_before_writing() {
+        let small = IMAGE_LEN + Seed::partition_size(512) - 512;
+        assert!(Seed::check_image(&mut mbr_image(small), IMAGE_LEN, 512, small).is_err());
+        assert!(Seed::check_image(&mut gpt_image(small), IMAGE_LEN, 512, small).is_err());
+
+        let mut blank = tempfile::tempfile().unwrap();
+        blank.set_len(IMAGE_LEN).unwrap();
+        assert!(Seed::check_image(&mut blank, IMAGE_LEN, 512, DISK_SIZE).is_err());
+
+        let mut full = mbr_image(DISK_SIZE);
+        let mut mbr = Mbr::read(&mut full, 512).unwrap().unwrap();
+        for slot in 2..4 {
+            mbr.partitions[slot] = MbrPartition { bootable: false, kind: 0x83, start_lba: 4096 * slot as u32, sectors: 2000 };
+        }
+        mbr.write(&mut full).unwrap();
+        assert!(Seed::check_image(&mut full, IMAGE_LEN, 512, DISK_SIZE).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+use crate::bootable::BootInfo;
+
+// What we know about the computer the wizard runs on, which is usually the
+// one the drive will boot.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirmwareType {
+    Bios,
+    Uefi,
+    Unknown,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Firmware {
+    // How Windows was booted. A UEFI firmware with the CSM enabled can boot
+    // both ways, but it's the best guess we have.
+    pub kind: FirmwareType,
+    pub secure_boot: bool,
+    // Whether we can add a boot option and set BootNext.
+    pub boot_next: bool,
+}
+
+impl Firmware {
+    pub fn describe(&self) -> &'static str {
+        match (self.kind, self.secure_boot) {
+            (FirmwareType::Uefi, true) => "UEFI, with Secure Boot",
+            (FirmwareType::Uefi, false) => "UEFI, without Secure Boot",
+            (FirmwareType::Bios, _) => "legacy BIOS",
+            (FirmwareType::Unknown, _) => "unknown firmware",
+        }
+    }
+
+    // Copying the files only gives a drive UEFI firmwares can boot.
+    pub fn can_boot_files(&self) -> bool {
+        self.kind != FirmwareType::Bios
+    }
+
+    // Whether to copy the files of `image` rather than write it as is, for
+    // it to boot here.
+    pub fn prefers_files(&self, image: &BootInfo) -> bool {
+        match self.kind {
+            FirmwareType::Bios => false,
+            _ => !image.uefi() && !image.bios() && image.efi_files,
+        }
+    }
+
+    // Why `image` wouldn't boot here, when it boots elsewhere.
+    pub fn boot_warning(&self, image: &BootInfo) -> Option<String> {
+        match self.kind {
+            FirmwareType::Bios if !image.bios() && (image.uefi() || image.efi_files) => {
+                Some("This computer started Windows in legacy BIOS mode, and this image only boots with UEFI. The drive will only boot on other computers, or after switching this one's firmware to UEFI.".to_string())
+            }
+            FirmwareType::Uefi if !image.uefi() && !image.efi_files && image.bios() => {
+                Some("This computer started Windows with UEFI, and this image only boots on legacy BIOSes. Booting it requires enabling the legacy mode (CSM) in the firmware settings.".to_string())
+            }
+            _ => None,
+        }
+    }
+
+    // What to tell users when we can't restart on the drive for them.
+    pub fn reboot_hint(&self) -> Option<&'static str> {
+        if self.boot_next {
+            return None;
+        }
+        Some(match self.kind {
+            FirmwareType::Bios => "This computer started Windows in legacy BIOS mode, so it can't be told to start on the USB flash drive. Restart it and pick the drive in its boot menu, usually with F12, F11, F9 or Esc.",
+            _ => "This computer can't be told to start on the USB flash drive. Restart it and pick the drive in its boot menu, usually with F12, F11, F9 or Esc.",
+        })
+    }
+}
+
+// Things to sort out before restarting on the drive.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RebootChecks {
+    // The drive Windows runs from, when BitLocker protects it.
+    pub bitlocker: Option<String>,
+    // Why we couldn't tell whether it does.
+    pub bitlocker_unknown: Option<String>,
+    // Windows hibernates instead of shutting down, and leaves its volumes in
+    // a state Ubuntu only mounts read-only.
+    pub fast_startup: bool,
+    // Updates get installed on the next restart, which would then end in
+    // Windows.
+    pub pending_update: bool,
+    // The storage controller in RAID mode, which hides the disks from the
+    // installer.
+    pub raid_controller: Option<String>,
+}
+
+#[cfg(windows)]
+pub use self::win::{boot_safe_mode_once, disable_fast_startup, firmware, reboot_checks, suspend_bitlocker};
+
+#[cfg(windows)]
+mod win {
+    use super::{Firmware, FirmwareType, RebootChecks};
+    use crate::compat;
+    use crate::disk::bitlocker_drives;
+    use crate::efi;
+
+    use winapi::um::winbase::{GetFirmwareType, CREATE_NO_WINDOW};
+    use winapi::um::winnt::{FirmwareTypeBios, FirmwareTypeUefi};
+    use winreg::RegKey;
+    use winreg::enums::{HKEY_LOCAL_MACHINE, KEY_SET_VALUE};
+
+    use std::io;
+    use std::os::windows::process::CommandExt;
+    use std::process::Command;
+
+    fn firmware_type() -> FirmwareType {
+        let mut kind = 0;
+        if unsafe { GetFirmwareType(&mut kind) } == 0 {
+            return FirmwareType::Unknown;
+        }
+        match kind {
+            FirmwareTypeBios => FirmwareType::Bios,
+            FirmwareTypeUefi => FirmwareType::Uefi,
+            _ => FirmwareType::Unknown,
+        }
+    }
+
+    // Reading the SecureBoot variable needs a privilege, Windows keeps a copy
+    // of it in the registry.
+    fn secure_boot() -> bool {
+        RegKey::predef(HKEY_LOCAL_MACHINE)
+            .open_subkey(r"SYSTEM\CurrentControlSet\Control\SecureBoot\State")
+            .and_then(|key| key.get_value::<u32, _>("UEFISecureBootEnabled"))
+            .map(|v| v != 0)
+            .unwrap_or(false)
+    }
+
+    pub fn firmware() -> Firmware {
+        let kind = firmware_type();
+        Firmware {
+            kind,
+            secure_boot: kind == FirmwareType::Uefi && secure_boot(),
+            boot_next: kind == FirmwareType::Uefi && efi::can_boot_next(),
+        }
+    }
+
+    // The drive Windows runs from, if BitLocker protects it. Once the boot
+    // order changes, the TPM won't unlock it anymore, and Windows asks for
+    // the recovery key on its next start.
+    fn system_drive_protected() -> io::Result<Option<String>> {
+        let drive = std::env::var("SystemDrive").unwrap_or_else(|_| "C:".to_string());
+        let protected = bitlocker_drives().map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+        Ok(if protected.iter().any(|v| v.eq_ignore_ascii_case(&drive)) { Some(drive) } else { None })
+    }
+
+    // Lets the TPM unlock the drive without checking the boot order, until
+    // Windows has started once more.
+    pub fn suspend_bitlocker(drive: &str) -> io::Result<()> {
+        let output = Command::new("manage-bde.exe")
+            .args(&["-protectors", "-disable", drive, "-RebootCount", "1"])
+            .creation_flags(CREATE_NO_WINDOW)
+            .output()?;
+        if !output.status.success() {
+            // It prints its errors on stdout, after a banner.
+            let text = String::from_utf8_lossy(&output.stdout);
+            let msg = text.lines().map(str::trim).filter(|v| !v.is_empty()).last().unwrap_or("manage-bde failed");
+            return Err(io::Error::new(io::ErrorKind::Other, msg.to_string()));
+        }
+        Ok(())
+    }
+
+    const POWER_KEY: &str = r"SYSTEM\CurrentControlSet\Control\Session Manager\Power";
+
+    // Fast Startup only kicks in when hibernation is available too.
+    fn fast_startup() -> bool {
+        let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+        let hiberboot = hklm.open_subkey(POWER_KEY).and_then(|key| key.get_value::<u32, _>("HiberbootEnabled")).unwrap_or(0);
+        let hibernate = hklm.open_subkey(r"SYSTEM\CurrentControlSet\Control\Power").and_then(|key| key.get_value::<u32, _>("HibernateEnabled")).unwrap_or(1);
+        hiberboot != 0 && hibernate != 0
+    }
+
+    pub fn disable_fast_startup() -> io::Result<()> {
+        let key = RegKey::predef(HKEY_LOCAL_MACHINE).open_subkey_with_flags(POWER_KEY, KEY_SET_VALUE)?;
+        key.set_value("HiberbootEnabled", &0u32)
+    }
+
+    // Windows Update and the servicing stack each flag a reboot they're
+    // waiting for with one of these keys.
+    fn pending_update() -> bool {
+        let hklm = RegKey::predef(HKEY_LOCAL_MACHINE);
+        [
+            r"SOFTWARE\Microsoft\Windows\CurrentVersion\WindowsUpdate\Auto Update\RebootRequired",
+            r"SOFTWARE\Microsoft\Windows\CurrentVersion\Component Based Servicing\RebootPending",
+        ]
+        .iter()
+        .any(|path| hklm.open_subkey(path).is_ok())
+    }
+
+    fn bcdedit(args: &[&str]) -> io::Result<()> {
+        let output = Command::new("bcdedit.exe")
+            .args(args)
+            .creation_flags(CREATE_NO_WINDOW)
+            .output()?;
+        if !output.status.success() {
+            let text = String::from_utf8_lossy(&output.stdout);
+            let msg = text.lines().map(str::trim).filter(|v| !v.is_empty()).last().unwrap_or("bcdedit failed");
+            return Err(io::Error::new(io::ErrorKind::Other, msg.to_string()));
+        }
+        Ok(())
+    }
+
+    // Windows only loads the storage driver it booted with last time, and
+    // fails with INACCESSIBLE_BOOT_DEVICE once the controller switches from
+    // RAID to AHCI. Safe mode loads them all, and makes the AHCI one stick.
+    // RunOnce values starting with * run in safe mode too, and bring the
+    // normal mode back for the restart after that.
+    pub fn boot_safe_mode_once() -> io::Result<()> {
+        let (key, _) = RegKey::predef(HKEY_LOCAL_MACHINE).create_subkey(r"SOFTWARE\Microsoft\Windows\CurrentVersion\RunOnce")?;
+        key.set_value("*LeaveSafeMode", &"bcdedit.exe /deletevalue {current} safeboot")?;
+        bcdedit(&["/set", "{current}", "safeboot", "minimal"])
+    }
+
+    pub fn reboot_checks() -> RebootChecks {
+        let (bitlocker, bitlocker_unknown) = match system_drive_protected() {
+            Ok(drive) => (drive, None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        RebootChecks {
+            bitlocker,
+            bitlocker_unknown,
+            fast_startup: fast_startup(),
+            pending_update: pending_update(),
+            raid_controller: compat::raid_controller(),
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use crate::filecopy::FileMap;
+use crate::iso::{DirEntry, Iso};
+use crate::writer::{read_full, round_up, AlignedBuffer, Target};
+
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Io(io::Error),
+    // The device returned different data than what was written, starting at
+    // this offset. Usually a failing or counterfeit flash drive.
+    Mismatch { offset: u64 },
+    // The data read back matches the image, but the image itself doesn't
+    // match its expected checksum.
+    ChecksumMismatch { expected: String, actual: String },
+    // Files that don't match md5sum.txt, or are missing.
+    CorruptedFiles(Vec<String>),
+}
+
+impl From<io::Error> for VerifyError {
+    fn from(err: io::Error) -> VerifyError {
+        VerifyError::Io(err)
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VerifyError::Io(err) => write!(f, "{}", err),
+            VerifyError::Mismatch { offset } => write!(f, "The drive returned different data than what was written, starting at offset {:#x}. It may be failing, or report a bigger size than it really has.", offset),
+            VerifyError::ChecksumMismatch { expected, actual } => write!(f, "The image is corrupted: expected SHA256 {}, got {}.", expected, actual),
+            VerifyError::CorruptedFiles(files) => {
+                write!(f, "{} file(s) are corrupted: {}", files.len(), files.iter().take(5).cloned().collect::<Vec<_>>().join(", "))?;
+                if files.len() > 5 {
+                    write!(f, "...")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+// Reads the first `image_len` bytes back from `target`, and checks that they
+// match both `image` and the `expected_sha256` hex digest, if any. Patched
+// images don't match the published digest anymore.
+pub fn verify<R, T, F>(image: &mut R, image_len: u64, target: &mut T, expected_sha256: Option<&str>, mut progress: F) -> Result<(), VerifyError>
+where
+    R: Read,
+    T: Target,
+    F: FnMut(u64, u64),
+{
+    let sector_size = target.sector_size();
+    target.seek(SeekFrom::Start(0))?;
+
+    let mut hasher = Sha256::new();
+    let mut image_buf = vec![0; CHUNK_SIZE];
+    let mut target_buf = AlignedBuffer::new(CHUNK_SIZE, sector_size as usize);
+    let mut offset = 0;
+    while offset < image_len {
+        let to_read = std::cmp::min(CHUNK_SIZE as u64, image_len - offset) as usize;
+        let read = read_full(image, &mut image_buf[..to_read])?;
+        if read != to_read {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The image is shorter than expected").into());
+        }
+
+        // Reads from the device must be a multiple of the sector size.
+        let aligned = round_up(to_read as u64, sector_size) as usize;
+        let read = read_full(target, &mut target_buf[..aligned])?;
+        if read < to_read {
+            return Err(VerifyError::Mismatch { offset: offset + read as u64 });
+        }
+
+        let written = &target_buf[..to_read];
+        if let Some(pos) = written.iter().zip(&image_buf[..to_read]).position(|(a, b)| a != b) {
+            return Err(VerifyError::Mismatch { offset: offset + pos as u64 });
+        }
+        hasher.update(written);
+
+        offset += to_read as u64;
+        progress(offset, image_len);
+    }
+
+    let actual = format!("{:x}", hasher.finalize());
+    match expected_sha256 {
+        Some(expected) if !actual.eq_ignore_ascii_case(expected) => {
+            Err(VerifyError::ChecksumMismatch { expected: expected.to_string(), actual })
+        }
+        _ => Ok(()),
+    }
+}
+
+// A file tree md5sum.txt can be checked against.
+pub trait Files {
+    fn size(&self, path: &str) -> Option<u64>;
+    fn open<'a>(&'a mut self, path: &str) -> io::Result<Box<dyn Read + 'a>>;
+    // Files we changed on purpose, which md5sum.txt no longer vouches for.
+    fn patched(&self, _path: &str) -> bool {
+        false
+    }
+}
+
+// The files inside an image.
+pub struct IsoFiles<R> {
+    iso: Iso<R>,
+    entries: HashMap<String, DirEntry>,
+}
+
+impl<R: Read + Seek> IsoFiles<R> {
+    pub fn new(mut iso: Iso<R>) -> io::Result<IsoFiles<R>> {
+        let mut entries = HashMap::new();
+        let mut dirs = vec![(String::new(), iso.root().clone())];
+        while let Some((path, dir)) = dirs.pop() {
+            for entry in iso.read_dir(&dir)? {
+                let entry_path = if path.is_empty() { entry.name.clone() } else { format!("{}/{}", path, entry.name) };
+                if entry.is_dir {
+                    dirs.push((entry_path, entry));
+                } else if entry.symlink.is_none() {
+                    entries.insert(entry_path, entry);
+                }
+            }
+        }
+        Ok(IsoFiles { iso, entries })
+    }
+}
+
+impl<R: Read + Seek> Files for IsoFiles<R> {
+    fn size(&self, path: &str) -> Option<u64> {
+        self.entries.get(path).map(|v| v.size())
+    }
+
+    fn open<'a>(&'a mut self, path: &str) -> io::Result<Box<dyn Read + 'a>> {
+        let entry = self.entries.get(path).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.to_string()))?;
+        Ok(Box::new(self.iso.open_file(entry)))
+    }
+}
+
+// The files on a drive written in file-copy mode. They're read back from
+// where they were written, so this doesn't depend on Windows mounting the
+// drive.
+pub struct DeviceFiles<'t, T> {
+    pub target: &'t mut T,
+    pub files: FileMap,
+    // Paths written with other contents, e.g. grub.cfg with boot options.
+    pub patched: Vec<String>,
+}
+
+impl<'t, T: Target> Files for DeviceFiles<'t, T> {
+    fn size(&self, path: &str) -> Option<u64> {
+        self.files.get(path).map(|v| v.1)
+    }
+
+    fn patched(&self, path: &str) -> bool {
+        self.patched.iter().any(|v| v.eq_ignore_ascii_case(path))
+    }
+
+    fn open<'a>(&'a mut self, path: &str) -> io::Result<Box<dyn Read + 'a>> {
+        let &(offset, len) = self.files.get(path).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, path.to_string()))?;
+        self.target.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(SectorReader {
+            buf: AlignedBuffer::new(CHUNK_SIZE, self.target.sector_size() as usize),
+            target: &mut *self.target,
+            left: len,
+            pos: 0,
+            filled: 0,
+        }))
+    }
+}
+
+// Reads `left` bytes from the current position of a device, in whole sectors.
+struct SectorReader<'a, T> {
+    target: &'a mut T,
+    left: u64,
+    buf: AlignedBuffer,
+    pos: usize,
+    filled: usize,
+}
+
+impl<'a, T: Target> Read for SectorReader<'a, T> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.filled {
+            if self.left == 0 {
+                return Ok(0);
+            }
+            let to_read = std::cmp::min(CHUNK_SIZE as u64, round_up(self.left, self.target.sector_size())) as usize;
+            let read = read_full(self.target, &mut self.buf[..to_read])?;
+            if read == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The drive is shorter than expected"));
+            }
+            self.filled = std::cmp::min(read as u64, self.left) as usize;
+            self.left -= self.filled as u64;
+            self.pos = 0;
+        }
+        let len = std::cmp::min(out.len(), self.filled - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+// Checks every file listed in md5sum.txt, like casper's "Check disc for
+// defects". Images without an md5sum.txt pass.
+pub fn check_md5sums<D, F>(files: &mut D, mut progress: F) -> Result<(), VerifyError>
+where
+    D: Files,
+    F: FnMut(u64, u64),
+{
+    if files.size("md5sum.txt").is_none() {
+        return Ok(());
+    }
+    let mut list = String::new();
+    files.open("md5sum.txt")?.read_to_string(&mut list)?;
+
+    // Lines look like "<md5>  ./casper/vmlinuz".
+    let listed: Vec<(&str, &str)> = list
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let md5 = parts.next()?;
+            let path = parts.next()?.trim_start().trim_start_matches("./");
+            Some((md5, path))
+        })
+        .filter(|(_, path)| !files.patched(path))
+        .collect();
+    let total = listed.iter().filter_map(|(_, path)| files.size(path)).sum();
+
+    let mut corrupted = Vec::new();
+    let mut done = 0;
+    let mut buf = vec![0; CHUNK_SIZE];
+    for (expected, path) in listed {
+        if files.size(path).is_none() {
+            corrupted.push(path.to_string());
+            continue;
+        }
+        let mut file = files.open(path)?;
+        let mut hasher = Md5::new();
+        loop {
+            let read = file.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buf[..read]);
+            done += read as u64;
+            progress(done, total);
+        }
+        if !format!("{:x}", hasher.finalize()).eq_ignore_ascii_case(expected) {
+            corrupted.push(path.to_string());
+        }
+    }
+
+    if corrupted.is_empty() {
+        Ok(())
+    } else {
+        Err(VerifyError::CorruptedFiles(corrupted))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs::File;
+    use std::io::{Cursor, Write};
+
+    // Not a multiple of the chunk or sector size.
+    const IMAGE_LEN: u64 = 3 * CHUNK_SIZE as u64 + 1000;
+
+    fn image() -> Vec<u8> {
+        (0..IMAGE_LEN).map(|v| (v * 31 + (v >> 9)) as u8).collect()
+    }
+
+    fn written(data: &[u8], size: u64) -> File {
+        let mut disk = tempfile::tempfile().unwrap();
+        disk.write_all(data).unwrap();
+        disk.set_len(size).unwrap();
+        disk
+    }
+
+    fn check(image: &[u8], disk: &mut File, expected_sha256: Option<&str>) -> Result<(), VerifyError> {
+        verify(&mut Cursor::new(image), IMAGE_LEN, disk, expected_sha256, |_, _| ())
+    }
+
+    #[test]
+    fn passes_matching_drives() {
+        let image = image();
+        let sha256 = format!("{:x}", Sha256::digest(&image));
+        let mut disk = written(&image, 8 << 20);
+        let mut last = (0, 0);
+        verify(&mut Cursor::new(&image), IMAGE_LEN, &mut disk, Some(&sha256), |cur, total| last = (cur, total)).unwrap();
+        assert_eq!(last, (IMAGE_LEN, IMAGE_LEN));
+        check(&image, &mut disk, Some(&sha256.to_ascii_uppercase())).unwrap();
+        check(&image, &mut disk, None).unwrap();
+    }
+
+    #[test]
+    fn finds_the_first_bad_byte() {
+        let image = image();
+        let mut data = image.clone();
+        let offset = CHUNK_SIZE as u64 + 12345;
+        data[offset as usize] ^= 0x10;
+        data[2 * CHUNK_SIZE + 7] ^= 0x01;
+        match check(&image, &mut written(&data, 8 << 20), None) {
+            Err(VerifyError::Mismatch { offset: found }) => assert_eq!(found, offset),
+            other => panic!("{:?}", other),
+        }
+        let mut data = image.clone();
+        data[IMAGE_LEN as usize - 1] ^= 0x80;
+        match check(&image, &mut written(&data, 8 << 20), None) {
+            Err(VerifyError::Mismatch { offset }) => assert_eq!(offset, IMAGE_LEN - 1),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn catches_drives_smaller_than_the_image() {
+        let image = image();
+        let size = 2 * CHUNK_SIZE as u64 + 4096;
+        match check(&image, &mut written(&image[..size as usize], size), None) {
+            Err(VerifyError::Mismatch { offset }) => assert_eq!(offset, size),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn catches_corrupted_images() {
+        let image = image();
+        let expected = "0".repeat(64);
+        match check(&image, &mut written(&image, 8 << 20), Some(&expected)) {
+            Err(VerifyError::ChecksumMismatch { expected: e, actual }) => {
+                assert_eq!(e, expected);
+                assert_eq!(actual, format!("{:x}", Sha256::digest(&image)));
+            }
+            other => panic!("{:?}", other),
+        }
+        // And images shorter than they should be.
+        let err = check(&image[..1000], &mut written(&image, 8 << 20), None).unwrap_err();
+        assert!(matches!(err, VerifyError::Io(ref err) if err.kind() == io::ErrorKind::UnexpectedEof), "{:?}", err);
+    }
+}
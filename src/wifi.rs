@@ -0,0 +1,491 @@
+use crate::autoinstall::{shell_quote, write_file_command};
+use crate::release::parse_version;
+
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+// The Wi-Fi networks Windows remembers, carried over to the installed system
+// so it gets online on its first start. Windows hands them out as the XML of
+// its WLAN profiles, which become NetworkManager keyfiles on desktops, and
+// netplan on servers.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Security {
+    Open,
+    // WPA or WPA2 personal.
+    Psk,
+    // WPA3 personal.
+    Sae,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WifiProfile {
+    // Profile names default to the SSID, but users can rename them.
+    pub name: String,
+    pub ssid: Vec<u8>,
+    pub hidden: bool,
+    pub security: Security,
+    // A passphrase, or 64 hex digits.
+    pub key: String,
+}
+
+// The text of the first <tag> element. Profiles only use namespaces on their
+// root element, and CDATA not at all.
+fn element<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let open = format!("<{}>", tag);
+    let start = xml.find(&open)? + open.len();
+    let end = xml[start..].find(&format!("</{}>", tag))? + start;
+    Some(&xml[start..end])
+}
+
+fn unescape(text: &str) -> String {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(pos) = rest.find('&') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(std::char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(std::char::from_u32),
+            _ => None,
+        };
+        match c {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// Parses a profile from WlanGetProfile, with its key in plain text. The
+// error says why it can't be carried over.
+pub fn parse_profile(xml: &str) -> Result<WifiProfile, String> {
+    let name = element(xml, "name").map(unescape).unwrap_or_default();
+    let ssid_config = element(xml, "SSIDConfig").ok_or_else(|| format!("{}: no SSID", name))?;
+    let ssid = match element(ssid_config, "hex").and_then(parse_hex) {
+        Some(ssid) => ssid,
+        None => element(ssid_config, "name").map(|v| unescape(v).into_bytes()).ok_or_else(|| format!("{}: no SSID", name))?,
+    };
+    let hidden = element(ssid_config, "nonBroadcast") == Some("true");
+    if element(xml, "connectionType") == Some("IBSS") {
+        return Err(format!("{}: ad hoc networks aren't supported", name));
+    }
+    let authentication = element(xml, "authentication").unwrap_or("open");
+    let encryption = element(xml, "encryption").unwrap_or("none");
+    let security = match (authentication, encryption) {
+        ("open", "none") => Security::Open,
+        ("WPAPSK", _) | ("WPA2PSK", _) => Security::Psk,
+        ("WPA3SAE", _) => Security::Sae,
+        (_, "WEP") => return Err(format!("{}: WEP isn't supported", name)),
+        _ => return Err(format!("{}: {} networks aren't supported", name, authentication)),
+    };
+    let key = match security {
+        Security::Open => String::new(),
+        _ => {
+            let shared_key = element(xml, "sharedKey").ok_or_else(|| format!("{}: no key", name))?;
+            if element(shared_key, "protected") == Some("true") {
+                return Err(format!("{}: the key is encrypted", name));
+            }
+            element(shared_key, "keyMaterial").map(unescape).ok_or_else(|| format!("{}: no key", name))?
+        }
+    };
+    Ok(WifiProfile { name, ssid, hidden, security, key })
+}
+
+// Keyfiles are GKeyFiles, which escape like C, and keep leading spaces with
+// \s.
+fn keyfile_escape(value: &str) -> String {
+    let mut out = String::new();
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            ' ' if i == 0 => out.push_str("\\s"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+impl WifiProfile {
+    fn ssid_text(&self) -> String {
+        String::from_utf8_lossy(&self.ssid).into_owned()
+    }
+
+    // SSIDs are bytes. NetworkManager takes them as text, or as a list of
+    // bytes for those that aren't.
+    fn keyfile_ssid(&self) -> String {
+        match std::str::from_utf8(&self.ssid) {
+            Ok(ssid) if !ssid.contains(';') && !ssid.chars().any(char::is_control) => keyfile_escape(ssid),
+            _ => self.ssid.iter().map(|v| format!("{};", v)).collect(),
+        }
+    }
+
+    // The NetworkManager connection, for
+    // /etc/NetworkManager/system-connections. NetworkManager makes up a UUID
+    // from the file name when it has none.
+    pub fn keyfile(&self) -> String {
+        let mut out = format!("[connection]\nid={}\ntype=wifi\n\n[wifi]\nmode=infrastructure\nssid={}\n", keyfile_escape(&self.name), self.keyfile_ssid());
+        if self.hidden {
+            out.push_str("hidden=true\n");
+        }
+        match self.security {
+            Security::Open => (),
+            Security::Psk => out.push_str(&format!("\n[wifi-security]\nkey-mgmt=wpa-psk\npsk={}\n", keyfile_escape(&self.key))),
+            Security::Sae => out.push_str(&format!("\n[wifi-security]\nkey-mgmt=sae\npsk={}\n", keyfile_escape(&self.key))),
+        }
+        out.push_str("\n[ipv4]\nmethod=auto\n\n[ipv6]\nmethod=auto\n");
+        out
+    }
+
+    // A file name NetworkManager accepts, made from the profile name.
+    pub fn keyfile_name(&self) -> String {
+        let name: String = self.name.chars()
+            .map(|v| if v.is_ascii_alphanumeric() || v == '-' || v == '_' { v } else { '_' })
+            .collect();
+        format!("{}.nmconnection", if name.is_empty() { "wifi" } else { &name })
+    }
+}
+
+#[derive(Serialize)]
+struct Netplan<'a> {
+    network: Network<'a>,
+}
+
+#[derive(Serialize)]
+struct Network<'a> {
+    version: u32,
+    wifis: BTreeMap<&'a str, Wifi>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Wifi {
+    dhcp4: bool,
+    access_points: BTreeMap<String, AccessPoint>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct AccessPoint {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<Auth>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    hidden: bool,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Auth {
+    key_management: &'static str,
+    password: String,
+}
+
+// netplan only knows the `hidden` key since 0.105, which came with 22.10.
+// Older ones refuse the whole file over it.
+pub fn netplan_knows_hidden(version: &str) -> bool {
+    parse_version(version).map_or(false, |v| v >= (22, 10))
+}
+
+// The netplan config connecting `interface` to any of the networks. The
+// networkd backend needs the interface name, it doesn't match Wi-Fi
+// interfaces by pattern.
+pub fn netplan(profiles: &[WifiProfile], interface: &str, hidden_key: bool) -> Result<String, serde_yaml::Error> {
+    let access_points = profiles.iter().map(|profile| {
+        let (password, auth) = match profile.security {
+            Security::Open => (None, None),
+            Security::Psk => (Some(profile.key.clone()), None),
+            Security::Sae => (None, Some(Auth { key_management: "sae", password: profile.key.clone() })),
+        };
+        (profile.ssid_text(), AccessPoint { password, auth, hidden: hidden_key && profile.hidden })
+    }).collect();
+    let mut wifis = BTreeMap::new();
+    wifis.insert(interface, Wifi { dhcp4: true, access_points });
+    let yaml = serde_yaml::to_string(&Netplan { network: Network { version: 2, wifis } })?;
+    Ok(format!("{}\n", yaml.trim_start_matches("---\n").trim_end()))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renderer {
+    NetworkManager,
+    // Whether netplan takes the `hidden` key.
+    Networkd { hidden_key: bool },
+}
+
+const INTERFACE: &str = "WIFI_INTERFACE";
+
+// The autoinstall late-commands writing the profiles into the installed
+// system, readable by root only.
+pub fn late_commands(profiles: &[WifiProfile], renderer: Renderer) -> Result<Vec<String>, serde_yaml::Error> {
+    if profiles.is_empty() {
+        return Ok(Vec::new());
+    }
+    match renderer {
+        Renderer::NetworkManager => Ok(profiles.iter().map(|profile| {
+            write_file_command(&format!("/target/etc/NetworkManager/system-connections/{}", profile.keyfile_name()), &profile.keyfile())
+        }).collect()),
+        Renderer::Networkd { hidden_key } => {
+            // The installer runs on the same hardware, so its first Wi-Fi
+            // interface has the name the installed system will give it.
+            let yaml = netplan(profiles, INTERFACE, hidden_key)?;
+            let pos = yaml.find(INTERFACE).unwrap_or(0);
+            Ok(vec![format!(
+                "iface=$(for d in /sys/class/ieee80211/*/device/net/*; do [ -e \"$d\" ] && basename \"$d\"; break; done); if [ -n \"$iface\" ]; then mkdir -p /target/etc/netplan && umask 077 && printf '%s%s%s' {} \"$iface\" {} > /target/etc/netplan/90-wifi.yaml; fi",
+                shell_quote(&yaml[..pos]),
+                shell_quote(&yaml[pos + INTERFACE.len()..]),
+            )])
+        }
+    }
+}
+
+#[cfg(windows)]
+pub use self::win::saved_profiles;
+
+#[cfg(windows)]
+mod win {
+    use super::{parse_profile, WifiProfile};
+    use crate::win32::{from_wide, to_wide};
+
+    use winapi::shared::winerror::ERROR_SUCCESS;
+    use winapi::um::wlanapi::{WlanCloseHandle, WlanEnumInterfaces, WlanFreeMemory, WlanGetProfile, WlanGetProfileList, WlanOpenHandle, WLAN_PROFILE_GET_PLAINTEXT_KEY};
+
+    use std::io;
+    use std::ptr;
+
+    fn check(res: u32) -> io::Result<()> {
+        if res != ERROR_SUCCESS {
+            return Err(io::Error::from_raw_os_error(res as i32));
+        }
+        Ok(())
+    }
+
+    // The profiles of every wireless interface, and why some can't be
+    // carried over. Plain text keys need us to run elevated.
+    pub fn saved_profiles() -> io::Result<(Vec<WifiProfile>, Vec<String>)> {
+        let mut profiles = Vec::new();
+        let mut skipped = Vec::new();
+        unsafe {
+            let mut version = 0;
+            let mut handle = ptr::null_mut();
+            check(WlanOpenHandle(2, ptr::null_mut(), &mut version, &mut handle))?;
+            let res = (|| -> io::Result<()> {
+                let mut interfaces = ptr::null_mut();
+                check(WlanEnumInterfaces(handle, ptr::null_mut(), &mut interfaces))?;
+                let interface_list = std::slice::from_raw_parts((*interfaces).InterfaceInfo.as_ptr(), (*interfaces).dwNumberOfItems as usize);
+                for interface in interface_list {
+                    let mut list = ptr::null_mut();
+                    if WlanGetProfileList(handle, &interface.InterfaceGuid, ptr::null_mut(), &mut list) != ERROR_SUCCESS {
+                        continue;
+                    }
+                    for info in std::slice::from_raw_parts((*list).ProfileInfo.as_ptr(), (*list).dwNumberOfItems as usize) {
+                        let name = from_wide(&info.strProfileName);
+                        let mut xml = ptr::null_mut();
+                        let mut flags = WLAN_PROFILE_GET_PLAINTEXT_KEY;
+                        let mut access = 0;
+                        let res = WlanGetProfile(handle, &interface.InterfaceGuid, to_wide(&name).as_ptr(), ptr::null_mut(), &mut xml, &mut flags, &mut access);
+                        if res != ERROR_SUCCESS {
+                            skipped.push(format!("{}: {}", name, io::Error::from_raw_os_error(res as i32)));
+                            continue;
+                        }
+                        let len = (0..).take_while(|&i| *xml.offset(i) != 0).count();
+                        let text = String::from_utf16_lossy(std::slice::from_raw_parts(xml, len));
+                        WlanFreeMemory(xml as _);
+                        // The same network can be saved for several
+                        // interfaces.
+                        match parse_profile(&text) {
+                            Ok(profile) => if !profiles.iter().any(|v: &WifiProfile| v.ssid == profile.ssid) {
+                                profiles.push(profile);
+                            },
+                            Err(err) => skipped.push(err),
+                        }
+                    }
+                    WlanFreeMemory(list as _);
+                }
+                WlanFreeMemory(interfaces as _);
+                Ok(())
+            })();
+            WlanCloseHandle(handle, ptr::null_mut());
+            res?;
+        }
+        Ok((profiles, skipped))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile_xml(ssid: &str, auth: &str, encryption: &str, key: Option<&str>) -> String {
+        let shared_key = match key {
+            Some(key) => format!("<sharedKey><keyType>passPhrase</keyType><protected>false</protected><keyMaterial>{}</keyMaterial></sharedKey>", key),
+            None => String::new(),
+        };
+        format!(
+            "<?xml version=\"1.0\"?>\r\n<WLANProfile xmlns=\"http://www.microsoft.com/networking/WLAN/profile/v1\">\r\n\
+             <name>{0}</name><SSIDConfig><SSID><hex>{1}</hex><name>{0}</name></SSID></SSIDConfig>\
+             <connectionType>ESS</connectionType><connectionMode>auto</connectionMode>\
+             <MSM><security><authEncryption><authentication>{2}</authentication><encryption>{3}</encryption><useOneX>false</useOneX></authEncryption>{4}</security></MSM>\
+             </WLANProfile>",
+            ssid, ssid.bytes().map(|v| format!("{:02X}", v)).collect::<String>(), auth, encryption, shared_key,
+        )
+    }
+
+    fn psk(name: &str, key: &str) -> WifiProfile {
+        WifiProfile { name: name.to_string(), ssid: name.as_bytes().to_vec(), hidden: false, security: Security::Psk, key: key.to_string() }
+    }
+
+    #[test]
+    fn parses_profiles() {
+        let profile = parse_profile(&profile_xml("Home &amp; Garden", "WPA2PSK", "AES", Some("p&lt;ss w0rd"))).unwrap();
+        assert_eq!(profile, WifiProfile {
+            name: "Home & Garden".to_string(),
+            // The hex wins over the name, which isn't unescaped there.
+            ssid: b"Home &amp; Garden".to_vec(),
+            hidden: false,
+            security: Security::Psk,
+            key: "p<ss w0rd".to_string(),
+        });
+        let profile = parse_profile(&profile_xml("Cafe", "open", "none", None)).unwrap();
+        assert_eq!(profile.security, Security::Open);
+        assert_eq!(profile.key, "");
+        let profile = parse_profile(&profile_xml("New", "WPA3SAE", "AES", Some("secret"))).unwrap();
+        assert_eq!(profile.security, Security::Sae);
+
+        let xml = profile_xml("Attic", "WPA2PSK", "AES", Some("secret")).replace("</SSID>", "</SSID><nonBroadcast>true</nonBroadcast>");
+        assert!(parse_profile(&xml).unwrap().hidden);
+        // Without hex, the name is the SSID.
+        let xml = "<WLANProfile><name>Renamed</name><SSIDConfig><SSID><name>caf&#xE9;</name></SSID></SSIDConfig><MSM><security><authEncryption><authentication>open</authentication><encryption>none</encryption></authEncryption></security></MSM></WLANProfile>";
+        let profile = parse_profile(xml).unwrap();
+        assert_eq!(profile.name, "Renamed");
+        assert_eq!(profile.ssid, "café".as_bytes());
+    }
+
+    #[test]
+    fn explains_what_it_skips() {
+        assert_eq!(parse_profile(&profile_xml("Old", "open", "WEP", Some("12345"))), Err("Old: WEP isn't supported".to_string()));
+        assert_eq!(parse_profile(&profile_xml("Work", "WPA2", "AES", None)), Err("Work: WPA2 networks aren't supported".to_string()));
+        assert_eq!(parse_profile(&profile_xml("Keyless", "WPA2PSK", "AES", None)), Err("Keyless: no key".to_string()));
+        let xml = profile_xml("Locked", "WPA2PSK", "AES", Some("01000000D08C9DDF")).replace("<protected>false", "<protected>true");
+        assert_eq!(parse_profile(&xml), Err("Locked: the key is encrypted".to_string()));
+        let xml = profile_xml("Party", "open", "none", None).replace(">ESS<", ">IBSS<");
+        assert_eq!(parse_profile(&xml), Err("Party: ad hoc networks aren't supported".to_string()));
+        assert_eq!(parse_profile("<WLANProfile><name>Empty</name></WLANProfile>"), Err("Empty: no SSID".to_string()));
+    }
+
+    #[test]
+    fn writes_keyfiles() {
+        let profile = psk(" Home\\Net", "pass\tword");
+        assert_eq!(profile.keyfile(), "[connection]\nid=\\sHome\\\\Net\ntype=wifi\n\n[wifi]\nmode=infrastructure\nssid=\\sHome\\\\Net\n\n\
+                                       [wifi-security]\nkey-mgmt=wpa-psk\npsk=pass\\tword\n\n[ipv4]\nmethod=auto\n\n[ipv6]\nmethod=auto\n");
+        assert_eq!(profile.keyfile_name(), "_Home_Net.nmconnection");
+
+        let profile = WifiProfile { name: "Attic".to_string(), ssid: vec![0xff, b';'], hidden: true, security: Security::Sae, key: "secret".to_string() };
+        let keyfile = profile.keyfile();
+        assert!(keyfile.contains("\nssid=255;59;\nhidden=true\n"));
+        assert!(keyfile.contains("\nkey-mgmt=sae\npsk=secret\n"));
+
+        let profile = WifiProfile { name: "Café".to_string(), ssid: b"Cafe".to_vec(), hidden: false, security: Security::Open, key: String::new() };
+        assert!(!profile.keyfile().contains("[wifi-security]"));
+        assert_eq!(profile.keyfile_name(), "Caf_.nmconnection");
+        assert_eq!(WifiProfile { name: String::new(), ..profile }.keyfile_name(), "wifi.nmconnection");
+    }
+
+    #[test]
+    fn writes_netplan() {
+        let mut attic = psk("Attic", "secret");
+        attic.hidden = true;
+        let new = WifiProfile { security: Security::Sae, ..psk("New", "sesame") };
+        let cafe = WifiProfile { security: Security::Open, key: String::new(), ..psk("Cafe", "") };
+        let profiles = [attic, new, cafe];
+
+        let yaml: serde_yaml::Value = serde_yaml::from_str(&netplan(&profiles, "wlan0", true).unwrap()).unwrap();
+        let access_points = &yaml["network"]["wifis"]["wlan0"]["access-points"];
+        assert_eq!(yaml["network"]["version"], serde_yaml::Value::from(2));
+        assert_eq!(yaml["network"]["wifis"]["wlan0"]["dhcp4"], serde_yaml::Value::from(true));
+        assert_eq!(access_points["Attic"]["password"], serde_yaml::Value::from("secret"));
+        assert_eq!(access_points["Attic"]["hidden"], serde_yaml::Value::from(true));
+        assert_eq!(access_points["New"]["auth"]["key-management"], serde_yaml::Value::from("sae"));
+        assert_eq!(access_points["New"]["auth"]["password"], serde_yaml::Value::from("sesame"));
+        assert!(access_points["New"].get("hidden").is_none());
+        assert_eq!(access_points["Cafe"], serde_yaml::from_str::<serde_yaml::Value>("{}").unwrap());
+
+        let yaml = netplan(&profiles, "wlan0", false).unwrap();
+        assert!(!yaml.contains("hidden"));
+    }
+
+    #[test]
+    fn knows_which_netplan_takes_hidden() {
+        assert!(!netplan_knows_hidden("20.04.6"));
+        assert!(!netplan_knows_hidden("22.04"));
+        assert!(netplan_knows_hidden("22.10"));
+        assert!(netplan_knows_hidden("24.04.1"));
+        assert!(!netplan_knows_hidden("rolling"));
+    }
+
+    #[test]
+    fn writes_late_commands() {
+        assert!(late_commands(&[], Renderer::NetworkManager).unwrap().is_empty());
+        let profiles = [psk("Home", "secret"), psk("Cafe", "latte")];
+        let commands = late_commands(&profiles, Renderer::NetworkManager).unwrap();
+        assert_eq!(commands.len(), 2);
+        assert!(commands[0].contains("/target/etc/NetworkManager/system-connections/Home.nmconnection"));
+        assert!(commands[1].contains("/target/etc/NetworkManager/system-connections/Cafe.nmconnection"));
+    }
+
+    // Runs the networkd command with /sys and /target moved somewhere else.
+    #[cfg(unix)]
+    fn run_networkd(interfaces: &[&str]) -> Option<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let sys = dir.path().join("sys");
+        std::fs::create_dir_all(sys.join("phy0/device/net")).unwrap();
+        for interface in interfaces {
+            std::fs::create_dir_all(sys.join("phy0/device/net").join(interface)).unwrap();
+        }
+        let target = dir.path().join("target");
+        let commands = late_commands(&[psk("Home", "it's")], Renderer::Networkd { hidden_key: true }).unwrap();
+        assert_eq!(commands.len(), 1);
+        let command = commands[0]
+            .replace("/sys/class/ieee80211", sys.to_str().unwrap())
+            .replace("/target", target.to_str().unwrap());
+        let status = std::process::Command::new("sh").arg("-c").arg(&command).status().unwrap();
+        assert!(status.success());
+        std::fs::read_to_string(target.join("etc/netplan/90-wifi.yaml")).ok()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn names_the_wifi_interface() {
+        let yaml = run_networkd(&["wlp2s0"]).unwrap();
+        assert_eq!(yaml, netplan(&[psk("Home", "it's")], "wlp2s0", true).unwrap());
+        // Without one, nothing gets written.
+        assert_eq!(run_networkd(&[]), None);
+    }
+}
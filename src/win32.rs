@@ -0,0 +1,165 @@
+use winapi::shared::windef::HWND;
+use winapi::shared::winerror::ERROR_NOT_ALL_ASSIGNED;
+use winapi::um::commdlg::{GetOpenFileNameW, GetSaveFileNameW, OFN_EXPLORER, OFN_FILEMUSTEXIST, OFN_OVERWRITEPROMPT, OFN_PATHMUSTEXIST, OPENFILENAMEW};
+use winapi::um::handleapi::CloseHandle;
+use winapi::um::ioapiset::DeviceIoControl;
+use winapi::um::processthreadsapi::{GetCurrentProcess, OpenProcessToken};
+use winapi::um::securitybaseapi::AdjustTokenPrivileges;
+use winapi::um::winbase::LookupPrivilegeValueW;
+use winapi::um::winnt::{FILE_SHARE_READ, FILE_SHARE_WRITE, SE_PRIVILEGE_ENABLED, TOKEN_ADJUST_PRIVILEGES, TOKEN_PRIVILEGES, TOKEN_QUERY};
+use winapi::um::winuser::{MessageBoxW, IDYES, MB_ICONERROR, MB_ICONINFORMATION, MB_ICONWARNING, MB_OK, MB_YESNO};
+
+use std::ffi::{OsStr, OsString};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::windows::ffi::{OsStrExt, OsStringExt};
+use std::os::windows::fs::OpenOptionsExt;
+use std::os::windows::io::AsRawHandle;
+use std::path::PathBuf;
+use std::ptr;
+
+// Opens a volume or a physical drive. With `access` set to 0, the handle can
+// only be used to query the device, but doesn't require admin rights.
+pub fn open_device(path: &str, access: u32) -> io::Result<File> {
+    OpenOptions::new()
+        .access_mode(access)
+        .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE)
+        .open(path)
+}
+
+// Sends an IOCTL to a device, returning the number of bytes written to
+// `output`.
+pub fn ioctl(file: &File, code: u32, input: &[u8], output: &mut [u8]) -> io::Result<usize> {
+    let mut returned = 0;
+    let ret = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle() as _,
+            code,
+            if input.is_empty() { ptr::null_mut() } else { input.as_ptr() as *mut _ },
+            input.len() as u32,
+            if output.is_empty() { ptr::null_mut() } else { output.as_mut_ptr() as *mut _ },
+            output.len() as u32,
+            &mut returned,
+            ptr::null_mut(),
+        )
+    };
+    if ret == 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(returned as usize)
+    }
+}
+
+// Same as ioctl, but for IOCTLs returning a fixed-size structure.
+pub fn ioctl_out<T: Copy>(file: &File, code: u32, input: &[u8]) -> io::Result<T> {
+    unsafe {
+        let mut out: T = mem::zeroed();
+        let out_bytes = std::slice::from_raw_parts_mut(&mut out as *mut T as *mut u8, mem::size_of::<T>());
+        ioctl(file, code, input, out_bytes)?;
+        Ok(out)
+    }
+}
+
+pub fn as_bytes<T>(val: &T) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(val as *const T as *const u8, mem::size_of::<T>()) }
+}
+
+pub fn from_wide(s: &[u16]) -> String {
+    String::from_utf16_lossy(&s[..s.iter().position(|v| *v == 0).unwrap_or(s.len())])
+}
+
+pub fn to_wide(s: &str) -> Vec<u16> {
+    OsStr::new(s).encode_wide().chain(Some(0)).collect()
+}
+
+// Shows the standard "Open" dialog. `filter` is a list of (description,
+// pattern) pairs.
+pub fn open_file_dialog(owner: HWND, title: &str, filter: &[(&str, &str)]) -> Option<PathBuf> {
+    file_dialog(owner, title, filter, None)
+}
+
+// Asks where to save a file, suggesting `file_name`.
+pub fn save_file_dialog(owner: HWND, title: &str, filter: &[(&str, &str)], file_name: &str) -> Option<PathBuf> {
+    file_dialog(owner, title, filter, Some(file_name))
+}
+
+fn file_dialog(owner: HWND, title: &str, filter: &[(&str, &str)], save_as: Option<&str>) -> Option<PathBuf> {
+    let mut filter_w = Vec::new();
+    for (description, pattern) in filter {
+        filter_w.extend(to_wide(description));
+        filter_w.extend(to_wide(pattern));
+    }
+    filter_w.push(0);
+    let title_w = to_wide(title);
+    let mut file = vec![0u16; 32768];
+    if let Some(name) = save_as {
+        let name_w: Vec<u16> = OsStr::new(name).encode_wide().collect();
+        file[..name_w.len()].copy_from_slice(&name_w);
+    }
+
+    let mut ofn: OPENFILENAMEW = unsafe { mem::zeroed() };
+    ofn.lStructSize = mem::size_of::<OPENFILENAMEW>() as u32;
+    ofn.hwndOwner = owner;
+    ofn.lpstrFilter = filter_w.as_ptr();
+    ofn.lpstrFile = file.as_mut_ptr();
+    ofn.nMaxFile = file.len() as u32;
+    ofn.lpstrTitle = title_w.as_ptr();
+    let ok = if save_as.is_some() {
+        ofn.Flags = OFN_OVERWRITEPROMPT | OFN_PATHMUSTEXIST | OFN_EXPLORER;
+        unsafe { GetSaveFileNameW(&mut ofn) }
+    } else {
+        ofn.Flags = OFN_FILEMUSTEXIST | OFN_PATHMUSTEXIST | OFN_EXPLORER;
+        unsafe { GetOpenFileNameW(&mut ofn) }
+    };
+    if ok == 0 {
+        return None;
+    }
+    let len = file.iter().position(|v| *v == 0).unwrap_or(file.len());
+    Some(PathBuf::from(OsString::from_wide(&file[..len])))
+}
+
+// Asks a yes or no question in a message box, with a warning icon.
+pub fn confirm(owner: HWND, title: &str, text: &str) -> bool {
+    let title_w = to_wide(title);
+    let text_w = to_wide(text);
+    unsafe { MessageBoxW(owner, text_w.as_ptr(), title_w.as_ptr(), MB_YESNO | MB_ICONWARNING) == IDYES }
+}
+
+pub fn show_error(owner: HWND, title: &str, text: &str) {
+    let title_w = to_wide(title);
+    let text_w = to_wide(text);
+    unsafe { MessageBoxW(owner, text_w.as_ptr(), title_w.as_ptr(), MB_OK | MB_ICONERROR); }
+}
+
+pub fn show_info(owner: HWND, title: &str, text: &str) {
+    let title_w = to_wide(title);
+    let text_w = to_wide(text);
+    unsafe { MessageBoxW(owner, text_w.as_ptr(), title_w.as_ptr(), MB_OK | MB_ICONINFORMATION); }
+}
+
+// Enables a privilege of the process, e.g. SE_SYSTEM_ENVIRONMENT_NAME.
+// Administrators hold most of them, but disabled.
+pub fn enable_privilege(name: &str) -> io::Result<()> {
+    let name_w = to_wide(name);
+    let mut privileges: TOKEN_PRIVILEGES = unsafe { mem::zeroed() };
+    privileges.PrivilegeCount = 1;
+    privileges.Privileges[0].Attributes = SE_PRIVILEGE_ENABLED;
+    if unsafe { LookupPrivilegeValueW(ptr::null(), name_w.as_ptr(), &mut privileges.Privileges[0].Luid) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut token = ptr::null_mut();
+    if unsafe { OpenProcessToken(GetCurrentProcess(), TOKEN_ADJUST_PRIVILEGES | TOKEN_QUERY, &mut token) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let ret = unsafe { AdjustTokenPrivileges(token, 0, &mut privileges, 0, ptr::null_mut(), ptr::null_mut()) };
+    // It succeeds without enabling anything when the token doesn't hold the
+    // privilege.
+    let err = io::Error::last_os_error();
+    unsafe { CloseHandle(token) };
+    if ret == 0 || err.raw_os_error() == Some(ERROR_NOT_ALL_ASSIGNED as i32) {
+        return Err(err);
+    }
+    Ok(())
+}
@@ -6,7 +6,7 @@ use bindings::windows::ui::xaml::controls::*;
 use bindings::windows::foundation::PropertyValue;
 
 use bindings::windows::foundation::TypedEventHandler;
-use bindings::windows::devices::enumeration::{DeviceClass, DeviceInformation, DeviceWatcher};
+use bindings::windows::devices::enumeration::{DeviceInformation, DeviceWatcher};
 
 //use bindings::windows::storage::ApplicationData;
 
@@ -14,18 +14,33 @@ use winrt::Object;
 use winit::window::Window;
 use raw_window_handle::HasRawWindowHandle;
 use winit::event_loop::EventLoopProxy;
-use winapi::um::fileapi::GetVolumePathNamesForVolumeNameW;
-use winapi::shared::minwindef::MAX_PATH;
-use winapi::um::errhandlingapi::GetLastError;
-use winapi::shared::ntdef::LPWSTR;
-use winapi::shared::minwindef::HLOCAL;
-use winapi::um::winbase::*;
-use widestring::U16String;
-
-use std::ptr;
-use std::os::windows::io::AsRawHandle;
-use std::path::PathBuf;
+
+use crate::autoinstall::{Autoinstall, LayoutMode, LayoutName};
+use crate::bootable::BootInfo;
+use crate::bootcfg::{self, BootOptions, ImagePatch};
+use crate::compat::{self, Requirements, Status};
+use crate::crypt;
+use crate::disk::{DiskInfo, format_size};
+use crate::dualboot::{self, ShrinkPlan};
+use crate::efi;
+use crate::iso::Iso;
+use crate::locale;
+use crate::migration::{self, Manifest};
+use crate::release::{self, Edition, Release};
+use crate::safety::{Refusal, SafetyPolicy};
+use crate::seed::Seed;
+use crate::system::{self, Firmware, RebootChecks};
+use crate::filecopy;
+use crate::persistence;
+use crate::verify::{self, DeviceFiles, IsoFiles};
+use crate::wifi::{self, Renderer};
+use crate::win32;
+use crate::writer::{self, PhysicalDrive, Target};
+
+use std::io;
+use std::path::{Path, PathBuf};
 use std::thread::JoinHandle;
+use tempfile::TempPath;
 
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
@@ -36,54 +51,717 @@ pub struct WizardUI {
     desktop_source: DesktopWindowXamlSource,
     el_proxy: EventLoopProxy<WizardEvent>,
     step: WizardStep,
+    target: Option<DiskInfo>,
+    // What the drive was allowed under, to check it again before writing.
+    policy: SafetyPolicy,
+    options: WriteOptions,
+    // An image the user already had, instead of downloading one.
+    local_image: Option<LocalImage>,
+    // Whether to go through the boot options step after picking the drive.
+    customize_boot: bool,
+    boot_options: BootOptions,
+    // Whether to go through the autoinstall steps after the boot options.
+    create_autoinstall: bool,
+    autoinstall: Autoinstall,
+    // Only kept until it gets hashed into `autoinstall`.
+    password: String,
+    password_confirm: String,
+    // How to make room for Ubuntu next to Windows, if there's room.
+    shrink_plan: Option<ShrinkPlan>,
+    // Whether to carry the saved Wi-Fi networks over, and the late-commands
+    // doing it. Kept apart from `autoinstall`, so their keys don't end up in
+    // a saved user-data.
+    migrate_wifi: bool,
+    wifi_commands: Vec<String>,
+    // The user's files to copy from Windows, when it stays.
+    migrate_files: bool,
+    migration: Option<Manifest>,
+    // How this computer boots, which decides how to write the drive.
+    firmware: Firmware,
+    // What to do about the checks before rebooting.
+    suspend_bitlocker: bool,
+    disable_fast_startup: bool,
+    prepare_ahci: bool,
+}
+
+struct LocalImage {
+    path: PathBuf,
+    name: String,
+    size: u64,
+    // The catalog entry it matches, if any.
+    release: Option<&'static Release>,
+    edition: Edition,
+    // As the image says, e.g. "22.04.3".
+    version: Option<String>,
+    requirements: Requirements,
+}
+
+pub enum Image {
+    Downloaded(TempPath),
+    Local(PathBuf),
+}
+
+impl Image {
+    fn path(&self) -> &Path {
+        match self {
+            Image::Downloaded(path) => path,
+            Image::Local(path) => path,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WriteOptions {
+    pub mode: WriteMode,
+    pub verify: bool,
+    pub persistence: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteMode {
+    // The image gets written as is, sector by sector.
+    Image,
+    // A FAT32 partition gets created, and the image's files copied to it.
+    Files,
 }
 
 impl WizardUI {
     pub fn new(win32_window: Window, xaml_source: DesktopWindowXamlSource, el: EventLoopProxy<WizardEvent>) -> winrt::Result<WizardUI> {
+        // Start from the settings of this Windows install.
+        let mut autoinstall = Autoinstall::default();
+        locale::windows_settings().apply(&mut autoinstall);
+
         let ui = WizardUI {
             window: win32_window,
             desktop_source: xaml_source,
             el_proxy: el.clone(),
             step: WizardStep::step1(el)?,
+            target: None,
+            policy: SafetyPolicy::default(),
+            options: WriteOptions {
+                mode: WriteMode::Image,
+                verify: true,
+                persistence: false,
+            },
+            local_image: None,
+            customize_boot: false,
+            boot_options: BootOptions::default(),
+            create_autoinstall: false,
+            autoinstall,
+            password: String::new(),
+            password_confirm: String::new(),
+            shrink_plan: None,
+            migrate_wifi: false,
+            wifi_commands: Vec::new(),
+            migrate_files: false,
+            migration: None,
+            firmware: system::firmware(),
+            suspend_bitlocker: false,
+            disable_fast_startup: false,
+            prepare_ahci: false,
         };
 
+        // The boot option of a previous run isn't needed once it has been
+        // booted.
+        if ui.firmware.boot_next {
+            if let Err(err) = efi::remove_stale_options() {
+                eprintln!("Failed to remove old boot options: {}", err);
+            }
+        }
+
         ui.update_window()?;
 
         Ok(ui)
     }
 
+    // Checks this computer can run the image, before picking a drive.
+    pub fn go_to_compatibility(&mut self) -> winrt::Result<()> {
+        let requirements = match &self.local_image {
+            Some(image) => image.requirements,
+            None => Requirements::new(Edition::Desktop, Some(release::default_release().arch)),
+        };
+        let findings = compat::report(&compat::hardware(), &requirements);
+        self.step = WizardStep::compatibility(self.el_proxy.clone(), &findings)?;
+        self.update_window()
+    }
+
     pub fn go_to_step2(&mut self) -> winrt::Result<()> {
-        self.step = WizardStep::step2(self.el_proxy.clone())?;
+        let (image_name, image_size) = match &self.local_image {
+            Some(image) => (image.name.clone(), image.size),
+            None => {
+                let release = release::default_release();
+                (release.name.to_string(), release.size)
+            }
+        };
+        self.step = WizardStep::step2(self.el_proxy.clone(), self.options, self.firmware, image_name, image_size)?;
         self.update_window()?;
         Ok(())
     }
 
+    // Lets the user pick an image they already downloaded, and checks it can
+    // boot from a USB flash drive.
+    pub fn pick_image(&mut self) -> winrt::Result<()> {
+        let hwnd = self.hwnd() as _;
+        let path = match win32::open_file_dialog(hwnd, "Choose an Ubuntu image", &[("Disk images (*.iso)", "*.iso"), ("All files", "*.*")]) {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let boot_info = match std::fs::File::open(&path).and_then(BootInfo::inspect) {
+            Ok(info) => {
+                if let Some(warning) = info.usb_warning().or_else(|| self.firmware.boot_warning(&info)) {
+                    if !win32::confirm(hwnd, "This image might not boot", &format!("{}\n\nUse it anyway?", warning)) {
+                        return Ok(());
+                    }
+                }
+                if self.firmware.prefers_files(&info) && self.options.mode != WriteMode::Files {
+                    self.options.mode = WriteMode::Files;
+                    let mut text = "This image can't boot from a USB flash drive when written as is, so its files will be copied to the drive instead. The drive will only boot on computers with UEFI.".to_string();
+                    if self.options.persistence {
+                        self.options.persistence = false;
+                        text.push_str("\n\nPersistence needs the image written as is, and was turned off.");
+                    }
+                    win32::show_info(hwnd, "Copying the files", &text);
+                }
+                Some(info)
+            }
+            Err(err) => {
+                if !win32::confirm(hwnd, "This image might not boot", &format!("This doesn't look like an Ubuntu image: {}\n\nUse it anyway?", err)) {
+                    return Ok(());
+                }
+                None
+            }
+        };
+
+        let size = std::fs::metadata(&path).map(|v| v.len()).unwrap_or(0);
+        let release_info = match std::fs::File::open(&path).and_then(Iso::open).and_then(|mut iso| release::identify(&mut iso)) {
+            Ok(info) => info,
+            Err(err) => {
+                eprintln!("Failed to identify {}: {}", path.display(), err);
+                None
+            }
+        };
+        let release = release_info.as_ref().and_then(|v| v.find_release(size));
+        let file_name = path.file_name().map(|v| v.to_string_lossy().into_owned()).unwrap_or_default();
+
+        let mut details = vec![format!("File: {} ({})", file_name, format_size(size))];
+        match &release_info {
+            Some(info) => {
+                details.push(format!("Release: {}", info.name));
+                let edition = match info.edition {
+                    Edition::Desktop => "Desktop",
+                    Edition::Server => "Server",
+                };
+                details.push(format!("Flavor: {} ({})", info.flavor.replace('-', " "), edition));
+                if let Some(arch) = &info.arch {
+                    details.push(format!("Architecture: {}", arch));
+                }
+                if let Some(build_date) = &info.build_date {
+                    details.push(format!("Built on: {}", build_date));
+                }
+                if let Some(packages) = info.packages {
+                    details.push(format!("Packages: {}", packages));
+                }
+            }
+            None => details.push("This image doesn't say which release it is.".to_string()),
+        }
+        if let Some(boot_info) = &boot_info {
+            details.push(format!("Boots with: {}", boot_info.describe()));
+        }
+        details.push(format!("This computer boots with: {}", self.firmware.describe()));
+        details.push(match release {
+            Some(_) => "This is a published image. It will be checked against its published checksum.".to_string(),
+            None => "This image isn't in our catalog, so its checksum can't be checked.".to_string(),
+        });
+
+        // Assume the desktop one, which needs the most, when we can't tell.
+        let edition = release_info.as_ref().map(|v| v.edition).unwrap_or(Edition::Desktop);
+        let requirements = Requirements::new(edition, release_info.as_ref().and_then(|v| v.arch.as_deref()));
+        let version = release_info.as_ref().map(|v| v.version.clone());
+        self.local_image = Some(LocalImage {
+            path,
+            name: release_info.map(|v| v.name).unwrap_or(file_name),
+            size,
+            release,
+            edition,
+            version,
+            requirements,
+        });
+        self.step = WizardStep::confirm_image(self.el_proxy.clone(), &details)?;
+        self.update_window()
+    }
+
+    // Goes through the optional steps between picking the drive and writing
+    // it.
     pub fn go_to_step3(&mut self) -> winrt::Result<()> {
+        match self.step {
+            WizardStep::Step2 { .. } => {
+                self.target = self.step.selected_device()?;
+                self.policy = self.step.policy();
+                let overridden = match &self.target {
+                    Some(target) => self.policy.overridden(target),
+                    None => {
+                        win32::show_error(self.hwnd() as _, "No drive selected", "Pick the USB flash drive to write to.");
+                        return Ok(());
+                    }
+                };
+                if !overridden.is_empty() {
+                    let reasons: Vec<String> = overridden.iter().map(|v| v.to_string()).collect();
+                    let text = format!("{}\n\nEverything on it will be deleted. Use it anyway?", reasons.join(" "));
+                    if !win32::confirm(self.hwnd() as _, "Use this disk?", &text) {
+                        return Ok(());
+                    }
+                }
+                if !self.image_has_subiquity() {
+                    self.boot_options.autoinstall = false;
+                    self.create_autoinstall = false;
+                }
+                if self.customize_boot {
+                    self.step = WizardStep::boot_options(self.el_proxy.clone(), &self.boot_options, self.create_autoinstall, self.image_has_subiquity())?;
+                    return self.update_window();
+                }
+            }
+            WizardStep::BootOptions { .. } => {
+                if let Err(err) = self.boot_options.validate() {
+                    self.step.set_error(&err)?;
+                    return self.update_window();
+                }
+                // A config of our own gets its own warning, once it's known
+                // where it installs.
+                if self.boot_options.autoinstall && !self.create_autoinstall && !self.confirm_erase(true) {
+                    return Ok(());
+                }
+                if self.create_autoinstall {
+                    self.step = WizardStep::autoinstall_identity(self.el_proxy.clone(), &self.autoinstall)?;
+                    return self.update_window();
+                }
+            }
+            WizardStep::AutoinstallIdentity { .. } => {
+                if let Err(err) = self.check_autoinstall() {
+                    self.step.set_error(&err)?;
+                    return self.update_window();
+                }
+                let shrink_plan = if self.image_version().map_or(false, release::supports_use_gap) {
+                    dualboot::system_shrink_plan().map_err(|err| err.to_string())
+                } else {
+                    Err("The installer in this release can't install alongside Windows, only replace it.".to_string())
+                };
+                self.shrink_plan = shrink_plan.as_ref().ok().copied();
+                if self.shrink_plan.is_none() {
+                    self.autoinstall.storage.layout.mode = None;
+                }
+                self.step = WizardStep::autoinstall_system(self.el_proxy.clone(), &self.autoinstall, &shrink_plan, self.migrate_wifi)?;
+                return self.update_window();
+            }
+            WizardStep::AutoinstallSystem { .. } => {
+                if let Err(err) = self.check_autoinstall().and_then(|()| self.export_wifi()) {
+                    self.step.set_error(&err)?;
+                    return self.update_window();
+                }
+                if self.dual_boot_plan().is_none() && !self.confirm_erase(false) {
+                    return Ok(());
+                }
+                // User files are only copied when Windows stays, from its
+                // partition. Measuring them takes a while, so the page only
+                // comes once that's done.
+                if self.dual_boot_plan().is_some() {
+                    self.migration = None;
+                    self.step = WizardStep::measuring_files()?;
+                    let el_proxy = self.el_proxy.clone();
+                    std::thread::spawn(move || {
+                        let user_files = migration::user_files().map_err(|err| err.to_string());
+                        let _ = el_proxy.send_event(WizardEvent::UserFilesMeasured(user_files));
+                    });
+                    return self.update_window();
+                }
+                self.migration = None;
+            }
+            _ => (),
+        }
+        if let Some(image) = &self.local_image {
+            let path = image.path.clone();
+            return self.go_to_step4(Image::Local(path));
+        }
         self.step = WizardStep::step3(self.el_proxy.clone())?;
         self.update_window()?;
         Ok(())
     }
 
-    pub fn add_usb_device(&mut self, device: &DeviceNameId) -> winrt::Result<()> {
+    pub fn go_to_step4(&mut self, image: Image) -> winrt::Result<()> {
+        let target = match &self.target {
+            Some(target) => target,
+            None => {
+                win32::show_error(self.hwnd() as _, "No drive selected", "Go back and pick the USB flash drive to write to.");
+                return Ok(());
+            }
+        };
+        // Local images only have a known checksum if they're in the catalog.
+        let expected_sha256 = match image {
+            Image::Downloaded(_) => Some(release::default_release().sha256),
+            Image::Local(_) => self.local_image.as_ref().and_then(|v| v.release).map(|v| v.sha256),
+        };
+        let mut boot_options = self.boot_options.clone();
+        boot_options.persistent = self.options.persistence;
+        // The config goes on a CIDATA partition of the drive.
+        let autoinstall = if self.create_autoinstall {
+            boot_options.autoinstall = true;
+            let mut config = self.autoinstall.clone();
+            config.late_commands.extend(self.wifi_commands.iter().cloned());
+            if let Some(manifest) = self.migration.as_ref().filter(|_| self.migrate_files) {
+                config.late_commands.extend(manifest.late_commands());
+            }
+            if self.firmware.boot_next {
+                config.late_commands.push(efi::remove_options_command());
+            }
+            Some(config)
+        } else {
+            None
+        };
+        self.step = WizardStep::step4(self.el_proxy.clone(), image, target, self.policy, self.options, boot_options, autoinstall, expected_sha256, self.firmware.reboot_hint())?;
+        self.update_window()?;
+        Ok(())
+    }
+
+    pub fn add_usb_device(&mut self, device: DiskInfo) -> winrt::Result<()> {
         self.step.add_usb_device(device)?;
         self.update_window()?;
         Ok(())
     }
 
+    pub fn set_show_all_disks(&mut self, show_all: bool) -> winrt::Result<()> {
+        self.step.set_show_all_disks(show_all)?;
+        self.update_window()?;
+        Ok(())
+    }
+
+    pub fn set_verify(&mut self, verify: bool) {
+        self.options.verify = verify;
+    }
+
+    pub fn set_write_mode(&mut self, mode: WriteMode) {
+        self.options.mode = mode;
+    }
+
+    pub fn set_persistence(&mut self, persistence: bool) {
+        self.options.persistence = persistence;
+    }
+
+    pub fn set_customize_boot(&mut self, customize: bool) {
+        self.customize_boot = customize;
+    }
+
+    pub fn set_nomodeset(&mut self, nomodeset: bool) {
+        self.boot_options.nomodeset = nomodeset;
+    }
+
+    pub fn set_autoinstall(&mut self, autoinstall: bool) {
+        self.boot_options.autoinstall = autoinstall;
+    }
+
+    pub fn set_serial_console(&mut self, serial_console: bool) {
+        self.boot_options.serial_console = serial_console;
+    }
+
+    pub fn set_create_autoinstall(&mut self, create: bool) {
+        self.create_autoinstall = create;
+    }
+
+    pub fn set_autoinstall_field(&mut self, field: AutoinstallField, value: String) {
+        let config = &mut self.autoinstall;
+        match field {
+            AutoinstallField::Hostname => config.identity.hostname = value.trim().to_string(),
+            AutoinstallField::RealName => config.identity.realname = value.trim().to_string(),
+            AutoinstallField::Username => config.identity.username = value.trim().to_string(),
+            AutoinstallField::Password => self.password = value,
+            AutoinstallField::PasswordConfirm => self.password_confirm = value,
+            AutoinstallField::Locale => config.locale = value.trim().to_string(),
+            AutoinstallField::KeyboardLayout => config.keyboard.layout = value.trim().to_string(),
+            AutoinstallField::KeyboardVariant => config.keyboard.variant = value.trim().to_string(),
+            AutoinstallField::Timezone => {
+                let timezone = value.trim();
+                config.timezone = if timezone.is_empty() { None } else { Some(timezone.to_string()) };
+            }
+            AutoinstallField::SshKeys => config.ssh.authorized_keys = non_empty_lines(&value),
+            AutoinstallField::Packages => config.packages = value.split(|v: char| v.is_whitespace() || v == ',').filter(|v| !v.is_empty()).map(str::to_string).collect(),
+            AutoinstallField::LateCommands => config.late_commands = non_empty_lines(&value),
+        }
+    }
+
+    pub fn set_install_ssh_server(&mut self, install: bool) {
+        self.autoinstall.ssh.install_server = install;
+    }
+
+    pub fn set_use_lvm(&mut self, lvm: bool) {
+        self.autoinstall.storage.layout.name = if lvm { LayoutName::Lvm } else { LayoutName::Direct };
+    }
+
+    pub fn set_install_alongside_windows(&mut self, alongside: bool) {
+        self.autoinstall.storage.layout.mode = if alongside { Some(LayoutMode::UseGap) } else { None };
+    }
+
+    pub fn set_migrate_wifi(&mut self, migrate: bool) {
+        self.migrate_wifi = migrate;
+    }
+
+    pub fn set_migrate_files(&mut self, migrate: bool) {
+        self.migrate_files = migrate;
+    }
+
+    pub fn show_user_files(&mut self, user_files: Result<(Manifest, Vec<String>), String>) -> winrt::Result<()> {
+        let plan = match (&self.step, self.dual_boot_plan()) {
+            (WizardStep::MeasuringFiles { .. }, Some(plan)) => plan,
+            _ => return Ok(()),
+        };
+        self.migration = user_files.as_ref().ok().map(|v| v.0.clone());
+        let can_migrate = self.migration.as_ref().map_or(false, |v| !v.items.is_empty() && v.size() < plan.free / 2);
+        self.migrate_files &= can_migrate;
+        self.step = WizardStep::migration(self.el_proxy.clone(), &user_files, plan.free, can_migrate, self.migrate_files)?;
+        self.update_window()
+    }
+
+    // Desktops come with NetworkManager, servers only with networkd.
+    fn export_wifi(&mut self) -> Result<(), String> {
+        self.wifi_commands.clear();
+        if !self.migrate_wifi {
+            return Ok(());
+        }
+        let (profiles, skipped) = wifi::saved_profiles().map_err(|err| format!("Failed to read the saved Wi-Fi networks: {}", err))?;
+        if profiles.is_empty() {
+            let mut err = "No saved Wi-Fi network can be carried over.".to_string();
+            for reason in &skipped {
+                err.push_str(&format!("\n{}", reason));
+            }
+            return Err(err);
+        }
+        if !skipped.is_empty() {
+            let text = format!("These saved Wi-Fi networks can't be carried over:\n\n{}\n\nGo on without them?", skipped.join("\n"));
+            if !win32::confirm(self.hwnd() as _, "Wi-Fi networks", &text) {
+                return Err(format!("Some saved Wi-Fi networks can't be carried over:\n{}", skipped.join("\n")));
+            }
+        }
+        let renderer = match self.local_image.as_ref().map(|v| v.edition) {
+            Some(Edition::Server) => Renderer::Networkd { hidden_key: self.image_version().map_or(false, wifi::netplan_knows_hidden) },
+            _ => Renderer::NetworkManager,
+        };
+        self.wifi_commands = wifi::late_commands(&profiles, renderer).map_err(|err| err.to_string())?;
+        Ok(())
+    }
+
+    // The release to be installed, when we can tell. Without a local image,
+    // it's the one we download.
+    fn image_version(&self) -> Option<&str> {
+        match &self.local_image {
+            Some(image) => image.version.as_deref(),
+            None => Some(release::default_release().version),
+        }
+    }
+
+    // Whether the image's installer follows autoinstall configs.
+    fn image_has_subiquity(&self) -> bool {
+        let edition = self.local_image.as_ref().map_or(Edition::Desktop, |v| v.edition);
+        self.image_version().map_or(false, |version| release::has_subiquity(edition, version))
+    }
+
+    // Autoinstall doesn't ask before taking the biggest disk, so we do.
+    fn confirm_erase(&self, seed_url: bool) -> bool {
+        let disk = compat::largest_disk().unwrap_or_else(|| "the biggest disk".to_string());
+        let text = if seed_url {
+            format!("When this computer starts from the USB flash drive, Ubuntu gets installed without asking for confirmation, as the config at the seed URL says. Unless it says otherwise, this erases {}, with Windows and all the files on it.\n\nGo on?", disk)
+        } else {
+            format!("When this computer starts from the USB flash drive, Ubuntu gets installed without asking for confirmation. This erases {}, with Windows and all the files on it.\n\nGo on?", disk)
+        };
+        win32::confirm(self.hwnd() as _, "This erases a disk", &text)
+    }
+
+    // The shrink to do before rebooting, when Ubuntu goes next to Windows.
+    fn dual_boot_plan(&self) -> Option<ShrinkPlan> {
+        if self.create_autoinstall && self.autoinstall.storage.layout.mode == Some(LayoutMode::UseGap) {
+            self.shrink_plan
+        } else {
+            None
+        }
+    }
+
+    // Hashes the password into the config, and checks the whole of it.
+    fn check_autoinstall(&mut self) -> Result<(), String> {
+        if self.password.is_empty() {
+            return Err("The password is missing".to_string());
+        }
+        if self.password != self.password_confirm {
+            return Err("The passwords don't match".to_string());
+        }
+        self.autoinstall.identity.password = crypt::sha512_crypt(&self.password);
+        self.autoinstall.validate()
+    }
+
+    // Saves the config, to serve it from the seed URL.
+    pub fn save_user_data(&mut self) -> winrt::Result<()> {
+        if let Err(err) = self.check_autoinstall() {
+            self.step.set_error(&err)?;
+            return self.update_window();
+        }
+        let hwnd = self.hwnd() as _;
+        let path = match win32::save_file_dialog(hwnd, "Save the autoinstall config", &[("All files", "*.*")], "user-data") {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let res = self.autoinstall.to_user_data().map_err(|err| err.to_string()).and_then(|user_data| {
+            std::fs::write(&path, user_data).map_err(|err| err.to_string())?;
+            std::fs::write(path.with_file_name("meta-data"), self.autoinstall.to_meta_data()).map_err(|err| err.to_string())
+        });
+        if let Err(err) = res {
+            win32::show_error(hwnd, "Failed to save the autoinstall config", &err);
+        }
+        Ok(())
+    }
+
+    pub fn set_seed_url(&mut self, url: String) {
+        let url = url.trim();
+        self.boot_options.seed_url = if url.is_empty() { None } else { Some(url.to_string()) };
+    }
+
+    pub fn set_verifying(&mut self, cur: u64, total: u64) -> winrt::Result<()> {
+        self.step.set_write_status("Checking the USB flash drive for errors...")?;
+        self.set_progress(cur, Some(total))
+    }
+
+    pub fn set_writing(&mut self, cur: u64, total: u64) -> winrt::Result<()> {
+        self.step.set_write_status("Do not unplug the USB flash drive.")?;
+        self.set_progress(cur, Some(total))
+    }
+
+    pub fn set_checking_image(&mut self, cur: u64, total: u64) -> winrt::Result<()> {
+        self.step.set_write_status("Checking the files of the image...")?;
+        self.set_progress(cur, Some(total))
+    }
+
+    pub fn set_creating_seed(&mut self) -> winrt::Result<()> {
+        self.step.set_write_status("Adding the autoinstall config...")?;
+        self.update_window()
+    }
+
+    pub fn set_creating_persistence(&mut self) -> winrt::Result<()> {
+        self.step.set_write_status("Creating the persistence partition...")?;
+        self.update_window()
+    }
+
     pub fn set_progress(&mut self, cur: u64, total: Option<u64>) -> winrt::Result<()> {
         self.step.set_progress(cur, total)?;
         self.update_window()?;
         Ok(())
     }
 
-    fn update_window(&self) -> winrt::Result<()> {
-        self.desktop_source.set_content(self.step.top_level())?;
-        let hwnd = match self.window.raw_window_handle() {
+    pub fn set_write_result(&mut self, res: Result<(), String>) -> winrt::Result<()> {
+        self.step.set_write_result(res)?;
+        self.update_window()?;
+        Ok(())
+    }
+
+    // Lists what could go wrong when rebooting, and what we can do about it.
+    pub fn go_to_reboot_checklist(&mut self) -> winrt::Result<()> {
+        let checks = system::reboot_checks();
+        self.suspend_bitlocker = checks.bitlocker.is_some();
+        self.disable_fast_startup = checks.fast_startup;
+        self.prepare_ahci = false;
+        self.step = WizardStep::reboot_checklist(self.el_proxy.clone(), checks, self.dual_boot_plan())?;
+        self.update_window()
+    }
+
+    pub fn set_suspend_bitlocker(&mut self, suspend: bool) {
+        self.suspend_bitlocker = suspend;
+    }
+
+    pub fn set_disable_fast_startup(&mut self, disable: bool) {
+        self.disable_fast_startup = disable;
+    }
+
+    pub fn set_prepare_ahci(&mut self, prepare: bool) {
+        self.prepare_ahci = prepare;
+    }
+
+    // Has the firmware boot the drive's ESP once, and restarts.
+    pub fn reboot_to_drive(&mut self) -> winrt::Result<()> {
+        let hwnd = self.hwnd() as _;
+        let device_number = match &self.target {
+            Some(target) => target.device_number,
+            None => return Ok(()),
+        };
+        let checks = match &self.step {
+            WizardStep::RebootChecklist { checks, .. } => checks.clone(),
+            _ => RebootChecks::default(),
+        };
+
+        // Changing the boot order on a BitLocker drive makes Windows ask for
+        // the recovery key on its next start, which most people don't have
+        // at hand.
+        if checks.bitlocker.is_some() && !self.suspend_bitlocker && !win32::confirm(hwnd, "BitLocker", "Make sure you have the recovery key before going on. It can usually be found at https://aka.ms/myrecoverykey.\n\nReboot without suspending BitLocker?") {
+            return Ok(());
+        }
+
+        let res = PhysicalDrive::open_read_only(device_number)
+            .and_then(|mut drive| {
+                let sector_size = drive.sector_size();
+                efi::find_esp(&mut drive, sector_size)
+            })
+            .and_then(|esp| esp.ok_or_else(|| io::Error::new(io::ErrorKind::Other, "The USB flash drive has no EFI system partition.")))
+            .and_then(|esp| efi::boot_next(&esp));
+        if let Err(err) = res {
+            win32::show_error(hwnd, "Failed to reboot on the USB flash drive", &err.to_string());
+            return Ok(());
+        }
+        if self.dual_boot_plan().is_some() {
+            if let Err(err) = dualboot::shrink_system_drive() {
+                self.cancel_reboot("Failed to shrink the Windows partition", &err);
+                return Ok(());
+            }
+        }
+        // BitLocker stays off until Windows starts again, so it only gets
+        // suspended once the boot on the drive is set.
+        if let Some(drive) = checks.bitlocker.as_ref().filter(|_| self.suspend_bitlocker) {
+            if let Err(err) = system::suspend_bitlocker(drive) {
+                self.cancel_reboot("Failed to suspend BitLocker", &err);
+                return Ok(());
+            }
+        }
+        if checks.fast_startup && self.disable_fast_startup {
+            if let Err(err) = system::disable_fast_startup() {
+                self.cancel_reboot("Failed to turn off Fast Startup", &err);
+                return Ok(());
+            }
+        }
+        // Safe mode sticks until Windows has started once, so it comes last,
+        // when only the restart is left to fail.
+        if checks.raid_controller.is_some() && self.prepare_ahci {
+            if let Err(err) = system::boot_safe_mode_once() {
+                self.cancel_reboot("Failed to prepare Windows for AHCI", &err);
+                return Ok(());
+            }
+        }
+        if let Err(err) = efi::restart() {
+            win32::show_error(hwnd, "Failed to reboot on the USB flash drive", &err.to_string());
+        }
+        Ok(())
+    }
+
+    // Reports what stopped the reboot, once the firmware was already told to
+    // boot the drive.
+    fn cancel_reboot(&self, title: &str, err: &io::Error) {
+        let mut text = err.to_string();
+        if efi::cancel_boot_next().is_err() {
+            text.push_str("\n\nThe next restart will still start from the USB flash drive.");
+        }
+        win32::show_error(self.hwnd() as _, title, &text);
+    }
+
+    fn hwnd(&self) -> *mut core::ffi::c_void {
+        match self.window.raw_window_handle() {
             raw_window_handle::RawWindowHandle::Windows(window_handle) => window_handle.hwnd,
             _ => panic!("Unsupported platform!"),
-        };
+        }
+    }
 
-        unsafe { UpdateWindow(hwnd); }
+    fn update_window(&self) -> winrt::Result<()> {
+        self.desktop_source.set_content(self.step.top_level())?;
+        unsafe { UpdateWindow(self.hwnd()); }
 
         Ok(())
     }
@@ -93,77 +771,670 @@ enum WizardStep {
     Step1 {
         container: RelativePanel
     },
+    // Shows what we found out about a local image.
+    ConfirmImage {
+        container: RelativePanel,
+    },
+    // What could go wrong running Ubuntu on this computer.
+    Compatibility {
+        container: RelativePanel,
+    },
     Step2 {
         container: RelativePanel,
         usb_list: ListBox,
+        devices: Vec<DiskInfo>,
+        // Index in `devices` of each entry in `usb_list`.
+        shown: Vec<usize>,
+        policy: SafetyPolicy,
+        image_name: String,
+        image_size: u64,
         _watcher: DeviceWatcher,
     },
+    // Extra kernel parameters for the boot menus of the image.
+    BootOptions {
+        container: RelativePanel,
+        error: TextBlock,
+    },
+    // The autoinstall config, in two pages.
+    AutoinstallIdentity {
+        container: RelativePanel,
+        error: TextBlock,
+    },
+    AutoinstallSystem {
+        container: RelativePanel,
+        error: TextBlock,
+    },
+    // While the user's files get measured.
+    MeasuringFiles {
+        container: RelativePanel,
+    },
+    // Which of the user's files to copy from Windows.
+    Migration {
+        container: RelativePanel,
+    },
     Step3 {
         container: RelativePanel,
         _handle: JoinHandle<()>,
         progress_bar: ProgressBar,
+    },
+    RebootChecklist {
+        container: RelativePanel,
+        checks: RebootChecks,
+    },
+    Step4 {
+        container: RelativePanel,
+        _handle: JoinHandle<()>,
+        // Deletes the downloaded image once we're done with it.
+        _image: Image,
+        progress_bar: ProgressBar,
+        status: TextBlock,
+        reboot_btn: Button,
+        // Why the button stays disabled.
+        reboot_hint: Option<&'static str>,
+    }
+}
+
+fn make_tb(s: &str) -> winrt::Result<TextBlock> {
+    let tb = TextBlock::new()?;
+    tb.set_text(s)?;
+    Ok(tb)
+}
+
+fn make_checkbox(label: &str, checked: bool, el_proxy: EventLoopProxy<WizardEvent>, event: fn(bool) -> WizardEvent) -> winrt::Result<CheckBox> {
+    let checkbox = winrt::factory::<CheckBox, ICheckBoxFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+    let label_s: Object = PropertyValue::create_string(label)?.into();
+    checkbox.set_content(label_s)?;
+    checkbox.set_is_checked(PropertyValue::create_boolean(checked)?)?;
+    {
+        let el_proxy = el_proxy.clone();
+        checkbox.checked(RoutedEventHandler::new(move |_, _| {
+            el_proxy.send_event(event(true)).unwrap();
+            Ok(())
+        }))?;
+    }
+    checkbox.unchecked(RoutedEventHandler::new(move |_, _| {
+        el_proxy.send_event(event(false)).unwrap();
+        Ok(())
+    }))?;
+    Ok(checkbox)
+}
+
+// XAML text boxes end lines with a lone \r.
+fn non_empty_lines(text: &str) -> Vec<String> {
+    text.split(|v| v == '\r' || v == '\n').map(str::trim).filter(|v| !v.is_empty()).map(str::to_string).collect()
+}
+
+fn add_text_field(form: &StackPanel, label: &str, text: &str, multi_line: bool, el_proxy: EventLoopProxy<WizardEvent>, field: AutoinstallField) -> winrt::Result<()> {
+    form.children()?.append(&make_tb(label)?)?;
+    let text_box = winrt::factory::<TextBox, ITextBoxFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+    text_box.set_text(text)?;
+    if multi_line {
+        text_box.set_accepts_return(true)?;
+        text_box.set_text_wrapping(TextWrapping::Wrap)?;
+        text_box.set_height(80.)?;
+    }
+    text_box.set_margin(Thickness {
+        bottom: 5., ..Thickness::default()
+    })?;
+    {
+        let text_box_ref = text_box.clone();
+        text_box.text_changed(TextChangedEventHandler::new(move |_, _| {
+            el_proxy.send_event(WizardEvent::AutoinstallField(field, text_box_ref.text()?.to_string())).unwrap();
+            Ok(())
+        }))?;
     }
+    form.children()?.append(&text_box)?;
+    Ok(())
 }
 
-fn make_tb(s: &str) -> winrt::Result<TextBlock> {
-    let tb = TextBlock::new()?;
-    tb.set_text(s)?;
-    Ok(tb)
-}
+fn add_password_field(form: &StackPanel, label: &str, el_proxy: EventLoopProxy<WizardEvent>, field: AutoinstallField) -> winrt::Result<()> {
+    form.children()?.append(&make_tb(label)?)?;
+    let password_box = PasswordBox::new()?;
+    password_box.set_margin(Thickness {
+        bottom: 5., ..Thickness::default()
+    })?;
+    {
+        let password_box_ref = password_box.clone();
+        password_box.password_changed(RoutedEventHandler::new(move |_, _| {
+            el_proxy.send_event(WizardEvent::AutoinstallField(field, password_box_ref.password()?.to_string())).unwrap();
+            Ok(())
+        }))?;
+    }
+    form.children()?.append(&password_box)?;
+    Ok(())
+}
+
+fn make_usb_entry(device: &DiskInfo, image_name: &str, image_size: u64, refusals: &[Refusal]) -> winrt::Result<ListBoxItem> {
+    let entry = winrt::factory::<StackPanel, IStackPanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+    entry.set_orientation(Orientation::Horizontal)?;
+
+    let mut warnings: Vec<String> = refusals.iter().map(|v| v.to_string()).collect();
+    if !device.can_hold(image_size) {
+        warnings.push(format!("Too small: {} requires at least {}.", image_name, format_size(image_size)));
+    }
+
+    if !warnings.is_empty() {
+        let warning = make_tb("\u{26A0}")?;
+        warning.set_font_size(24.)?;
+        let orange_brush = SolidColorBrush::new()?;
+        orange_brush.set_color(Color { r: 0xe9, g: 0x54, b: 0x20, a: 255 })?;
+        warning.set_foreground(orange_brush)?;
+        warning.set_margin(Thickness {
+            right: 10., ..Thickness::default()
+        })?;
+        entry.children()?.append(&warning)?;
+    }
+
+    let text = winrt::factory::<StackPanel, IStackPanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+    let name = make_tb(&device.display_name())?;
+    name.set_font_size(18.)?;
+    text.children()?.append(&name)?;
+    text.children()?.append(&make_tb(&device.details())?)?;
+    for warning in warnings {
+        text.children()?.append(&make_tb(&warning)?)?;
+    }
+    entry.children()?.append(&text)?;
+
+    let item = winrt::factory::<ListBoxItem, IListBoxItemFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+    item.set_content(Object::from(entry))?;
+    Ok(item)
+}
+
+impl WizardStep {
+    fn step1(el_proxy: EventLoopProxy<WizardEvent>) -> winrt::Result<WizardStep> {
+        let xaml_container = winrt::factory::<RelativePanel, IRelativePanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let grey_brush = SolidColorBrush::new()?;
+        grey_brush.set_color(Color { r: 0x5e, g: 0x27, b: 0x50, a: 255})?;
+        xaml_container.set_background(grey_brush)?;
+
+        let title = make_tb("Ubuntu Media Creation Wizard")?;
+        title.set_font_size(48.)?;
+        RelativePanel::set_align_horizontal_center_with_panel(&title, true)?;
+        title.set_margin(Thickness {
+            top: 10.,
+            ..Default::default()
+        })?;
+        xaml_container.children()?.append(&title)?;
+
+        let explanation = make_tb("The installer will guide you through the steps required to create a bootable Ubuntu USB flash drive, and reboot on it.")?;
+        explanation.set_text_wrapping(TextWrapping::Wrap)?;
+        explanation.set_margin(Thickness {
+            top: 10., left: 10., right: 10., bottom: 10.,
+        })?;
+        RelativePanel::set_below(&explanation, Object::from(title))?;
+        xaml_container.children()?.append(&explanation)?;
+
+        let next_btn = winrt::factory::<Button, IButtonFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let next_s: Object = PropertyValue::create_string("Next")?.into();
+        next_btn.set_content(next_s)?;
+        next_btn.set_margin(Thickness {
+            top: 0., left: 0., right: 10., bottom: 10.
+        })?;
+        {
+            let el_proxy = el_proxy.clone();
+            next_btn.click(RoutedEventHandler::new(move |_, _| {
+                let _ = el_proxy.send_event(WizardEvent::CheckCompatibility);
+                Ok(())
+            }))?;
+        }
+        RelativePanel::set_align_bottom_with_panel(&next_btn, true)?;
+        RelativePanel::set_align_right_with_panel(&next_btn, true)?;
+
+        xaml_container.children()?.append(next_btn)?;
+
+        let local_btn = winrt::factory::<Button, IButtonFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let local_s: Object = PropertyValue::create_string("Use an ISO file I already have...")?.into();
+        local_btn.set_content(local_s)?;
+        local_btn.set_margin(Thickness {
+            top: 0., left: 10., right: 0., bottom: 10.
+        })?;
+        local_btn.click(RoutedEventHandler::new(move |_, _| {
+            let _ = el_proxy.send_event(WizardEvent::PickImage);
+            Ok(())
+        }))?;
+        RelativePanel::set_align_bottom_with_panel(&local_btn, true)?;
+        RelativePanel::set_align_left_with_panel(&local_btn, true)?;
+
+        xaml_container.children()?.append(local_btn)?;
+
+        xaml_container.update_layout()?;
+
+        Ok(WizardStep::Step1 {
+            container: xaml_container
+        })
+    }
+
+    fn confirm_image(el_proxy: EventLoopProxy<WizardEvent>, details: &[String]) -> winrt::Result<WizardStep> {
+        let xaml_container = winrt::factory::<RelativePanel, IRelativePanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let grey_brush = SolidColorBrush::new()?;
+        grey_brush.set_color(Color { r: 0x5e, g: 0x27, b: 0x50, a: 255})?;
+        xaml_container.set_background(grey_brush)?;
+
+        let title = make_tb("Is this the right image?")?;
+        title.set_font_size(48.)?;
+        RelativePanel::set_align_horizontal_center_with_panel(&title, true)?;
+        title.set_margin(Thickness {
+            top: 10., ..Thickness::default()
+        })?;
+        xaml_container.children()?.append(&title)?;
+
+        let details_panel = winrt::factory::<StackPanel, IStackPanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        for line in details {
+            let tb = make_tb(line)?;
+            tb.set_text_wrapping(TextWrapping::Wrap)?;
+            details_panel.children()?.append(&tb)?;
+        }
+        details_panel.set_margin(Thickness {
+            top: 10., left: 10., right: 10., bottom: 10.,
+        })?;
+        RelativePanel::set_below(&details_panel, Object::from(title))?;
+        xaml_container.children()?.append(&details_panel)?;
+
+        let next_btn = winrt::factory::<Button, IButtonFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let next_s: Object = PropertyValue::create_string("Next")?.into();
+        next_btn.set_content(next_s)?;
+        next_btn.set_margin(Thickness {
+            top: 0., left: 0., right: 10., bottom: 10.
+        })?;
+        {
+            let el_proxy = el_proxy.clone();
+            next_btn.click(RoutedEventHandler::new(move |_, _| {
+                let _ = el_proxy.send_event(WizardEvent::CheckCompatibility);
+                Ok(())
+            }))?;
+        }
+        RelativePanel::set_align_bottom_with_panel(&next_btn, true)?;
+        RelativePanel::set_align_right_with_panel(&next_btn, true)?;
+        xaml_container.children()?.append(next_btn)?;
+
+        let other_btn = winrt::factory::<Button, IButtonFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let other_s: Object = PropertyValue::create_string("Choose another image...")?.into();
+        other_btn.set_content(other_s)?;
+        other_btn.set_margin(Thickness {
+            top: 0., left: 10., right: 0., bottom: 10.
+        })?;
+        other_btn.click(RoutedEventHandler::new(move |_, _| {
+            let _ = el_proxy.send_event(WizardEvent::PickImage);
+            Ok(())
+        }))?;
+        RelativePanel::set_align_bottom_with_panel(&other_btn, true)?;
+        RelativePanel::set_align_left_with_panel(&other_btn, true)?;
+        xaml_container.children()?.append(other_btn)?;
+
+        xaml_container.update_layout()?;
+
+        Ok(WizardStep::ConfirmImage {
+            container: xaml_container
+        })
+    }
+
+    pub fn step2(el_proxy: EventLoopProxy<WizardEvent>, options: WriteOptions, firmware: Firmware, image_name: String, image_size: u64) -> winrt::Result<WizardStep> {
+        let xaml_container = winrt::factory::<RelativePanel, IRelativePanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let grey_brush = SolidColorBrush::new()?;
+        grey_brush.set_color(Color { r: 0x5e, g: 0x27, b: 0x50, a: 255})?;
+        xaml_container.set_background(grey_brush)?;
+
+        let title = make_tb("Select a USB Flash Drive")?;
+        title.set_font_size(48.)?;
+        RelativePanel::set_align_horizontal_center_with_panel(&title, true)?;
+        title.set_margin(Thickness {
+            top: 10., ..Thickness::default()
+        })?;
+        xaml_container.children()?.append(&title)?;
+
+        let explanation = make_tb("The files on your USB will be deleted. Please back them up to a safe location before proceeding.")?;
+        explanation.set_text_wrapping(TextWrapping::Wrap)?;
+        explanation.set_margin(Thickness {
+            top: 10., left: 10., right: 10., bottom: 10.,
+        })?;
+        RelativePanel::set_below(&explanation, Object::from(title))?;
+        xaml_container.children()?.append(&explanation)?;
+
+        let usb_list = winrt::factory::<ListBox, IListBoxFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        RelativePanel::set_below(&usb_list, Object::from(explanation))?;
+        xaml_container.children()?.append(&usb_list)?;
+
+        let show_all = make_checkbox("Show all disks (advanced)", false, el_proxy.clone(), WizardEvent::ShowAllDisks)?;
+        show_all.set_margin(Thickness {
+            top: 10., left: 10., ..Thickness::default()
+        })?;
+        RelativePanel::set_below(&show_all, Object::from(usb_list.clone()))?;
+        xaml_container.children()?.append(&show_all)?;
+
+        let verify = make_checkbox("Check the USB flash drive for errors after writing", options.verify, el_proxy.clone(), WizardEvent::VerifyAfterWriting)?;
+        verify.set_margin(Thickness {
+            left: 10., ..Thickness::default()
+        })?;
+        RelativePanel::set_below(&verify, Object::from(show_all))?;
+        xaml_container.children()?.append(&verify)?;
+
+        let persistence = make_checkbox("Keep files and settings between reboots (persistence)", options.persistence, el_proxy.clone(), WizardEvent::Persistence)?;
+        persistence.set_margin(Thickness {
+            left: 10., ..Thickness::default()
+        })?;
+        RelativePanel::set_below(&persistence, Object::from(verify))?;
+        xaml_container.children()?.append(&persistence)?;
+
+        let copy_files = make_checkbox("Copy the files instead of writing the image (UEFI only)", options.mode == WriteMode::Files, el_proxy.clone(), WizardEvent::CopyFiles)?;
+        copy_files.set_margin(Thickness {
+            left: 10., ..Thickness::default()
+        })?;
+        // The drive wouldn't boot here.
+        copy_files.set_is_enabled(firmware.can_boot_files())?;
+        RelativePanel::set_below(&copy_files, Object::from(persistence))?;
+        xaml_container.children()?.append(&copy_files)?;
+
+        let customize_boot = make_checkbox("Customize the boot options (advanced)", false, el_proxy.clone(), WizardEvent::CustomizeBoot)?;
+        customize_boot.set_margin(Thickness {
+            left: 10., ..Thickness::default()
+        })?;
+        RelativePanel::set_below(&customize_boot, Object::from(copy_files))?;
+        xaml_container.children()?.append(&customize_boot)?;
+
+        let next_btn = winrt::factory::<Button, IButtonFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let next_s: Object = PropertyValue::create_string("Next")?.into();
+        next_btn.set_content(next_s)?;
+        next_btn.set_margin(Thickness {
+            top: 0., left: 0., right: 10., bottom: 10.
+        })?;
+        {
+            let el_proxy = el_proxy.clone();
+            next_btn.click(RoutedEventHandler::new(move |_, _| {
+                el_proxy.send_event(WizardEvent::GoToStep3).unwrap();
+                Ok(())
+            }))?;
+        }
+        next_btn.set_is_enabled(false)?;
+        RelativePanel::set_align_bottom_with_panel(&next_btn, true)?;
+        RelativePanel::set_align_right_with_panel(&next_btn, true)?;
+        xaml_container.children()?.append(&next_btn)?;
+
+        {
+            // The list gets rebuilt when toggling "show all disks", which
+            // clears the selection.
+            let usb_list_ref = usb_list.clone();
+            usb_list.selection_changed(SelectionChangedEventHandler::new(move |_, _| {
+                next_btn.set_is_enabled(usb_list_ref.selected_index()? >= 0)?;
+                Ok(())
+            }))?;
+        }
+
+        // TODO: Move to using WMI MSFT_StorageEvent.
+        // BODY: Using the WinRT APIs here is a bad idea. They're too
+        // BODY: restrictive. Instead, what I should be doing is using WMI to
+        // BODY: list the disks, partitions, and volumes. WMI has an event
+        // BODY: system as well which would allow us to see live changes to the
+        // BODY: disks, similar to what we have right now.
+
+        // Watch every volume, not just portable devices: the safety policy
+        // decides what gets shown.
+        let watcher = DeviceInformation::create_watcher_aqs_filter(VOLUME_AQS_FILTER)?;
+        {
+            let el_proxy = el_proxy.clone();
+            watcher.added(TypedEventHandler::new(move |_, info: &DeviceInformation| {
+                match DiskInfo::query(&info.id()?.to_string()) {
+                    Ok(Some(device)) => el_proxy.send_event(WizardEvent::UsbDeviceFound(device)).unwrap(),
+                    Ok(None) => (),
+                    Err(err) => eprintln!("Failed to query device {}: {}", info.id()?, err),
+                }
+                Ok(())
+            }))?;
+        }
+        watcher.updated(TypedEventHandler::new(move |_, _| {
+            Ok(())
+        }))?;
+        watcher.removed(TypedEventHandler::new(move |_, _| {
+            Ok(())
+        }))?;
+        watcher.start()?;
+
+        xaml_container.update_layout()?;
+
+        Ok(WizardStep::Step2 {
+            container: xaml_container,
+            usb_list, devices: Vec::new(), shown: Vec::new(),
+            policy: SafetyPolicy::default(), image_name, image_size, _watcher: watcher
+        })
+    }
+
+    fn boot_options(el_proxy: EventLoopProxy<WizardEvent>, options: &BootOptions, create_autoinstall: bool, subiquity: bool) -> winrt::Result<WizardStep> {
+        let xaml_container = winrt::factory::<RelativePanel, IRelativePanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let grey_brush = SolidColorBrush::new()?;
+        grey_brush.set_color(Color { r: 0x5e, g: 0x27, b: 0x50, a: 255})?;
+        xaml_container.set_background(grey_brush)?;
+
+        let title = make_tb("Boot Options")?;
+        title.set_font_size(48.)?;
+        RelativePanel::set_align_horizontal_center_with_panel(&title, true)?;
+        title.set_margin(Thickness {
+            top: 10., ..Thickness::default()
+        })?;
+        xaml_container.children()?.append(&title)?;
+
+        let explanation = make_tb(if subiquity {
+            "These get added to the kernel command line of every entry of the boot menu."
+        } else {
+            "These get added to the kernel command line of every entry of the boot menu. Autoinstall needs Ubuntu Server, or Ubuntu Desktop 23.04 or later."
+        })?;
+        explanation.set_text_wrapping(TextWrapping::Wrap)?;
+        explanation.set_margin(Thickness {
+            top: 10., left: 10., right: 10., bottom: 10.,
+        })?;
+        RelativePanel::set_below(&explanation, Object::from(title))?;
+        xaml_container.children()?.append(&explanation)?;
+
+        let nomodeset = make_checkbox("Safe graphics (nomodeset)", options.nomodeset, el_proxy.clone(), WizardEvent::Nomodeset)?;
+        nomodeset.set_margin(Thickness {
+            left: 10., ..Thickness::default()
+        })?;
+        RelativePanel::set_below(&nomodeset, Object::from(explanation))?;
+        xaml_container.children()?.append(&nomodeset)?;
+
+        let autoinstall = make_checkbox("Install without asking for confirmation (autoinstall)", options.autoinstall, el_proxy.clone(), WizardEvent::Autoinstall)?;
+        autoinstall.set_is_enabled(subiquity)?;
+        autoinstall.set_margin(Thickness {
+            left: 10., ..Thickness::default()
+        })?;
+        RelativePanel::set_below(&autoinstall, Object::from(nomodeset))?;
+        xaml_container.children()?.append(&autoinstall)?;
+
+        let serial_console = make_checkbox("Use the first serial port as a console too (ttyS0, 115200 bauds)", options.serial_console, el_proxy.clone(), WizardEvent::SerialConsole)?;
+        serial_console.set_margin(Thickness {
+            left: 10., ..Thickness::default()
+        })?;
+        RelativePanel::set_below(&serial_console, Object::from(autoinstall))?;
+        xaml_container.children()?.append(&serial_console)?;
+
+        let seed_label = make_tb("Autoinstall seed URL (ds=nocloud-net), optional:")?;
+        seed_label.set_margin(Thickness {
+            top: 10., left: 10., right: 10., ..Thickness::default()
+        })?;
+        RelativePanel::set_below(&seed_label, Object::from(serial_console))?;
+        xaml_container.children()?.append(&seed_label)?;
+
+        let seed_url = winrt::factory::<TextBox, ITextBoxFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        seed_url.set_placeholder_text("http://192.168.0.1/ubuntu/")?;
+        if let Some(url) = &options.seed_url {
+            seed_url.set_text(url.as_str())?;
+        }
+        seed_url.set_margin(Thickness {
+            top: 5., left: 10., right: 10., ..Thickness::default()
+        })?;
+        RelativePanel::set_below(&seed_url, Object::from(seed_label))?;
+        RelativePanel::set_align_left_with_panel(&seed_url, true)?;
+        RelativePanel::set_align_right_with_panel(&seed_url, true)?;
+        {
+            let el_proxy = el_proxy.clone();
+            let seed_url_ref = seed_url.clone();
+            seed_url.text_changed(TextChangedEventHandler::new(move |_, _| {
+                el_proxy.send_event(WizardEvent::SeedUrl(seed_url_ref.text()?.to_string())).unwrap();
+                Ok(())
+            }))?;
+        }
+        xaml_container.children()?.append(&seed_url)?;
+
+        let autoinstall = make_checkbox("Create an autoinstall config", create_autoinstall, el_proxy.clone(), WizardEvent::CreateAutoinstall)?;
+        autoinstall.set_is_enabled(subiquity)?;
+        autoinstall.set_margin(Thickness {
+            top: 10., left: 10., ..Thickness::default()
+        })?;
+        RelativePanel::set_below(&autoinstall, Object::from(seed_url))?;
+        xaml_container.children()?.append(&autoinstall)?;
+
+        let error = make_tb("")?;
+        error.set_text_wrapping(TextWrapping::Wrap)?;
+        error.set_margin(Thickness {
+            top: 10., left: 10., right: 10., bottom: 10.,
+        })?;
+        RelativePanel::set_below(&error, Object::from(autoinstall))?;
+        xaml_container.children()?.append(&error)?;
+
+        let next_btn = winrt::factory::<Button, IButtonFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let next_s: Object = PropertyValue::create_string("Next")?.into();
+        next_btn.set_content(next_s)?;
+        next_btn.set_margin(Thickness {
+            top: 0., left: 0., right: 10., bottom: 10.
+        })?;
+        next_btn.click(RoutedEventHandler::new(move |_, _| {
+            el_proxy.send_event(WizardEvent::GoToStep3).unwrap();
+            Ok(())
+        }))?;
+        RelativePanel::set_align_bottom_with_panel(&next_btn, true)?;
+        RelativePanel::set_align_right_with_panel(&next_btn, true)?;
+        xaml_container.children()?.append(&next_btn)?;
+
+        xaml_container.update_layout()?;
 
-impl WizardStep {
-    fn step1(el_proxy: EventLoopProxy<WizardEvent>) -> winrt::Result<WizardStep> {
+        Ok(WizardStep::BootOptions {
+            container: xaml_container,
+            error,
+        })
+    }
+
+    // A page with a title, a form to fill, and a button at the bottom.
+    fn form_page(el_proxy: EventLoopProxy<WizardEvent>, title: &str, button: &str, event: fn() -> WizardEvent) -> winrt::Result<(RelativePanel, StackPanel)> {
         let xaml_container = winrt::factory::<RelativePanel, IRelativePanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
         let grey_brush = SolidColorBrush::new()?;
         grey_brush.set_color(Color { r: 0x5e, g: 0x27, b: 0x50, a: 255})?;
         xaml_container.set_background(grey_brush)?;
 
-        let title = make_tb("Ubuntu Media Creation Wizard")?;
+        let title = make_tb(title)?;
         title.set_font_size(48.)?;
         RelativePanel::set_align_horizontal_center_with_panel(&title, true)?;
         title.set_margin(Thickness {
-            top: 10.,
-            ..Default::default()
+            top: 10., ..Thickness::default()
         })?;
         xaml_container.children()?.append(&title)?;
 
-        let explanation = make_tb("The installer will guide you through the steps required to create a bootable Ubuntu USB flash drive, and reboot on it.")?;
-        explanation.set_text_wrapping(TextWrapping::Wrap)?;
-        explanation.set_margin(Thickness {
+        let form = winrt::factory::<StackPanel, IStackPanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        form.set_margin(Thickness {
             top: 10., left: 10., right: 10., bottom: 10.,
         })?;
-        RelativePanel::set_below(&explanation, Object::from(title))?;
-        xaml_container.children()?.append(&explanation)?;
+        RelativePanel::set_below(&form, Object::from(title))?;
+        RelativePanel::set_align_left_with_panel(&form, true)?;
+        RelativePanel::set_align_right_with_panel(&form, true)?;
+        xaml_container.children()?.append(&form)?;
 
         let next_btn = winrt::factory::<Button, IButtonFactory>()?.create_instance(Object::default(), &mut Object::default())?;
-        let next_s: Object = PropertyValue::create_string("Next")?.into();
+        let next_s: Object = PropertyValue::create_string(button)?.into();
         next_btn.set_content(next_s)?;
         next_btn.set_margin(Thickness {
             top: 0., left: 0., right: 10., bottom: 10.
         })?;
         next_btn.click(RoutedEventHandler::new(move |_, _| {
-            let _ = el_proxy.send_event(WizardEvent::GoToStep2);
+            el_proxy.send_event(event()).unwrap();
             Ok(())
         }))?;
         RelativePanel::set_align_bottom_with_panel(&next_btn, true)?;
         RelativePanel::set_align_right_with_panel(&next_btn, true)?;
+        xaml_container.children()?.append(&next_btn)?;
 
-        xaml_container.children()?.append(next_btn)?;
+        Ok((xaml_container, form))
+    }
+
+    fn autoinstall_identity(el_proxy: EventLoopProxy<WizardEvent>, config: &Autoinstall) -> winrt::Result<WizardStep> {
+        let (xaml_container, form) = WizardStep::form_page(el_proxy.clone(), "Who Are You?", "Next", || WizardEvent::GoToStep3)?;
+        let identity = &config.identity;
+        add_text_field(&form, "Your name:", &identity.realname, false, el_proxy.clone(), AutoinstallField::RealName)?;
+        add_text_field(&form, "The computer's name:", &identity.hostname, false, el_proxy.clone(), AutoinstallField::Hostname)?;
+        add_text_field(&form, "Pick a username:", &identity.username, false, el_proxy.clone(), AutoinstallField::Username)?;
+        add_password_field(&form, "Choose a password:", el_proxy.clone(), AutoinstallField::Password)?;
+        add_password_field(&form, "Confirm your password:", el_proxy, AutoinstallField::PasswordConfirm)?;
+
+        let error = make_tb("")?;
+        error.set_text_wrapping(TextWrapping::Wrap)?;
+        form.children()?.append(&error)?;
 
         xaml_container.update_layout()?;
 
-        Ok(WizardStep::Step1 {
-            container: xaml_container
+        Ok(WizardStep::AutoinstallIdentity {
+            container: xaml_container,
+            error,
+        })
+    }
+
+    fn autoinstall_system(el_proxy: EventLoopProxy<WizardEvent>, config: &Autoinstall, shrink_plan: &Result<ShrinkPlan, String>, migrate_wifi: bool) -> winrt::Result<WizardStep> {
+        let (xaml_container, form) = WizardStep::form_page(el_proxy.clone(), "Installed System", "Next", || WizardEvent::GoToStep3)?;
+        add_text_field(&form, "Locale:", &config.locale, false, el_proxy.clone(), AutoinstallField::Locale)?;
+        add_text_field(&form, "Keyboard layout:", &config.keyboard.layout, false, el_proxy.clone(), AutoinstallField::KeyboardLayout)?;
+        add_text_field(&form, "Keyboard variant, optional:", &config.keyboard.variant, false, el_proxy.clone(), AutoinstallField::KeyboardVariant)?;
+        add_text_field(&form, "Time zone (e.g. Europe/Paris), optional:", config.timezone.as_deref().unwrap_or(""), false, el_proxy.clone(), AutoinstallField::Timezone)?;
+
+        let lvm = make_checkbox("Use LVM on the disk", config.storage.layout.name == LayoutName::Lvm, el_proxy.clone(), WizardEvent::UseLvm)?;
+        form.children()?.append(&lvm)?;
+        let alongside = make_checkbox("Install alongside Windows", config.storage.layout.mode == Some(LayoutMode::UseGap), el_proxy.clone(), WizardEvent::InstallAlongsideWindows)?;
+        alongside.set_is_enabled(shrink_plan.is_ok())?;
+        form.children()?.append(&alongside)?;
+        let alongside_details = make_tb(&match shrink_plan {
+            Ok(plan) => plan.describe(),
+            Err(err) => format!("Ubuntu can't go next to Windows on this computer: {}.", err),
+        })?;
+        alongside_details.set_text_wrapping(TextWrapping::Wrap)?;
+        form.children()?.append(&alongside_details)?;
+        let ssh_server = make_checkbox("Install the OpenSSH server", config.ssh.install_server, el_proxy.clone(), WizardEvent::InstallSshServer)?;
+        form.children()?.append(&ssh_server)?;
+        add_text_field(&form, "Authorized SSH keys, one per line:", &config.ssh.authorized_keys.join("\r"), true, el_proxy.clone(), AutoinstallField::SshKeys)?;
+        add_text_field(&form, "Extra packages:", &config.packages.join(" "), false, el_proxy.clone(), AutoinstallField::Packages)?;
+        add_text_field(&form, "Commands to run after installing, one per line:", &config.late_commands.join("\r"), true, el_proxy.clone(), AutoinstallField::LateCommands)?;
+        let wifi = make_checkbox("Connect to the Wi-Fi networks saved on this computer", migrate_wifi, el_proxy.clone(), WizardEvent::MigrateWifi)?;
+        form.children()?.append(&wifi)?;
+        let wifi_details = make_tb("Their passwords get stored on the USB flash drive unencrypted.")?;
+        wifi_details.set_text_wrapping(TextWrapping::Wrap)?;
+        form.children()?.append(&wifi_details)?;
+
+        let error = make_tb("")?;
+        error.set_text_wrapping(TextWrapping::Wrap)?;
+        form.children()?.append(&error)?;
+
+        let save_btn = winrt::factory::<Button, IButtonFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let save_s: Object = PropertyValue::create_string("Save user-data...")?.into();
+        save_btn.set_content(save_s)?;
+        save_btn.set_margin(Thickness {
+            top: 0., left: 10., right: 0., bottom: 10.
+        })?;
+        save_btn.click(RoutedEventHandler::new(move |_, _| {
+            el_proxy.send_event(WizardEvent::SaveUserData).unwrap();
+            Ok(())
+        }))?;
+        RelativePanel::set_align_bottom_with_panel(&save_btn, true)?;
+        RelativePanel::set_align_left_with_panel(&save_btn, true)?;
+        xaml_container.children()?.append(&save_btn)?;
+
+        xaml_container.update_layout()?;
+
+        Ok(WizardStep::AutoinstallSystem {
+            container: xaml_container,
+            error,
         })
     }
 
-    pub fn step2(el_proxy: EventLoopProxy<WizardEvent>) -> winrt::Result<WizardStep> {
+    fn measuring_files() -> winrt::Result<WizardStep> {
         let xaml_container = winrt::factory::<RelativePanel, IRelativePanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
         let grey_brush = SolidColorBrush::new()?;
         grey_brush.set_color(Color { r: 0x5e, g: 0x27, b: 0x50, a: 255})?;
         xaml_container.set_background(grey_brush)?;
 
-        let title = make_tb("Select a USB Flash Drive")?;
+        let title = make_tb("Your Files")?;
         title.set_font_size(48.)?;
         RelativePanel::set_align_horizontal_center_with_panel(&title, true)?;
         title.set_margin(Thickness {
@@ -171,71 +1442,140 @@ impl WizardStep {
         })?;
         xaml_container.children()?.append(&title)?;
 
-        let explanation = make_tb("The files on your USB will be deleted. Please back them up to a safe location before proceeding.")?;
-        explanation.set_text_wrapping(TextWrapping::Wrap)?;
-        explanation.set_margin(Thickness {
-            top: 10., left: 10., right: 10., bottom: 10.,
+        let text = make_tb("Looking through your files in Windows...")?;
+        RelativePanel::set_below(&text, Object::from(title))?;
+        RelativePanel::set_align_horizontal_center_with_panel(&text, true)?;
+        text.set_margin(Thickness {
+            top: 10., ..Thickness::default()
         })?;
-        RelativePanel::set_below(&explanation, Object::from(title))?;
-        xaml_container.children()?.append(&explanation)?;
+        xaml_container.children()?.append(&text)?;
 
-        let usb_list = winrt::factory::<ListBox, IListBoxFactory>()?.create_instance(Object::default(), &mut Object::default())?;
-        RelativePanel::set_below(&usb_list, Object::from(explanation))?;
-        xaml_container.children()?.append(&usb_list)?;
-
-        let next_btn = winrt::factory::<Button, IButtonFactory>()?.create_instance(Object::default(), &mut Object::default())?;
-        let next_s: Object = PropertyValue::create_string("Next")?.into();
-        next_btn.set_content(next_s)?;
-        next_btn.set_margin(Thickness {
-            top: 0., left: 0., right: 10., bottom: 10.
+        let progress_bar = winrt::factory::<ProgressBar, IProgressBarFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        RelativePanel::set_below(&progress_bar, Object::from(text))?;
+        RelativePanel::set_align_left_with_panel(&progress_bar, true)?;
+        RelativePanel::set_align_right_with_panel(&progress_bar, true)?;
+        progress_bar.set_is_indeterminate(true)?;
+        progress_bar.set_margin(Thickness {
+            top: 10., left: 10., right: 10., ..Thickness::default()
         })?;
-        {
-            let el_proxy = el_proxy.clone();
-            next_btn.click(RoutedEventHandler::new(move |_, _| {
-                el_proxy.send_event(WizardEvent::GoToStep3).unwrap();
-                Ok(())
-            }))?;
-        }
-        next_btn.set_is_enabled(false)?;
-        RelativePanel::set_align_bottom_with_panel(&next_btn, true)?;
-        RelativePanel::set_align_right_with_panel(&next_btn, true)?;
-        xaml_container.children()?.append(&next_btn)?;
+        xaml_container.children()?.append(&progress_bar)?;
 
-        usb_list.selection_changed(SelectionChangedEventHandler::new(move |_, _| {
-            next_btn.set_is_enabled(true)?;
-            Ok(())
-        }))?;
+        xaml_container.update_layout()?;
 
-        // TODO: Move to using WMI MSFT_StorageEvent.
-        // BODY: Using the WinRT APIs here is a bad idea. They're too
-        // BODY: restrictive. Instead, what I should be doing is using WMI to
-        // BODY: list the disks, partitions, and volumes. WMI has an event
-        // BODY: system as well which would allow us to see live changes to the
-        // BODY: disks, similar to what we have right now.
+        Ok(WizardStep::MeasuringFiles {
+            container: xaml_container,
+        })
+    }
 
-        let watcher = DeviceInformation::create_watcher_device_class(DeviceClass::PortableStorageDevice)?;
-        {
-            let el_proxy = el_proxy.clone();
-            watcher.added(TypedEventHandler::new(move |_, info: &DeviceInformation| {
-                if let Some(device) = DeviceNameId::new(info)? {
-                    el_proxy.send_event(WizardEvent::UsbDeviceFound(device)).unwrap();
+    fn migration(el_proxy: EventLoopProxy<WizardEvent>, user_files: &Result<(Manifest, Vec<String>), String>, free: u64, can_migrate: bool, migrate: bool) -> winrt::Result<WizardStep> {
+        let (xaml_container, form) = WizardStep::form_page(el_proxy.clone(), "Your Files", "Next", || WizardEvent::GoToStep3)?;
+        let add_paragraph = |text: &str| -> winrt::Result<()> {
+            let tb = make_tb(text)?;
+            tb.set_text_wrapping(TextWrapping::Wrap)?;
+            tb.set_margin(Thickness {
+                top: 10., ..Thickness::default()
+            })?;
+            form.children()?.append(&tb)
+        };
+
+        add_paragraph("Ubuntu can copy your files from Windows into the home folder of the new user, when it first starts. They stay in Windows too.")?;
+        match user_files {
+            Ok((manifest, skipped)) => {
+                for item in &manifest.items {
+                    add_paragraph(&item.describe())?;
                 }
-                Ok(())
-            }))?;
+                for reason in skipped {
+                    add_paragraph(&format!("Left out: {}", reason))?;
+                }
+                if manifest.items.is_empty() {
+                    add_paragraph("There's nothing to copy.")?;
+                } else if !can_migrate {
+                    add_paragraph(&format!("They take {}, more than half of the {} Ubuntu gets.", format_size(manifest.size()), format_size(free)))?;
+                } else {
+                    add_paragraph(&format!("They take {}, out of the {} Ubuntu gets.", format_size(manifest.size()), format_size(free)))?;
+                }
+            }
+            Err(err) => add_paragraph(&format!("Your files can't be copied: {}.", err))?,
         }
-        watcher.updated(TypedEventHandler::new(move |_, _| {
-            Ok(())
-        }))?;
-        watcher.removed(TypedEventHandler::new(move |_, _| {
-            Ok(())
-        }))?;
-        watcher.start()?;
+        let checkbox = make_checkbox("Copy them", migrate, el_proxy, WizardEvent::MigrateFiles)?;
+        checkbox.set_is_enabled(can_migrate)?;
+        form.children()?.append(&checkbox)?;
 
         xaml_container.update_layout()?;
 
-        Ok(WizardStep::Step2 {
+        Ok(WizardStep::Migration {
+            container: xaml_container,
+        })
+    }
+
+    fn compatibility(el_proxy: EventLoopProxy<WizardEvent>, findings: &[compat::Finding]) -> winrt::Result<WizardStep> {
+        let (xaml_container, form) = WizardStep::form_page(el_proxy, "Will Ubuntu Run Here?", "Next", || WizardEvent::GoToStep2)?;
+        let add_paragraph = |text: &str| -> winrt::Result<()> {
+            let tb = make_tb(text)?;
+            tb.set_text_wrapping(TextWrapping::Wrap)?;
+            tb.set_margin(Thickness {
+                top: 10., ..Thickness::default()
+            })?;
+            form.children()?.append(&tb)
+        };
+
+        add_paragraph("This is about this computer. If the USB flash drive is for another one, none of this applies.")?;
+        for finding in findings {
+            let status = match finding.status {
+                Status::Ok => "OK",
+                Status::Warning => "Check",
+                Status::Problem => "Problem",
+            };
+            add_paragraph(&format!("{}: {}", status, finding.text))?;
+        }
+
+        xaml_container.update_layout()?;
+
+        Ok(WizardStep::Compatibility {
+            container: xaml_container,
+        })
+    }
+
+    fn reboot_checklist(el_proxy: EventLoopProxy<WizardEvent>, checks: RebootChecks, shrink_plan: Option<ShrinkPlan>) -> winrt::Result<WizardStep> {
+        let (xaml_container, form) = WizardStep::form_page(el_proxy.clone(), "Before Rebooting", "Reboot now", || WizardEvent::RebootToDrive)?;
+        let add_paragraph = |text: &str| -> winrt::Result<()> {
+            let tb = make_tb(text)?;
+            tb.set_text_wrapping(TextWrapping::Wrap)?;
+            tb.set_margin(Thickness {
+                top: 10., ..Thickness::default()
+            })?;
+            form.children()?.append(&tb)
+        };
+
+        add_paragraph("Save your work and close your other programs. The computer will start on the USB flash drive this time only, and on Windows again afterwards.")?;
+        if let Some(drive) = &checks.bitlocker {
+            add_paragraph(&format!("BitLocker protects {}. Unless it's suspended, Windows will ask for its recovery key the next time it starts. It can usually be found at https://aka.ms/myrecoverykey.", drive))?;
+            form.children()?.append(&make_checkbox("Suspend BitLocker until Windows starts again", true, el_proxy.clone(), WizardEvent::SuspendBitLocker)?)?;
+        }
+        if let Some(err) = &checks.bitlocker_unknown {
+            add_paragraph(&format!("Couldn't check whether BitLocker protects Windows ({}). If it does, have its recovery key at hand.", err))?;
+        }
+        if checks.fast_startup {
+            add_paragraph("Fast Startup is on: Windows hibernates instead of shutting down, and leaves its drives in a state where Ubuntu can only read them.")?;
+            form.children()?.append(&make_checkbox("Turn off Fast Startup", true, el_proxy.clone(), WizardEvent::DisableFastStartup)?)?;
+        }
+        if checks.pending_update {
+            add_paragraph("Windows has updates waiting to be installed on the next restart, and they could take it over. Restart Windows to finish installing them, then come back to reboot on the USB flash drive.")?;
+        }
+        if let Some(controller) = &checks.raid_controller {
+            add_paragraph(&format!("{} runs the disks in RAID mode, and Ubuntu's installer won't see them. The firmware settings can switch them to AHCI, usually under \"SATA mode\" or \"VMD\", but Windows won't start anymore afterwards, unless it starts in safe mode once first.", controller))?;
+            form.children()?.append(&make_checkbox("Start Windows in safe mode next time, so I can switch to AHCI", false, el_proxy.clone(), WizardEvent::PrepareAhciSwitch)?)?;
+            add_paragraph("Then: open the firmware settings when the computer starts (usually with F2 or Del), switch to AHCI, and save. Windows starts in safe mode once, then normally again after one more restart. Turn off AHCI again if Windows still doesn't start.")?;
+        }
+        if let Some(plan) = shrink_plan.filter(ShrinkPlan::needs_shrinking) {
+            add_paragraph(&format!("{} This happens before rebooting, and can take a few minutes.", plan.describe()))?;
+        }
+
+        xaml_container.update_layout()?;
+
+        Ok(WizardStep::RebootChecklist {
             container: xaml_container,
-            usb_list, _watcher: watcher
+            checks,
         })
     }
 
@@ -263,10 +1603,14 @@ impl WizardStep {
         })?;
         let join_handle = {
             let el_proxy = el_proxy.clone();
+            let complete_proxy = el_proxy.clone();
             download_iso(move |cur_prog, total_bytes| {
                 el_proxy.send_event(WizardEvent::SetProgress(cur_prog, total_bytes)).unwrap();
-            }, move |_res| {
-                // Whatever.
+            }, move |res| {
+                match res {
+                    Ok(image) => complete_proxy.send_event(WizardEvent::GoToStep4(image)).unwrap(),
+                    Err(()) => eprintln!("Failed to download the ISO"),
+                }
             })
         };
 
@@ -280,122 +1624,207 @@ impl WizardStep {
         })
     }
 
-    pub fn add_usb_device(&self, device: &DeviceNameId) -> winrt::Result<()> {
-        if let WizardStep::Step2 { container, usb_list, .. } = self {
-            usb_list.items()?.append(Object::from(make_tb(&format!("{} ({})", device.path, device.name))?))?;
+    pub fn step4(el_proxy: EventLoopProxy<WizardEvent>, image: Image, target: &DiskInfo, policy: SafetyPolicy, options: WriteOptions, boot_options: BootOptions, autoinstall: Option<Autoinstall>, expected_sha256: Option<&'static str>, reboot_hint: Option<&'static str>) -> winrt::Result<WizardStep> {
+        let xaml_container = winrt::factory::<RelativePanel, IRelativePanelFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let grey_brush = SolidColorBrush::new()?;
+        grey_brush.set_color(Color { r: 0x5e, g: 0x27, b: 0x50, a: 255})?;
+        xaml_container.set_background(grey_brush)?;
+
+        let title = make_tb(&format!("Writing to {}", target.display_name()))?;
+        title.set_font_size(48.)?;
+        RelativePanel::set_align_horizontal_center_with_panel(&title, true)?;
+        title.set_margin(Thickness {
+            top: 10., ..Thickness::default()
+        })?;
+        xaml_container.children()?.append(&title)?;
+
+        let progress_bar = winrt::factory::<ProgressBar, IProgressBarFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        RelativePanel::set_below(&progress_bar, Object::from(title))?;
+        RelativePanel::set_align_left_with_panel(&progress_bar, true)?;
+        RelativePanel::set_align_right_with_panel(&progress_bar, true)?;
+        progress_bar.set_is_indeterminate(false)?;
+        progress_bar.set_margin(Thickness {
+            top: 10., left: 10., right: 10., ..Thickness::default()
+        })?;
+        xaml_container.children()?.append(&progress_bar)?;
+
+        let status = make_tb("Do not unplug the USB flash drive.")?;
+        status.set_text_wrapping(TextWrapping::Wrap)?;
+        status.set_margin(Thickness {
+            top: 10., left: 10., right: 10., bottom: 10.,
+        })?;
+        RelativePanel::set_below(&status, Object::from(progress_bar.clone()))?;
+        xaml_container.children()?.append(&status)?;
+
+        let reboot_btn = winrt::factory::<Button, IButtonFactory>()?.create_instance(Object::default(), &mut Object::default())?;
+        let reboot_s: Object = PropertyValue::create_string("Reboot on the USB flash drive")?.into();
+        reboot_btn.set_content(reboot_s)?;
+        reboot_btn.set_margin(Thickness {
+            top: 0., left: 0., right: 10., bottom: 10.
+        })?;
+        // Until the drive is ready.
+        reboot_btn.set_is_enabled(false)?;
+        {
+            let el_proxy = el_proxy.clone();
+            reboot_btn.click(RoutedEventHandler::new(move |_, _| {
+                el_proxy.send_event(WizardEvent::RebootChecklist).unwrap();
+                Ok(())
+            }))?;
+        }
+        RelativePanel::set_align_bottom_with_panel(&reboot_btn, true)?;
+        RelativePanel::set_align_right_with_panel(&reboot_btn, true)?;
+        xaml_container.children()?.append(&reboot_btn)?;
+
+        let join_handle = {
+            let el_proxy = el_proxy.clone();
+            let complete_proxy = el_proxy.clone();
+            write_image(image.path(), target.clone(), policy, options, boot_options, autoinstall, expected_sha256, move |progress| {
+                el_proxy.send_event(progress).unwrap();
+            }, move |res| {
+                complete_proxy.send_event(WizardEvent::WriteFinished(res)).unwrap();
+            })
+        };
+
+        xaml_container.update_layout()?;
+
+        Ok(WizardStep::Step4 {
+            container: xaml_container,
+            _handle: join_handle,
+            _image: image,
+            progress_bar,
+            status,
+            reboot_btn,
+            reboot_hint,
+        })
+    }
+
+    fn selected_device(&self) -> winrt::Result<Option<DiskInfo>> {
+        if let WizardStep::Step2 { usb_list, devices, shown, .. } = self {
+            let idx = usb_list.selected_index()?;
+            if idx >= 0 {
+                return Ok(shown.get(idx as usize).map(|idx| devices[*idx].clone()));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn add_usb_device(&mut self, device: DiskInfo) -> winrt::Result<()> {
+        if let WizardStep::Step2 { devices, .. } = self {
+            // Disks with several volumes show up once per volume.
+            if devices.iter().any(|v| v.device_number == device.device_number) {
+                return Ok(());
+            }
+            devices.push(device);
+        }
+        self.refresh_usb_list()
+    }
+
+    fn policy(&self) -> SafetyPolicy {
+        match self {
+            WizardStep::Step2 { policy, .. } => *policy,
+            _ => SafetyPolicy::default(),
+        }
+    }
+
+    pub fn set_show_all_disks(&mut self, show_all: bool) -> winrt::Result<()> {
+        if let WizardStep::Step2 { policy, .. } = self {
+            policy.show_all_disks = show_all;
+        }
+        self.refresh_usb_list()
+    }
+
+    fn refresh_usb_list(&mut self) -> winrt::Result<()> {
+        if let WizardStep::Step2 { container, usb_list, devices, shown, policy, image_name, image_size, .. } = self {
+            let items = usb_list.items()?;
+            items.clear()?;
+            shown.clear();
+            for (idx, device) in devices.iter().enumerate() {
+                let refusal = policy.check(device).err();
+                if refusal.is_some() && !policy.show_all_disks {
+                    continue;
+                }
+                // Overridden refusals stay as warnings.
+                let warnings = match refusal {
+                    Some(refusal) => vec![refusal],
+                    None => policy.overridden(device),
+                };
+                let item = make_usb_entry(device, image_name, *image_size, &warnings)?;
+                // Refused drives and drives too small for the image are still
+                // listed when showing all disks, so the user understands why
+                // their drive can't be picked.
+                item.set_is_enabled(refusal.is_none() && device.can_hold(*image_size))?;
+                items.append(Object::from(item))?;
+                shown.push(idx);
+            }
             container.update_layout()?;
         }
         Ok(())
     }
 
     pub fn set_progress(&self, cur: u64, total: Option<u64>) -> winrt::Result<()> {
-        if let WizardStep::Step3 { container, progress_bar, .. } = self {
-            progress_bar.set_value(cur as f64)?;
-            if let Some(v) = total {
-                progress_bar.set_maximum(v as f64)?;
+        match self {
+            WizardStep::Step3 { container, progress_bar, .. } | WizardStep::Step4 { container, progress_bar, .. } => {
+                progress_bar.set_value(cur as f64)?;
+                if let Some(v) = total {
+                    progress_bar.set_maximum(v as f64)?;
+                }
+                container.update_layout()?;
             }
-            container.update_layout()?;
+            _ => (),
         }
         Ok(())
     }
 
-    fn top_level(&self) -> UIElement {
+    pub fn set_error(&self, text: &str) -> winrt::Result<()> {
         match self {
-            WizardStep::Step1 { ref container } => container.into(),
-            WizardStep::Step2 { ref container, .. } => container.into(),
-            WizardStep::Step3 { ref container, .. } => container.into(),
+            WizardStep::BootOptions { container, error } | WizardStep::AutoinstallIdentity { container, error } | WizardStep::AutoinstallSystem { container, error } => {
+                error.set_text(text)?;
+                container.update_layout()?;
+            }
+            _ => (),
         }
+        Ok(())
     }
-}
 
-#[derive(Debug)]
-pub struct DeviceNameId {
-    id: String,
-    name: String,
-    path: String,
-}
+    pub fn set_write_status(&self, text: &str) -> winrt::Result<()> {
+        if let WizardStep::Step4 { status, .. } = self {
+            status.set_text(text)?;
+        }
+        Ok(())
+    }
 
-impl DeviceNameId {
-    fn new(info: &DeviceInformation) -> winrt::Result<Option<DeviceNameId>> {
-        let id = info.id()?;
-        let mut id_with_backslash = Vec::from(id.as_wide());
-        id_with_backslash.extend(&*'\\'.encode_utf16(&mut [0; 2]));
-        id_with_backslash.extend(&*'\0'.encode_utf16(&mut [0; 2]));
-        println!("id: {:?}", id);
-        // TODO: My own error.
-        let file = std::fs::File::open(String::from_utf16_lossy(id.as_wide())).unwrap();
-        unsafe {
-            let volume_name = &mut [0; MAX_PATH + 1];
-            to_win32_err(winapi::um::fileapi::GetVolumeInformationByHandleW(
-                file.as_raw_handle(),
-                volume_name.as_mut_ptr(),
-                volume_name.len() as u32,
-                ptr::null_mut(),
-                ptr::null_mut(),
-                ptr::null_mut(),
-                ptr::null_mut(),
-                0,
-            )).unwrap();
-            let name = String::from_utf16_lossy(&volume_name[..volume_name.iter().position(|v| *v == 0).unwrap_or(volume_name.len())]);
-            println!("Name: {}", name);
-
-            to_win32_err(winapi::um::fileapi::GetVolumeNameForVolumeMountPointW(
-                id_with_backslash.as_ptr(),
-                volume_name.as_mut_ptr(),
-                volume_name.len() as u32,
-            )).unwrap();
-            println!("GUID: {:?}", String::from_utf16_lossy(&volume_name[..volume_name.iter().position(|v| *v == 0).unwrap_or(volume_name.len())]));
-
-            let names = &mut [0; MAX_PATH * 4];
-            let mut char_count = 0;
-            to_win32_err(GetVolumePathNamesForVolumeNameW(
-                volume_name.as_ptr(),
-                names.as_mut_ptr(),
-                names.len() as u32,
-                &mut char_count
-            )).unwrap();
-
-            let names = &names[..char_count as usize];
-            while !names.is_empty() {
-                let end_pos = names.iter().position(|v| *v == 0).unwrap_or(names.len());
-                let drive_letter = String::from_utf16_lossy(&names[..end_pos]);
-                if drive_letter != "" {
-                    return Ok(Some(DeviceNameId {
-                        id: id.to_string(), name, path: drive_letter
-                    }));
-                }
+    pub fn set_write_result(&self, res: Result<(), String>) -> winrt::Result<()> {
+        if let WizardStep::Step4 { container, status, reboot_btn, reboot_hint, .. } = self {
+            match res {
+                Ok(()) => match reboot_hint {
+                    Some(hint) => status.set_text(format!("Your USB flash drive is ready. {}", hint))?,
+                    None => {
+                        status.set_text("Your USB flash drive is ready.")?;
+                        reboot_btn.set_is_enabled(true)?;
+                    }
+                },
+                Err(err) => status.set_text(format!("Failed to write the USB flash drive: {}", err))?,
             }
-            Ok(None)
+            container.update_layout()?;
         }
+        Ok(())
     }
-}
 
-fn to_win32_err_arg(err: u32) -> Result<u32, String> {
-    if err == 0 {
-        unsafe {
-            let err = GetLastError();
-            let mut buffer: LPWSTR = ptr::null_mut();
-            let strlen = FormatMessageW(FORMAT_MESSAGE_FROM_SYSTEM |
-                FORMAT_MESSAGE_ALLOCATE_BUFFER |
-                FORMAT_MESSAGE_IGNORE_INSERTS,
-                ptr::null(),
-                err,
-                0,
-                (&mut buffer as *mut LPWSTR) as LPWSTR,
-                0,
-                ptr::null_mut());
-
-            // Get the buffer as a wide string
-            let msg = U16String::from_ptr(buffer, strlen as usize);
-
-            // Since U16String creates an owned copy, it's safe to free original buffer now
-            // If you didn't want an owned copy, you could use &U16Str.
-            LocalFree(buffer as HLOCAL);
-
-            Err(msg.to_string_lossy())
-        }
-    } else {
-        Ok(err)
+    fn top_level(&self) -> UIElement {
+        match self {
+            WizardStep::Step1 { ref container } => container.into(),
+            WizardStep::ConfirmImage { ref container } => container.into(),
+            WizardStep::Step2 { ref container, .. } => container.into(),
+            WizardStep::BootOptions { ref container, .. } => container.into(),
+            WizardStep::AutoinstallIdentity { ref container, .. } => container.into(),
+            WizardStep::AutoinstallSystem { ref container, .. } => container.into(),
+            WizardStep::Step3 { ref container, .. } => container.into(),
+            WizardStep::Step4 { ref container, .. } => container.into(),
+            WizardStep::RebootChecklist { ref container, .. } => container.into(),
+            WizardStep::Compatibility { ref container } => container.into(),
+            WizardStep::MeasuringFiles { ref container } => container.into(),
+            WizardStep::Migration { ref container } => container.into(),
+        }
     }
 }
 
@@ -403,15 +1832,15 @@ fn to_win32_err_arg(err: u32) -> Result<u32, String> {
 fn download_iso<ProgCb, ComplCb>(mut progress_cb: ProgCb, mut complete_cb: ComplCb) -> JoinHandle<()>
 where
     ProgCb: FnMut(u64, Option<u64>) + Send + 'static,
-    ComplCb: FnMut(Result<PathBuf, ()>) + Send + 'static,
+    ComplCb: FnMut(Result<TempPath, ()>) + Send + 'static,
 {
-    // TODO: Find an URL through the RSS feed https://launchpad.net/ubuntu/+cdmirrors-rss
-    const URL: &'static str = "https://mirrors.melbourne.co.uk/ubuntu-releases/20.04/ubuntu-20.04-desktop-amd64.iso";
+    let url = release::default_release().url;
     std::thread::spawn(move || {
         let mut rt = tokio::runtime::Runtime::new().unwrap();
         rt.block_on(async {
-            let mut file = File::from_std(tempfile::tempfile().unwrap());
-            let resp = reqwest::get(URL)
+            let (file, path) = tempfile::Builder::new().suffix(".iso").tempfile().unwrap().into_parts();
+            let mut file = File::from_std(file);
+            let resp = reqwest::get(url)
                 .await
                 .unwrap();
 
@@ -425,7 +1854,8 @@ where
                     current_len += val.len();
                     progress_cb(current_len as u64, content_len);
                 }
-                //complete_cb(Ok(file))
+                file.flush().await.unwrap();
+                complete_cb(Ok(path))
             } else {
                 complete_cb(Err(()))
             }
@@ -433,10 +1863,131 @@ where
     })
 }
 
-fn to_win32_err(err: i32) -> Result<(), String> {
-    to_win32_err_arg(err as u32).map(|_| ())
+// Makes sure the drive is still the one that was picked, and still one we
+// may wipe, right before opening it.
+fn open_target(picked: &DiskInfo, policy: &SafetyPolicy) -> Result<PhysicalDrive, String> {
+    let gone = "The USB flash drive was unplugged.";
+    let current = DiskInfo::query(&picked.id).map_err(|_| gone.to_string())?.ok_or_else(|| gone.to_string())?;
+    policy.recheck(picked, &current).map_err(|err| err.to_string())?;
+    let mut drive = PhysicalDrive::open(current.device_number).map_err(|err| err.to_string())?;
+    if drive.size().map_err(|err| err.to_string())? != picked.size {
+        return Err(Refusal::Changed.to_string());
+    }
+    Ok(drive)
+}
+
+// The progress callback receives CheckProgress, WriteProgress, VerifyProgress,
+// CreatingSeed and CreatingPersistence events.
+fn write_image<ProgCb, ComplCb>(image: &Path, target: DiskInfo, policy: SafetyPolicy, options: WriteOptions, boot_options: BootOptions, autoinstall: Option<Autoinstall>, expected_sha256: Option<&'static str>, mut progress_cb: ProgCb, mut complete_cb: ComplCb) -> JoinHandle<()>
+where
+    ProgCb: FnMut(WizardEvent) + Send + 'static,
+    ComplCb: FnMut(Result<(), String>) + Send + 'static,
+{
+    let image_path = image.to_path_buf();
+    std::thread::spawn(move || {
+        let res = (|| -> Result<(), String> {
+            if options.mode == WriteMode::Files && options.persistence {
+                return Err("Persistence requires writing the image as is.".to_string());
+            }
+            let image_len = std::fs::metadata(&image_path).map_err(|err| err.to_string())?.len();
+            let seed = match &autoinstall {
+                Some(config) => Some(Seed::new(config)?),
+                None => None,
+            };
+
+            if options.mode == WriteMode::Files {
+                let replaced = std::fs::File::open(&image_path)
+                    .and_then(Iso::open)
+                    .and_then(|mut iso| bootcfg::patched_files(&mut iso, &boot_options))
+                    .map_err(|err| err.to_string())?;
+                let mut target = open_target(&target, &policy)?;
+                // There's nothing to compare sector by sector in this mode,
+                // but the files can be checked against md5sum.txt.
+                let image = std::fs::File::open(&image_path).map_err(|err| err.to_string())?;
+                let reserved = if seed.is_some() { Seed::partition_size(target.sector_size()) } else { 0 };
+                let files = filecopy::write_files(image, &mut target, &replaced, reserved, |cur, total| {
+                    progress_cb(WizardEvent::WriteProgress(cur, total))
+                }).map_err(|err| err.to_string())?;
+                if let Some(seed) = &seed {
+                    progress_cb(WizardEvent::CreatingSeed);
+                    seed.add_partition(&mut target, 0).map_err(|err| err.to_string())?;
+                }
+                if options.verify {
+                    let patched = replaced.into_iter().map(|v| v.0).collect();
+                    verify::check_md5sums(&mut DeviceFiles { target: &mut target, files, patched }, |cur, total| {
+                        progress_cb(WizardEvent::VerifyProgress(cur, total))
+                    }).map_err(|err| err.to_string())?;
+                }
+                return Ok(());
+            }
+
+            let patch = std::fs::File::open(&image_path)
+                .and_then(|image| ImagePatch::new(image, image_len, &boot_options))
+                .map_err(|err| err.to_string())?;
+            let open_image = || std::fs::File::open(&image_path).map(|image| patch.reader(image, image_len));
+
+            // Catch a corrupted image before wiping the drive for it, and
+            // before locking the drive, as this can take a while. Not every
+            // image is an ISO9660 one, so only its files are checked.
+            if options.verify {
+                let iso = std::fs::File::open(&image_path).and_then(Iso::open).and_then(IsoFiles::new);
+                if let Ok(mut files) = iso {
+                    verify::check_md5sums(&mut files, |cur, total| {
+                        progress_cb(WizardEvent::CheckProgress(cur, total))
+                    }).map_err(|err| err.to_string())?;
+                }
+            }
+            let mut target = open_target(&target, &policy)?;
+            if seed.is_some() || options.persistence {
+                // Check the image as it's going to be written, as moving the
+                // patched files past its end grows the ISO partition.
+                let disk_size = target.size().map_err(|err| err.to_string())?;
+                let sector_size = target.sector_size();
+                let mut image = open_image().map_err(|err| err.to_string())?;
+                if seed.is_some() {
+                    Seed::check_image(&mut image, patch.len, sector_size, disk_size).map_err(|err| err.to_string())?;
+                }
+                if options.persistence {
+                    persistence::check_image(&mut image, patch.len, sector_size, disk_size).map_err(|err| err.to_string())?;
+                }
+            }
+
+            let mut image = open_image().map_err(|err| err.to_string())?;
+            writer::write_image(&mut image, patch.len, &mut target, |cur, total| {
+                progress_cb(WizardEvent::WriteProgress(cur, total))
+            }).map_err(|err| err.to_string())?;
+
+            if options.verify {
+                let mut image = open_image().map_err(|err| err.to_string())?;
+                let expected_sha256 = if patch.is_empty() { expected_sha256 } else { None };
+                verify::verify(&mut image, patch.len, &mut target, expected_sha256, |cur, total| {
+                    progress_cb(WizardEvent::VerifyProgress(cur, total))
+                }).map_err(|err| err.to_string())?;
+            }
+
+            // Before persistence, which takes whatever space is left.
+            if let Some(seed) = &seed {
+                progress_cb(WizardEvent::CreatingSeed);
+                seed.add_partition(&mut target, patch.len).map_err(|err| err.to_string())?;
+            }
+            if options.persistence {
+                progress_cb(WizardEvent::CreatingPersistence);
+                let version = std::fs::File::open(&image_path)
+                    .and_then(Iso::open)
+                    .and_then(|mut iso| release::identify(&mut iso))
+                    .map(|info| info.map(|v| v.version))
+                    .unwrap_or(None);
+                persistence::add_partition(&mut target, patch.len, persistence::label(version.as_deref())).map_err(|err| err.to_string())?;
+            }
+            Ok(())
+        })();
+        complete_cb(res)
+    })
 }
 
+// Devices implementing GUID_DEVINTERFACE_VOLUME.
+const VOLUME_AQS_FILTER: &str = "System.Devices.InterfaceClassGuid:=\"{53f5630d-b6bf-11d0-94f2-00a0c91efb8b}\" AND System.Devices.InterfaceEnabled:=System.StructuredQueryType.Boolean#True";
+
 #[link(name = "user32")]
 extern "stdcall" {
     fn UpdateWindow(
@@ -446,9 +1997,56 @@ extern "stdcall" {
 
 #[derive(Debug)]
 pub enum WizardEvent {
+    CheckCompatibility,
     GoToStep2,
-    UsbDeviceFound(DeviceNameId),
-    // TODO: Selected Device
+    PickImage,
+    UsbDeviceFound(DiskInfo),
+    ShowAllDisks(bool),
+    VerifyAfterWriting(bool),
+    Persistence(bool),
+    CopyFiles(bool),
+    CustomizeBoot(bool),
+    Nomodeset(bool),
+    Autoinstall(bool),
+    SerialConsole(bool),
+    SeedUrl(String),
+    CreateAutoinstall(bool),
+    AutoinstallField(AutoinstallField, String),
+    InstallSshServer(bool),
+    UseLvm(bool),
+    InstallAlongsideWindows(bool),
+    MigrateWifi(bool),
+    MigrateFiles(bool),
+    UserFilesMeasured(Result<(Manifest, Vec<String>), String>),
+    SaveUserData,
     GoToStep3,
     SetProgress(u64, Option<u64>),
-}
\ No newline at end of file
+    GoToStep4(TempPath),
+    CheckProgress(u64, u64),
+    WriteProgress(u64, u64),
+    VerifyProgress(u64, u64),
+    CreatingSeed,
+    CreatingPersistence,
+    WriteFinished(Result<(), String>),
+    RebootChecklist,
+    SuspendBitLocker(bool),
+    DisableFastStartup(bool),
+    PrepareAhciSwitch(bool),
+    RebootToDrive,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AutoinstallField {
+    Hostname,
+    RealName,
+    Username,
+    Password,
+    PasswordConfirm,
+    Locale,
+    KeyboardLayout,
+    KeyboardVariant,
+    Timezone,
+    SshKeys,
+    Packages,
+    LateCommands,
+}
@@ -0,0 +1,432 @@
+use std::collections::hash_map::RandomState;
+use std::fs::File;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+
+// Writes happen in chunks of this size. Must be a multiple of any sector size
+// we can come across.
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+// Something an image can be written to: a physical disk on Windows, or a
+// plain file (or loop device) everywhere else.
+pub trait Target: Read + Write + Seek {
+    // Every read and write must be aligned on, and a multiple of, this size.
+    fn sector_size(&self) -> u64;
+    fn size(&mut self) -> io::Result<u64>;
+
+    // Wipes the MBR and both GPTs. Isohybrid images don't cover the end of
+    // the disk, and a leftover backup GPT there confuses firmwares.
+    fn clear_partition_table(&mut self) -> io::Result<()> {
+        zero_partition_tables(self)
+    }
+
+    // Makes the OS pick up the new partition table.
+    fn rescan(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// The GPT takes at most 34 sectors at the start of the disk, and 33 at its
+// end. Zeroing a whole MiB on each side covers 4K sector disks as well.
+const PARTITION_TABLE_AREA: u64 = 1024 * 1024;
+
+pub fn zero_partition_tables<T: Target + ?Sized>(target: &mut T) -> io::Result<()> {
+    let size = target.size()?;
+    let area = std::cmp::min(PARTITION_TABLE_AREA, size);
+    let zeroes = AlignedBuffer::new(area as usize, target.sector_size() as usize);
+
+    target.seek(SeekFrom::Start(0))?;
+    target.write_all(&zeroes)?;
+    target.seek(SeekFrom::Start(size - area))?;
+    target.write_all(&zeroes)?;
+    target.flush()
+}
+
+impl Target for File {
+    fn sector_size(&self) -> u64 {
+        512
+    }
+
+    // Seeking works for block devices too, unlike the metadata's length.
+    fn size(&mut self) -> io::Result<u64> {
+        let pos = self.seek(SeekFrom::Current(0))?;
+        let size = self.seek(SeekFrom::End(0))?;
+        self.seek(SeekFrom::Start(pos))?;
+        Ok(size)
+    }
+}
+
+// Unbuffered I/O requires the buffer's address to be aligned on the sector
+// size too.
+pub struct AlignedBuffer {
+    storage: Vec<u8>,
+    offset: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    pub fn new(len: usize, align: usize) -> AlignedBuffer {
+        let storage = vec![0; len + align];
+        let offset = storage.as_ptr().align_offset(align);
+        AlignedBuffer { storage, offset, len }
+    }
+}
+
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.storage[self.offset..self.offset + self.len]
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.storage[self.offset..self.offset + self.len]
+    }
+}
+
+// Reads until the buffer is full or the reader is exhausted.
+pub fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+pub fn round_up(val: u64, align: u64) -> u64 {
+    (val + align - 1) / align * align
+}
+
+// Good enough for UUIDs and volume serial numbers: RandomState gets seeded
+// from the OS.
+pub fn random_bytes(buf: &mut [u8]) {
+    for (i, chunk) in buf.chunks_mut(8).enumerate() {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_usize(i);
+        chunk.copy_from_slice(&hasher.finish().to_le_bytes()[..chunk.len()]);
+    }
+}
+
+// Writes `image_len` bytes of `image` at the start of `target`, after wiping
+// its partition tables. The last sector gets padded with zeroes.
+pub fn write_image<R, T, F>(image: &mut R, image_len: u64, target: &mut T, mut progress: F) -> io::Result<()>
+where
+    R: Read,
+    T: Target,
+    F: FnMut(u64, u64),
+{
+    let sector_size = target.sector_size();
+    if round_up(image_len, sector_size) > target.size()? {
+        return Err(io::Error::new(io::ErrorKind::Other, "The image is bigger than the target disk"));
+    }
+
+    target.clear_partition_table()?;
+
+    target.seek(SeekFrom::Start(0))?;
+    let mut buf = AlignedBuffer::new(CHUNK_SIZE, sector_size as usize);
+    let mut written = 0;
+    while written < image_len {
+        let to_read = std::cmp::min(CHUNK_SIZE as u64, image_len - written) as usize;
+        let read = read_full(image, &mut buf[..to_read])?;
+        if read == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The image is shorter than expected"));
+        }
+        let to_write = round_up(read as u64, sector_size) as usize;
+        for byte in &mut buf[read..to_write] {
+            *byte = 0;
+        }
+        target.write_all(&buf[..to_write])?;
+        written += read as u64;
+        progress(written, image_len);
+    }
+    target.flush()?;
+    target.rescan()
+}
+
+#[cfg(windows)]
+pub use self::win::PhysicalDrive;
+
+#[cfg(windows)]
+mod win {
+    use super::{AlignedBuffer, Target, CHUNK_SIZE};
+    use crate::disk::volume_disks;
+    use crate::win32::{from_wide, ioctl, ioctl_out};
+
+    use winapi::shared::minwindef::MAX_PATH;
+    use winapi::um::fileapi::{FindFirstVolumeW, FindNextVolumeW, FindVolumeClose};
+    use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+    use winapi::um::winbase::{FILE_FLAG_NO_BUFFERING, FILE_FLAG_WRITE_THROUGH};
+    use winapi::um::winioctl::{DISK_GEOMETRY_EX, FSCTL_DISMOUNT_VOLUME, FSCTL_LOCK_VOLUME, IOCTL_DISK_DELETE_DRIVE_LAYOUT, IOCTL_DISK_GET_DRIVE_GEOMETRY_EX, IOCTL_DISK_UPDATE_PROPERTIES};
+    use winapi::um::winnt::{FILE_SHARE_READ, FILE_SHARE_WRITE, GENERIC_READ, GENERIC_WRITE};
+
+    use std::fs::{File, OpenOptions};
+    use std::io::{self, Read, Seek, SeekFrom, Write};
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::thread;
+    use std::time::Duration;
+
+    pub struct PhysicalDrive {
+        file: File,
+        // Locked volumes. The lock is released when the handle is closed.
+        _volumes: Vec<File>,
+        sector_size: u64,
+        size: u64,
+        // Unbuffered I/O from or to unaligned buffers goes through here.
+        bounce: AlignedBuffer,
+    }
+
+    impl PhysicalDrive {
+        // Opens the disk for raw writing, after locking and dismounting every
+        // volume on it so Windows doesn't get in the way.
+        pub fn open(device_number: u32) -> io::Result<PhysicalDrive> {
+            let mut volumes = Vec::new();
+            for volume_path in list_volumes()? {
+                match volume_disks(&volume_path) {
+                    Ok(ref disks) if disks.contains(&device_number) => (),
+                    _ => continue,
+                }
+                let volume = open_rw(&volume_path, 0)?;
+                lock_volume(&volume)?;
+                ioctl(&volume, FSCTL_DISMOUNT_VOLUME, &[], &mut [])?;
+                volumes.push(volume);
+            }
+
+            let file = open_rw(&format!(r"\\.\PhysicalDrive{}", device_number), FILE_FLAG_NO_BUFFERING | FILE_FLAG_WRITE_THROUGH)?;
+            let geometry: DISK_GEOMETRY_EX = ioctl_out(&file, IOCTL_DISK_GET_DRIVE_GEOMETRY_EX, &[])?;
+
+            Ok(PhysicalDrive {
+                file,
+                _volumes: volumes,
+                sector_size: geometry.Geometry.BytesPerSector as u64,
+                size: unsafe { *geometry.DiskSize.QuadPart() } as u64,
+                bounce: AlignedBuffer::new(CHUNK_SIZE, geometry.Geometry.BytesPerSector as usize),
+            })
+        }
+    }
+
+    impl PhysicalDrive {
+        // For reading the partition table, while Windows keeps using the
+        // disk.
+        pub fn open_read_only(device_number: u32) -> io::Result<PhysicalDrive> {
+            let file = OpenOptions::new()
+                .access_mode(GENERIC_READ)
+                .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE)
+                .open(format!(r"\\.\PhysicalDrive{}", device_number))?;
+            let geometry: DISK_GEOMETRY_EX = ioctl_out(&file, IOCTL_DISK_GET_DRIVE_GEOMETRY_EX, &[])?;
+            Ok(PhysicalDrive {
+                file,
+                _volumes: Vec::new(),
+                sector_size: geometry.Geometry.BytesPerSector as u64,
+                size: unsafe { *geometry.DiskSize.QuadPart() } as u64,
+                bounce: AlignedBuffer::new(CHUNK_SIZE, geometry.Geometry.BytesPerSector as usize),
+            })
+        }
+    }
+
+    fn open_rw(path: &str, flags: u32) -> io::Result<File> {
+        OpenOptions::new()
+            .access_mode(GENERIC_READ | GENERIC_WRITE)
+            .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE)
+            .custom_flags(flags)
+            .open(path)
+    }
+
+    // Explorer and the indexer like to keep files open on freshly plugged
+    // drives, so give them a chance to let go.
+    fn lock_volume(volume: &File) -> io::Result<()> {
+        let mut tries = 0;
+        loop {
+            match ioctl(volume, FSCTL_LOCK_VOLUME, &[], &mut []) {
+                Ok(_) => return Ok(()),
+                Err(_) if tries < 10 => {
+                    tries += 1;
+                    thread::sleep(Duration::from_millis(500));
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    // Device paths of every volume on the system, e.g.
+    // \\?\Volume{4c1b02c1-d990-11dc-99ae-806e6f6e6963}.
+    fn list_volumes() -> io::Result<Vec<String>> {
+        let mut volumes = Vec::new();
+        let mut name = [0; MAX_PATH + 1];
+        unsafe {
+            let handle = FindFirstVolumeW(name.as_mut_ptr(), name.len() as u32);
+            if handle == INVALID_HANDLE_VALUE {
+                return Err(io::Error::last_os_error());
+            }
+            loop {
+                // Opening the volume requires dropping the trailing backslash.
+                volumes.push(from_wide(&name).trim_end_matches('\\').to_string());
+                if FindNextVolumeW(handle, name.as_mut_ptr(), name.len() as u32) == 0 {
+                    break;
+                }
+            }
+            FindVolumeClose(handle);
+        }
+        Ok(volumes)
+    }
+
+    impl PhysicalDrive {
+        fn is_aligned(&self, buf: &[u8]) -> bool {
+            buf.as_ptr() as usize % self.sector_size as usize == 0
+        }
+    }
+
+    impl Read for PhysicalDrive {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.is_aligned(buf) {
+                return self.file.read(buf);
+            }
+            let len = std::cmp::min(buf.len(), self.bounce.len());
+            let read = self.file.read(&mut self.bounce[..len])?;
+            buf[..read].copy_from_slice(&self.bounce[..read]);
+            Ok(read)
+        }
+    }
+
+    impl Write for PhysicalDrive {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.is_aligned(buf) {
+                return self.file.write(buf);
+            }
+            let len = std::cmp::min(buf.len(), self.bounce.len());
+            self.bounce[..len].copy_from_slice(&buf[..len]);
+            self.file.write(&self.bounce[..len])
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.file.sync_all()
+        }
+    }
+
+    impl Seek for PhysicalDrive {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.file.seek(pos)
+        }
+    }
+
+    impl Target for PhysicalDrive {
+        fn sector_size(&self) -> u64 {
+            self.sector_size
+        }
+
+        fn size(&mut self) -> io::Result<u64> {
+            Ok(self.size)
+        }
+
+        // Windows knows how to wipe both GPTs.
+        fn clear_partition_table(&mut self) -> io::Result<()> {
+            ioctl(&self.file, IOCTL_DISK_DELETE_DRIVE_LAYOUT, &[], &mut [])?;
+            Ok(())
+        }
+
+        fn rescan(&mut self) -> io::Result<()> {
+            ioctl(&self.file, IOCTL_DISK_UPDATE_PROPERTIES, &[], &mut [])?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(file: &mut File) -> Vec<u8> {
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|v| (v * 7 + v / 4096) as u8).collect()
+    }
+
+    #[test]
+    fn file_sizes() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(3 << 20).unwrap();
+        file.seek(SeekFrom::Start(1000)).unwrap();
+        assert_eq!(file.size().unwrap(), 3 << 20);
+        assert_eq!(file.seek(SeekFrom::Current(0)).unwrap(), 1000);
+        assert_eq!(file.sector_size(), 512);
+    }
+
+    #[test]
+    fn writes_images() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(8 << 20).unwrap();
+        // Over several chunks, and not a whole number of sectors.
+        let image = pattern((2 << 20) + 1000);
+        let mut progress = Vec::new();
+        write_image(&mut &image[..], image.len() as u64, &mut file, |cur, total| progress.push((cur, total))).unwrap();
+
+        let data = read_all(&mut file);
+        assert_eq!(data.len(), 8 << 20);
+        assert_eq!(&data[..image.len()], &image[..]);
+        // The last sector gets padded.
+        assert!(data[image.len()..round_up(image.len() as u64, 512) as usize].iter().all(|&v| v == 0));
+        assert_eq!(progress.last(), Some(&(image.len() as u64, image.len() as u64)));
+        assert_eq!(progress.len(), 3);
+    }
+
+    #[test]
+    fn wipes_the_backup_gpt() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(&vec![0xAA; 8 << 20]).unwrap();
+        let image = pattern(1 << 20);
+        write_image(&mut &image[..], image.len() as u64, &mut file, |_, _| ()).unwrap();
+
+        let data = read_all(&mut file);
+        assert_eq!(&data[..1 << 20], &image[..]);
+        assert!(data[(1 << 20)..(7 << 20)].iter().all(|&v| v == 0xAA));
+        assert!(data[(7 << 20)..].iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn refuses_images_too_big() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(1 << 20).unwrap();
+        let image = pattern((1 << 20) + 1);
+        assert!(write_image(&mut &image[..], image.len() as u64, &mut file, |_, _| ()).is_err());
+        // Nothing got touched.
+        assert!(read_all(&mut file).iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn catches_short_images() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.set_len(4 << 20).unwrap();
+        let image = pattern(1 << 20);
+        let err = write_image(&mut &image[..], 2 << 20, &mut file, |_, _| ()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn aligns_buffers() {
+        for &align in &[512, 4096] {
+            let buf = AlignedBuffer::new(CHUNK_SIZE, align);
+            assert_eq!(buf.as_ptr() as usize % align, 0);
+            assert_eq!(buf.len(), CHUNK_SIZE);
+        }
+    }
+
+    #[test]
+    fn reads_until_full() {
+        let data = pattern(100);
+        let mut reader = io::Read::chain(&data[..40], &data[40..]);
+        let mut buf = [0; 150];
+        assert_eq!(read_full(&mut reader, &mut buf).unwrap(), 100);
+        assert_eq!(&buf[..100], &data[..]);
+    }
+}
@@ -0,0 +1,30 @@
+#!/bin/sh
+# Makes small.iso, a tiny image laid out like an Ubuntu one, with Rock Ridge
+# and Joliet names, an EFI El Torito entry, a symlink looping back to the
+# root, and directories deeper than ISO9660 allows.
+set -e
+tree=$(mktemp -d)
+trap 'rm -rf "$tree"' EXIT
+cd "$tree"
+mkdir -p .disk casper boot/grub EFI/boot "pool/main/a b/deep/er/than/eight/levels/of/dirs"
+printf 'Ubuntu 22.04.3 LTS "Jammy Jellyfish" - Release amd64 (20230807.2)' > .disk/info
+yes vmlinuz | head -c 9000 > casper/vmlinuz
+yes squashfs | head -c 5000 > casper/filesystem.squashfs
+yes minimal | head -c 3000 > casper/minimal.squashfs
+printf 'linux\t1.0\n' > casper/filesystem.manifest
+printf 'menuentry "Try or Install Ubuntu" {\n\tlinux\t/casper/vmlinuz  ---\n}\n' > boot/grub/grub.cfg
+yes efi | head -c 2048 > EFI/boot/bootx64.efi
+printf 'hello\n' > "pool/main/a b/A File With A Long Name.txt"
+printf 'deep\n' > "pool/main/a b/deep/er/than/eight/levels/of/dirs/file"
+ln -s . ubuntu
+find . -type f | sort | sed 's|^\./||' | while read -r f; do
+    printf '%s  ./%s\n' "$(md5sum < "$f" | cut -d' ' -f1)" "$f"
+done > md5sum.txt
+bsdtar -cf "$OLDPWD/small.iso" --format iso9660 \
+    --options 'volume-id=Ubuntu 22.04.3 LTS amd64,rockridge,joliet,boot=EFI/boot/bootx64.efi,boot-type=no-emulation,!pad' .
+# bsdtar marks its boot entry as an x86 one. Make it the EFI entry it is, and
+# fix the validation entry's checksum to match. The El Torito boot record is
+# the second volume descriptor.
+catalog=$(( $(od -An -tu4 -j $((17 * 2048 + 71)) -N4 "$OLDPWD/small.iso") * 2048 ))
+printf '\357' | dd of="$OLDPWD/small.iso" bs=1 seek=$((catalog + 1)) conv=notrunc 2>/dev/null
+printf '\252\146' | dd of="$OLDPWD/small.iso" bs=1 seek=$((catalog + 28)) conv=notrunc 2>/dev/null